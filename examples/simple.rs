@@ -319,18 +319,16 @@ fn plot_2() {
     
     let mut l0 = dg::Leaf(dg::ValType::F(0.));
     let mut a = dg::Tan(l0.clone()).clone();
-    let mut dx = a.rev()
-        .get_mut(&l0)
-        .expect("l0 adjoint missing")
-        .clone();
 
-    let mut ddx = dx.rev()
-        .get_mut(&l0)
-        .expect("l0 adjoint missing").clone();
-    
-    let mut dddx = ddx.rev()
-        .get_mut(&l0)
-        .expect("l0 adjoint missing").clone();
+    //each order builds on the cached graph of the order below
+    let mut cache = dg::DerivativeCache::new();
+    let mut derivative = |order| {
+        cache.derivative(&a, &l0, order, dg::Mode::Rev)
+            .expect("l0 adjoint missing")
+    };
+    let mut dx = derivative(1);
+    let mut ddx = derivative(2);
+    let mut dddx = derivative(3);
 
     let pi = std::f32::consts::PI;
     let count = 200;
@@ -0,0 +1,289 @@
+//! export of graphs as source in other languages
+//!
+//! the generated rust function computes the value of a graph and its gradient wrt chosen
+//! leaves as straight-line code, with one binding per node of the primal and adjoint graphs,
+//! so it can be compiled into a binary that does not link the graph interpreter
+//!
+//! sympy export writes a graph or its adjoints as expressions that sympify parses, for
+//! cross-checking or simplification in a computer algebra system
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Write;
+
+use crate::core::{topo_order, PtrVWrap};
+
+/// floating point type of the generated code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Float {
+    F32,
+    F64,
+}
+
+impl Float {
+    fn name(self) -> &'static str {
+        match self {
+            Float::F32 => "f32",
+            Float::F64 => "f64",
+        }
+    }
+}
+
+/// operation without a counterpart in the target language
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodegenError {
+    /// debug representation of the operation
+    pub op: String,
+    /// construction location and annotations of the node, empty if it has none
+    pub origin: String,
+}
+
+impl fmt::Display for CodegenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "operation {}{} cannot be exported", self.op, self.origin)
+    }
+}
+
+impl std::error::Error for CodegenError {}
+
+fn literal(v: f64, float: Float) -> String {
+    let t = float.name();
+    if v.is_nan() {
+        format!("{}::NAN", t)
+    } else if v.is_infinite() {
+        let sign = if v < 0. { "NEG_" } else { "" };
+        format!("{}::{}INFINITY", t, sign)
+    } else {
+        match float {
+            Float::F32 => format!("{:?}{}", v as f32, t),
+            Float::F64 => format!("{:?}{}", v, t),
+        }
+    }
+}
+
+/// source of a function `name(x)` returning the value of output and its gradient wrt
+/// leaves, where x holds the leaf values in the given order
+///
+/// leaves not in the list are embedded with their current values, and gradients of leaves
+/// that output does not depend on are zero
+pub fn to_rust(
+    name: &str,
+    output: &PtrVWrap,
+    leaves: &[PtrVWrap],
+    float: Float,
+) -> Result<String, CodegenError> {
+    let adjoints = output.rev();
+    let grads: Vec<Option<PtrVWrap>> = leaves.iter().map(|l| adjoints.get(l).cloned()).collect();
+    let mut roots = vec![output.clone()];
+    roots.extend(grads.iter().flatten().cloned());
+
+    let slots: HashMap<PtrVWrap, usize> = leaves
+        .iter()
+        .enumerate()
+        .map(|(i, l)| (l.clone(), i))
+        .collect();
+    let mut names: HashMap<PtrVWrap, String> = HashMap::new();
+    let mut body = String::new();
+    for (k, n) in topo_order(&roots).into_iter().enumerate() {
+        let expr = if let Some(i) = slots.get(&n) {
+            format!("x[{}]", i)
+        } else if let Some(v) = n.fixed_value() {
+            literal(v.into(), float)
+        } else {
+            //arguments are always bindings, so expressions need no outer parentheses
+            let args: Vec<String> = n.inputs().iter().map(|i| names[i].clone()).collect();
+            n.rust_expr(&args).ok_or_else(|| CodegenError {
+                op: n.op_name(),
+                origin: n.origin(),
+            })?
+        };
+        writeln!(body, "    let v{} = {};", k, expr).unwrap();
+        names.insert(n, format!("v{}", k));
+    }
+
+    let t = float.name();
+    let grad_exprs: Vec<String> = grads
+        .iter()
+        .map(|g| match g {
+            Some(g) => names[g].clone(),
+            None => literal(0., float),
+        })
+        .collect();
+    let mut src = String::new();
+    writeln!(src, "/// value and gradient wrt x").unwrap();
+    writeln!(
+        src,
+        "pub fn {}(x: &[{t}; {n}]) -> ({t}, [{t}; {n}]) {{",
+        name,
+        t = t,
+        n = leaves.len()
+    )
+    .unwrap();
+    src.push_str(&body);
+    writeln!(src, "    ({}, [{}])", names[output], grad_exprs.join(", ")).unwrap();
+    src.push_str("}\n");
+    Ok(src)
+}
+
+/// whether an expression must be parenthesized when used as an operand
+fn compound(e: &str) -> bool {
+    let mut depth = 0;
+    for c in e.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ' ' | '+' | '-' | '*' | '/' if depth == 0 => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+fn sympy_literal(v: f64) -> String {
+    if v.is_nan() {
+        "nan".to_string()
+    } else if v.is_infinite() {
+        if v < 0. { "-oo" } else { "oo" }.to_string()
+    } else if (v as f32) as f64 == v {
+        format!("{:?}", v as f32)
+    } else {
+        format!("{:?}", v)
+    }
+}
+
+/// sympy expressions of the given nodes, where leaf i is the symbol x{i}
+///
+/// shared nodes are written out at every use, so expressions grow with the number of paths
+/// through the graph
+fn sympy_exprs(roots: &[PtrVWrap], leaves: &[PtrVWrap]) -> Result<Vec<String>, CodegenError> {
+    let slots: HashMap<PtrVWrap, usize> = leaves
+        .iter()
+        .enumerate()
+        .map(|(i, l)| (l.clone(), i))
+        .collect();
+    let mut exprs: HashMap<PtrVWrap, String> = HashMap::new();
+    for n in topo_order(roots) {
+        let expr = if let Some(i) = slots.get(&n) {
+            format!("x{}", i)
+        } else if let Some(v) = n.fixed_value() {
+            sympy_literal(v.into())
+        } else {
+            let args: Vec<String> = n
+                .inputs()
+                .iter()
+                .map(|i| {
+                    let e = &exprs[i];
+                    if compound(e) {
+                        format!("({})", e)
+                    } else {
+                        e.clone()
+                    }
+                })
+                .collect();
+            n.sympy_expr(&args).ok_or_else(|| CodegenError {
+                op: n.op_name(),
+                origin: n.origin(),
+            })?
+        };
+        exprs.insert(n, expr);
+    }
+    Ok(roots.iter().map(|r| exprs[r].clone()).collect())
+}
+
+/// sympy expression of output, where leaf i is the symbol x{i}
+///
+/// leaves not in the list are embedded with their current values
+pub fn to_sympy(output: &PtrVWrap, leaves: &[PtrVWrap]) -> Result<String, CodegenError> {
+    Ok(sympy_exprs(std::slice::from_ref(output), leaves)?.remove(0))
+}
+
+/// sympy expressions of the adjoint graphs of output wrt leaves, where leaf i is the
+/// symbol x{i}
+///
+/// leaves that output does not depend on get "0"
+pub fn to_sympy_grad(output: &PtrVWrap, leaves: &[PtrVWrap]) -> Result<Vec<String>, CodegenError> {
+    let adjoints = output.rev();
+    let grads: Vec<PtrVWrap> = leaves
+        .iter()
+        .filter_map(|l| adjoints.get(l).cloned())
+        .collect();
+    let mut exprs = sympy_exprs(&grads, leaves)?.into_iter();
+    Ok(leaves
+        .iter()
+        .map(|l| match adjoints.get(l) {
+            Some(_) => exprs.next().expect("adjoint expression missing"),
+            None => "0".to_string(),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+use crate::core::{Add, BesselJ0, Div, Exp, Leaf, Mul, Pow, Sin, Sinc};
+#[cfg(test)]
+use crate::valtype::ValType;
+
+#[test]
+fn test_to_rust() {
+    //y=x0*sin(x1)/exp(c)+x1 where x0=2, x1=0.5, c=0.25
+    let x0 = Leaf(ValType::F(2.));
+    let x1 = Leaf(ValType::F(0.5));
+    let c = Leaf(ValType::F(0.25));
+    let y = Add(Div(Mul(x0.clone(), Sin(x1.clone())), Exp(c)), x1.clone());
+    let src = to_rust("f", &y, &[x0.clone(), x1], Float::F64).expect("codegen failed");
+    //compiled and run separately: (1.24675, [0.37338, 2.36692])
+    assert!(src.starts_with(
+        "/// value and gradient wrt x\npub fn f(x: &[f64; 2]) -> (f64, [f64; 2]) {\n"
+    ));
+    assert!(src.contains("    let v0 = x[0];\n    let v1 = x[1];\n    let v2 = v1.sin();\n"));
+    assert!(src.contains("    let v4 = 0.25f64;\n    let v5 = v4.exp();\n"));
+    assert!(src.ends_with("    (v7, [v12, v16])\n}\n"));
+
+    //leaves the output does not depend on get a zero gradient
+    let other = Leaf(ValType::F(1.));
+    let src = to_rust("g", &x0, &[x0.clone(), other], Float::F32).expect("codegen failed");
+    assert!(src.contains("pub fn g(x: &[f32; 2]) -> (f32, [f32; 2]) {"));
+    assert!(src.ends_with("    (v0, [v1, 0.0f32])\n}\n"));
+}
+
+#[test]
+fn test_to_rust_unsupported() {
+    let x0 = Leaf(ValType::F(1.));
+    let y = BesselJ0(x0.clone());
+    let e = to_rust("f", &y, &[x0], Float::F32).expect_err("bessel has no rust form");
+    assert!(e.op.contains("Bessel"));
+}
+
+#[test]
+fn test_to_sympy() {
+    //y=x0*sin(x1)/exp(c)+x1**2 where c=0.25
+    let x0 = Leaf(ValType::F(2.));
+    let x1 = Leaf(ValType::F(0.5));
+    let c = Leaf(ValType::F(0.25));
+    let y = Add(
+        Div(Mul(x0.clone(), Sin(x1.clone())), Exp(c)),
+        Pow(x1.clone(), Leaf(ValType::F(2.))),
+    );
+    let leaves = [x0.clone(), x1.clone()];
+    assert_eq!(
+        to_sympy(&y, &leaves).expect("export failed"),
+        "((x0*sin(x1))/exp(0.25)) + (x1**2.0)"
+    );
+    assert_eq!(
+        to_sympy(&BesselJ0(Leaf(ValType::F(-1.))), &[]).expect("export failed"),
+        "besselj(0, (-1.0))"
+    );
+
+    let grads = to_sympy_grad(&Mul(x0.clone(), Sin(x1.clone())), &leaves).expect("export failed");
+    assert_eq!(grads.len(), 2);
+    assert_eq!(grads[0], "sin(x1)*1.0");
+    assert_eq!(grads[1], "cos(x1)*(x0*1.0)");
+    let other = Leaf(ValType::F(1.));
+    assert_eq!(
+        to_sympy_grad(&x0, &[other, x0.clone()]).expect("export failed"),
+        vec!["0".to_string(), "1.0".to_string()]
+    );
+
+    //derivatives of sinc have no sympy form
+    let e = to_sympy_grad(&Sinc(x0.clone()), &[x0]).expect_err("sinc' has no sympy form");
+    assert!(e.op.contains("OpSinc"));
+}
@@ -9,9 +9,14 @@ use std::cmp::{Eq, PartialEq};
 use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
 use std::ops::Deref;
+use std::panic::Location;
 use std::rc::{Rc, Weak};
+use std::sync::Arc;
+
+use crate::linalg;
+use crate::special;
 #[cfg(test)]
-use std::sync::{atomic, Arc};
+use std::sync::atomic;
 
 #[derive(Clone, Debug)]
 pub struct PtrVWrap(pub Rc<RefCell<VWrap>>);
@@ -61,8 +66,34 @@ pub struct VWrap {
 
     pub eval_g: bool,
 
+    /// treated as a constant by fwd and rev
+    pub frozen: bool,
+
+    /// sum of the adjoints evaluated for this leaf since the last zero_grad, None unless
+    /// retain_grad was called
+    pub grad: Option<f64>,
+
+    /// how adjoint graphs built by rev from this node sum the contributions to each adjoint
+    adj_sum: AdjointSum,
+
+    /// how derivative graphs built from this node treat leaves holding integers
+    integers: IntegerSemantics,
+
+    /// leaf whose grad buffer receives the value of this adjoint when evaluated by apply_rev
+    grad_of: Option<Weak<RefCell<VWrap>>>,
+
     /// adjoint accumulation expression
     pub adj_accum: Option<PtrVWrap>,
+
+    /// user key/value annotations
+    meta: Vec<(String, String)>,
+
+    /// where the node was constructed, recorded while set_track_locations is enabled
+    location: Option<&'static Location<'static>>,
+
+    /// structural hash of an interior node with the rewrite epoch it was computed in,
+    /// computed on first use
+    structural: Option<(u64, u64)>,
 }
 use std::fmt;
 
@@ -71,109 +102,535 @@ impl fmt::Debug for VWrap {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(
             f,
-            "VWrap {{ inp: {:#?}, raw:: {:?}, val: {:?}, id: {:?}, eval_g: {:?} }}",
-            self.inp, self.raw, self.val, self.id, self.eval_g
+            "VWrap {{ inp: {:#?}, raw:: {:?}, val: {:?}, id: {:?}, eval_g: {:?}, frozen: {:?} }}",
+            self.inp, self.raw, self.val, self.id, self.eval_g, self.frozen
         )
     }
     #[cfg(not(test))]
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(
             f,
-            "VWrap {{ inp: {:#?}, raw:: {:?}, val: {:?}, eval_g: {:?} }}",
-            self.inp, self.raw, self.val, self.eval_g
+            "VWrap {{ inp: {:#?}, raw:: {:?}, val: {:?}, eval_g: {:?}, frozen: {:?} }}",
+            self.inp, self.raw, self.val, self.eval_g, self.frozen
         )
     }
 }
 
+/// frees the input dependencies iteratively, so dropping very deep graphs cannot
+/// overflow the stack through nested Rc drops
+impl Drop for VWrap {
+    fn drop(&mut self) {
+        let mut stack = std::mem::take(&mut self.inp);
+        stack.extend(self.adj_accum.take());
+        while let Some(n) = stack.pop() {
+            //detach the inputs of nodes about to be freed so their own drop stays shallow
+            if Rc::strong_count(&n.0) == 1 {
+                if let Ok(mut v) = n.0.try_borrow_mut() {
+                    stack.append(&mut v.inp);
+                    stack.extend(v.adj_accum.take());
+                }
+            }
+        }
+    }
+}
+
 /// initializer functions
 #[allow(dead_code)]
 impl VWrap {
+    #[track_caller]
     fn new(v: Box<dyn FWrap>) -> PtrVWrap {
-        PtrVWrap(Rc::new(RefCell::new(VWrap {
+        alloc(VWrap {
             inp: vec![],
             raw: v,
             val: None,
             #[cfg(test)]
             id: get_id(),
             eval_g: false,
+            frozen: false,
+            grad: None,
+            adj_sum: AdjointSum::Chain,
+            integers: IntegerSemantics::Promote,
+            grad_of: None,
             adj_accum: None,
-        })))
+            meta: vec![],
+            location: tracked_location(),
+            structural: None,
+        })
     }
 
+    #[track_caller]
     fn new_with_input(f: Box<dyn FWrap>, v: Vec<PtrVWrap>) -> PtrVWrap {
-        PtrVWrap(Rc::new(RefCell::new(VWrap {
+        alloc(VWrap {
             inp: v,
             raw: f,
             val: None,
             #[cfg(test)]
             id: get_id(),
             eval_g: false,
+            frozen: false,
+            grad: None,
+            adj_sum: AdjointSum::Chain,
+            integers: IntegerSemantics::Promote,
+            grad_of: None,
             adj_accum: None,
-        })))
+            meta: vec![],
+            location: tracked_location(),
+            structural: None,
+        })
     }
 
+    #[track_caller]
     fn new_with_val(v: Box<dyn FWrap>, val: ValType) -> PtrVWrap {
-        PtrVWrap(Rc::new(RefCell::new(VWrap {
+        alloc(VWrap {
             inp: vec![],
             raw: v,
             val: Some(val),
             #[cfg(test)]
             id: get_id(),
             eval_g: false,
+            frozen: false,
+            grad: None,
+            adj_sum: AdjointSum::Chain,
+            integers: IntegerSemantics::Promote,
+            grad_of: None,
             adj_accum: None,
-        })))
+            meta: vec![],
+            location: tracked_location(),
+            structural: None,
+        })
+    }
+}
+
+/// nodes allocated while a RevPool builds adjoints, see RevPool
+#[derive(Default)]
+struct NodePool {
+    /// nodes handed out, oldest first
+    live: Vec<PtrVWrap>,
+    /// reclaimed nodes, ready to be overwritten
+    free: Vec<PtrVWrap>,
+    reused: usize,
+}
+
+impl NodePool {
+    /// moves the nodes referenced by nothing but the pool to the free list
+    ///
+    /// consumers are allocated after their inputs, so a single pass from the newest node
+    /// releases whole unused graphs
+    fn reclaim(&mut self) {
+        let mut live = std::mem::take(&mut self.live);
+        while let Some(n) = live.pop() {
+            if Rc::strong_count(&n.0) == 1 && Rc::weak_count(&n.0) == 0 {
+                let mut node = n.0.deref().borrow_mut();
+                node.inp.clear();
+                node.adj_accum = None;
+                drop(node);
+                self.free.push(n);
+            } else {
+                self.live.push(n);
+            }
+        }
+        self.live.reverse();
+    }
+}
+
+thread_local! {
+    static POOL: RefCell<Option<NodePool>> = const { RefCell::new(None) };
+    static CONSTANTS: RefCell<Option<HashMap<ConstKey, PtrVWrap>>> = const { RefCell::new(None) };
+}
+
+/// shared constant nodes: the zero and one of derivative graphs, and constants by type
+/// and bit pattern, so 0. and -0. or f32 and f64 values stay distinct
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ConstKey {
+    Zero,
+    One,
+    Value(u8, u64),
+}
+
+impl ConstKey {
+    fn of(v: ValType) -> ConstKey {
+        match v {
+            ValType::F(x) => ConstKey::Value(0, x.to_bits() as u64),
+            ValType::D(x) => ConstKey::Value(1, x.to_bits()),
+            ValType::I(x) => ConstKey::Value(2, x as u32 as u64),
+            ValType::L(x) => ConstKey::Value(3, x as u64),
+        }
+    }
+}
+
+/// node of the given key shared within with_shared_constants, made anew outside of it
+fn shared(key: ConstKey, make: impl FnOnce() -> PtrVWrap) -> PtrVWrap {
+    CONSTANTS.with(|c| match c.borrow_mut().as_mut() {
+        Some(c) => c.entry(key).or_insert_with(make).clone(),
+        None => make(),
+    })
+}
+
+/// zero of derivative graphs
+fn zero() -> PtrVWrap {
+    shared(ConstKey::Zero, || {
+        VWrap::new_with_val(OpZero::new(), ValType::F(0.))
+    })
+}
+
+/// one of derivative graphs
+fn one() -> PtrVWrap {
+    shared(ConstKey::One, || VWrap::new(OpOne::new()))
+}
+
+/// runs build with the constants it creates deduplicated by value, see Const
+///
+/// calls may be nested, constants are then shared with the outermost call. fwd and rev
+/// build their derivative graphs this way
+pub fn with_shared_constants<R>(build: impl FnOnce() -> R) -> R {
+    struct Reset(bool);
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            if self.0 {
+                CONSTANTS.with(|c| *c.borrow_mut() = None);
+            }
+        }
+    }
+    let _reset = Reset(CONSTANTS.with(|c| {
+        let mut c = c.borrow_mut();
+        let outer = c.is_none();
+        if outer {
+            *c = Some(HashMap::new());
+        }
+        outer
+    }));
+    build()
+}
+
+/// node holding v, in storage recycled from the active pool if any
+fn alloc(v: VWrap) -> PtrVWrap {
+    POOL.with(|p| {
+        let mut pool = p.borrow_mut();
+        let pool = match pool.as_mut() {
+            Some(pool) => pool,
+            None => return PtrVWrap(Rc::new(RefCell::new(v))),
+        };
+        let n = match pool.free.pop() {
+            Some(n) => {
+                *n.0.deref().borrow_mut() = v;
+                pool.reused += 1;
+                n
+            }
+            None => PtrVWrap(Rc::new(RefCell::new(v))),
+        };
+        pool.live.push(n.clone());
+        n
+    })
+}
+
+/// leaf adjoints of a variable, each built by rev_wrt when first requested
+pub struct LazyRev {
+    root: PtrVWrap,
+    resolved: HashMap<PtrVWrap, Option<PtrVWrap>>,
+}
+
+impl LazyRev {
+    /// adjoint wrt leaf x, None if x does not appear in the graph
+    pub fn get(&mut self, x: &PtrVWrap) -> Option<PtrVWrap> {
+        let root = &self.root;
+        self.resolved
+            .entry(x.clone())
+            .or_insert_with(|| root.rev_wrt(x))
+            .clone()
+    }
+
+    /// number of leaves requested so far
+    pub fn len(&self) -> usize {
+        self.resolved.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.resolved.is_empty()
+    }
+}
+
+/// adjoints of a variable wrt every node of its graph, interior nodes included, as built
+/// by rev_retained
+pub struct RetainedAdjoints {
+    /// (node, adjoint graph) in reverse topological order, the variable itself first
+    adjoints: Vec<(PtrVWrap, PtrVWrap)>,
+}
+
+impl RetainedAdjoints {
+    /// (node, adjoint graph) pairs, from the variable towards the leaves
+    pub fn iter(&self) -> std::slice::Iter<'_, (PtrVWrap, PtrVWrap)> {
+        self.adjoints.iter()
+    }
+
+    /// adjoint graph of a node, None for nodes outside the graph and frozen nodes
+    pub fn get(&self, n: &PtrVWrap) -> Option<&PtrVWrap> {
+        self.adjoints.iter().find(|(m, _)| m == n).map(|(_, a)| a)
+    }
+
+    /// values of all adjoints at the current leaf values, in the order of iter
+    ///
+    /// the adjoint graphs are evaluated together, so subexpressions shared between them
+    /// are evaluated once; grad buffers of leaves with retain_grad are not updated
+    pub fn values(&self) -> Vec<(PtrVWrap, ValType)> {
+        let roots: Vec<PtrVWrap> = self.adjoints.iter().map(|(_, a)| a.clone()).collect();
+        for n in topo_order(&roots) {
+            let inputs = n.inputs();
+            if inputs.is_empty() && n.fixed_value().is_none() {
+                panic!("{}", MissingValue { path: vec![n] });
+            }
+            let args: Vec<(ValType, bool)> = inputs
+                .iter()
+                .map(|i| {
+                    let node = i.0.deref().borrow();
+                    (node.val.expect("input not evaluated"), node.eval_g)
+                })
+                .collect();
+            n.eval_with(args);
+        }
+        self.adjoints
+            .iter()
+            .map(|(n, a)| {
+                (
+                    n.clone(),
+                    a.0.deref().borrow().val.expect("adjoint not evaluated"),
+                )
+            })
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.adjoints.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.adjoints.is_empty()
+    }
+}
+
+impl<'a> IntoIterator for &'a RetainedAdjoints {
+    type Item = &'a (PtrVWrap, PtrVWrap);
+    type IntoIter = std::slice::Iter<'a, (PtrVWrap, PtrVWrap)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// activity analysis of the outermost fwd call on this thread
+struct FwdPrune {
+    /// nodes of the primal graph at the time fwd was called
+    known: HashSet<PtrVWrap>,
+    /// known nodes that depend on an active node
+    depends: HashSet<PtrVWrap>,
+    /// tangent shared by every known node that does not depend on an active node
+    zero: PtrVWrap,
+}
+
+/// tangents built while a DerivativeCache builds a forward derivative on this thread
+struct TangentCache {
+    /// leaf the tangents are taken wrt, its tangent is one and that of other leaves zero
+    /// instead of links reading the activity flags
+    leaf: PtrVWrap,
+    tangents: HashMap<PtrVWrap, PtrVWrap>,
+    hits: usize,
+}
+
+thread_local! {
+    static FWD_PRUNE: RefCell<Option<FwdPrune>> = const { RefCell::new(None) };
+    static TANGENTS: RefCell<Option<TangentCache>> = const { RefCell::new(None) };
+    /// bumped by every fuse that rewrites nodes, invalidating the structural hashes cached
+    /// before, as consumers of a rewritten node cannot be reached from it
+    static REWRITES: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+}
+
+///1/c if c is an f32 power of two with a normal reciprocal, so x*(1/c) rounds as x/c
+fn exact_recip(c: ValType) -> Option<f32> {
+    match c {
+        ValType::F(c) if c.is_normal() && c.to_bits() & 0x7f_ffff == 0 => {
+            Some(1. / c).filter(|r| r.is_normal())
+        }
+        _ => None,
     }
 }
 
 impl PtrVWrap {
     fn set_inp(&mut self, v: Vec<PtrVWrap>) {
-        self.0.deref().borrow_mut().inp = v;
+        let mut node = self.0.deref().borrow_mut();
+        node.inp = v;
+        node.structural = None;
     }
 
     pub fn set_val(&mut self, v: ValType) {
         self.0.deref().borrow_mut().val = Some(v);
     }
 
+    /// evaluates the node's function on the given input values and stores the result
+    ///
+    /// no borrow of the node is held while the function runs
+    fn eval_with(&self, args: Vec<(ValType, bool)>) -> ValType {
+        let (mut f, val) = (self.eval_fn(), self.0.deref().borrow().val);
+
+        let v = f(&args, val);
+
+        self.0.deref().borrow_mut().val = Some(v);
+
+        v
+    }
+
+    /// evaluation function of the node
+    pub(crate) fn eval_fn(&self) -> EvalFn {
+        self.0.deref().borrow().raw.f()
+    }
+
+    /// input dependencies, cloned so no borrow of the node outlives the call
+    pub(crate) fn inputs(&self) -> Vec<PtrVWrap> {
+        self.0.deref().borrow().inp.clone()
+    }
+
     /// forward mode (tanget-linear)
+    ///
+    /// panics with the path to the first leaf without a value, see try_apply_fwd
     pub fn apply_fwd(&mut self) -> ValType {
-        let mut args: Vec<(ValType, bool)> = vec![];
+        self.try_apply_fwd().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// same as apply_fwd, returning the path to a leaf without a value instead of panicking
+    pub fn try_apply_fwd(&mut self) -> Result<ValType, MissingValue> {
+        self.apply_recurse()
+    }
+
+    fn apply_recurse(&mut self) -> Result<ValType, MissingValue> {
+        let inputs = self.inputs();
+        if inputs.is_empty() && self.fixed_value().is_none() {
+            return Err(MissingValue {
+                path: vec![self.clone()],
+            });
+        }
 
         //recursive apply
-        for i in self.0.deref().borrow_mut().inp.iter_mut() {
-            let val = i.apply_fwd();
+        let mut args = Vec::with_capacity(inputs.len());
+        for mut i in inputs {
+            let val = i.apply_recurse().map_err(|mut e| {
+                e.path.insert(0, self.clone());
+                e
+            })?;
             args.push((val, i.0.deref().borrow().eval_g));
         }
 
-        let v = self.0.deref().borrow().raw.f()(args, self.0.deref().borrow().val);
-
-        self.0.deref().borrow_mut().val = Some(v);
-
-        v
+        Ok(self.eval_with(args))
     }
 
     /// reverse mode (adjoint)
-    fn apply_rev_recurse(&mut self) -> ValType {
-        let mut args: Vec<(ValType, bool)> = vec![];
+    ///
+    /// evaluating the adjoint of a leaf with retain_grad adds the value to the leaf's grad
+    pub fn apply_rev(&mut self) -> ValType {
+        let v = self.apply_recurse().unwrap_or_else(|e| panic!("{}", e));
+        self.accumulate_grad(v);
+        v
+    }
 
-        //recursive apply
-        for i in self.0.deref().borrow_mut().inp.iter_mut() {
-            let val = i.apply_rev_recurse();
-            let temp = i.0.deref().borrow().eval_g;
-            args.push((val, temp));
+    /// adds v, the value of this adjoint, to the grad buffer of the leaf it belongs to
+    pub(crate) fn accumulate_grad(&self, v: ValType) {
+        let target = self
+            .0
+            .deref()
+            .borrow()
+            .grad_of
+            .as_ref()
+            .and_then(Weak::upgrade);
+        if let Some(leaf) = target {
+            if let Some(g) = leaf.deref().borrow_mut().grad.as_mut() {
+                *g += f64::from(v);
+            }
         }
+    }
 
-        let v = self.0.deref().borrow().raw.f()(args, self.0.deref().borrow().val);
+    /// value of the current variable with the values of the given nodes substituted, without
+    /// storing any value in the graph
+    ///
+    /// overrides usually target leaves; an overridden interior node is not evaluated, nor are
+    /// its inputs unless other nodes need them. nodes shared by several consumers are
+    /// evaluated once
+    pub fn apply_fwd_with(&self, values: &HashMap<PtrVWrap, ValType>) -> ValType {
+        self.eval_substituted(values, false)
+    }
 
-        self.0.deref().borrow_mut().val = Some(v);
+    /// value of the current variable with all values promoted to f64, without storing any
+    /// value in the graph
+    ///
+    /// leaves, constants and f32 results of every node are widened before they are passed
+    /// on, so operations with an f64 kernel run in double precision; comparing with
+    /// apply_fwd tells whether e.g. an unstable adjoint is an artifact of f32 rounding.
+    /// integer values are kept as they are
+    pub fn eval_as_f64(&self) -> f64 {
+        self.eval_substituted(&HashMap::new(), true).into()
+    }
 
-        v
+    fn eval_substituted(&self, values: &HashMap<PtrVWrap, ValType>, promote: bool) -> ValType {
+        let widen = |v: ValType| match v {
+            ValType::F(x) if promote => ValType::D(x.into()),
+            v => v,
+        };
+        let mut computed: HashMap<PtrVWrap, ValType> = HashMap::new();
+        let mut stack = vec![(self.clone(), false)];
+        while let Some((n, expanded)) = stack.pop() {
+            if computed.contains_key(&n) {
+                continue;
+            }
+            if let Some(v) = values.get(&n) {
+                computed.insert(n, widen(*v));
+                continue;
+            }
+            let inputs = n.inputs();
+            if expanded {
+                let args: Vec<(ValType, bool)> = inputs
+                    .iter()
+                    .map(|i| (computed[i], i.0.deref().borrow().eval_g))
+                    .collect();
+                let (mut f, val) = (n.eval_fn(), n.0.deref().borrow().val);
+                let v = f(&args, val.map(widen));
+                computed.insert(n, widen(v));
+                continue;
+            }
+            if inputs.is_empty() && n.fixed_value().is_none() {
+                let path = self.path_to(&n);
+                panic!("{}", MissingValue { path });
+            }
+            stack.push((n, true));
+            for i in inputs.into_iter().rev() {
+                stack.push((i, false));
+            }
+        }
+        computed[self]
     }
 
-    /// reverse mode (adjoint)
-    pub fn apply_rev(&mut self) -> ValType {
-        let v = self.apply_rev_recurse();
+    /// same as apply_fwd_with for adjoint graphs; grad buffers of leaves with retain_grad
+    /// are not updated
+    pub fn apply_rev_with(&self, values: &HashMap<PtrVWrap, ValType>) -> ValType {
+        self.apply_fwd_with(values)
+    }
 
-        v
+    /// nodes from the current variable down to a node of its graph
+    fn path_to(&self, target: &PtrVWrap) -> Vec<PtrVWrap> {
+        let mut parent: HashMap<PtrVWrap, PtrVWrap> = HashMap::new();
+        let mut stack = vec![self.clone()];
+        while let Some(n) = stack.pop() {
+            if n == *target {
+                break;
+            }
+            for i in n.inputs() {
+                if i != *self && !parent.contains_key(&i) {
+                    parent.insert(i.clone(), n.clone());
+                    stack.push(i);
+                }
+            }
+        }
+        let mut path = vec![target.clone()];
+        while let Some(p) = parent.get(path.last().expect("empty path")) {
+            path.push(p.clone());
+        }
+        path.reverse();
+        path
     }
 
     /// create adjoint graph starting from current variable and go through input dependencies
@@ -181,1535 +638,10598 @@ impl PtrVWrap {
     /// resulting sensitivity graphs are propagated to leaf nodes' adjoint accumulation
     /// where it can be collected
     pub fn rev(&self) -> HashMap<PtrVWrap, PtrVWrap> {
-        use std::collections::VecDeque;
+        self.rev_ordered().into_iter().collect()
+    }
 
-        let mut q = VecDeque::new();
+    /// same as rev, with the leaf adjoints listed in order of first appearance in a
+    /// depth-first walk over the inputs
+    ///
+    /// nodes are visited in reverse topological order, so every consumer has contributed to
+    /// a node's adjoint before it is propagated further, and contributions are accumulated in
+    /// the same order on every run
+    pub fn rev_ordered(&self) -> Vec<(PtrVWrap, PtrVWrap)> {
+        strict_entry(
+            "rev",
+            self,
+            false,
+            || {
+                let nodes = self.reachable();
+                nodes.into_iter().filter(|n| n.is_variable()).collect()
+            },
+            || self.rev_ordered_unchecked(),
+        )
+    }
 
-        let mut adjoints_collected = HashMap::new();
+    fn rev_ordered_unchecked(&self) -> Vec<(PtrVWrap, PtrVWrap)> {
+        let discovered: HashMap<PtrVWrap, usize> = self
+            .reachable()
+            .into_iter()
+            .enumerate()
+            .map(|(i, n)| (n, i))
+            .collect();
+        let order = topo_order(std::slice::from_ref(self));
 
-        //initialization of sensitity=1 for starting node
-        self.0.deref().borrow_mut().adj_accum = Some(VWrap::new(OpOne::new()));
+        let mut adjoints_collected = self.adjoint_sweep(order, false, &HashSet::new());
 
-        q.push_back(self.clone());
+        adjoints_collected.sort_by_key(|(n, _)| discovered[n]);
+        adjoints_collected
+    }
 
-        let mut visited: HashSet<PtrVWrap> = HashSet::new();
+    /// propagates adjoints from the current variable over the given nodes in reverse of the
+    /// given topological order, returning the leaf adjoints in sweep order, and those of
+    /// all swept nodes if interior
+    ///
+    /// inputs outside of the swept nodes receive no contributions, and frozen nodes and
+    /// integer leaves under IntegerSemantics::Constant are never swept. nodes of boundary
+    /// are treated as leaves: their adjoints are returned and not propagated to their inputs
+    ///
+    /// unless summed by a chain of additions, the contributions to a node are collected and
+    /// summed by a single node once they are all known
+    ///
+    /// zero contributions are left out of the sums, and nodes whose adjoint is zero pass
+    /// nothing on to their inputs
+    pub(crate) fn adjoint_sweep(
+        &self,
+        order: Vec<PtrVWrap>,
+        interior: bool,
+        boundary: &HashSet<PtrVWrap>,
+    ) -> Vec<(PtrVWrap, PtrVWrap)> {
+        with_shared_constants(|| self.adjoint_sweep_shared(order, interior, boundary))
+    }
 
-        //breadth-first
-        while !q.is_empty() {
-            let n = q.pop_front().unwrap();
+    fn adjoint_sweep_shared(
+        &self,
+        order: Vec<PtrVWrap>,
+        interior: bool,
+        boundary: &HashSet<PtrVWrap>,
+    ) -> Vec<(PtrVWrap, PtrVWrap)> {
+        let order: Vec<PtrVWrap> = order
+            .into_iter()
+            .filter(|n| !self.treats_as_constant(n))
+            .collect();
+        let swept: HashSet<PtrVWrap> = order.iter().cloned().collect();
+        let mut adjoints_collected = vec![];
+        let mode = self.0.deref().borrow().adj_sum;
+
+        //accumulations are moved out as the sweep goes, but a panicking adjoint builder
+        //would leave them behind, keeping the adjoint graphs and the primal graph alive
+        struct Clear<'a>(&'a PtrVWrap, &'a HashSet<PtrVWrap>);
+        impl Drop for Clear<'_> {
+            fn drop(&mut self) {
+                if std::thread::panicking() {
+                    for n in self.1.iter().chain(std::iter::once(self.0)) {
+                        if let Ok(mut node) = n.0.try_borrow_mut() {
+                            node.adj_accum = None;
+                        }
+                    }
+                }
+            }
+        }
+        let _clear = Clear(self, &swept);
+        let mut pending: HashMap<PtrVWrap, Vec<PtrVWrap>> = HashMap::new();
 
-            if visited.contains(&n) {
-                //skip already traversed nodes
+        //initialization of sensitity=1 for starting node
+        self.0.deref().borrow_mut().adj_accum = Some(one());
+
+        for n in order.into_iter().rev() {
+            if let Some(mut contributions) = pending.remove(&n) {
+                let acc = match (contributions.len(), mode) {
+                    (1, _) => contributions.remove(0),
+                    (_, AdjointSum::Compensated) => {
+                        VWrap::new_with_input(Box::new(OpKahanSum {}), contributions)
+                    }
+                    _ => sum(contributions),
+                };
+                n.0.deref().borrow_mut().adj_accum = Some(acc);
+            }
+            if n.0.deref().borrow_mut().adj_accum.is_none() {
+                n.0.deref().borrow_mut().adj_accum = Some(zero());
+            }
+            if boundary.contains(&n) {
+                let adj = n.0.deref().borrow_mut().adj_accum.take();
+                adjoints_collected.push((n.clone(), adj.expect("adj_accum empty")));
                 continue;
             }
 
-            if n.0.deref().borrow_mut().adj_accum.is_none() {
-                n.0.deref().borrow_mut().adj_accum = Some(VWrap::new(OpZero::new()));
-            }
-
-            //delegate adjoint calc to operation
-            let adjoints = {
-                let mut f = n.0.deref().borrow().raw.adjoint();
-                f(
-                    n.0.deref().borrow().inp.clone(),
-                    n.0.deref()
-                        .borrow()
-                        .adj_accum
-                        .as_ref()
-                        .expect("adj_accum empty")
-                        .clone(),
-                    &n,
+            //delegate adjoint calc to operation, without holding a borrow of the node
+            let (mut f, inp, out_adj) = {
+                let node = n.0.deref().borrow();
+                (
+                    node.raw.adjoint(),
+                    node.inp.clone(),
+                    node.adj_accum.clone().expect("adj_accum empty"),
                 )
             };
+            //a zero adjoint contributes nothing to the inputs
+            let adjoints = if out_adj.is_zero() {
+                vec![]
+            } else {
+                let adjoints = f(inp.clone(), out_adj, &n);
+                assert_eq!(adjoints.len(), inp.len());
+                adjoints
+            };
 
-            assert_eq!(adjoints.len(), n.0.deref().borrow().inp.len());
-
-            //propagate adjoints to inputs
-            let l = adjoints.len();
-            for idx in 0..l {
-                if n.0.deref().borrow_mut().inp[idx]
-                    .0
-                    .deref()
-                    .borrow_mut()
-                    .adj_accum
-                    .is_none()
-                {
-                    n.0.deref().borrow_mut().inp[idx]
-                        .0
-                        .deref()
-                        .borrow_mut()
-                        .adj_accum = Some(VWrap::new(OpZero::new()));
+            //propagate adjoints to inputs, skipping zero contributions
+            for (i, adj) in inp.iter().zip(adjoints) {
+                if !swept.contains(i) || adj.is_zero() {
+                    continue;
                 }
-
-                let temp = n.0.deref().borrow().inp[idx]
-                    .0
-                    .deref()
-                    .borrow()
-                    .adj_accum
-                    .as_ref()
-                    .unwrap()
-                    .clone();
-
-                n.0.deref().borrow_mut().inp[idx]
-                    .0
-                    .deref()
-                    .borrow_mut()
-                    .adj_accum = Some(Add(temp, adjoints[idx].clone()));
+                if mode != AdjointSum::Chain {
+                    pending.entry(i.clone()).or_default().push(adj);
+                    continue;
+                }
+                let temp = i.0.deref().borrow_mut().adj_accum.take();
+                let acc = match temp {
+                    Some(temp) if !temp.is_zero() => Add(temp, adj),
+                    _ => adj,
+                };
+                i.0.deref().borrow_mut().adj_accum = Some(acc);
             }
 
-            //reset adjoint accumulation for current node to zero
-            if !n.0.deref().borrow().inp.is_empty() {
+            if !inp.is_empty() {
                 //reset adjoints for internal nodes
-                n.0.deref().borrow_mut().adj_accum = None;
+                let adj = n.0.deref().borrow_mut().adj_accum.take();
+                if interior {
+                    adjoints_collected.push((n.clone(), adj.expect("adj_accum empty")));
+                }
             } else {
                 //collect adjoints for leaf nodes
                 let adj = n.0.deref().borrow_mut().adj_accum.take();
-                adjoints_collected.insert(n.clone(), adj.expect("leaf adjoint missing"));
+                let mut adj = adj.expect("leaf adjoint missing");
+                if n.0.deref().borrow().grad.is_some() {
+                    //the grad buffer is attached to the adjoint, which must not be shared
+                    if adj.inputs().is_empty() {
+                        adj = match adj.kind() {
+                            OpKind::Zero => VWrap::new_with_val(OpZero::new(), ValType::F(0.)),
+                            OpKind::One => VWrap::new(OpOne::new()),
+                            _ => VWrap::new_with_val(
+                                OpConst::new(),
+                                adj.fixed_value().expect("constant value missing"),
+                            ),
+                        };
+                    }
+                    adj.0.deref().borrow_mut().grad_of = Some(Rc::downgrade(&n.0));
+                }
+                adjoints_collected.push((n.clone(), adj));
             }
+        }
 
-            //do adjoints for inputs
-            for i in n.0.deref().borrow().inp.iter() {
-                q.push_back(i.clone());
-            }
+        adjoints_collected
+    }
 
-            visited.insert(n.clone());
+    /// reverse mode keeping the adjoints of interior nodes, e.g. for locating vanishing or
+    /// exploding gradients within the graph
+    ///
+    /// every node of the graph, frozen nodes excepted, gets its adjoint graph; leaf
+    /// adjoints are the same as those of rev
+    pub fn rev_retained(&self) -> RetainedAdjoints {
+        RetainedAdjoints {
+            adjoints: self.adjoint_sweep(
+                topo_order(std::slice::from_ref(self)),
+                true,
+                &HashSet::new(),
+            ),
         }
+    }
 
-        adjoints_collected
+    /// adjoint of the current variable wrt leaf x, None if x does not appear in the graph
+    ///
+    /// only nodes that depend on x are swept, unlike rev which builds the adjoints of all
+    /// leaves
+    pub fn rev_wrt(&self, x: &PtrVWrap) -> Option<PtrVWrap> {
+        strict_entry(
+            "rev_wrt",
+            self,
+            false,
+            || vec![x.clone()],
+            || self.rev_wrt_unchecked(x),
+        )
     }
 
-    /// create tangent-linear starting from current variable
-    pub fn fwd(&self) -> PtrVWrap {
-        let mut g = self.0.deref().borrow().raw.tangent();
-        let ret = g(self.0.deref().borrow().inp.clone(), self);
-        ret
+    fn rev_wrt_unchecked(&self, x: &PtrVWrap) -> Option<PtrVWrap> {
+        let mut depends: HashSet<PtrVWrap> = HashSet::new();
+        let order: Vec<PtrVWrap> = topo_order(std::slice::from_ref(self))
+            .into_iter()
+            .filter(|n| {
+                let d = n == x || n.0.deref().borrow().inp.iter().any(|i| depends.contains(i));
+                if d {
+                    depends.insert(n.clone());
+                }
+                d
+            })
+            .collect();
+        if !depends.contains(x) {
+            return None;
+        }
+        self.adjoint_sweep(order, false, &HashSet::new())
+            .into_iter()
+            .find(|(l, _)| l == x)
+            .map(|(_, adj)| adj)
     }
 
-    /// indicator in fwd propagation
-    pub fn active(&mut self) -> Self {
-        self.0.deref().borrow_mut().eval_g = true;
-        self.clone()
+    /// leaf adjoints of the current variable that are built on first access
+    pub fn rev_lazy(&self) -> LazyRev {
+        LazyRev {
+            root: self.clone(),
+            resolved: HashMap::new(),
+        }
     }
 
-    pub fn inactive(&mut self) -> Self {
-        self.0.deref().borrow_mut().eval_g = false;
-        self.clone()
+    fn kind(&self) -> OpKind {
+        self.0.deref().borrow().raw.kind()
     }
 
-    pub fn adjoint(&self) -> Option<PtrVWrap> {
-        self.0.deref().borrow().adj_accum.clone()
+    /// whether the node is the zero of derivative graphs
+    fn is_zero(&self) -> bool {
+        self.kind() == OpKind::Zero
     }
 
-    pub fn reset_adjoint(&mut self) {
-        self.0.deref().borrow_mut().adj_accum = None;
+    /// value of a node that does not depend on the values of its inputs, leaves give their
+    /// current value
+    pub(crate) fn fixed_value(&self) -> Option<ValType> {
+        let node = self.0.deref().borrow();
+        match node.raw.kind() {
+            OpKind::Zero => Some(ValType::F(0.)),
+            OpKind::One => Some(ValType::F(1.)),
+            OpKind::Link => {
+                let active = node.inp[0].0.deref().borrow().eval_g;
+                Some(ValType::F(if active { 1. } else { 0. }))
+            }
+            _ if node.inp.is_empty() => node.val,
+            _ => None,
+        }
     }
-}
 
-/// wrapper for function
-trait FWrap: std::fmt::Debug {
-    fn new() -> Box<dyn FWrap>
-    where
-        Self: Sized;
+    /// elementary form of the node's operation
+    pub(crate) fn scalar_op(&self) -> Option<ScalarOp> {
+        self.0.deref().borrow().raw.scalar_op()
+    }
 
-    /// creates a function to evaluate given values
-    fn f(&self) -> Box<dyn FnMut(Vec<(ValType, bool)>, Option<ValType>) -> ValType>;
+    /// rust expression of the node given the expressions of its inputs
+    pub(crate) fn rust_expr(&self, args: &[String]) -> Option<String> {
+        self.0.deref().borrow().raw.rust_expr(args)
+    }
 
-    /// creates a function to evaluate given values for reverse pass
-    fn f_rev(&self) -> Box<dyn FnMut(Vec<(ValType, bool)>, Option<ValType>) -> ValType> {
-        self.f()
+    /// sympy expression of the node given the expressions of its inputs
+    pub(crate) fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        self.0.deref().borrow().raw.sympy_expr(args)
     }
 
-    /// creates linear tangent function with given input dependencies and returns wrapped variable
-    /// used in forward mode
-    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap>;
+    /// name of the node's operation
+    pub(crate) fn op_name(&self) -> String {
+        format!("{:?}", self.0.deref().borrow().raw)
+    }
 
-    /// creates function to compute the adjoint for the input dependencies
-    /// used in reverse mode
-    fn adjoint(
-        &self,
-    ) -> Box<
-        dyn FnMut(
-            Vec<PtrVWrap>, /*inputs*/
-            PtrVWrap,      /*accumulated adjoint*/
-            &PtrVWrap,     /*self*/
-        ) -> Vec<PtrVWrap>,
-    >;
-}
+    /// hash of the structure of the graph of the current variable, the same on every run
+    ///
+    /// covers the operations with their parameters and the order of inputs; leaves are
+    /// told apart by their current value and annotations only, so leaves that agree in both
+    /// hash alike. the hash of an interior node is computed once and kept until fuse rewrites
+    /// a graph
+    pub fn structural_hash(&self) -> u64 {
+        let epoch = REWRITES.with(|r| r.get());
+        let mut memo: HashMap<PtrVWrap, u64> = HashMap::new();
+        let mut stack = vec![(self.clone(), false)];
+        while let Some((n, expanded)) = stack.pop() {
+            if memo.contains_key(&n) {
+                continue;
+            }
+            let inputs = n.inputs();
+            if inputs.is_empty() {
+                let node = n.0.deref().borrow();
+                let text = format!("{:?} {:?} {:?}", node.raw, n.fixed_value(), node.meta);
+                drop(node);
+                memo.insert(n, fnv1a(FNV_OFFSET, text.as_bytes()));
+                continue;
+            }
+            let cached = n.0.deref().borrow().structural;
+            if let Some((_, h)) = cached.filter(|(e, _)| *e == epoch) {
+                memo.insert(n, h);
+                continue;
+            }
+            if !expanded {
+                stack.push((n, true));
+                stack.extend(inputs.into_iter().map(|i| (i, false)));
+                continue;
+            }
+            let mut h = fnv1a(FNV_OFFSET, n.op_name().as_bytes());
+            for i in inputs.iter() {
+                h = fnv1a(h, &memo[i].to_le_bytes());
+            }
+            n.0.deref().borrow_mut().structural = Some((epoch, h));
+            memo.insert(n, h);
+        }
+        memo[self]
+    }
 
-#[derive(Debug, Clone, Copy)]
-struct OpMul {}
-#[derive(Debug, Clone, Copy)]
-struct OpAdd {}
-#[derive(Debug, Clone, Copy)]
-struct OpLeaf {}
-#[derive(Debug, Clone, Copy)]
-struct OpOne {}
-/// special link to variable of interest for gradient calc
-#[derive(Debug, Clone, Copy)]
-struct OpLink {}
-#[derive(Debug, Clone, Copy)]
-struct OpZero {}
-#[derive(Debug, Clone, Copy)]
-struct OpConst {}
-#[derive(Debug, Clone, Copy)]
-struct OpSin {}
-#[derive(Debug, Clone, Copy)]
-struct OpCos {}
-#[derive(Debug, Clone, Copy)]
-struct OpTan {}
-#[derive(Debug, Clone, Copy)]
-struct OpPow {}
-#[derive(Debug, Clone, Copy)]
-struct OpExp {}
-#[derive(Debug, Clone, Copy)]
-struct OpLn {}
-#[derive(Debug, Clone, Copy)]
-struct OpDiv {}
-
-impl FWrap for OpMul {
-    fn new() -> Box<dyn FWrap>
-    where
-        Self: Sized,
-    {
-        Box::new(OpMul {})
+    /// follows Add(0,x), Add(x,0), Mul(1,x) and Mul(x,1) down to x
+    fn bypass_identity(&self) -> PtrVWrap {
+        let mut n = self.clone();
+        loop {
+            let inp = n.0.deref().borrow().inp.clone();
+            let unit = match n.kind() {
+                OpKind::Add => OpKind::Zero,
+                OpKind::Mul => OpKind::One,
+                _ => return n,
+            };
+            match inp.iter().position(|x| x.kind() == unit) {
+                Some(idx) if inp.len() == 2 => n = inp[1 - idx].clone(),
+                _ => return n,
+            }
+        }
     }
-    fn f(&self) -> Box<dyn FnMut(Vec<(ValType, bool)>, Option<ValType>) -> ValType> {
-        Box::new(move |x: Vec<(ValType, bool)>, _: Option<ValType>| {
-            assert!(x.len() == 2);
-            match (x[0].0, x[1].0) {
-                (ValType::F(v0), ValType::F(v1)) => ValType::F(v0 * v1),
-                (ValType::I(v0), ValType::I(v1)) => ValType::I(v0 * v1),
-                (ValType::F(v0), ValType::I(v1)) => ValType::F(v0 * v1 as f32),
-                (ValType::I(v0), ValType::F(v1)) => ValType::F(v0 as f32 * v1),
-                _ => {
-                    panic!("type not supported");
+
+    /// rewrites this node in place, returning the number of rewrites
+    fn fuse_node(&self) -> usize {
+        let mut count = 0;
+        let kind = self.kind();
+
+        //link nodes read the activity flag of their input, so keep their edges as is
+        if kind != OpKind::Link {
+            let inp: Vec<PtrVWrap> = self
+                .0
+                .deref()
+                .borrow()
+                .inp
+                .iter()
+                .map(|x| {
+                    let y = x.bypass_identity();
+                    if y != *x {
+                        count += 1;
+                    }
+                    y
+                })
+                .collect();
+            self.0.deref().borrow_mut().inp = inp;
+        }
+
+        let inp = self.0.deref().borrow().inp.clone();
+        match kind {
+            OpKind::Add if inp.len() == 2 => {
+                //a*b+c -> fma(a,b,c)
+                if let Some(idx) = inp.iter().position(|x| x.kind() == OpKind::Mul) {
+                    let ab = inp[idx].0.deref().borrow().inp.clone();
+                    let mut node = self.0.deref().borrow_mut();
+                    node.raw = OpFma::new();
+                    node.inp = vec![ab[0].clone(), ab[1].clone(), inp[1 - idx].clone()];
+                    count += 1;
                 }
             }
-        })
+            OpKind::Div if inp.len() == 2 && inp[1].kind() == OpKind::Const => {
+                //x/c -> (1/c)*x, only where both round to the same f32
+                let c = inp[1].0.deref().borrow().val;
+                if let Some(factor) = c.and_then(exact_recip) {
+                    let mut node = self.0.deref().borrow_mut();
+                    node.raw = Box::new(OpScale { factor });
+                    node.inp = vec![inp[0].clone()];
+                    count += 1;
+                }
+            }
+            _ => {}
+        }
+        count
     }
-    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
-        Box::new(move |args: Vec<PtrVWrap>, _: &PtrVWrap| {
-            assert!(args.len() == 2);
 
-            //apply chain rule: (xy)' = x'y + xy'
+    /// optimization pass over the graph reachable from the current variable
+    ///
+    /// rewrites nodes in place without changing their results: identity Add/Mul with the
+    /// zero/one nodes produced by tangent and adjoint builders are skipped, Mul feeding Add
+    /// becomes a single fused multiply-add and division by an f32 constant power of two
+    /// becomes a scaling, the only divisors whose reciprocal gives bit-identical results;
+    /// returns the number of rewrites
+    pub fn fuse(&self) -> usize {
+        let mut count = 0;
+        let mut visited: HashSet<PtrVWrap> = HashSet::new();
+        let mut stack = vec![(self.clone(), false)];
 
-            let a_prime = args[0].fwd();
-            let m1 = VWrap::new_with_input(OpMul::new(), vec![a_prime, args[1].clone()]);
+        //post-order, so inputs are rewritten before the nodes consuming them
+        while let Some((n, expanded)) = stack.pop() {
+            if expanded {
+                count += n.fuse_node();
+                continue;
+            }
+            if !visited.insert(n.clone()) {
+                continue;
+            }
+            stack.push((n.clone(), true));
+            for i in n.0.deref().borrow().inp.iter() {
+                stack.push((i.clone(), false));
+            }
+        }
+        if count > 0 {
+            REWRITES.with(|r| r.set(r.get() + 1));
+        }
+        count
+    }
 
-            let b_prime = args[1].fwd();
-            let m2 = VWrap::new_with_input(OpMul::new(), vec![args[0].clone(), b_prime]);
+    /// estimated floating point operations to evaluate the graph of the current variable,
+    /// counting every node once as CompiledGraph and Workspace do
+    ///
+    /// divisions and transcendental functions are weighted by COST_DIV and
+    /// COST_TRANSCENDENTAL, so costs of forward and reverse derivative graphs or of a graph
+    /// before and after fuse can be compared
+    pub fn cost(&self) -> u64 {
+        self.reachable()
+            .iter()
+            .map(|n| {
+                let node = n.0.deref().borrow();
+                node.raw.flops(node.inp.len())
+            })
+            .sum()
+    }
 
-            VWrap::new_with_input(OpAdd::new(), vec![m1, m2])
-        })
+    /// indented text of the graph of the current variable, one line per node with its op
+    /// and current value
+    ///
+    /// nodes are numbered in order of appearance; a node consumed more than once is expanded
+    /// at its first appearance and referenced as @id afterwards. construction locations and
+    /// annotations follow the value
+    pub fn tree_string(&self) -> String {
+        let mut ids: HashMap<PtrVWrap, usize> = HashMap::new();
+        let mut text = String::new();
+        //node, prefix of its own line, prefix of the lines of its inputs
+        let mut stack = vec![(self.clone(), String::new(), String::new())];
+        while let Some((n, head, indent)) = stack.pop() {
+            if let Some(id) = ids.get(&n) {
+                text.push_str(&format!("{}@n{}\n", head, id));
+                continue;
+            }
+            let id = ids.len();
+            ids.insert(n.clone(), id);
+            text.push_str(&format!("{}n{} {}", head, id, n.op_name()));
+            if let Some(v) = n.0.deref().borrow().val {
+                text.push_str(&format!(" = {}", v));
+            }
+            text.push_str(&n.origin());
+            text.push('\n');
+
+            let inp = n.inputs();
+            for (k, i) in inp.iter().enumerate().rev() {
+                let (branch, cont) = if k + 1 == inp.len() {
+                    ("`- ", "   ")
+                } else {
+                    ("|- ", "|  ")
+                };
+                stack.push((i.clone(), indent.clone() + branch, indent.clone() + cont));
+            }
+        }
+        text
     }
-    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
-        Box::new(
-            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
-                assert_eq!(inputs.len(), 2);
-                vec![
-                    Mul(inputs[1].clone(), out_adj.clone()),
-                    Mul(inputs[0].clone(), out_adj),
-                ]
-            },
+
+    /// prints tree_string to stdout
+    pub fn print_tree(&self) {
+        print!("{}", self.tree_string());
+    }
+
+    /// create tangent-linear starting from current variable
+    ///
+    /// subgraphs that do not depend on an active node get a shared zero tangent instead of
+    /// their own tangent subgraph, and frozen nodes, as well as integer leaves under
+    /// IntegerSemantics::Constant, always get the zero tangent. activity must thus be set
+    /// before calling fwd: leaves activated afterwards contribute nothing to the tangent,
+    /// while leaves deactivated afterwards still have their contribution zeroed when it is
+    /// evaluated
+    pub fn fwd(&self) -> PtrVWrap {
+        strict_entry(
+            "fwd",
+            self,
+            true,
+            || self.fwd_targets(),
+            || with_shared_constants(|| self.fwd_shared()),
         )
     }
-}
 
-impl FWrap for OpAdd {
-    fn new() -> Box<dyn FWrap>
-    where
-        Self: Sized,
-    {
-        Box::new(OpAdd {})
+    /// nodes marked active in the graph of the current variable, or all of its leaves if
+    /// none is, which are then all inactive targets
+    fn fwd_targets(&self) -> Vec<PtrVWrap> {
+        let nodes = self.reachable();
+        let active: Vec<PtrVWrap> = nodes
+            .iter()
+            .filter(|n| n.0.deref().borrow().eval_g)
+            .cloned()
+            .collect();
+        if active.is_empty() {
+            nodes.into_iter().filter(|n| n.is_variable()).collect()
+        } else {
+            active
+        }
     }
-    fn f(&self) -> Box<dyn FnMut(Vec<(ValType, bool)>, Option<ValType>) -> ValType> {
-        Box::new(move |x: Vec<(ValType, bool)>, _: Option<ValType>| {
-            assert_eq!(x.len(), 2);
-            match (x[0].0, x[1].0) {
-                (ValType::F(v0), ValType::F(v1)) => ValType::F(v0 + v1),
-                (ValType::I(v0), ValType::I(v1)) => ValType::I(v0 + v1),
-                _ => {
-                    panic!("type not supported");
+
+    fn fwd_shared(&self) -> PtrVWrap {
+        struct Reset(bool);
+        impl Drop for Reset {
+            fn drop(&mut self) {
+                if self.0 {
+                    FWD_PRUNE.with(|p| *p.borrow_mut() = None);
                 }
             }
-        })
-    }
-    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
-        Box::new(move |args: Vec<PtrVWrap>, _: &PtrVWrap| {
-            //apply rule: (a+b+c+...)' = a'+b'+c'+...
-
-            let mut inp_grad = vec![];
-
-            for i in args.iter() {
-                let d = i.fwd();
-                inp_grad.push(d);
+        }
+        let outer = FWD_PRUNE.with(|p| p.borrow().is_none());
+        let _reset = Reset(outer);
+        if outer {
+            let order = topo_order(std::slice::from_ref(self));
+            let mut depends = HashSet::new();
+            for n in order.iter() {
+                if self.treats_as_constant(n) {
+                    continue;
+                }
+                let node = n.0.deref().borrow();
+                if node.eval_g || node.inp.iter().any(|i| depends.contains(i)) {
+                    depends.insert(n.clone());
+                }
             }
+            let prune = FwdPrune {
+                known: order.into_iter().collect(),
+                depends,
+                zero: zero(),
+            };
+            FWD_PRUNE.with(|p| *p.borrow_mut() = Some(prune));
+        }
 
-            assert!(inp_grad.len() > 0);
-
-            let count = inp_grad.len();
-
-            if count > 1 {
-                for i in 1..count {
-                    let temp = VWrap::new_with_input(
-                        OpAdd::new(),
-                        vec![inp_grad[i - 1].clone(), inp_grad[i].clone()],
-                    );
-
-                    inp_grad[i] = temp;
+        //nodes created by tangent rules are not known and are never pruned
+        let zero = FWD_PRUNE.with(|p| {
+            p.borrow().as_ref().and_then(|p| {
+                if self.is_frozen() || (p.known.contains(self) && !p.depends.contains(self)) {
+                    Some(p.zero.clone())
+                } else {
+                    None
                 }
+            })
+        });
+
+        let cached = || {
+            TANGENTS.with(|c| {
+                let mut c = c.borrow_mut();
+                let c = c.as_mut()?;
+                let t = c.tangents.get(self).cloned();
+                c.hits += t.is_some() as usize;
+                t
+            })
+        };
+        match zero.or_else(cached) {
+            Some(t) => t,
+            None => {
+                let mut g = self.0.deref().borrow().raw.tangent();
+                let t = g(self.inputs(), self);
+                TANGENTS.with(|c| {
+                    if let Some(c) = c.borrow_mut().as_mut() {
+                        c.tangents.insert(self.clone(), t.clone());
+                    }
+                });
+                t
             }
-            inp_grad[count - 1].clone()
-        })
-    }
-    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
-        Box::new(
-            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
-                assert_eq!(inputs.len(), 2);
-                vec![out_adj.clone(), out_adj]
-            },
-        )
+        }
     }
-}
 
-impl FWrap for OpLeaf {
-    fn new() -> Box<dyn FWrap>
-    where
-        Self: Sized,
-    {
-        Box::new(OpLeaf {})
+    /// indicator in fwd propagation
+    pub fn active(&mut self) -> Self {
+        self.0.deref().borrow_mut().eval_g = true;
+        self.clone()
     }
-    fn f(&self) -> Box<dyn FnMut(Vec<(ValType, bool)>, Option<ValType>) -> ValType> {
-        Box::new(move |_x: Vec<(ValType, bool)>, v: Option<ValType>| v.expect("leaf value missing"))
+
+    pub fn inactive(&mut self) -> Self {
+        self.0.deref().borrow_mut().eval_g = false;
+        self.clone()
     }
-    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
-        Box::new(move |_args: Vec<PtrVWrap>, self_ptr: &PtrVWrap| {
-            VWrap::new_with_input(OpLink::new(), vec![self_ptr.clone()])
-        })
+
+    /// treats the node as a constant in graphs built by fwd and rev from now on: it gets no
+    /// tangent, receives no adjoint and passes none on to its inputs
+    pub fn freeze(&mut self) -> Self {
+        self.0.deref().borrow_mut().frozen = true;
+        self.clone()
     }
-    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
-        Box::new(
-            move |inputs: Vec<PtrVWrap>, _out_adj: PtrVWrap, _cur: &PtrVWrap| {
-                assert_eq!(inputs.len(), 0);
-                vec![]
-            },
-        )
+
+    pub fn unfreeze(&mut self) -> Self {
+        self.0.deref().borrow_mut().frozen = false;
+        self.clone()
     }
-}
 
-/// special construct for representing derivative of a variable created in tangent-linear pass
-impl FWrap for OpLink {
-    fn new() -> Box<dyn FWrap>
-    where
-        Self: Sized,
-    {
-        Box::new(OpLink {})
+    pub fn is_frozen(&self) -> bool {
+        self.0.deref().borrow().frozen
     }
-    fn f(&self) -> Box<dyn FnMut(Vec<(ValType, bool)>, Option<ValType>) -> ValType> {
-        Box::new(move |x: Vec<(ValType, bool)>, _v: Option<ValType>| {
-            assert!(x.len() == 1);
-            if x[0].1 {
-                //indicator for calculating gradient of the linked variable
-                ValType::F(1.)
-            } else {
-                ValType::F(0.)
-            }
-        })
+
+    /// makes the adjoint graphs built by rev from this node sum the contributions to each
+    /// adjoint in f64 before rounding to f32, reducing cancellation in long gradient sums
+    /// while the values of the graph stay f32
+    pub fn mixed_precision(&mut self) -> Self {
+        self.0.deref().borrow_mut().adj_sum = AdjointSum::F64;
+        self.clone()
     }
-    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
-        Box::new(move |_args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
-            VWrap::new_with_val(OpZero::new(), ValType::F(0.))
-        })
+
+    /// makes the adjoint graphs built by rev from this node sum the contributions to each
+    /// adjoint with compensated (kahan-neumaier) summation, for nodes of high fan-out
+    pub fn compensated_summation(&mut self) -> Self {
+        self.0.deref().borrow_mut().adj_sum = AdjointSum::Compensated;
+        self.clone()
     }
-    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
-        Box::new(
-            move |inputs: Vec<PtrVWrap>, _out_adj: PtrVWrap, _cur: &PtrVWrap| {
-                vec![VWrap::new_with_val(OpZero::new(), ValType::F(0.)); inputs.len()]
-            },
-        )
+
+    /// sets how the derivative graphs built by fwd and rev from this node treat leaves holding
+    /// ValType::I or ValType::L values
+    pub fn integer_semantics(&mut self, semantics: IntegerSemantics) -> Self {
+        self.0.deref().borrow_mut().integers = semantics;
+        self.clone()
     }
-}
 
-impl FWrap for OpConst {
-    fn new() -> Box<dyn FWrap>
-    where
-        Self: Sized,
-    {
-        Box::new(OpConst {})
+    /// whether the node is a leaf set by the user, as opposed to a constant
+    pub(crate) fn is_variable(&self) -> bool {
+        self.0.deref().borrow().inp.is_empty() && self.kind() == OpKind::Other
     }
-    fn f(&self) -> Box<dyn FnMut(Vec<(ValType, bool)>, Option<ValType>) -> ValType> {
-        Box::new(move |_x: Vec<(ValType, bool)>, v: Option<ValType>| v.expect("leaf value missing"))
+
+    /// whether the node is a leaf holding an integer value
+    fn is_integer(&self) -> bool {
+        let node = self.0.deref().borrow();
+        node.inp.is_empty() && matches!(node.val, Some(ValType::I(_)) | Some(ValType::L(_)))
     }
-    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
-        Box::new(move |_args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
-            VWrap::new_with_val(OpZero::new(), ValType::F(0.))
-        })
+
+    /// whether derivative graphs built from the current variable give n no derivative
+    fn treats_as_constant(&self, n: &PtrVWrap) -> bool {
+        n.is_frozen()
+            || (self.0.deref().borrow().integers == IntegerSemantics::Constant && n.is_integer())
     }
-    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
-        Box::new(
-            move |inputs: Vec<PtrVWrap>, _out_adj: PtrVWrap, _cur: &PtrVWrap| {
-                assert_eq!(inputs.len(), 0);
-                vec![]
-            },
-        )
+
+    /// accumulates into a grad buffer: every apply_rev of an adjoint of this leaf built by
+    /// rev from now on adds its value, across graphs and calls
+    pub fn retain_grad(&mut self) -> Self {
+        let mut node = self.0.deref().borrow_mut();
+        node.grad = node.grad.or(Some(0.));
+        drop(node);
+        self.clone()
     }
-}
 
-impl FWrap for OpOne {
-    fn new() -> Box<dyn FWrap>
-    where
-        Self: Sized,
-    {
-        Box::new(OpOne {})
+    /// accumulated adjoints since the last zero_grad, None unless retain_grad was called
+    pub fn grad(&self) -> Option<f32> {
+        self.0.deref().borrow().grad.map(|g| g as f32)
     }
-    fn f(&self) -> Box<dyn FnMut(Vec<(ValType, bool)>, Option<ValType>) -> ValType> {
-        Box::new(move |_x: Vec<(ValType, bool)>, _v: Option<ValType>| ValType::F(1.))
+
+    /// resets the grad buffer of a leaf with retain_grad
+    pub fn zero_grad(&mut self) {
+        if let Some(g) = self.0.deref().borrow_mut().grad.as_mut() {
+            *g = 0.;
+        }
     }
-    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
-        Box::new(move |_args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
-            VWrap::new_with_val(OpZero::new(), ValType::F(0.))
-        })
+
+    /// attaches a key/value annotation, replacing an earlier value of the key
+    pub fn with_meta(&mut self, key: &str, value: &str) -> Self {
+        let mut node = self.0.deref().borrow_mut();
+        match node.meta.iter_mut().find(|(k, _)| k == key) {
+            Some(entry) => entry.1 = value.to_string(),
+            None => node.meta.push((key.to_string(), value.to_string())),
+        }
+        drop(node);
+        self.clone()
     }
-    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
-        Box::new(
-            move |inputs: Vec<PtrVWrap>, _out_adj: PtrVWrap, _cur: &PtrVWrap| {
-                assert_eq!(inputs.len(), 0);
-                vec![]
-            },
-        )
+
+    /// value annotated under key
+    pub fn meta(&self, key: &str) -> Option<String> {
+        let node = self.0.deref().borrow();
+        node.meta
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.clone())
     }
-}
 
-impl FWrap for OpZero {
-    fn new() -> Box<dyn FWrap>
-    where
-        Self: Sized,
-    {
-        Box::new(OpZero {})
+    /// all annotations in order of first insertion
+    pub fn metadata(&self) -> Vec<(String, String)> {
+        self.0.deref().borrow().meta.clone()
     }
-    fn f(&self) -> Box<dyn FnMut(Vec<(ValType, bool)>, Option<ValType>) -> ValType> {
-        Box::new(move |_x: Vec<(ValType, bool)>, _v: Option<ValType>| {
-            //todo
-            ValType::F(0.)
-        })
+
+    /// source location of the construction, if set_track_locations was enabled
+    pub fn location(&self) -> Option<&'static Location<'static>> {
+        self.0.deref().borrow().location
     }
-    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
-        Box::new(move |_args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
-            VWrap::new_with_val(OpZero::new(), ValType::F(0.))
-        })
+
+    /// location and annotations as a suffix for messages, empty if there are none
+    pub(crate) fn origin(&self) -> String {
+        let node = self.0.deref().borrow();
+        let mut parts: Vec<String> = node
+            .location
+            .iter()
+            .map(|l| format!("created at {}", l))
+            .collect();
+        parts.extend(node.meta.iter().map(|(k, v)| format!("{}={}", k, v)));
+        if parts.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", parts.join(", "))
+        }
     }
-    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
-        Box::new(
-            move |inputs: Vec<PtrVWrap>, _out_adj: PtrVWrap, _cur: &PtrVWrap| {
-                assert_eq!(inputs.len(), 0);
-                vec![]
-            },
+
+    /// derivative of the current variable wrt leaf x in forward mode, evaluated at the current leaf values
+    ///
+    /// x is the only active leaf while the tangent is evaluated, activity flags set by the
+    /// caller are restored afterwards, and the result is zero if x does not appear in the graph
+    pub fn fwd_wrt(&self, x: &PtrVWrap) -> ValType {
+        //activity is set here, so only the target itself is checked
+        strict_entry(
+            "fwd_wrt",
+            self,
+            false,
+            || vec![x.clone()],
+            || self.fwd_wrt_unchecked(x),
         )
     }
-}
 
-impl FWrap for OpSin {
-    fn new() -> Box<dyn FWrap>
-    where
-        Self: Sized,
-    {
-        Box::new(OpSin {})
-    }
-    fn f(&self) -> Box<dyn FnMut(Vec<(ValType, bool)>, Option<ValType>) -> ValType> {
-        Box::new(move |x: Vec<(ValType, bool)>, _v: Option<ValType>| {
-            assert!(x.len() == 1);
-            match x[0].0 {
-                ValType::F(v0) => ValType::F(v0.sin()),
-                ValType::D(v0) => ValType::D(v0.sin()),
-                ValType::I(v0) => ValType::F((v0 as f32).sin()),
-                ValType::L(v0) => ValType::F((v0 as f32).sin()),
-            }
-        })
-    }
-    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
-        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
-            assert_eq!(args.len(), 1);
-            VWrap::new_with_input(OpCos::new(), vec![args[0].clone()])
+    fn fwd_wrt_unchecked(&self, x: &PtrVWrap) -> ValType {
+        let leaves: Vec<PtrVWrap> = self
+            .reachable()
+            .into_iter()
+            .filter(|n| n.0.deref().borrow().inp.is_empty())
+            .collect();
+        if !leaves.contains(x) {
+            return ValType::F(0.);
+        }
+        with_active(std::slice::from_ref(self), std::slice::from_ref(x), || {
+            self.fwd().apply_fwd()
         })
     }
-    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
-        Box::new(
-            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
-                assert_eq!(inputs.len(), 1);
-                let a = VWrap::new_with_input(OpCos::new(), vec![inputs[0].clone()]);
-                vec![Mul(a, out_adj.clone())]
-            },
-        )
-    }
-}
 
-impl FWrap for OpCos {
-    fn new() -> Box<dyn FWrap>
-    where
-        Self: Sized,
-    {
-        Box::new(OpCos {})
-    }
-    fn f(&self) -> Box<dyn FnMut(Vec<(ValType, bool)>, Option<ValType>) -> ValType> {
-        Box::new(move |x: Vec<(ValType, bool)>, _v: Option<ValType>| {
-            assert!(x.len() == 1);
-            match x[0].0 {
-                ValType::F(v0) => ValType::F(v0.cos()),
-                ValType::D(v0) => ValType::D(v0.cos()),
-                ValType::I(v0) => ValType::F((v0 as f32).cos()),
-                ValType::L(v0) => ValType::F((v0 as f32).cos()),
-            }
-        })
-    }
-    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
-        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
-            assert_eq!(args.len(), 1);
-            Mul(
-                VWrap::new_with_val(OpConst::new(), ValType::F(-1.)),
-                VWrap::new_with_input(OpSin::new(), vec![args[0].clone()]),
-            )
-        })
-    }
-    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
-        Box::new(
-            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
-                assert_eq!(inputs.len(), 1);
-                let a = Mul(
-                    VWrap::new_with_val(OpConst::new(), ValType::F(-1.)),
-                    VWrap::new_with_input(OpSin::new(), vec![inputs[0].clone()]),
-                );
-                vec![Mul(a, out_adj.clone())]
-            },
-        )
+    pub fn adjoint(&self) -> Option<PtrVWrap> {
+        self.0.deref().borrow().adj_accum.clone()
     }
-}
 
-impl FWrap for OpTan {
-    fn new() -> Box<dyn FWrap>
-    where
-        Self: Sized,
-    {
-        Box::new(OpTan {})
+    pub fn reset_adjoint(&mut self) {
+        self.0.deref().borrow_mut().adj_accum = None;
     }
-    fn f(&self) -> Box<dyn FnMut(Vec<(ValType, bool)>, Option<ValType>) -> ValType> {
-        Box::new(move |x: Vec<(ValType, bool)>, _v: Option<ValType>| {
-            assert!(x.len() == 1);
-            match x[0].0 {
-                ValType::F(v0) => ValType::F(v0.tan()),
-                ValType::D(v0) => ValType::D(v0.tan()),
-                ValType::I(v0) => ValType::F((v0 as f32).tan()),
-                ValType::L(v0) => ValType::F((v0 as f32).tan()),
+
+    /// nodes reachable from the current variable through input dependencies, depth-first
+    fn reachable(&self) -> Vec<PtrVWrap> {
+        let mut visited: HashSet<PtrVWrap> = HashSet::new();
+        let mut nodes = vec![];
+        let mut stack = vec![self.clone()];
+        while let Some(n) = stack.pop() {
+            if visited.insert(n.clone()) {
+                stack.extend(n.0.deref().borrow().inp.iter().rev().cloned());
+                nodes.push(n);
             }
-        })
-    }
-    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
-        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
-            //y'=1/(cos(x))^2
-            assert_eq!(args.len(), 1);
-            let one = VWrap::new_with_val(OpConst::new(), ValType::F(1.));
-            Mul(
-                Div(one, Mul(Cos(args[0].clone()), Cos(args[0].clone()))),
-                args[0].fwd(),
-            )
-        })
+        }
+        nodes
     }
-    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
-        Box::new(
-            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
-                assert_eq!(inputs.len(), 1);
+}
 
-                let one = VWrap::new_with_val(OpConst::new(), ValType::F(1.));
-                let a = Div(one, Mul(Cos(inputs[0].clone()), Cos(inputs[0].clone())));
+/// cost of a division relative to an addition or multiplication
+pub const COST_DIV: u64 = 4;
+/// cost of an elementary transcendental function (sin, exp, ln, ...)
+pub const COST_TRANSCENDENTAL: u64 = 20;
 
-                vec![Mul(a, out_adj.clone())]
-            },
-        )
-    }
+/// n^3 for a node over the n^2 entries of a square matrix
+fn cube(entries: usize) -> u64 {
+    let n = (entries as f64).sqrt().round() as u64;
+    n * n * n
 }
 
-impl FWrap for OpPow {
-    fn new() -> Box<dyn FWrap>
-    where
-        Self: Sized,
-    {
-        Box::new(OpPow {})
-    }
-    fn f(&self) -> Box<dyn FnMut(Vec<(ValType, bool)>, Option<ValType>) -> ValType> {
-        Box::new(move |x: Vec<(ValType, bool)>, _v: Option<ValType>| {
-            assert!(x.len() == 2);
-            let base: f32 = x[0].0.into();
-            let expo: f32 = x[1].0.into();
-            if expo < 1e-15 && expo > -1e-15 {
-                ValType::F(1.)
-            } else {
-                ValType::F(base.powf(expo))
-            }
-        })
-    }
-    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
-        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
-            //y = x^a = exp(ln(x^a)) = exp(a ln(x))
-            //y' = exp(a ln(x))( a'*ln(x) + a/x*x') = x^a *(a'*ln(x)+a/x*x')
-
-            assert_eq!(args.len(), 2);
+/// n for a node over the n^2 entries of a square matrix
+fn square_root(entries: usize) -> u64 {
+    (entries as f64).sqrt().round() as u64
+}
 
-            Mul(
-                Pow(args[0].clone(), args[1].clone()),
-                Add(
-                    Mul(args[1].fwd(), Ln(args[0].clone())),
-                    Mul(Div(args[1].clone(), args[0].clone()), args[0].fwd()),
-                ),
-            )
-        })
-    }
-    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
-        Box::new(
-            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
-                //y = x^a = exp(ln(x^a)) = exp(a ln(x))
-                //y' = exp(a ln(x))( a'*ln(x) + a/x*x')
-                //   = x^(a-1)*a*x' + x^a*ln(x) a'
+/// elementary operations that can be evaluated on any scalar type
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ScalarOp {
+    Add,
+    Mul,
+    Div,
+    /// atan2(y, x)
+    Atan2,
+    Sin,
+    Cos,
+    Tan,
+    Tanh,
+    /// logistic function 1/(1+exp(-x))
+    Sigmoid,
+    /// ln(1+exp(x))
+    Softplus,
+    Exp,
+    Ln,
+    Sqrt,
+    Pow,
+    /// floor-based remainder
+    Rem,
+    Sum,
+    /// a*b+c
+    Fma,
+    /// a+t*(b-a)
+    Lerp,
+    Scale(f32),
+}
 
-                assert_eq!(inputs.len(), 2);
+/// summation of the contributions to an adjoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AdjointSum {
+    /// chain of additions in the order of the sweep
+    Chain,
+    /// single sum in f64, rounded once
+    F64,
+    /// single compensated sum
+    Compensated,
+}
 
-                let one = VWrap::new_with_val(OpConst::new(), ValType::F(1.));
+/// operation classes recognized by the fusion pass
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OpKind {
+    Add,
+    Mul,
+    Div,
+    Const,
+    Zero,
+    One,
+    Link,
+    Other,
+}
 
-                vec![
-                    Mul(
-                        Mul(
-                            Pow(inputs[0].clone(), Minus(inputs[1].clone(), one)),
-                            inputs[1].clone(),
-                        ),
-                        out_adj.clone(),
-                    ),
-                    Mul(
-                        Mul(
-                            Pow(inputs[0].clone(), inputs[1].clone()),
-                            Ln(inputs[0].clone()),
-                        ),
-                        out_adj.clone(),
-                    ),
-                ]
-            },
-        )
+/// evaluation function of a node, called with input values and activity flags and the
+/// node's stored value
+pub(crate) type EvalFn = Box<dyn FnMut(&[(ValType, bool)], Option<ValType>) -> ValType + Send>;
+
+/// applies a binary operation exactly to integers, in the wider integer type if they differ,
+/// and otherwise in the widest float type involved, promoting integers to floats
+fn arith(a: ValType, b: ValType, int: fn(i64, i64) -> i64, float: fn(f64, f64) -> f64) -> ValType {
+    match (a, b) {
+        (ValType::I(v0), ValType::I(v1)) => ValType::I(int(v0.into(), v1.into()) as i32),
+        (ValType::I(_), ValType::L(_)) | (ValType::L(_), ValType::I(_) | ValType::L(_)) => {
+            let wide = |v| match v {
+                ValType::I(v) => i64::from(v),
+                ValType::L(v) => v,
+                _ => unreachable!(),
+            };
+            ValType::L(int(wide(a), wide(b)))
+        }
+        (ValType::D(_), _) | (_, ValType::D(_)) => ValType::D(float(a.into(), b.into())),
+        _ => ValType::F(float(a.into(), b.into()) as f32),
     }
 }
 
-impl FWrap for OpExp {
-    fn new() -> Box<dyn FWrap>
-    where
-        Self: Sized,
-    {
-        Box::new(OpExp {})
-    }
-    fn f(&self) -> Box<dyn FnMut(Vec<(ValType, bool)>, Option<ValType>) -> ValType> {
-        Box::new(move |x: Vec<(ValType, bool)>, _v: Option<ValType>| {
-            assert!(x.len() == 1);
-            let expo: f32 = x[0].0.into();
-            ValType::F(expo.exp())
-        })
-    }
-    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
-        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
-            //y=exp(x)
-            //y'=exp(x)*x'
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
 
-            assert_eq!(args.len(), 1);
+/// 64-bit FNV-1a, which unlike the std hashers is fixed across runs and versions
+fn fnv1a(mut h: u64, bytes: &[u8]) -> u64 {
+    for b in bytes {
+        h ^= u64::from(*b);
+        h = h.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    h
+}
 
-            Mul(Exp(args[0].clone()), args[0].fwd())
-        })
+/// operands of a commutative op, ordered by structural hash while canonical_order is on
+fn canonical_pair(a: PtrVWrap, b: PtrVWrap) -> Vec<PtrVWrap> {
+    if canonical_order() && b.structural_hash() < a.structural_hash() {
+        vec![b, a]
+    } else {
+        vec![a, b]
     }
-    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
-        Box::new(
-            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
-                assert_eq!(inputs.len(), 1);
+}
 
-                vec![Mul(Exp(inputs[0].clone()), out_adj.clone())]
-            },
-        )
+/// nodes reachable from the roots with every node listed after its inputs
+pub(crate) fn topo_order(roots: &[PtrVWrap]) -> Vec<PtrVWrap> {
+    let mut visited: HashSet<PtrVWrap> = HashSet::new();
+    let mut order = vec![];
+    let mut stack: Vec<(PtrVWrap, bool)> = roots.iter().rev().map(|r| (r.clone(), false)).collect();
+    while let Some((n, expanded)) = stack.pop() {
+        if expanded {
+            order.push(n);
+            continue;
+        }
+        if !visited.insert(n.clone()) {
+            continue;
+        }
+        stack.push((n.clone(), true));
+        for i in n.0.deref().borrow().inp.iter().rev() {
+            stack.push((i.clone(), false));
+        }
     }
+    order
 }
 
-impl FWrap for OpLn {
+/// wrapper for function
+trait FWrap: std::fmt::Debug {
     fn new() -> Box<dyn FWrap>
     where
-        Self: Sized,
-    {
-        Box::new(OpLn {})
-    }
-    fn f(&self) -> Box<dyn FnMut(Vec<(ValType, bool)>, Option<ValType>) -> ValType> {
-        Box::new(move |x: Vec<(ValType, bool)>, _v: Option<ValType>| {
-            assert!(x.len() == 1);
-            let expo: f32 = x[0].0.into();
-            ValType::F(expo.ln())
-        })
-    }
-    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
-        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
-            //y=ln(x)
-            //y'= 1/x *x'
+        Self: Sized;
 
-            assert_eq!(args.len(), 1);
+    /// creates a function to evaluate given values
+    fn f(&self) -> EvalFn;
 
-            let one = VWrap::new_with_val(OpConst::new(), ValType::F(1.));
+    /// creates a function to evaluate given values for reverse pass
+    fn f_rev(&self) -> EvalFn {
+        self.f()
+    }
 
-            Mul(Div(one, args[0].clone()), args[0].fwd())
-        })
+    /// coarse operation class used by graph rewrites
+    fn kind(&self) -> OpKind {
+        OpKind::Other
     }
-    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
-        Box::new(
-            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
-                assert_eq!(inputs.len(), 1);
 
-                let one = VWrap::new_with_val(OpConst::new(), ValType::F(1.));
+    /// estimated floating point operations of one evaluation given the number of inputs,
+    /// weighted by COST_DIV and COST_TRANSCENDENTAL; one per input by default
+    fn flops(&self, inputs: usize) -> u64 {
+        inputs as u64
+    }
 
-                vec![Mul(Div(one, inputs[0].clone()), out_adj.clone())]
-            },
-        )
+    /// elementary form of the operation for evaluation on generic scalars, None if the
+    /// operation has none
+    fn scalar_op(&self) -> Option<ScalarOp> {
+        None
     }
-}
 
-impl FWrap for OpDiv {
-    fn new() -> Box<dyn FWrap>
-    where
-        Self: Sized,
-    {
-        Box::new(OpDiv {})
+    /// rust expression evaluating the operation on the given argument expressions, None if
+    /// the operation has no straight-line form
+    fn rust_expr(&self, _args: &[String]) -> Option<String> {
+        None
     }
-    fn f(&self) -> Box<dyn FnMut(Vec<(ValType, bool)>, Option<ValType>) -> ValType> {
-        Box::new(move |x: Vec<(ValType, bool)>, _v: Option<ValType>| {
-            assert!(x.len() == 2);
-            let a: f32 = x[0].0.into();
-            let b: f32 = x[1].0.into();
-            ValType::F(a / b)
-        })
+
+    /// sympy expression of the operation on the given argument expressions, None if sympy
+    /// has no counterpart
+    fn sympy_expr(&self, _args: &[String]) -> Option<String> {
+        None
     }
-    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
-        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
-            //y=a/b
-            //y'= (a'b-ab')/(b*b)
 
-            assert_eq!(args.len(), 2);
+    /// creates linear tangent function with given input dependencies and returns wrapped variable
+    /// used in forward mode
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap>;
 
-            Div(
-                Minus(
-                    Mul(args[0].fwd(), args[1].clone()),
-                    Mul(args[0].clone(), args[1].fwd()),
-                ),
-                Mul(args[1].clone(), args[1].clone()),
-            )
+    /// creates function to compute the adjoint for the input dependencies
+    /// used in reverse mode
+    fn adjoint(
+        &self,
+    ) -> Box<
+        dyn FnMut(
+            Vec<PtrVWrap>, /*inputs*/
+            PtrVWrap,      /*accumulated adjoint*/
+            &PtrVWrap,     /*self*/
+        ) -> Vec<PtrVWrap>,
+    >;
+}
+
+#[derive(Debug, Clone, Copy)]
+struct OpMul {}
+#[derive(Debug, Clone, Copy)]
+struct OpAdd {}
+#[derive(Debug, Clone, Copy)]
+struct OpLeaf {}
+#[derive(Debug, Clone, Copy)]
+struct OpOne {}
+/// special link to variable of interest for gradient calc
+#[derive(Debug, Clone, Copy)]
+struct OpLink {}
+#[derive(Debug, Clone, Copy)]
+struct OpZero {}
+#[derive(Debug, Clone, Copy)]
+struct OpConst {}
+#[derive(Debug, Clone, Copy)]
+struct OpSin {}
+#[derive(Debug, Clone, Copy)]
+struct OpCos {}
+#[derive(Debug, Clone, Copy)]
+struct OpTan {}
+#[derive(Debug, Clone, Copy)]
+struct OpTanh {}
+#[derive(Debug, Clone, Copy)]
+struct OpSigmoid {}
+#[derive(Debug, Clone, Copy)]
+struct OpSoftplus {}
+/// cumulative distribution function of the standard normal distribution
+#[derive(Debug, Clone, Copy)]
+struct OpNormalCdf {}
+#[derive(Debug, Clone, Copy)]
+struct OpGelu {}
+#[derive(Debug, Clone, Copy)]
+struct OpPow {}
+#[derive(Debug, Clone, Copy)]
+struct OpExp {}
+#[derive(Debug, Clone, Copy)]
+struct OpLn {}
+#[derive(Debug, Clone, Copy)]
+struct OpSqrt {}
+#[derive(Debug, Clone, Copy)]
+struct OpDiv {}
+#[derive(Debug, Clone, Copy)]
+struct OpAtan2 {}
+/// floor-based remainder, derivative wrt divisor is optional
+#[derive(Debug, Clone, Copy)]
+struct OpRem {
+    divisor_grad: bool,
+}
+/// bessel functions of integer order 0 and 1, and the scaled forms used by their derivatives
+#[derive(Debug, Clone, Copy)]
+enum BesselKind {
+    J0,
+    J1,
+    Y0,
+    Y1,
+    I0,
+    I1,
+    K0,
+    K1,
+    /// J_n(x)/x^n, finite at x=0
+    JScaled(u32),
+    /// I_n(x)/x^n, finite at x=0
+    IScaled(u32),
+}
+#[derive(Debug, Clone, Copy)]
+struct OpBessel {
+    kind: BesselKind,
+}
+/// n-th derivative of sin(x)/x, finite at x=0
+#[derive(Debug, Clone, Copy)]
+struct OpSinc {
+    order: u32,
+}
+/// ln|gamma(x)| for order 0, the (order-1)-th polygamma function otherwise
+#[derive(Debug, Clone, Copy)]
+struct OpLnGamma {
+    order: u32,
+}
+/// closed form KL(N(mu1, s1) || N(mu2, s2)) over inputs mu1, s1, mu2, s2
+#[derive(Debug, Clone, Copy)]
+struct OpKlNormal {}
+/// closed form KL(Bernoulli(p) || Bernoulli(q)) over inputs p, q
+#[derive(Debug, Clone, Copy)]
+struct OpKlBernoulli {}
+/// linear interpolation a + t*(b-a)
+#[derive(Debug, Clone, Copy)]
+struct OpLerp {}
+/// n-th derivative of sin(sqrt(s))/sqrt(s) wrt s, smooth through s=0
+#[derive(Debug, Clone, Copy)]
+struct OpSincSqrt {
+    order: u32,
+}
+/// acos(c)^2, smooth through c=1
+#[derive(Debug, Clone, Copy)]
+struct OpAcosSq {}
+/// log-sum-exp smooth max (sign=1) or min (sign=-1) with temperature tau
+#[derive(Debug, Clone, Copy)]
+struct OpSoftExtremum {
+    tau: f32,
+    sign: f32,
+}
+/// sum of all inputs
+#[derive(Debug, Clone, Copy)]
+struct OpSum {}
+/// sum of all inputs with kahan-neumaier compensation in the precision of the inputs
+#[derive(Debug, Clone, Copy)]
+struct OpKahanSum {}
+/// real (imag=false) or imaginary part of (re+i*im)*(a+i*b) for inputs a, b
+#[derive(Debug, Clone, Copy)]
+struct OpTwiddle {
+    re: f64,
+    im: f64,
+    imag: bool,
+}
+/// real (imag=false) or imaginary part of bin k of the discrete fourier transform
+/// of inputs [re_0..re_(n-1), im_0..im_(n-1)], scaled by 1/n when inverse
+#[derive(Debug, Clone, Copy)]
+struct OpDft {
+    k: usize,
+    imag: bool,
+    inverse: bool,
+}
+/// inner product sum(a_k*b_k) of the first and second half of the inputs
+#[derive(Debug, Clone, Copy)]
+struct OpDot {}
+/// determinant of the n by n row-major inputs
+#[derive(Debug, Clone, Copy)]
+struct OpDet {
+    n: usize,
+}
+/// entry (i,j) of the adjugate of the n by n row-major inputs, n > 1
+#[derive(Debug, Clone, Copy)]
+struct OpAdjEntry {
+    n: usize,
+    i: usize,
+    j: usize,
+}
+/// entry (i,j) of the inverse of the n by n row-major inputs
+#[derive(Debug, Clone, Copy)]
+struct OpInvEntry {
+    n: usize,
+    i: usize,
+    j: usize,
+}
+/// entry (i,j) of the lower cholesky factor of the n by n row-major inputs
+#[derive(Debug, Clone, Copy)]
+struct OpCholEntry {
+    n: usize,
+    i: usize,
+    j: usize,
+}
+/// entry i of the solution of L x = b, or of L^T x = b if transposed, for inputs L
+/// (n by n row-major) followed by b
+#[derive(Debug, Clone, Copy)]
+struct OpSolveEntry {
+    n: usize,
+    i: usize,
+    transpose: bool,
+}
+/// eigenvalue i of the n by n row-major symmetric inputs
+#[derive(Debug, Clone, Copy)]
+struct OpEigval {
+    n: usize,
+    i: usize,
+}
+/// component r of eigenvector i of the n by n row-major symmetric inputs
+#[derive(Debug, Clone, Copy)]
+struct OpEigvec {
+    n: usize,
+    r: usize,
+    i: usize,
+}
+/// part of the thin svd of the rows by cols row-major inputs, rows >= cols
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SvdPart {
+    U,
+    S,
+    V,
+}
+/// entry (r,i) of U or V, or singular value i, of the thin svd of the rows by cols
+/// row-major inputs, rows >= cols
+#[derive(Debug, Clone, Copy)]
+struct OpSvdEntry {
+    rows: usize,
+    cols: usize,
+    part: SvdPart,
+    r: usize,
+    i: usize,
+}
+/// fused multiply-add a*b+c
+#[derive(Debug, Clone, Copy)]
+struct OpFma {}
+/// factor*x for a constant factor
+#[derive(Debug, Clone, Copy)]
+struct OpScale {
+    factor: f32,
+}
+/// exp(x) whose derivative builders evaluate the node itself, exercising reentrant access
+#[cfg(test)]
+#[derive(Debug, Clone, Copy)]
+struct OpReentrantExp {}
+/// scheme used between the samples of an interpolated signal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interpolation {
+    Linear,
+    /// cubic hermite with catmull-rom tangents, one-sided at the ends
+    Cubic,
+}
+/// order-th derivative wrt t of a sampled signal interpolated at t, inputs are t and then
+/// the samples, or with a weight index only t and the output is the weight of that sample
+#[derive(Debug, Clone)]
+struct OpInterp {
+    times: Arc<Vec<f64>>,
+    scheme: Interpolation,
+    order: u32,
+    weight: Option<usize>,
+}
+/// entry selected when the graph is built, the only input (Gather, Embedding)
+#[derive(Debug, Clone, Copy)]
+struct OpSelect {}
+/// threshold t with sum(sigmoid((x_i-t)/tau)) = k over all inputs
+#[derive(Debug, Clone, Copy)]
+struct OpTopKThreshold {
+    k: f32,
+    tau: f32,
+}
+/// 1/x, or 0 where |x| <= tol
+#[derive(Debug, Clone, Copy)]
+struct OpSafeRecip {
+    tol: f32,
+}
+/// entry i of (x-mean(x))/sqrt(var(x)+eps) over all inputs
+#[derive(Debug, Clone, Copy)]
+struct OpNormalize {
+    index: usize,
+    eps: f32,
+}
+/// 1/sqrt(var(x)+eps) over all inputs
+#[derive(Debug, Clone, Copy)]
+struct OpInvStd {
+    eps: f32,
+}
+/// n-th derivative of the hermite step polynomial clamped to [0,1]
+#[derive(Debug, Clone, Copy)]
+struct OpStep {
+    coeffs: &'static [f64],
+    order: u32,
+    /// resolves derivatives that jump at 0 or 1
+    policy: SubgradientPolicy,
+}
+/// max(x, 0)
+#[derive(Debug, Clone, Copy)]
+struct OpRelu {}
+/// unit step, with the value at 0 given by the policy over the one-sided limits 0 and 1
+#[derive(Debug, Clone, Copy)]
+struct OpHeaviside {
+    policy: SubgradientPolicy,
+}
+/// x clamped to [lo, hi], with the derivative of x passed through unchanged if pass_through
+#[derive(Debug, Clone, Copy)]
+struct OpClip {
+    lo: f32,
+    hi: f32,
+    pass_through: bool,
+}
+/// derivative of clamping to [lo, hi]: 1 inside, 0 outside, given by the policy at the bounds
+#[derive(Debug, Clone, Copy)]
+struct OpClipGate {
+    lo: f32,
+    hi: f32,
+    policy: SubgradientPolicy,
+}
+/// |x|
+#[derive(Debug, Clone, Copy)]
+struct OpAbs {}
+/// max(a, b), or min(a, b) if not max
+#[derive(Debug, Clone, Copy)]
+struct OpMaxMin {
+    max: bool,
+}
+/// sign of x, with the value at 0 given by the policy over the one-sided limits -1 and 1
+#[derive(Debug, Clone, Copy)]
+struct OpSign {
+    policy: SubgradientPolicy,
+}
+
+impl FWrap for OpMul {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpMul {})
+    }
+    fn kind(&self) -> OpKind {
+        OpKind::Mul
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        1
+    }
+    fn scalar_op(&self) -> Option<ScalarOp> {
+        Some(ScalarOp::Mul)
+    }
+    fn rust_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("{} * {}", args[0], args[1]))
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("{}*{}", args[0], args[1]))
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |x: &[(ValType, bool)], _: Option<ValType>| {
+            assert!(x.len() == 2);
+            match (x[0].0, x[1].0) {
+                (ValType::F(v0), ValType::F(v1)) => ValType::F(v0 * v1),
+                (a, b) => arith(a, b, |a, b| a.wrapping_mul(b), |a, b| a * b),
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |args: Vec<PtrVWrap>, _: &PtrVWrap| {
+            assert!(args.len() == 2);
+
+            //apply chain rule: (xy)' = x'y + xy'
+
+            let a_prime = args[0].fwd();
+            let m1 = VWrap::new_with_input(OpMul::new(), vec![a_prime, args[1].clone()]);
+
+            let b_prime = args[1].fwd();
+            let m2 = VWrap::new_with_input(OpMul::new(), vec![args[0].clone(), b_prime]);
+
+            VWrap::new_with_input(OpAdd::new(), vec![m1, m2])
         })
     }
     fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
         Box::new(
-            //y=a/b
-            //y'= (a'b-ab')/(b*b) = a'/b - ab'/(b*b)
             move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
                 assert_eq!(inputs.len(), 2);
-
-                let one = VWrap::new_with_val(OpConst::new(), ValType::F(1.));
-                let minus_one = VWrap::new_with_val(OpConst::new(), ValType::F(-1.));
-
                 vec![
-                    Mul(Div(one, inputs[1].clone()), out_adj.clone()),
-                    Mul(
-                        Div(
-                            Mul(minus_one, inputs[0].clone()),
-                            Mul(inputs[1].clone(), inputs[1].clone()),
-                        ),
-                        out_adj.clone(),
-                    ),
+                    Mul(inputs[1].clone(), out_adj.clone()),
+                    Mul(inputs[0].clone(), out_adj),
                 ]
             },
         )
     }
 }
 
-#[allow(dead_code)]
-pub fn Mul(arg0: PtrVWrap, arg1: PtrVWrap) -> PtrVWrap {
-    let mut a = VWrap::new(OpMul::new());
-    a.set_inp(vec![arg0, arg1]);
-    a
-}
+impl FWrap for OpAdd {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpAdd {})
+    }
+    fn kind(&self) -> OpKind {
+        OpKind::Add
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        1
+    }
+    fn scalar_op(&self) -> Option<ScalarOp> {
+        Some(ScalarOp::Add)
+    }
+    fn rust_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("{} + {}", args[0], args[1]))
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("{} + {}", args[0], args[1]))
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |x: &[(ValType, bool)], _: Option<ValType>| {
+            assert_eq!(x.len(), 2);
+            match (x[0].0, x[1].0) {
+                (ValType::F(v0), ValType::F(v1)) => ValType::F(v0 + v1),
+                (a, b) => arith(a, b, |a, b| a.wrapping_add(b), |a, b| a + b),
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |args: Vec<PtrVWrap>, _: &PtrVWrap| {
+            //apply rule: (a+b+c+...)' = a'+b'+c'+...
 
-#[allow(dead_code)]
-pub fn Add(arg0: PtrVWrap, arg1: PtrVWrap) -> PtrVWrap {
-    let mut a = VWrap::new(OpAdd::new());
-    a.set_inp(vec![arg0, arg1]);
-    a
-}
+            let mut inp_grad = vec![];
 
-#[allow(dead_code)]
-pub fn Minus(arg0: PtrVWrap, arg1: PtrVWrap) -> PtrVWrap {
-    let mut a = VWrap::new(OpAdd::new());
-    let temp = VWrap::new_with_val(OpConst::new(), ValType::F(-1.));
-    a.set_inp(vec![arg0, Mul(arg1, temp)]);
-    a
-}
+            for i in args.iter() {
+                let d = i.fwd();
+                inp_grad.push(d);
+            }
 
-#[allow(dead_code)]
-pub fn Leaf(arg0: ValType) -> PtrVWrap {
-    let a = VWrap::new_with_val(OpLeaf::new(), arg0);
-    a
-}
+            assert!(inp_grad.len() > 0);
 
-#[allow(dead_code)]
-pub fn Sin(arg0: PtrVWrap) -> PtrVWrap {
-    let mut a = VWrap::new(OpSin::new());
-    a.set_inp(vec![arg0]);
-    a
-}
+            let count = inp_grad.len();
 
-#[allow(dead_code)]
-pub fn Cos(arg0: PtrVWrap) -> PtrVWrap {
-    let mut a = VWrap::new(OpCos::new());
-    a.set_inp(vec![arg0]);
-    a
-}
+            if count > 1 {
+                for i in 1..count {
+                    let temp = VWrap::new_with_input(
+                        OpAdd::new(),
+                        vec![inp_grad[i - 1].clone(), inp_grad[i].clone()],
+                    );
 
-#[allow(dead_code)]
-pub fn Tan(arg0: PtrVWrap) -> PtrVWrap {
-    let mut a = VWrap::new(OpTan::new());
-    a.set_inp(vec![arg0]);
-    a
+                    inp_grad[i] = temp;
+                }
+            }
+            inp_grad[count - 1].clone()
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 2);
+                vec![out_adj.clone(), out_adj]
+            },
+        )
+    }
 }
 
+impl FWrap for OpLeaf {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpLeaf {})
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        0
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |_x: &[(ValType, bool)], v: Option<ValType>| v.expect("leaf value missing"))
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |_args: Vec<PtrVWrap>, self_ptr: &PtrVWrap| {
+            let seeded = TANGENTS.with(|c| c.borrow().as_ref().map(|c| c.leaf == *self_ptr));
+            match seeded {
+                Some(true) => one(),
+                Some(false) => zero(),
+                None => VWrap::new_with_input(OpLink::new(), vec![self_ptr.clone()]),
+            }
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, _out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 0);
+                vec![]
+            },
+        )
+    }
+}
+
+/// special construct for representing derivative of a variable created in tangent-linear pass
+impl FWrap for OpLink {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpLink {})
+    }
+    fn kind(&self) -> OpKind {
+        OpKind::Link
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        0
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() == 1);
+            if x[0].1 {
+                //indicator for calculating gradient of the linked variable
+                ValType::F(1.)
+            } else {
+                ValType::F(0.)
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |_args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| zero())
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, _out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                vec![zero(); inputs.len()]
+            },
+        )
+    }
+}
+
+impl FWrap for OpConst {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpConst {})
+    }
+    fn kind(&self) -> OpKind {
+        OpKind::Const
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        0
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |_x: &[(ValType, bool)], v: Option<ValType>| v.expect("leaf value missing"))
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |_args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| zero())
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, _out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 0);
+                vec![]
+            },
+        )
+    }
+}
+
+impl FWrap for OpOne {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpOne {})
+    }
+    fn kind(&self) -> OpKind {
+        OpKind::One
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        0
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |_x: &[(ValType, bool)], _v: Option<ValType>| ValType::F(1.))
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |_args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| zero())
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, _out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 0);
+                vec![]
+            },
+        )
+    }
+}
+
+impl FWrap for OpZero {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpZero {})
+    }
+    fn kind(&self) -> OpKind {
+        OpKind::Zero
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        0
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |_x: &[(ValType, bool)], _v: Option<ValType>| {
+            //todo
+            ValType::F(0.)
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |_args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| zero())
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, _out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 0);
+                vec![]
+            },
+        )
+    }
+}
+
+impl FWrap for OpSin {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpSin {})
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        COST_TRANSCENDENTAL
+    }
+    fn scalar_op(&self) -> Option<ScalarOp> {
+        Some(ScalarOp::Sin)
+    }
+    fn rust_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("{}.sin()", args[0]))
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("sin({})", args[0]))
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() == 1);
+            match x[0].0 {
+                ValType::F(v0) => ValType::F(v0.sin()),
+                ValType::D(v0) => ValType::D(v0.sin()),
+                ValType::I(v0) => ValType::F((v0 as f32).sin()),
+                ValType::L(v0) => ValType::F((v0 as f32).sin()),
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //y'=cos(x)*x'
+            assert_eq!(args.len(), 1);
+            Mul(
+                VWrap::new_with_input(OpCos::new(), vec![args[0].clone()]),
+                args[0].fwd(),
+            )
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 1);
+                let a = VWrap::new_with_input(OpCos::new(), vec![inputs[0].clone()]);
+                vec![Mul(a, out_adj.clone())]
+            },
+        )
+    }
+}
+
+impl FWrap for OpCos {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpCos {})
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        COST_TRANSCENDENTAL
+    }
+    fn scalar_op(&self) -> Option<ScalarOp> {
+        Some(ScalarOp::Cos)
+    }
+    fn rust_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("{}.cos()", args[0]))
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("cos({})", args[0]))
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() == 1);
+            match x[0].0 {
+                ValType::F(v0) => ValType::F(v0.cos()),
+                ValType::D(v0) => ValType::D(v0.cos()),
+                ValType::I(v0) => ValType::F((v0 as f32).cos()),
+                ValType::L(v0) => ValType::F((v0 as f32).cos()),
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //y'=-sin(x)*x'
+            assert_eq!(args.len(), 1);
+            Mul(
+                Mul(
+                    constant(ValType::F(-1.)),
+                    VWrap::new_with_input(OpSin::new(), vec![args[0].clone()]),
+                ),
+                args[0].fwd(),
+            )
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 1);
+                let a = Mul(
+                    constant(ValType::F(-1.)),
+                    VWrap::new_with_input(OpSin::new(), vec![inputs[0].clone()]),
+                );
+                vec![Mul(a, out_adj.clone())]
+            },
+        )
+    }
+}
+
+impl FWrap for OpTan {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpTan {})
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        COST_TRANSCENDENTAL
+    }
+    fn scalar_op(&self) -> Option<ScalarOp> {
+        Some(ScalarOp::Tan)
+    }
+    fn rust_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("{}.tan()", args[0]))
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("tan({})", args[0]))
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() == 1);
+            match x[0].0 {
+                ValType::F(v0) => ValType::F(v0.tan()),
+                ValType::D(v0) => ValType::D(v0.tan()),
+                ValType::I(v0) => ValType::F((v0 as f32).tan()),
+                ValType::L(v0) => ValType::F((v0 as f32).tan()),
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //y'=1/(cos(x))^2
+            assert_eq!(args.len(), 1);
+            let one = constant(ValType::F(1.));
+            Mul(
+                Div(one, Mul(Cos(args[0].clone()), Cos(args[0].clone()))),
+                args[0].fwd(),
+            )
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 1);
+
+                let one = constant(ValType::F(1.));
+                let a = Div(one, Mul(Cos(inputs[0].clone()), Cos(inputs[0].clone())));
+
+                vec![Mul(a, out_adj.clone())]
+            },
+        )
+    }
+}
+
+impl FWrap for OpPow {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpPow {})
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        2 * COST_TRANSCENDENTAL
+    }
+    fn scalar_op(&self) -> Option<ScalarOp> {
+        Some(ScalarOp::Pow)
+    }
+    fn rust_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("{}.powf({})", args[0], args[1]))
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("{}**{}", args[0], args[1]))
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() == 2);
+            let base: f32 = x[0].0.into();
+            let expo: f32 = x[1].0.into();
+            if expo < 1e-15 && expo > -1e-15 {
+                ValType::F(1.)
+            } else {
+                ValType::F(base.powf(expo))
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //y = x^a = exp(ln(x^a)) = exp(a ln(x))
+            //y' = exp(a ln(x))( a'*ln(x) + a/x*x') = x^a *(a'*ln(x)+a/x*x')
+
+            assert_eq!(args.len(), 2);
+
+            Mul(
+                Pow(args[0].clone(), args[1].clone()),
+                Add(
+                    Mul(args[1].fwd(), Ln(args[0].clone())),
+                    Mul(Div(args[1].clone(), args[0].clone()), args[0].fwd()),
+                ),
+            )
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                //y = x^a = exp(ln(x^a)) = exp(a ln(x))
+                //y' = exp(a ln(x))( a'*ln(x) + a/x*x')
+                //   = x^(a-1)*a*x' + x^a*ln(x) a'
+
+                assert_eq!(inputs.len(), 2);
+
+                let one = constant(ValType::F(1.));
+
+                vec![
+                    Mul(
+                        Mul(
+                            Pow(inputs[0].clone(), Minus(inputs[1].clone(), one)),
+                            inputs[1].clone(),
+                        ),
+                        out_adj.clone(),
+                    ),
+                    Mul(
+                        Mul(
+                            Pow(inputs[0].clone(), inputs[1].clone()),
+                            Ln(inputs[0].clone()),
+                        ),
+                        out_adj.clone(),
+                    ),
+                ]
+            },
+        )
+    }
+}
+
+impl FWrap for OpTanh {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpTanh {})
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        COST_TRANSCENDENTAL
+    }
+    fn scalar_op(&self) -> Option<ScalarOp> {
+        Some(ScalarOp::Tanh)
+    }
+    fn rust_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("{}.tanh()", args[0]))
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("tanh({})", args[0]))
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() == 1);
+            match x[0].0 {
+                ValType::D(v0) => ValType::D(v0.tanh()),
+                v0 => ValType::F(f32::from(v0).tanh()),
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |args: Vec<PtrVWrap>, self_ptr: &PtrVWrap| {
+            //y=tanh(x)
+            //y'=(1-y^2)*x'
+
+            assert_eq!(args.len(), 1);
+
+            let one = constant(ValType::F(1.));
+            let d = Minus(one, Mul(self_ptr.clone(), self_ptr.clone()));
+
+            Mul(d, args[0].fwd())
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 1);
+
+                let one = constant(ValType::F(1.));
+                let d = Minus(one, Mul(cur.clone(), cur.clone()));
+
+                vec![Mul(d, out_adj)]
+            },
+        )
+    }
+}
+
+impl OpSigmoid {
+    /// 1/(1+exp(-x)), with exp only taken of non-positive arguments
+    fn eval(x: f64) -> f64 {
+        if x >= 0. {
+            1. / (1. + (-x).exp())
+        } else {
+            let e = x.exp();
+            e / (1. + e)
+        }
+    }
+}
+
+impl FWrap for OpSigmoid {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpSigmoid {})
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        COST_TRANSCENDENTAL + COST_DIV
+    }
+    fn scalar_op(&self) -> Option<ScalarOp> {
+        Some(ScalarOp::Sigmoid)
+    }
+    fn rust_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!(
+            "{{ let x = {}; if x >= 0. {{ 1. / (1. + (-x).exp()) }} else {{ x.exp() / (1. + x.exp()) }} }}",
+            args[0]
+        ))
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("1/(1 + exp(-({})))", args[0]))
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() == 1);
+            match x[0].0 {
+                ValType::D(v0) => ValType::D(OpSigmoid::eval(v0)),
+                v0 => ValType::F(OpSigmoid::eval(v0.into()) as f32),
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |args: Vec<PtrVWrap>, self_ptr: &PtrVWrap| {
+            //y=1/(1+exp(-x))
+            //y'=y*(1-y)*x'
+
+            assert_eq!(args.len(), 1);
+
+            let one = constant(ValType::F(1.));
+            let d = Mul(self_ptr.clone(), Minus(one, self_ptr.clone()));
+
+            Mul(d, args[0].fwd())
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 1);
+
+                let one = constant(ValType::F(1.));
+                let d = Mul(cur.clone(), Minus(one, cur.clone()));
+
+                vec![Mul(d, out_adj)]
+            },
+        )
+    }
+}
+
+impl OpSoftplus {
+    /// ln(1+exp(x)) as max(x,0) + ln(1+exp(-|x|)), with exp only taken of non-positive
+    /// arguments
+    fn eval(x: f64) -> f64 {
+        x.max(0.) + (-x.abs()).exp().ln_1p()
+    }
+}
+
+impl FWrap for OpSoftplus {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpSoftplus {})
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        2 * COST_TRANSCENDENTAL
+    }
+    fn scalar_op(&self) -> Option<ScalarOp> {
+        Some(ScalarOp::Softplus)
+    }
+    fn rust_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!(
+            "{{ let x = {}; x.max(0.) + (-x.abs()).exp().ln_1p() }}",
+            args[0]
+        ))
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("log(1 + exp({}))", args[0]))
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() == 1);
+            match x[0].0 {
+                ValType::D(v0) => ValType::D(OpSoftplus::eval(v0)),
+                v0 => ValType::F(OpSoftplus::eval(v0.into()) as f32),
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //y=ln(1+exp(x))
+            //y'=sigmoid(x)*x'
+
+            assert_eq!(args.len(), 1);
+
+            Mul(Sigmoid(args[0].clone()), args[0].fwd())
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 1);
+
+                vec![Mul(Sigmoid(inputs[0].clone()), out_adj)]
+            },
+        )
+    }
+}
+
+/// density of the standard normal distribution, exp(-x^2/2)/sqrt(2pi)
+fn normal_pdf(x: PtrVWrap) -> PtrVWrap {
+    let scale = constant(ValType::F(
+        0.5 * std::f32::consts::FRAC_2_SQRT_PI / std::f32::consts::SQRT_2,
+    ));
+    let minus_half = constant(ValType::F(-0.5));
+    Mul(scale, Exp(Mul(minus_half, Mul(x.clone(), x))))
+}
+
+fn normal_cdf(x: PtrVWrap) -> PtrVWrap {
+    let mut a = VWrap::new(OpNormalCdf::new());
+    a.set_inp(vec![x]);
+    a
+}
+
+impl FWrap for OpNormalCdf {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpNormalCdf {})
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        COST_TRANSCENDENTAL
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("(1 + erf(({})/sqrt(2)))/2", args[0]))
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() == 1);
+            match x[0].0 {
+                ValType::D(v0) => ValType::D(special::normal_cdf(v0)),
+                v0 => ValType::F(special::normal_cdf(v0.into()) as f32),
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //y=cdf(x)
+            //y'=pdf(x)*x'
+
+            assert_eq!(args.len(), 1);
+
+            Mul(normal_pdf(args[0].clone()), args[0].fwd())
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 1);
+
+                vec![Mul(normal_pdf(inputs[0].clone()), out_adj)]
+            },
+        )
+    }
+}
+
+impl FWrap for OpGelu {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpGelu {})
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        COST_TRANSCENDENTAL + 1
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("({0})*(1 + erf(({0})/sqrt(2)))/2", args[0]))
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() == 1);
+            match x[0].0 {
+                ValType::D(v0) => ValType::D(v0 * special::normal_cdf(v0)),
+                v0 => {
+                    let v0 = f64::from(v0);
+                    ValType::F((v0 * special::normal_cdf(v0)) as f32)
+                }
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //y=x*cdf(x)
+            //y'=(cdf(x)+x*pdf(x))*x'
+
+            assert_eq!(args.len(), 1);
+
+            let x = args[0].clone();
+            let d = Add(normal_cdf(x.clone()), Mul(x.clone(), normal_pdf(x)));
+
+            Mul(d, args[0].fwd())
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 1);
+
+                let x = inputs[0].clone();
+                let d = Add(normal_cdf(x.clone()), Mul(x.clone(), normal_pdf(x)));
+
+                vec![Mul(d, out_adj)]
+            },
+        )
+    }
+}
+
+impl FWrap for OpExp {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpExp {})
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        COST_TRANSCENDENTAL
+    }
+    fn scalar_op(&self) -> Option<ScalarOp> {
+        Some(ScalarOp::Exp)
+    }
+    fn rust_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("{}.exp()", args[0]))
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("exp({})", args[0]))
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() == 1);
+            let expo: f32 = x[0].0.into();
+            ValType::F(expo.exp())
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //y=exp(x)
+            //y'=exp(x)*x'
+
+            assert_eq!(args.len(), 1);
+
+            Mul(Exp(args[0].clone()), args[0].fwd())
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 1);
+
+                vec![Mul(Exp(inputs[0].clone()), out_adj.clone())]
+            },
+        )
+    }
+}
+
+impl FWrap for OpLn {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpLn {})
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        COST_TRANSCENDENTAL
+    }
+    fn scalar_op(&self) -> Option<ScalarOp> {
+        Some(ScalarOp::Ln)
+    }
+    fn rust_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("{}.ln()", args[0]))
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("log({})", args[0]))
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() == 1);
+            let expo: f32 = x[0].0.into();
+            ValType::F(expo.ln())
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //y=ln(x)
+            //y'= 1/x *x'
+
+            assert_eq!(args.len(), 1);
+
+            let one = constant(ValType::F(1.));
+
+            Mul(Div(one, args[0].clone()), args[0].fwd())
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 1);
+
+                let one = constant(ValType::F(1.));
+
+                vec![Mul(Div(one, inputs[0].clone()), out_adj.clone())]
+            },
+        )
+    }
+}
+
+impl FWrap for OpSqrt {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpSqrt {})
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        COST_DIV
+    }
+    fn scalar_op(&self) -> Option<ScalarOp> {
+        Some(ScalarOp::Sqrt)
+    }
+    fn rust_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("{}.sqrt()", args[0]))
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("sqrt({})", args[0]))
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() == 1);
+            match x[0].0 {
+                ValType::D(a) => ValType::D(a.sqrt()),
+                a => ValType::F(f32::from(a).sqrt()),
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |args: Vec<PtrVWrap>, self_ptr: &PtrVWrap| {
+            //y=sqrt(x)
+            //y'=x'/(2*y)
+
+            assert_eq!(args.len(), 1);
+
+            let two = constant(ValType::F(2.));
+
+            Div(args[0].fwd(), Mul(two, self_ptr.clone()))
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 1);
+
+                let two = constant(ValType::F(2.));
+
+                vec![Div(out_adj, Mul(two, cur.clone()))]
+            },
+        )
+    }
+}
+
+impl FWrap for OpDiv {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpDiv {})
+    }
+    fn kind(&self) -> OpKind {
+        OpKind::Div
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        COST_DIV
+    }
+    fn scalar_op(&self) -> Option<ScalarOp> {
+        Some(ScalarOp::Div)
+    }
+    fn rust_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("{} / {}", args[0], args[1]))
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("{}/{}", args[0], args[1]))
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() == 2);
+            let a: f32 = x[0].0.into();
+            let b: f32 = x[1].0.into();
+            ValType::F(a / b)
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //y=a/b
+            //y'= (a'b-ab')/(b*b)
+
+            assert_eq!(args.len(), 2);
+
+            Div(
+                Minus(
+                    Mul(args[0].fwd(), args[1].clone()),
+                    Mul(args[0].clone(), args[1].fwd()),
+                ),
+                Mul(args[1].clone(), args[1].clone()),
+            )
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            //y=a/b
+            //y'= (a'b-ab')/(b*b) = a'/b - ab'/(b*b)
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 2);
+
+                let one = constant(ValType::F(1.));
+                let minus_one = constant(ValType::F(-1.));
+
+                vec![
+                    Mul(Div(one, inputs[1].clone()), out_adj.clone()),
+                    Mul(
+                        Div(
+                            Mul(minus_one, inputs[0].clone()),
+                            Mul(inputs[1].clone(), inputs[1].clone()),
+                        ),
+                        out_adj.clone(),
+                    ),
+                ]
+            },
+        )
+    }
+}
+
+impl FWrap for OpAtan2 {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpAtan2 {})
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        COST_TRANSCENDENTAL
+    }
+    fn scalar_op(&self) -> Option<ScalarOp> {
+        Some(ScalarOp::Atan2)
+    }
+    fn rust_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("{}.atan2({})", args[0], args[1]))
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("atan2({}, {})", args[0], args[1]))
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() == 2);
+            match (x[0].0, x[1].0) {
+                (ValType::D(_), _) | (_, ValType::D(_)) => {
+                    ValType::D(f64::from(x[0].0).atan2(f64::from(x[1].0)))
+                }
+                (a, b) => ValType::F(f32::from(a).atan2(f32::from(b))),
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //y=atan2(a,b)
+            //y'=(b*a'-a*b')/(a*a+b*b)
+
+            assert_eq!(args.len(), 2);
+
+            let r2 = Add(
+                Mul(args[0].clone(), args[0].clone()),
+                Mul(args[1].clone(), args[1].clone()),
+            );
+            Div(
+                Minus(
+                    Mul(args[1].clone(), args[0].fwd()),
+                    Mul(args[0].clone(), args[1].fwd()),
+                ),
+                r2,
+            )
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 2);
+
+                let r2 = Add(
+                    Mul(inputs[0].clone(), inputs[0].clone()),
+                    Mul(inputs[1].clone(), inputs[1].clone()),
+                );
+                let w = Div(out_adj, r2);
+                let minus_one = constant(ValType::F(-1.));
+
+                vec![
+                    Mul(inputs[1].clone(), w.clone()),
+                    Mul(Mul(minus_one, inputs[0].clone()), w),
+                ]
+            },
+        )
+    }
+}
+
+impl FWrap for OpRem {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpRem {
+            divisor_grad: false,
+        })
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        2 * COST_DIV
+    }
+    fn scalar_op(&self) -> Option<ScalarOp> {
+        Some(ScalarOp::Rem)
+    }
+    fn rust_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("{0} - {1} * ({0} / {1}).floor()", args[0], args[1]))
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("Mod({}, {})", args[0], args[1]))
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() == 2);
+            let a: f32 = x[0].0.into();
+            let b: f32 = x[1].0.into();
+            ValType::F(a - b * (a / b).floor())
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        let divisor_grad = self.divisor_grad;
+        Box::new(move |args: Vec<PtrVWrap>, self_ptr: &PtrVWrap| {
+            //y = a - b*floor(a/b)
+            //y' = a' - floor(a/b)*b', where floor(a/b) = (a-y)/b
+            //the floor term is dropped unless requested
+
+            assert_eq!(args.len(), 2);
+
+            if divisor_grad {
+                Minus(
+                    args[0].fwd(),
+                    Mul(
+                        Div(Minus(args[0].clone(), self_ptr.clone()), args[1].clone()),
+                        args[1].fwd(),
+                    ),
+                )
+            } else {
+                args[0].fwd()
+            }
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        let divisor_grad = self.divisor_grad;
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 2);
+
+                let b_adj = if divisor_grad {
+                    let minus_one = constant(ValType::F(-1.));
+                    Mul(
+                        Mul(
+                            minus_one,
+                            Div(Minus(inputs[0].clone(), cur.clone()), inputs[1].clone()),
+                        ),
+                        out_adj.clone(),
+                    )
+                } else {
+                    zero()
+                };
+
+                vec![out_adj, b_adj]
+            },
+        )
+    }
+}
+
+impl OpBessel {
+    fn eval(kind: BesselKind, x: f64) -> f64 {
+        match kind {
+            BesselKind::J0 => special::bessel_j0(x),
+            BesselKind::J1 => special::bessel_j1(x),
+            BesselKind::Y0 => special::bessel_y0(x),
+            BesselKind::Y1 => special::bessel_y1(x),
+            BesselKind::I0 => special::bessel_i0(x),
+            BesselKind::I1 => special::bessel_i1(x),
+            BesselKind::K0 => special::bessel_k0(x),
+            BesselKind::K1 => special::bessel_k1(x),
+            BesselKind::JScaled(n) => special::bessel_j_scaled(n, x),
+            BesselKind::IScaled(n) => special::bessel_i_scaled(n, x),
+        }
+    }
+    ///derivative wrt x expressed using the recurrences:
+    ///J0'=-J1, J1'=J0-J1/x, Y0'=-Y1, Y1'=Y0-Y1/x,
+    ///I0'=I1, I1'=I0-I1/x, K0'=-K1, K1'=-K0-K1/x,
+    ///(J_n/x^n)'=-x J_(n+1)/x^(n+1), (I_n/x^n)'=x I_(n+1)/x^(n+1)
+    ///
+    ///J1/x and I1/x use the scaled kinds so the derivatives are finite at x=0
+    fn derivative(kind: BesselKind, x: PtrVWrap) -> PtrVWrap {
+        let minus_one = constant(ValType::F(-1.));
+        let node = |k: BesselKind| bessel(k, x.clone());
+        match kind {
+            BesselKind::J0 => Mul(minus_one, node(BesselKind::J1)),
+            BesselKind::J1 => Minus(node(BesselKind::J0), node(BesselKind::JScaled(1))),
+            BesselKind::Y0 => Mul(minus_one, node(BesselKind::Y1)),
+            BesselKind::Y1 => Minus(node(BesselKind::Y0), Div(node(BesselKind::Y1), x.clone())),
+            BesselKind::I0 => node(BesselKind::I1),
+            BesselKind::I1 => Minus(node(BesselKind::I0), node(BesselKind::IScaled(1))),
+            BesselKind::JScaled(n) => {
+                Mul(minus_one, Mul(x.clone(), node(BesselKind::JScaled(n + 1))))
+            }
+            BesselKind::IScaled(n) => Mul(x.clone(), node(BesselKind::IScaled(n + 1))),
+            BesselKind::K0 => Mul(minus_one, node(BesselKind::K1)),
+            BesselKind::K1 => Minus(
+                Mul(minus_one, node(BesselKind::K0)),
+                Div(node(BesselKind::K1), x.clone()),
+            ),
+        }
+    }
+}
+
+impl FWrap for OpBessel {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpBessel {
+            kind: BesselKind::J0,
+        })
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        2 * COST_TRANSCENDENTAL
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        let (name, order) = match self.kind {
+            BesselKind::J0 => ("besselj", 0),
+            BesselKind::J1 => ("besselj", 1),
+            BesselKind::Y0 => ("bessely", 0),
+            BesselKind::Y1 => ("bessely", 1),
+            BesselKind::I0 => ("besseli", 0),
+            BesselKind::I1 => ("besseli", 1),
+            BesselKind::K0 => ("besselk", 0),
+            BesselKind::K1 => ("besselk", 1),
+            BesselKind::JScaled(n) => {
+                return Some(format!("besselj({}, {x})/({x})**{}", n, n, x = args[0]))
+            }
+            BesselKind::IScaled(n) => {
+                return Some(format!("besseli({}, {x})/({x})**{}", n, n, x = args[0]))
+            }
+        };
+        Some(format!("{}({}, {})", name, order, args[0]))
+    }
+    fn f(&self) -> EvalFn {
+        let kind = self.kind;
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() == 1);
+            match x[0].0 {
+                ValType::D(a) => ValType::D(OpBessel::eval(kind, a)),
+                a => ValType::F(OpBessel::eval(kind, a.into()) as f32),
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        let kind = self.kind;
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //y=B(x)
+            //y'=B'(x)*x'
+
+            assert_eq!(args.len(), 1);
+
+            Mul(OpBessel::derivative(kind, args[0].clone()), args[0].fwd())
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        let kind = self.kind;
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 1);
+
+                vec![Mul(
+                    OpBessel::derivative(kind, inputs[0].clone()),
+                    out_adj.clone(),
+                )]
+            },
+        )
+    }
+}
+
+impl FWrap for OpSinc {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpSinc { order: 0 })
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        2 * COST_TRANSCENDENTAL
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        match self.order {
+            0 => Some(format!("sinc({})", args[0])),
+            _ => None,
+        }
+    }
+    fn f(&self) -> EvalFn {
+        let order = self.order;
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() == 1);
+            match x[0].0 {
+                ValType::D(a) => ValType::D(special::sinc_derivative(order, a)),
+                a => ValType::F(special::sinc_derivative(order, a.into()) as f32),
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        let order = self.order;
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //y=sinc_n(x)
+            //y'=sinc_(n+1)(x)*x'
+
+            assert_eq!(args.len(), 1);
+
+            Mul(sinc(order + 1, args[0].clone()), args[0].fwd())
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        let order = self.order;
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 1);
+
+                vec![Mul(sinc(order + 1, inputs[0].clone()), out_adj.clone())]
+            },
+        )
+    }
+}
+
+impl FWrap for OpLnGamma {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpLnGamma { order: 0 })
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        4 * COST_TRANSCENDENTAL
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        match self.order {
+            0 => Some(format!("loggamma({})", args[0])),
+            n => Some(format!("polygamma({}, {})", n - 1, args[0])),
+        }
+    }
+    fn f(&self) -> EvalFn {
+        let order = self.order;
+        let f = move |x: f64| match order {
+            0 => special::ln_gamma(x),
+            n => special::polygamma(n - 1, x),
+        };
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() == 1);
+            match x[0].0 {
+                ValType::D(a) => ValType::D(f(a)),
+                a => ValType::F(f(a.into()) as f32),
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        let order = self.order;
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //y=psi_(n-1)(x)
+            //y'=psi_n(x)*x'
+
+            assert_eq!(args.len(), 1);
+
+            Mul(ln_gamma(order + 1, args[0].clone()), args[0].fwd())
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        let order = self.order;
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 1);
+
+                vec![Mul(ln_gamma(order + 1, inputs[0].clone()), out_adj.clone())]
+            },
+        )
+    }
+}
+
+impl OpStep {
+    ///3t^2-2t^3
+    const SMOOTH: &'static [f64] = &[0., 0., 3., -2.];
+    ///6t^5-15t^4+10t^3
+    const SMOOTHER: &'static [f64] = &[0., 0., 0., 10., -15., 6.];
+
+    fn eval(coeffs: &[f64], order: u32, policy: SubgradientPolicy, t: f64) -> f64 {
+        if order > 0 && t == 0. {
+            return policy.resolve(0., OpStep::poly(coeffs, order, t));
+        }
+        if order > 0 && t == 1. {
+            return policy.resolve(OpStep::poly(coeffs, order, t), 0.);
+        }
+        if t <= 0. || t >= 1. {
+            //flat outside the unit interval
+            return if order == 0 && t >= 1. { 1. } else { 0. };
+        }
+        OpStep::poly(coeffs, order, t)
+    }
+
+    fn poly(coeffs: &[f64], order: u32, t: f64) -> f64 {
+        coeffs
+            .iter()
+            .enumerate()
+            .skip(order as usize)
+            .map(|(p, c)| {
+                let falling: f64 = ((p + 1 - order as usize)..=p).map(|i| i as f64).product();
+                c * falling * t.powi(p as i32 - order as i32)
+            })
+            .sum()
+    }
+}
+
+impl FWrap for OpStep {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpStep {
+            coeffs: OpStep::SMOOTH,
+            order: 0,
+            policy: SubgradientPolicy::Zero,
+        })
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        2 * self.coeffs.len() as u64
+    }
+    fn f(&self) -> EvalFn {
+        let (coeffs, order, policy) = (self.coeffs, self.order, self.policy);
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() == 1);
+            match x[0].0 {
+                ValType::D(a) => ValType::D(OpStep::eval(coeffs, order, policy, a)),
+                a => ValType::F(OpStep::eval(coeffs, order, policy, a.into()) as f32),
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        let (coeffs, order) = (self.coeffs, self.order);
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //y=p_n(t)
+            //y'=p_(n+1)(t)*t'
+
+            assert_eq!(args.len(), 1);
+
+            Mul(step(coeffs, order + 1, args[0].clone()), args[0].fwd())
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        let (coeffs, order) = (self.coeffs, self.order);
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 1);
+
+                vec![Mul(
+                    step(coeffs, order + 1, inputs[0].clone()),
+                    out_adj.clone(),
+                )]
+            },
+        )
+    }
+}
+
+impl FWrap for OpRelu {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpRelu {})
+    }
+    fn rust_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("{}.max(0.0)", args[0]))
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("Max({}, 0)", args[0]))
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() == 1);
+            match x[0].0 {
+                ValType::D(a) => ValType::D(a.max(0.)),
+                a => ValType::F(f32::from(a).max(0.)),
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //y=max(x,0)
+            //y'=H(x)*x'
+
+            assert_eq!(args.len(), 1);
+
+            Mul(heaviside(args[0].clone()), args[0].fwd())
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 1);
+
+                vec![Mul(heaviside(inputs[0].clone()), out_adj)]
+            },
+        )
+    }
+}
+
+impl FWrap for OpClip {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpClip {
+            lo: 0.,
+            hi: 1.,
+            pass_through: false,
+        })
+    }
+    fn rust_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("{}.max({:?}).min({:?})", args[0], self.lo, self.hi))
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!(
+            "Min(Max({}, {:?}), {:?})",
+            args[0], self.lo, self.hi
+        ))
+    }
+    fn f(&self) -> EvalFn {
+        let (lo, hi) = (self.lo, self.hi);
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() == 1);
+            match x[0].0 {
+                ValType::D(a) => ValType::D(a.max(lo.into()).min(hi.into())),
+                a => ValType::F(f32::from(a).max(lo).min(hi)),
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        let op = *self;
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //y=min(max(x,lo),hi)
+            //y'=x' if pass_through, 1[lo<x<hi]*x' otherwise
+
+            assert_eq!(args.len(), 1);
+
+            if op.pass_through {
+                args[0].fwd()
+            } else {
+                Mul(clip_gate(op.lo, op.hi, args[0].clone()), args[0].fwd())
+            }
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        let op = *self;
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 1);
+
+                if op.pass_through {
+                    vec![out_adj]
+                } else {
+                    vec![Mul(clip_gate(op.lo, op.hi, inputs[0].clone()), out_adj)]
+                }
+            },
+        )
+    }
+}
+
+impl OpClipGate {
+    fn eval(&self, x: f64) -> f64 {
+        let (lo, hi) = (f64::from(self.lo), f64::from(self.hi));
+        if x < lo || x > hi {
+            0.
+        } else if x == lo && x == hi {
+            self.policy.resolve(0., 0.)
+        } else if x == lo {
+            self.policy.resolve(0., 1.)
+        } else if x == hi {
+            self.policy.resolve(1., 0.)
+        } else {
+            1.
+        }
+    }
+}
+
+impl FWrap for OpClipGate {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpClipGate {
+            lo: 0.,
+            hi: 1.,
+            policy: SubgradientPolicy::Zero,
+        })
+    }
+    fn f(&self) -> EvalFn {
+        let op = *self;
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() == 1);
+            match x[0].0 {
+                ValType::D(a) => ValType::D(op.eval(a)),
+                a => ValType::F(op.eval(a.into()) as f32),
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //piecewise constant
+            assert_eq!(args.len(), 1);
+
+            zero()
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, _out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 1);
+
+                vec![zero()]
+            },
+        )
+    }
+}
+
+impl OpHeaviside {
+    fn eval(policy: SubgradientPolicy, x: f64) -> f64 {
+        if x > 0. {
+            1.
+        } else if x < 0. {
+            0.
+        } else {
+            policy.resolve(0., 1.)
+        }
+    }
+}
+
+impl FWrap for OpHeaviside {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpHeaviside {
+            policy: SubgradientPolicy::Zero,
+        })
+    }
+    fn rust_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!(
+            "if {0} > 0.0 {{ 1.0 }} else if {0} < 0.0 {{ 0.0 }} else {{ {1:?} }}",
+            args[0],
+            self.policy.resolve(0., 1.)
+        ))
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!(
+            "Heaviside({}, {:?})",
+            args[0],
+            self.policy.resolve(0., 1.)
+        ))
+    }
+    fn f(&self) -> EvalFn {
+        let policy = self.policy;
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() == 1);
+            match x[0].0 {
+                ValType::D(a) => ValType::D(OpHeaviside::eval(policy, a)),
+                a => ValType::F(OpHeaviside::eval(policy, a.into()) as f32),
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //piecewise constant
+            assert_eq!(args.len(), 1);
+
+            zero()
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, _out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 1);
+
+                vec![zero()]
+            },
+        )
+    }
+}
+
+impl FWrap for OpAbs {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpAbs {})
+    }
+    fn rust_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("{}.abs()", args[0]))
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("Abs({})", args[0]))
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() == 1);
+            match x[0].0 {
+                ValType::D(a) => ValType::D(a.abs()),
+                a => ValType::F(f32::from(a).abs()),
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //y=|x|
+            //y'=sign(x)*x'
+
+            assert_eq!(args.len(), 1);
+
+            Mul(sign(args[0].clone()), args[0].fwd())
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 1);
+
+                vec![Mul(sign(inputs[0].clone()), out_adj)]
+            },
+        )
+    }
+}
+
+impl OpMaxMin {
+    /// share of the adjoint routed to a, from the unit step at a-b (b-a for min) with the
+    /// tie resolved by the policy; b gets the rest
+    #[track_caller]
+    fn weight_a(self, a: PtrVWrap, b: PtrVWrap) -> PtrVWrap {
+        if self.max {
+            heaviside(Minus(a, b))
+        } else {
+            heaviside(Minus(b, a))
+        }
+    }
+}
+
+impl FWrap for OpMaxMin {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpMaxMin { max: true })
+    }
+    fn rust_expr(&self, args: &[String]) -> Option<String> {
+        let f = if self.max { "max" } else { "min" };
+        Some(format!("{}.{}({})", args[0], f, args[1]))
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        let f = if self.max { "Max" } else { "Min" };
+        Some(format!("{}({}, {})", f, args[0], args[1]))
+    }
+    fn f(&self) -> EvalFn {
+        let max = self.max;
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert_eq!(x.len(), 2);
+            if max {
+                arith(x[0].0, x[1].0, i64::max, f64::max)
+            } else {
+                arith(x[0].0, x[1].0, i64::min, f64::min)
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        let op = *self;
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //y=max(a,b)
+            //y'=H(a-b)*a'+(1-H(a-b))*b', H(b-a) in place of H(a-b) for min
+
+            assert_eq!(args.len(), 2);
+
+            let w = op.weight_a(args[0].clone(), args[1].clone());
+            let one = constant(ValType::F(1.));
+
+            Add(
+                Mul(w.clone(), args[0].fwd()),
+                Mul(Minus(one, w), args[1].fwd()),
+            )
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        let op = *self;
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 2);
+
+                let w = op.weight_a(inputs[0].clone(), inputs[1].clone());
+                let one = constant(ValType::F(1.));
+
+                vec![Mul(w.clone(), out_adj.clone()), Mul(Minus(one, w), out_adj)]
+            },
+        )
+    }
+}
+
+impl OpSign {
+    fn eval(policy: SubgradientPolicy, x: f64) -> f64 {
+        if x > 0. {
+            1.
+        } else if x < 0. {
+            -1.
+        } else {
+            policy.resolve(-1., 1.)
+        }
+    }
+}
+
+impl FWrap for OpSign {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpSign {
+            policy: SubgradientPolicy::Zero,
+        })
+    }
+    fn rust_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!(
+            "if {0} > 0.0 {{ 1.0 }} else if {0} < 0.0 {{ -1.0 }} else {{ {1:?} }}",
+            args[0],
+            self.policy.resolve(-1., 1.)
+        ))
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!(
+            "Piecewise((sign({0}), Ne({0}, 0)), ({1:?}, True))",
+            args[0],
+            self.policy.resolve(-1., 1.)
+        ))
+    }
+    fn f(&self) -> EvalFn {
+        let policy = self.policy;
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() == 1);
+            match x[0].0 {
+                ValType::D(a) => ValType::D(OpSign::eval(policy, a)),
+                a => ValType::F(OpSign::eval(policy, a.into()) as f32),
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //piecewise constant
+            assert_eq!(args.len(), 1);
+
+            zero()
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, _out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 1);
+
+                vec![zero()]
+            },
+        )
+    }
+}
+
+impl FWrap for OpLerp {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpLerp {})
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        3
+    }
+    fn scalar_op(&self) -> Option<ScalarOp> {
+        Some(ScalarOp::Lerp)
+    }
+    fn rust_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!(
+            "{0} + {2} * ({1} - {0})",
+            args[0], args[1], args[2]
+        ))
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("{0} + {2}*({1} - {0})", args[0], args[1], args[2]))
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() == 3);
+            match (x[0].0, x[1].0, x[2].0) {
+                (ValType::D(a), ValType::D(b), ValType::D(t)) => ValType::D(a + t * (b - a)),
+                (a, b, t) => {
+                    let (a, b, t): (f32, f32, f32) = (a.into(), b.into(), t.into());
+                    ValType::F(a + t * (b - a))
+                }
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //y=a+t(b-a)
+            //y'=lerp(a',b',t) + t'(b-a)
+
+            assert_eq!(args.len(), 3);
+
+            Add(
+                Lerp(args[0].fwd(), args[1].fwd(), args[2].clone()),
+                Mul(args[2].fwd(), Minus(args[1].clone(), args[0].clone())),
+            )
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 3);
+
+                let one = constant(ValType::F(1.));
+
+                vec![
+                    Mul(Minus(one, inputs[2].clone()), out_adj.clone()),
+                    Mul(inputs[2].clone(), out_adj.clone()),
+                    Mul(Minus(inputs[1].clone(), inputs[0].clone()), out_adj),
+                ]
+            },
+        )
+    }
+}
+
+impl OpKlNormal {
+    fn eval(mu1: f64, s1: f64, mu2: f64, s2: f64) -> f64 {
+        let d = mu1 - mu2;
+        (s2 / s1).ln() + (s1 * s1 + d * d) / (2. * s2 * s2) - 0.5
+    }
+
+    ///partial derivatives wrt mu1, s1, mu2, s2
+    fn partials(inputs: &[PtrVWrap]) -> Vec<PtrVWrap> {
+        let one = constant(ValType::F(1.));
+        let (mu1, s1, mu2, s2) = (&inputs[0], &inputs[1], &inputs[2], &inputs[3]);
+        let d = Minus(mu1.clone(), mu2.clone());
+        let var2 = Mul(s2.clone(), s2.clone());
+        let dmu1 = Div(d.clone(), var2.clone());
+        vec![
+            dmu1.clone(),
+            Minus(Div(s1.clone(), var2.clone()), Div(one.clone(), s1.clone())),
+            Minus(constant(ValType::F(0.)), dmu1),
+            Minus(
+                Div(one, s2.clone()),
+                Div(
+                    Add(Mul(s1.clone(), s1.clone()), Mul(d.clone(), d)),
+                    Mul(var2, s2.clone()),
+                ),
+            ),
+        ]
+    }
+}
+
+impl FWrap for OpKlNormal {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpKlNormal {})
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        6 + 2 * COST_DIV + COST_TRANSCENDENTAL
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!(
+            "log({3}/{1}) + ({1}**2 + ({0} - {2})**2)/(2*{3}**2) - 1/2",
+            args[0], args[1], args[2], args[3]
+        ))
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() == 4);
+            match (x[0].0, x[1].0, x[2].0, x[3].0) {
+                (ValType::D(mu1), ValType::D(s1), ValType::D(mu2), ValType::D(s2)) => {
+                    ValType::D(OpKlNormal::eval(mu1, s1, mu2, s2))
+                }
+                (mu1, s1, mu2, s2) => {
+                    ValType::F(
+                        OpKlNormal::eval(mu1.into(), s1.into(), mu2.into(), s2.into()) as f32,
+                    )
+                }
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //y'=sum(dy/dx_i * x_i')
+
+            assert_eq!(args.len(), 4);
+
+            let terms = OpKlNormal::partials(&args)
+                .into_iter()
+                .zip(args.iter())
+                .map(|(d, x)| Mul(d, x.fwd()))
+                .collect();
+            sum(terms)
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 4);
+
+                OpKlNormal::partials(&inputs)
+                    .into_iter()
+                    .map(|d| Mul(d, out_adj.clone()))
+                    .collect()
+            },
+        )
+    }
+}
+
+impl OpKlBernoulli {
+    fn eval(p: f64, q: f64) -> f64 {
+        p * (p / q).ln() + (1. - p) * ((1. - p) / (1. - q)).ln()
+    }
+
+    ///partial derivatives wrt p, q
+    fn partials(inputs: &[PtrVWrap]) -> Vec<PtrVWrap> {
+        let one = constant(ValType::F(1.));
+        let (p, q) = (&inputs[0], &inputs[1]);
+        let np = Minus(one.clone(), p.clone());
+        let nq = Minus(one, q.clone());
+        vec![
+            Minus(
+                Minus(Ln(p.clone()), Ln(q.clone())),
+                Minus(Ln(np.clone()), Ln(nq.clone())),
+            ),
+            Minus(Div(np, nq), Div(p.clone(), q.clone())),
+        ]
+    }
+}
+
+impl FWrap for OpKlBernoulli {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpKlBernoulli {})
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        4 + 2 * COST_DIV + 2 * COST_TRANSCENDENTAL
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!(
+            "{0}*log({0}/{1}) + (1 - {0})*log((1 - {0})/(1 - {1}))",
+            args[0], args[1]
+        ))
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() == 2);
+            match (x[0].0, x[1].0) {
+                (ValType::D(p), ValType::D(q)) => ValType::D(OpKlBernoulli::eval(p, q)),
+                (p, q) => ValType::F(OpKlBernoulli::eval(p.into(), q.into()) as f32),
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //y'=dy/dp*p' + dy/dq*q'
+
+            assert_eq!(args.len(), 2);
+
+            let d = OpKlBernoulli::partials(&args);
+            Add(
+                Mul(d[0].clone(), args[0].fwd()),
+                Mul(d[1].clone(), args[1].fwd()),
+            )
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 2);
+
+                OpKlBernoulli::partials(&inputs)
+                    .into_iter()
+                    .map(|d| Mul(d, out_adj.clone()))
+                    .collect()
+            },
+        )
+    }
+}
+
+impl OpSoftExtremum {
+    ///softmax weight of input x: exp(sign*(x-y)/tau)
+    fn weight(&self, x: PtrVWrap, y: PtrVWrap) -> PtrVWrap {
+        let scale = constant(ValType::F(self.sign / self.tau));
+        Exp(Mul(scale, Minus(x, y)))
+    }
+}
+
+impl FWrap for OpSoftExtremum {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpSoftExtremum { tau: 1., sign: 1. })
+    }
+    fn flops(&self, inputs: usize) -> u64 {
+        inputs as u64 * (COST_TRANSCENDENTAL + 2) + COST_TRANSCENDENTAL
+    }
+    fn f(&self) -> EvalFn {
+        let (tau, sign) = (self.tau as f64, self.sign as f64);
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(!x.is_empty());
+            //y = sign*tau*ln(sum(exp(sign*x_i/tau))), shifted by the largest exponent
+            let z: Vec<f64> = x.iter().map(|(v, _)| sign * f64::from(*v) / tau).collect();
+            let m = z.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+            let sum: f64 = z.iter().map(|zi| (zi - m).exp()).sum();
+            let y = sign * tau * (m + sum.ln());
+            if x.iter().all(|(v, _)| matches!(v, ValType::D(_))) {
+                ValType::D(y)
+            } else {
+                ValType::F(y as f32)
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        let op = *self;
+        Box::new(move |args: Vec<PtrVWrap>, self_ptr: &PtrVWrap| {
+            //y'=sum(w_i*x_i')
+
+            assert!(!args.is_empty());
+
+            args.iter()
+                .map(|x| Mul(op.weight(x.clone(), self_ptr.clone()), x.fwd()))
+                .reduce(Add)
+                .unwrap()
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        let op = *self;
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, cur: &PtrVWrap| {
+                assert!(!inputs.is_empty());
+
+                inputs
+                    .iter()
+                    .map(|x| Mul(op.weight(x.clone(), cur.clone()), out_adj.clone()))
+                    .collect()
+            },
+        )
+    }
+}
+
+impl OpDft {
+    ///d out/d input for each of the 2n inputs
+    fn coeffs(&self, n: usize) -> Vec<f64> {
+        //X_k = sum((a+ib)*(cos(p) -+ i*sin(p))), p = 2*pi*k*j/n
+        let sign = if self.inverse { -1. } else { 1. };
+        let scale = if self.inverse { 1. / n as f64 } else { 1. };
+        let phase = |j: usize| 2. * std::f64::consts::PI * ((self.k * j) % n) as f64 / n as f64;
+        let (re, im): (Vec<f64>, Vec<f64>) = (0..n)
+            .map(|j| {
+                let (sin, cos) = phase(j).sin_cos();
+                if self.imag {
+                    (-sign * sin * scale, cos * scale)
+                } else {
+                    (cos * scale, sign * sin * scale)
+                }
+            })
+            .unzip();
+        re.into_iter().chain(im).collect()
+    }
+}
+
+impl FWrap for OpDft {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpDft {
+            k: 0,
+            imag: false,
+            inverse: false,
+        })
+    }
+    fn flops(&self, inputs: usize) -> u64 {
+        2 * inputs as u64 + COST_TRANSCENDENTAL * inputs as u64 / 2
+    }
+    fn f(&self) -> EvalFn {
+        let op = *self;
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() & 1 == 0);
+            let sum: f64 = op
+                .coeffs(x.len() / 2)
+                .iter()
+                .zip(x.iter())
+                .map(|(c, (v, _))| c * f64::from(*v))
+                .sum();
+            float_like(x, sum)
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        let op = *self;
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //linear, so the tangent is the same transform of the input tangents
+
+            assert!(args.len() & 1 == 0);
+
+            let mut a = VWrap::new(Box::new(op));
+            a.set_inp(args.iter().map(|x| x.fwd()).collect());
+            a
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        let op = *self;
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                //conjugate transform of the output adjoint
+
+                assert!(inputs.len() & 1 == 0);
+
+                op.coeffs(inputs.len() / 2)
+                    .into_iter()
+                    .map(|c| {
+                        let c = constant(ValType::F(c as f32));
+                        Mul(c, out_adj.clone())
+                    })
+                    .collect()
+            },
+        )
+    }
+}
+
+#[track_caller]
+fn twiddle(re: f64, im: f64, imag: bool, a: PtrVWrap, b: PtrVWrap) -> PtrVWrap {
+    VWrap::new_with_input(Box::new(OpTwiddle { re, im, imag }), vec![a, b])
+}
+
+impl FWrap for OpTwiddle {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpTwiddle {
+            re: 1.,
+            im: 0.,
+            imag: false,
+        })
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        3
+    }
+    fn f(&self) -> EvalFn {
+        let op = *self;
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert_eq!(x.len(), 2);
+            let (a, b) = (f64::from(x[0].0), f64::from(x[1].0));
+            if op.imag {
+                float_like(x, op.im * a + op.re * b)
+            } else {
+                float_like(x, op.re * a - op.im * b)
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        let op = *self;
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //linear, so the tangent is the same product of the input tangents
+
+            assert_eq!(args.len(), 2);
+
+            twiddle(op.re, op.im, op.imag, args[0].fwd(), args[1].fwd())
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        let op = *self;
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                //adj(a)+i*adj(b) is conj(w)*adj(y) for the real part, i*conj(w)*adj(y) for the imaginary part
+
+                assert_eq!(inputs.len(), 2);
+
+                let (re, im) = if op.imag {
+                    (op.im, op.re)
+                } else {
+                    (op.re, -op.im)
+                };
+                vec![
+                    twiddle(re, im, false, out_adj.clone(), zero()),
+                    twiddle(re, im, true, out_adj, zero()),
+                ]
+            },
+        )
+    }
+}
+
+impl FWrap for OpSum {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpSum {})
+    }
+    fn scalar_op(&self) -> Option<ScalarOp> {
+        Some(ScalarOp::Sum)
+    }
+    fn rust_expr(&self, args: &[String]) -> Option<String> {
+        Some(args.join(" + "))
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        Some(args.join(" + "))
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            let sum: f64 = x.iter().map(|(v, _)| f64::from(*v)).sum();
+            float_like(x, sum)
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            sum(args.iter().map(|x| x.fwd()).collect())
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                vec![out_adj; inputs.len()]
+            },
+        )
+    }
+}
+
+impl OpKahanSum {
+    fn sum<T: num_traits::Float>(x: impl Iterator<Item = T>) -> T {
+        let (mut s, mut c) = (T::zero(), T::zero());
+        for v in x {
+            let t = s + v;
+            //low order bits lost by the larger operand
+            c = c + if s.abs() >= v.abs() {
+                (s - t) + v
+            } else {
+                (v - t) + s
+            };
+            s = t;
+        }
+        s + c
+    }
+}
+
+impl FWrap for OpKahanSum {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpKahanSum {})
+    }
+    fn flops(&self, inputs: usize) -> u64 {
+        4 * inputs as u64
+    }
+    fn scalar_op(&self) -> Option<ScalarOp> {
+        Some(ScalarOp::Sum)
+    }
+    fn rust_expr(&self, args: &[String]) -> Option<String> {
+        Some(args.join(" + "))
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        Some(args.join(" + "))
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            if x.iter().all(|(v, _)| matches!(v, ValType::D(_))) {
+                ValType::D(OpKahanSum::sum(x.iter().map(|(v, _)| f64::from(*v))))
+            } else {
+                ValType::F(OpKahanSum::sum(x.iter().map(|(v, _)| f32::from(*v))))
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            VWrap::new_with_input(
+                Box::new(OpKahanSum {}),
+                args.iter().map(|x| x.fwd()).collect(),
+            )
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                vec![out_adj; inputs.len()]
+            },
+        )
+    }
+}
+
+impl FWrap for OpFma {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpFma {})
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        2
+    }
+    fn scalar_op(&self) -> Option<ScalarOp> {
+        Some(ScalarOp::Fma)
+    }
+    fn rust_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("{} * {} + {}", args[0], args[1], args[2]))
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("{}*{} + {}", args[0], args[1], args[2]))
+    }
+    fn f(&self) -> EvalFn {
+        let (mut mul, mut add) = (OpMul {}.f(), OpAdd {}.f());
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert_eq!(x.len(), 3);
+            //same rounding as the unfused Mul and Add
+            let ab = mul(&[x[0], x[1]], None);
+            add(&[(ab, false), x[2]], None)
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //(ab+c)' = a'b+(ab'+c')
+            assert_eq!(args.len(), 3);
+            let inner = fma(args[0].clone(), args[1].fwd(), args[2].fwd());
+            fma(args[0].fwd(), args[1].clone(), inner)
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 3);
+                vec![
+                    Mul(inputs[1].clone(), out_adj.clone()),
+                    Mul(inputs[0].clone(), out_adj.clone()),
+                    out_adj,
+                ]
+            },
+        )
+    }
+}
+
+impl FWrap for OpScale {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpScale { factor: 1. })
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        1
+    }
+    fn scalar_op(&self) -> Option<ScalarOp> {
+        Some(ScalarOp::Scale(self.factor))
+    }
+    fn rust_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("{} * {:?}", args[0], self.factor))
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        Some(format!("{:?}*{}", self.factor, args[0]))
+    }
+    fn f(&self) -> EvalFn {
+        let factor = self.factor;
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert_eq!(x.len(), 1);
+            let a: f32 = x[0].0.into();
+            ValType::F(a * factor)
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        let factor = self.factor;
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| scale(factor, args[0].fwd()))
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        let factor = self.factor;
+        Box::new(
+            move |_inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                vec![scale(factor, out_adj)]
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+impl FWrap for OpReentrantExp {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpReentrantExp {})
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            let a: f32 = x[0].0.into();
+            ValType::F(a.exp())
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |args: Vec<PtrVWrap>, self_ptr: &PtrVWrap| {
+            //y'=y*x' with y evaluated now
+            let y = self_ptr.clone().apply_fwd();
+            Mul(constant(y), args[0].fwd())
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |_inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, cur: &PtrVWrap| {
+                let y = cur.clone().apply_fwd();
+                vec![Mul(constant(y), out_adj)]
+            },
+        )
+    }
+}
+
+impl FWrap for OpSelect {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpSelect {})
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        0
+    }
+    fn rust_expr(&self, args: &[String]) -> Option<String> {
+        Some(args[0].clone())
+    }
+    fn sympy_expr(&self, args: &[String]) -> Option<String> {
+        Some(args[0].clone())
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert_eq!(x.len(), 1);
+            x[0].0
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //y'=x'
+            args[0].fwd()
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 1);
+                vec![out_adj]
+            },
+        )
+    }
+}
+
+/// weights w_j with order-th derivative wrt t of the interpolant equal to sum(w_j y_j)
+///
+/// t is clamped to the sampled range, outside of which all derivatives vanish
+fn interp_weights(times: &[f64], scheme: Interpolation, order: u32, t: f64) -> Vec<f64> {
+    let n = times.len();
+    let mut w = vec![0.; n];
+    let (first, last) = (times[0], times[n - 1]);
+    if order > 0 && (t < first || t > last) {
+        return w;
+    }
+    let t = t.max(first).min(last);
+    let k = match times.iter().rposition(|&x| x <= t) {
+        Some(k) => k.min(n - 2),
+        None => 0,
+    };
+    let h = times[k + 1] - times[k];
+    let s = (t - times[k]) / h;
+    let scale = h.powi(-(order as i32));
+
+    match scheme {
+        Interpolation::Linear => {
+            let (a, b) = match order {
+                0 => (1. - s, s),
+                1 => (-1., 1.),
+                _ => (0., 0.),
+            };
+            w[k] = a * scale;
+            w[k + 1] = b * scale;
+        }
+        Interpolation::Cubic => {
+            //order-th derivatives wrt s of the hermite basis h00, h10, h01, h11
+            let (s2, s3) = (s * s, s * s * s);
+            let basis = match order {
+                0 => [
+                    2. * s3 - 3. * s2 + 1.,
+                    s3 - 2. * s2 + s,
+                    -2. * s3 + 3. * s2,
+                    s3 - s2,
+                ],
+                1 => [
+                    6. * s2 - 6. * s,
+                    3. * s2 - 4. * s + 1.,
+                    -6. * s2 + 6. * s,
+                    3. * s2 - 2. * s,
+                ],
+                2 => [12. * s - 6., 6. * s - 4., -12. * s + 6., 6. * s - 2.],
+                3 => [12., 6., -12., 6.],
+                _ => [0.; 4],
+            };
+            //tangent at sample i as (lo, hi, c) with m_i = c*(y_hi - y_lo)
+            let tangent = |i: usize| {
+                let (lo, hi) = (i.saturating_sub(1), (i + 1).min(n - 1));
+                (lo, hi, 1. / (times[hi] - times[lo]))
+            };
+            w[k] += basis[0] * scale;
+            w[k + 1] += basis[2] * scale;
+            for (i, b) in [(k, basis[1]), (k + 1, basis[3])] {
+                let (lo, hi, c) = tangent(i);
+                w[hi] += b * h * c * scale;
+                w[lo] -= b * h * c * scale;
+            }
+        }
+    }
+    w
+}
+
+impl FWrap for OpInterp {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpInterp {
+            times: Arc::new(vec![0., 1.]),
+            scheme: Interpolation::Linear,
+            order: 0,
+            weight: None,
+        })
+    }
+    fn flops(&self, inputs: usize) -> u64 {
+        2 * inputs as u64 + COST_DIV
+    }
+    fn f(&self) -> EvalFn {
+        let (times, scheme, order, weight) =
+            (self.times.clone(), self.scheme, self.order, self.weight);
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            let w = interp_weights(&times, scheme, order, x[0].0.into());
+            match weight {
+                Some(j) => ValType::F(w[j] as f32),
+                None => {
+                    assert_eq!(x.len(), times.len() + 1);
+                    let v: f64 = w
+                        .iter()
+                        .zip(x[1..].iter())
+                        .map(|(w, (y, _))| w * f64::from(*y))
+                        .sum();
+                    ValType::F(v as f32)
+                }
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        let op = self.clone();
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //y=sum(w_j(t) y_j)
+            //y'=sum(w_j'(t) y_j)*t' + sum(w_j(t) y_j')
+            let dt = Mul(op.derivative(&args), args[0].fwd());
+            match op.weight {
+                Some(_) => dt,
+                None => {
+                    let mut tangents = vec![args[0].clone()];
+                    tangents.extend(args[1..].iter().map(|y| y.fwd()));
+                    Add(dt, op.build(tangents))
+                }
+            }
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        let op = self.clone();
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                let mut adj = vec![Mul(out_adj.clone(), op.derivative(&inputs))];
+                if op.weight.is_none() {
+                    adj.extend((0..op.times.len()).map(|j| {
+                        let w = OpInterp {
+                            weight: Some(j),
+                            ..op.clone()
+                        };
+                        Mul(out_adj.clone(), w.build(vec![inputs[0].clone()]))
+                    }));
+                }
+                adj
+            },
+        )
+    }
+}
+
+impl OpInterp {
+    fn build(&self, inputs: Vec<PtrVWrap>) -> PtrVWrap {
+        let mut a = VWrap::new(Box::new(self.clone()));
+        a.set_inp(inputs);
+        a
+    }
+
+    /// same node one derivative order higher in t
+    fn derivative(&self, inputs: &[PtrVWrap]) -> PtrVWrap {
+        OpInterp {
+            order: self.order + 1,
+            ..self.clone()
+        }
+        .build(inputs.to_vec())
+    }
+}
+
+impl FWrap for OpDot {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpDot {})
+    }
+    fn flops(&self, inputs: usize) -> u64 {
+        inputs as u64
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() & 1 == 0);
+            let (a, b) = x.split_at(x.len() / 2);
+            let sum: f64 = a
+                .iter()
+                .zip(b.iter())
+                .map(|((ai, _), (bi, _))| f64::from(*ai) * f64::from(*bi))
+                .sum();
+            float_like(x, sum)
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //y=a.b
+            //y'=a'.b+a.b'
+
+            assert!(args.len() & 1 == 0);
+
+            let (a, b) = args.split_at(args.len() / 2);
+            let da: Vec<PtrVWrap> = a.iter().map(|x| x.fwd()).collect();
+            let db: Vec<PtrVWrap> = b.iter().map(|x| x.fwd()).collect();
+            Add(Dot(da, b.to_vec()), Dot(a.to_vec(), db))
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert!(inputs.len() & 1 == 0);
+
+                let n = inputs.len() / 2;
+                (0..inputs.len())
+                    .map(|k| Mul(inputs[(k + n) % inputs.len()].clone(), out_adj.clone()))
+                    .collect()
+            },
+        )
+    }
+}
+
+fn values_f64(x: &[(ValType, bool)]) -> Vec<f64> {
+    x.iter().map(|(v, _)| f64::from(*v)).collect()
+}
+
+/// result of an n-ary op, in double precision when any input is
+fn float_like(x: &[(ValType, bool)], y: f64) -> ValType {
+    if x.iter().any(|(v, _)| matches!(v, ValType::D(_))) {
+        ValType::D(y)
+    } else {
+        ValType::F(y as f32)
+    }
+}
+
+/// last result of each dense matrix function with the bits of the input values it was
+/// computed from
+type MatrixResults = HashMap<&'static str, (Vec<u64>, Rc<Vec<f64>>)>;
+
+thread_local! {
+    /// lets the entry nodes of one matrix share a single factorization
+    static MATRIX_RESULTS: RefCell<Option<MatrixResults>> = const { RefCell::new(None) };
+}
+
+/// f of the input values, reusing the previous result of the same function for identical inputs
+fn matrix_result(
+    name: &'static str,
+    x: &[(ValType, bool)],
+    f: impl FnOnce(&[f64]) -> Vec<f64>,
+) -> Rc<Vec<f64>> {
+    let v = values_f64(x);
+    let key: Vec<u64> = v.iter().map(|x| x.to_bits()).collect();
+    MATRIX_RESULTS.with(|m| {
+        let mut m = m.borrow_mut();
+        let m = m.get_or_insert_with(HashMap::new);
+        match m.get(name) {
+            Some((k, r)) if *k == key => r.clone(),
+            _ => {
+                let r = Rc::new(f(&v));
+                m.insert(name, (key, r.clone()));
+                r
+            }
+        }
+    })
+}
+
+/// entry (i,j) of the adjugate, 1 for a 1 by 1 matrix
+#[track_caller]
+fn adj_entry(n: usize, i: usize, j: usize, args: Vec<PtrVWrap>) -> PtrVWrap {
+    if n == 1 {
+        return one();
+    }
+    let mut a = VWrap::new(Box::new(OpAdjEntry { n, i, j }));
+    a.set_inp(args);
+    a
+}
+
+/// derivatives d det(A)/d a_kl = adj_lk in row-major order of the inputs
+fn det_coeffs(n: usize, args: &[PtrVWrap]) -> Vec<PtrVWrap> {
+    (0..n * n)
+        .map(|k| adj_entry(n, k % n, k / n, args.to_vec()))
+        .collect()
+}
+
+/// positions of the inputs of A without row r and column c among the n by n inputs
+fn minor_positions(n: usize, r: usize, c: usize) -> Vec<usize> {
+    (0..n * n).filter(|k| k / n != r && k % n != c).collect()
+}
+
+#[track_caller]
+fn inv_entry(n: usize, i: usize, j: usize, args: Vec<PtrVWrap>) -> PtrVWrap {
+    let mut a = VWrap::new(Box::new(OpInvEntry { n, i, j }));
+    a.set_inp(args);
+    a
+}
+
+impl FWrap for OpDet {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpDet { n: 0 })
+    }
+    fn flops(&self, inputs: usize) -> u64 {
+        cube(inputs) * 2 / 3
+    }
+    fn f(&self) -> EvalFn {
+        let n = self.n;
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert_eq!(x.len(), n * n);
+            float_like(x, linalg::det(&values_f64(x), n))
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        let n = self.n;
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //y=det(A)
+            //y'=tr(adj(A)*A')=sum(adj_ji*a_ij'), also for singular A
+
+            assert_eq!(args.len(), n * n);
+
+            let da: Vec<PtrVWrap> = args.iter().map(|x| x.fwd()).collect();
+            Dot(det_coeffs(n, &args), da)
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        let n = self.n;
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                //adj(a_ij)=adj(A)_ji*adj(y)
+
+                assert_eq!(inputs.len(), n * n);
+
+                det_coeffs(n, &inputs)
+                    .into_iter()
+                    .map(|c| Mul(c, out_adj.clone()))
+                    .collect()
+            },
+        )
+    }
+}
+
+impl FWrap for OpAdjEntry {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpAdjEntry { n: 2, i: 0, j: 0 })
+    }
+    fn flops(&self, inputs: usize) -> u64 {
+        cube(inputs) * 2
+    }
+    fn f(&self) -> EvalFn {
+        let (n, i, j) = (self.n, self.i, self.j);
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert_eq!(x.len(), n * n);
+            float_like(
+                x,
+                matrix_result("adjugate", x, |v| linalg::adjugate(v, n))[i * n + j],
+            )
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        let (n, i, j) = (self.n, self.i, self.j);
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //adj_ij=(-1)^(i+j)*det(M) with M the matrix without row j and column i
+            //adj_ij'=(-1)^(i+j)*sum(adj(M)_lk*m_kl')
+
+            assert_eq!(args.len(), n * n);
+
+            let m: Vec<PtrVWrap> = minor_positions(n, j, i)
+                .into_iter()
+                .map(|k| args[k].clone())
+                .collect();
+            let dm: Vec<PtrVWrap> = m.iter().map(|x| x.fwd()).collect();
+            let d = Dot(det_coeffs(n - 1, &m), dm);
+            if (i + j) % 2 == 0 {
+                d
+            } else {
+                Mul(constant(ValType::F(-1.)), d)
+            }
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        let (n, i, j) = (self.n, self.i, self.j);
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                //adj(m_kl)=(-1)^(i+j)*adj(M)_lk*adj(y), zero outside of the minor
+
+                assert_eq!(inputs.len(), n * n);
+
+                let pos = minor_positions(n, j, i);
+                let m: Vec<PtrVWrap> = pos.iter().map(|&k| inputs[k].clone()).collect();
+                let scale = if (i + j) % 2 == 0 {
+                    out_adj
+                } else {
+                    Mul(constant(ValType::F(-1.)), out_adj)
+                };
+                let mut ret = vec![zero(); n * n];
+                for (k, c) in pos.into_iter().zip(det_coeffs(n - 1, &m)) {
+                    ret[k] = Mul(c, scale.clone());
+                }
+                ret
+            },
+        )
+    }
+}
+
+impl FWrap for OpInvEntry {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpInvEntry { n: 0, i: 0, j: 0 })
+    }
+    fn flops(&self, inputs: usize) -> u64 {
+        cube(inputs) * 2
+    }
+    fn f(&self) -> EvalFn {
+        let (n, i, j) = (self.n, self.i, self.j);
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert_eq!(x.len(), n * n);
+            float_like(
+                x,
+                matrix_result("inverse", x, |v| linalg::inverse(v, n))[i * n + j],
+            )
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        let (n, i, j) = (self.n, self.i, self.j);
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //B=inv(A)
+            //B'=-B*A'*B, b_ij'=-sum(b_ik*a_kl'*b_lj)
+
+            assert_eq!(args.len(), n * n);
+
+            let minus_one = constant(ValType::F(-1.));
+            let coeffs: Vec<PtrVWrap> = (0..n * n)
+                .map(|k| {
+                    Mul(
+                        inv_entry(n, i, k / n, args.clone()),
+                        inv_entry(n, k % n, j, args.clone()),
+                    )
+                })
+                .collect();
+            let da: Vec<PtrVWrap> = args.iter().map(|x| x.fwd()).collect();
+            Mul(minus_one, Dot(coeffs, da))
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        let (n, i, j) = (self.n, self.i, self.j);
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                //adj(a_kl)=-b_ik*b_lj*adj(b_ij)
+
+                assert_eq!(inputs.len(), n * n);
+
+                let minus_one = constant(ValType::F(-1.));
+                let scale = Mul(minus_one, out_adj);
+                (0..n * n)
+                    .map(|k| {
+                        Mul(
+                            Mul(
+                                inv_entry(n, i, k / n, inputs.clone()),
+                                inv_entry(n, k % n, j, inputs.clone()),
+                            ),
+                            scale.clone(),
+                        )
+                    })
+                    .collect()
+            },
+        )
+    }
+}
+
+#[track_caller]
+fn chol_entry(n: usize, i: usize, j: usize, args: Vec<PtrVWrap>) -> PtrVWrap {
+    let mut a = VWrap::new(Box::new(OpCholEntry { n, i, j }));
+    a.set_inp(args);
+    a
+}
+
+#[track_caller]
+fn solve_entry(n: usize, i: usize, transpose: bool, args: Vec<PtrVWrap>) -> PtrVWrap {
+    let mut a = VWrap::new(Box::new(OpSolveEntry { n, i, transpose }));
+    a.set_inp(args);
+    a
+}
+
+///row r of inv(L) by back substitution if transposed, else column r by forward substitution,
+///reading only the lower triangle of the flattened L
+#[track_caller]
+fn inv_lower_line(n: usize, r: usize, transpose: bool, l: &[PtrVWrap]) -> Vec<PtrVWrap> {
+    let mut args = l.to_vec();
+    args.extend((0..n).map(|k| if k == r { one() } else { zero() }));
+    (0..n)
+        .map(|k| solve_entry(n, k, transpose, args.clone()))
+        .collect()
+}
+
+///dL_ij/da_kl for all k,l where dL = L*phi(inv(L)*dA*inv(L)^T),
+///phi keeps the strict lower triangle and halves the diagonal,
+///a_kl below the diagonal also stands for its mirrored entry
+#[track_caller]
+fn chol_coeffs(n: usize, i: usize, j: usize, inputs: &[PtrVWrap]) -> Vec<PtrVWrap> {
+    let l: Vec<PtrVWrap> = (0..n * n)
+        .map(|k| chol_entry(n, k / n, k % n, inputs.to_vec()))
+        .collect();
+    //rows j..=i of inv(L)
+    let linv: Vec<Vec<PtrVWrap>> = (j..=i).map(|m| inv_lower_line(n, m, true, &l)).collect();
+    //w_m=L_im*c_mj for j<=m<=i
+    let w: Vec<(usize, PtrVWrap)> = (j..=i)
+        .map(|m| {
+            let c = if m == j { 0.5 } else { 1. };
+            let c = constant(ValType::F(c));
+            (m - j, Mul(l[i * n + m].clone(), c))
+        })
+        .collect();
+    sym_lower_coeffs(n, |k, l| {
+        w.iter()
+            .map(|(m, wm)| Mul(wm.clone(), Mul(linv[*m][k].clone(), linv[0][l].clone())))
+            .reduce(Add)
+    })
+}
+
+///dx_i/dL_kl for the lower triangle of L followed by dx_i/db_k
+///
+///dx_i/db_k is g_k, row i of inv(L) or column i if transposed, and
+///dx_i/dL_kl is -g_k*x_l, or -g_l*x_k if transposed
+#[track_caller]
+fn solve_coeffs(n: usize, i: usize, transpose: bool, inputs: &[PtrVWrap]) -> Vec<PtrVWrap> {
+    let g = inv_lower_line(n, i, !transpose, &inputs[..n * n]);
+    let minus_one = constant(ValType::F(-1.));
+    let mut coeffs: Vec<PtrVWrap> = (0..n * n)
+        .map(|idx| {
+            let (k, l) = (idx / n, idx % n);
+            let (gi, xi) = if transpose { (l, k) } else { (k, l) };
+            if k >= l {
+                Mul(
+                    minus_one.clone(),
+                    Mul(
+                        g[gi].clone(),
+                        solve_entry(n, xi, transpose, inputs.to_vec()),
+                    ),
+                )
+            } else {
+                zero()
+            }
+        })
+        .collect();
+    coeffs.extend(g);
+    coeffs
+}
+
+impl FWrap for OpCholEntry {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpCholEntry { n: 0, i: 0, j: 0 })
+    }
+    fn flops(&self, inputs: usize) -> u64 {
+        cube(inputs) / 3 + square_root(inputs) * COST_TRANSCENDENTAL
+    }
+    fn f(&self) -> EvalFn {
+        let (n, i, j) = (self.n, self.i, self.j);
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert_eq!(x.len(), n * n);
+            float_like(
+                x,
+                matrix_result("cholesky", x, |v| linalg::cholesky(v, n))[i * n + j],
+            )
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        let (n, i, j) = (self.n, self.i, self.j);
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            assert_eq!(args.len(), n * n);
+
+            let da: Vec<PtrVWrap> = args.iter().map(|x| x.fwd()).collect();
+            Dot(chol_coeffs(n, i, j, &args), da)
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        let (n, i, j) = (self.n, self.i, self.j);
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), n * n);
+
+                chol_coeffs(n, i, j, &inputs)
+                    .into_iter()
+                    .map(|c| Mul(c, out_adj.clone()))
+                    .collect()
+            },
+        )
+    }
+}
+
+impl FWrap for OpSolveEntry {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpSolveEntry {
+            n: 0,
+            i: 0,
+            transpose: false,
+        })
+    }
+    fn flops(&self, inputs: usize) -> u64 {
+        cube(inputs) / 3 + 2 * inputs as u64
+    }
+    fn f(&self) -> EvalFn {
+        let (n, i, transpose) = (self.n, self.i, self.transpose);
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert_eq!(x.len(), n * n + n);
+            let x_all = if transpose {
+                matrix_result("solve_transpose", x, |v| {
+                    linalg::solve_lower_transpose(&v[..n * n], &v[n * n..], n)
+                })
+            } else {
+                matrix_result("solve", x, |v| {
+                    linalg::solve_lower(&v[..n * n], &v[n * n..], n)
+                })
+            };
+            float_like(x, x_all[i])
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        let (n, i, transpose) = (self.n, self.i, self.transpose);
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //x=inv(L)*b
+            //x'=inv(L)*(b'-L'*x), likewise with L^T if transposed
+
+            assert_eq!(args.len(), n * n + n);
+
+            let d: Vec<PtrVWrap> = args.iter().map(|x| x.fwd()).collect();
+            Dot(solve_coeffs(n, i, transpose, &args), d)
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        let (n, i, transpose) = (self.n, self.i, self.transpose);
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                //adj(b)=inv(L)^T*adj(x), adj(L)=-tril(adj(b)*x^T)
+
+                assert_eq!(inputs.len(), n * n + n);
+
+                solve_coeffs(n, i, transpose, &inputs)
+                    .into_iter()
+                    .map(|c| Mul(c, out_adj.clone()))
+                    .collect()
+            },
+        )
+    }
+}
+
+///eigenvalues followed by the row-major eigenvector matrix, shared by the entry nodes of one matrix
+fn sym_eig_result(x: &[(ValType, bool)], n: usize) -> Rc<Vec<f64>> {
+    matrix_result("sym_eig", x, |v| {
+        let (vals, vecs) = linalg::sym_eig(v, n);
+        vals.into_iter().chain(vecs).collect()
+    })
+}
+
+///eigenvalue gaps at or below this are treated as degenerate and dropped from eigenvector derivatives
+const EIG_DEGENERATE_TOL: f32 = 1e-5;
+
+#[track_caller]
+fn eigval(n: usize, i: usize, args: Vec<PtrVWrap>) -> PtrVWrap {
+    let mut a = VWrap::new(Box::new(OpEigval { n, i }));
+    a.set_inp(args);
+    a
+}
+
+#[track_caller]
+fn eigvec(n: usize, r: usize, i: usize, args: Vec<PtrVWrap>) -> PtrVWrap {
+    let mut a = VWrap::new(Box::new(OpEigvec { n, r, i }));
+    a.set_inp(args);
+    a
+}
+
+#[track_caller]
+fn svd_entry(
+    rows: usize,
+    cols: usize,
+    part: SvdPart,
+    r: usize,
+    i: usize,
+    args: Vec<PtrVWrap>,
+) -> PtrVWrap {
+    let mut a = VWrap::new(Box::new(OpSvdEntry {
+        rows,
+        cols,
+        part,
+        r,
+        i,
+    }));
+    a.set_inp(args);
+    a
+}
+
+///U, singular values and V, row-major, shared by the entry nodes of one matrix
+fn svd_result(x: &[(ValType, bool)], rows: usize, cols: usize) -> Rc<Vec<f64>> {
+    matrix_result("svd", x, |v| {
+        let (u, s, v) = linalg::svd(v, rows, cols);
+        u.into_iter().chain(s).chain(v).collect()
+    })
+}
+
+///derivatives of an svd entry wrt a_kl in row-major order of the inputs, with
+///P=U^T*dA*V and F_ji=1/(s_i^2-s_j^2) dropped for (near) equal singular values:
+///ds_i=P_ii,
+///dU=U*(F.(P*S+S*P^T))+(I-U*U^T)*dA*V*inv(S),
+///dV=V*(F.(S*P+P^T*S)),
+///inv(s_i) is taken as 0 for a zero singular value
+#[track_caller]
+fn svd_coeffs(op: OpSvdEntry, inputs: &[PtrVWrap]) -> Vec<PtrVWrap> {
+    let (m, n, r, i) = (op.rows, op.cols, op.r, op.i);
+    let node = |part: SvdPart, r: usize, i: usize| svd_entry(m, n, part, r, i, inputs.to_vec());
+    let u: Vec<Vec<PtrVWrap>> = (0..m)
+        .map(|k| (0..n).map(|j| node(SvdPart::U, k, j)).collect())
+        .collect();
+    let v: Vec<Vec<PtrVWrap>> = (0..n)
+        .map(|k| (0..n).map(|j| node(SvdPart::V, k, j)).collect())
+        .collect();
+    if op.part == SvdPart::S {
+        return (0..m * n)
+            .map(|kl| Mul(u[kl / n][i].clone(), v[kl % n][i].clone()))
+            .collect();
+    }
+    let s: Vec<PtrVWrap> = (0..n).map(|j| node(SvdPart::S, 0, j)).collect();
+    let x = if op.part == SvdPart::U { &u } else { &v };
+    //dX_ri=sum_(j!=i)(p_j*P_ji+q_j*P_ij) for P_ji=u_kj*v_li and P_ij=u_ki*v_lj
+    let pq: Vec<(usize, PtrVWrap, PtrVWrap)> = (0..n)
+        .filter(|&j| j != i)
+        .map(|j| {
+            let gap = Minus(
+                Mul(s[i].clone(), s[i].clone()),
+                Mul(s[j].clone(), s[j].clone()),
+            );
+            let w = Mul(x[r][j].clone(), safe_recip(EIG_DEGENERATE_TOL, gap));
+            let (p, q) = if op.part == SvdPart::U {
+                (s[i].clone(), s[j].clone())
+            } else {
+                (s[j].clone(), s[i].clone())
+            };
+            (j, Mul(w.clone(), p), Mul(w, q))
+        })
+        .collect();
+    //(I-U*U^T)_rk*inv(s_i), zero for square matrices
+    let proj: Option<Vec<PtrVWrap>> = if op.part == SvdPart::U && m > n {
+        let inv_s = safe_recip(EIG_DEGENERATE_TOL, s[i].clone());
+        Some(
+            (0..m)
+                .map(|k| {
+                    let uu = (0..n)
+                        .map(|a| Mul(u[r][a].clone(), u[k][a].clone()))
+                        .reduce(Add)
+                        .expect("svd of an empty matrix");
+                    let p = if k == r {
+                        Minus(one(), uu)
+                    } else {
+                        Mul(constant(ValType::F(-1.)), uu)
+                    };
+                    Mul(p, inv_s.clone())
+                })
+                .collect(),
+        )
+    } else {
+        None
+    };
+    (0..m * n)
+        .map(|kl| {
+            let (k, l) = (kl / n, kl % n);
+            let terms = pq.iter().flat_map(|(j, p, q)| {
+                vec![
+                    Mul(p.clone(), Mul(u[k][*j].clone(), v[l][i].clone())),
+                    Mul(q.clone(), Mul(u[k][i].clone(), v[l][*j].clone())),
+                ]
+            });
+            let terms = terms.chain(proj.iter().map(|p| Mul(p[k].clone(), v[l][i].clone())));
+            terms.reduce(Add).unwrap_or_else(zero)
+        })
+        .collect()
+}
+
+impl FWrap for OpSvdEntry {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpSvdEntry {
+            rows: 0,
+            cols: 0,
+            part: SvdPart::S,
+            r: 0,
+            i: 0,
+        })
+    }
+    fn flops(&self, inputs: usize) -> u64 {
+        10 * cube(inputs)
+    }
+    fn f(&self) -> EvalFn {
+        let op = *self;
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            let (m, n) = (op.rows, op.cols);
+            assert_eq!(x.len(), m * n);
+            let idx = match op.part {
+                SvdPart::U => op.r * n + op.i,
+                SvdPart::S => m * n + op.i,
+                SvdPart::V => m * n + n + op.r * n + op.i,
+            };
+            float_like(x, svd_result(x, m, n)[idx])
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        let op = *self;
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            assert_eq!(args.len(), op.rows * op.cols);
+
+            let da: Vec<PtrVWrap> = args.iter().map(|x| x.fwd()).collect();
+            Dot(svd_coeffs(op, &args), da)
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        let op = *self;
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), op.rows * op.cols);
+
+                svd_coeffs(op, &inputs)
+                    .into_iter()
+                    .map(|c| Mul(c, out_adj.clone()))
+                    .collect()
+            },
+        )
+    }
+}
+
+/// threshold t with sum(sigmoid((x_i-t)/tau)) = k, differentiated implicitly
+#[track_caller]
+pub(crate) fn TopKThreshold(args: Vec<PtrVWrap>, k: f32, tau: f32) -> PtrVWrap {
+    let mut a = VWrap::new(Box::new(OpTopKThreshold { k, tau }));
+    a.set_inp(args);
+    a
+}
+
+/// sigmoid((x-t)/tau) written as exp((x-softmax(x,t))/tau), which never overflows
+#[track_caller]
+pub(crate) fn soft_sigmoid(x: PtrVWrap, t: PtrVWrap, tau: f32) -> PtrVWrap {
+    let scale = constant(ValType::F(1. / tau));
+    let lse = SoftMax(vec![x.clone(), t], tau);
+    Exp(Mul(scale, Minus(x, lse)))
+}
+
+#[track_caller]
+fn safe_recip(tol: f32, arg0: PtrVWrap) -> PtrVWrap {
+    let mut a = VWrap::new(Box::new(OpSafeRecip { tol }));
+    a.set_inp(vec![arg0]);
+    a
+}
+
+///sum over a_kl of the lower triangle of a symmetric perturbation E_kl+E_lk
+///given the bilinear term x^T E y as f(k,l)=x_k*y_l
+#[track_caller]
+fn sym_lower_coeffs(n: usize, f: impl Fn(usize, usize) -> Option<PtrVWrap>) -> Vec<PtrVWrap> {
+    let zero = || zero();
+    (0..n * n)
+        .map(|idx| {
+            let (k, l) = (idx / n, idx % n);
+            if k < l {
+                zero()
+            } else if k == l {
+                f(k, k).unwrap_or_else(zero)
+            } else {
+                match (f(k, l), f(l, k)) {
+                    (Some(x), Some(y)) => Add(x, y),
+                    _ => zero(),
+                }
+            }
+        })
+        .collect()
+}
+
+///dlambda_i/da_kl = v_ki*v_li
+#[track_caller]
+fn eigval_coeffs(n: usize, i: usize, inputs: &[PtrVWrap]) -> Vec<PtrVWrap> {
+    let v: Vec<PtrVWrap> = (0..n).map(|r| eigvec(n, r, i, inputs.to_vec())).collect();
+    sym_lower_coeffs(n, |k, l| Some(Mul(v[k].clone(), v[l].clone())))
+}
+
+///dv_ri/da_kl = sum_(j!=i) v_rj*v_kj*v_li/(lambda_i-lambda_j)
+#[track_caller]
+fn eigvec_coeffs(n: usize, r: usize, i: usize, inputs: &[PtrVWrap]) -> Vec<PtrVWrap> {
+    let v: Vec<Vec<PtrVWrap>> = (0..n)
+        .map(|row| (0..n).map(|c| eigvec(n, row, c, inputs.to_vec())).collect())
+        .collect();
+    let lambda_i = eigval(n, i, inputs.to_vec());
+    let g: Vec<(usize, PtrVWrap)> = (0..n)
+        .filter(|&j| j != i)
+        .map(|j| {
+            let gap = Minus(lambda_i.clone(), eigval(n, j, inputs.to_vec()));
+            (j, Mul(v[r][j].clone(), safe_recip(EIG_DEGENERATE_TOL, gap)))
+        })
+        .collect();
+    sym_lower_coeffs(n, |k, l| {
+        g.iter()
+            .map(|(j, gj)| Mul(gj.clone(), Mul(v[k][*j].clone(), v[l][i].clone())))
+            .reduce(Add)
+    })
+}
+
+impl FWrap for OpEigval {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpEigval { n: 0, i: 0 })
+    }
+    fn flops(&self, inputs: usize) -> u64 {
+        10 * cube(inputs)
+    }
+    fn f(&self) -> EvalFn {
+        let (n, i) = (self.n, self.i);
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert_eq!(x.len(), n * n);
+            float_like(x, sym_eig_result(x, n)[i])
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        let (n, i) = (self.n, self.i);
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //lambda_i'=v_i^T*A'*v_i
+
+            assert_eq!(args.len(), n * n);
+
+            let da: Vec<PtrVWrap> = args.iter().map(|x| x.fwd()).collect();
+            Dot(eigval_coeffs(n, i, &args), da)
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        let (n, i) = (self.n, self.i);
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), n * n);
+
+                eigval_coeffs(n, i, &inputs)
+                    .into_iter()
+                    .map(|c| Mul(c, out_adj.clone()))
+                    .collect()
+            },
+        )
+    }
+}
+
+impl FWrap for OpEigvec {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpEigvec { n: 0, r: 0, i: 0 })
+    }
+    fn flops(&self, inputs: usize) -> u64 {
+        10 * cube(inputs)
+    }
+    fn f(&self) -> EvalFn {
+        let (n, r, i) = (self.n, self.r, self.i);
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert_eq!(x.len(), n * n);
+            float_like(x, sym_eig_result(x, n)[n + r * n + i])
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        let (n, r, i) = (self.n, self.r, self.i);
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            //v_i'=sum_(j!=i) v_j*(v_j^T*A'*v_i)/(lambda_i-lambda_j)
+
+            assert_eq!(args.len(), n * n);
+
+            let da: Vec<PtrVWrap> = args.iter().map(|x| x.fwd()).collect();
+            Dot(eigvec_coeffs(n, r, i, &args), da)
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        let (n, r, i) = (self.n, self.r, self.i);
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), n * n);
+
+                eigvec_coeffs(n, r, i, &inputs)
+                    .into_iter()
+                    .map(|c| Mul(c, out_adj.clone()))
+                    .collect()
+            },
+        )
+    }
+}
+
+impl OpTopKThreshold {
+    ///dt/dx_j = s_j*(1-s_j)/sum(s_i*(1-s_i)) with s_j = sigmoid((x_j-t)/tau)
+    fn coeffs(&self, inputs: &[PtrVWrap], t: &PtrVWrap) -> Vec<PtrVWrap> {
+        let one = || constant(ValType::F(1.));
+        let ds: Vec<PtrVWrap> = inputs
+            .iter()
+            .map(|x| {
+                let s = soft_sigmoid(x.clone(), t.clone(), self.tau);
+                Mul(s.clone(), Minus(one(), s))
+            })
+            .collect();
+        let inv_total = Div(one(), sum(ds.clone()));
+        ds.into_iter().map(|d| Mul(d, inv_total.clone())).collect()
+    }
+}
+
+impl FWrap for OpTopKThreshold {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpTopKThreshold { k: 1., tau: 1. })
+    }
+    fn flops(&self, inputs: usize) -> u64 {
+        200 * inputs as u64 * (COST_TRANSCENDENTAL + 2)
+    }
+    fn f(&self) -> EvalFn {
+        let (k, tau) = (self.k as f64, self.tau as f64);
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            let v = values_f64(x);
+            assert!(k > 0. && k < v.len() as f64);
+            //the count sum(sigmoid((x_i-t)/tau)) decreases in t, so bisect
+            let count =
+                |t: f64| -> f64 { v.iter().map(|xi| 1. / (1. + (-(xi - t) / tau).exp())).sum() };
+            let lo_x = v.iter().cloned().fold(f64::INFINITY, f64::min);
+            let hi_x = v.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+            let (mut lo, mut hi) = (lo_x - 50. * tau, hi_x + 50. * tau);
+            for _ in 0..200 {
+                let mid = 0.5 * (lo + hi);
+                if count(mid) > k {
+                    lo = mid;
+                } else {
+                    hi = mid;
+                }
+            }
+            let t = 0.5 * (lo + hi);
+            if x.iter().all(|(v, _)| matches!(v, ValType::D(_))) {
+                ValType::D(t)
+            } else {
+                ValType::F(t as f32)
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        let op = *self;
+        Box::new(move |args: Vec<PtrVWrap>, self_ptr: &PtrVWrap| {
+            //t'=sum(dt/dx_j*x_j')
+            let xs = args.iter().map(|x| x.fwd()).collect();
+            Dot(op.coeffs(&args, self_ptr), xs)
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        let op = *self;
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, cur: &PtrVWrap| {
+                op.coeffs(&inputs, cur)
+                    .into_iter()
+                    .map(|c| Mul(c, out_adj.clone()))
+                    .collect()
+            },
+        )
+    }
+}
+
+impl FWrap for OpSafeRecip {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpSafeRecip { tol: 0. })
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        COST_DIV
+    }
+    fn f(&self) -> EvalFn {
+        let tol = self.tol;
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() == 1);
+            let a: f32 = x[0].0.into();
+            ValType::F(if a.abs() <= tol { 0. } else { 1. / a })
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |args: Vec<PtrVWrap>, self_ptr: &PtrVWrap| {
+            //y=1/x
+            //y'=-y^2*x', zero wherever y is
+
+            assert_eq!(args.len(), 1);
+
+            let minus_one = constant(ValType::F(-1.));
+            Mul(
+                Mul(minus_one, Mul(self_ptr.clone(), self_ptr.clone())),
+                args[0].fwd(),
+            )
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 1);
+
+                let minus_one = constant(ValType::F(-1.));
+                vec![Mul(Mul(minus_one, Mul(cur.clone(), cur.clone())), out_adj)]
+            },
+        )
+    }
+}
+
+///mean and 1/sqrt(var+eps) of the input values
+fn norm_stats(x: &[(ValType, bool)], eps: f32) -> (f64, f64) {
+    let n = x.len() as f64;
+    let mu = x.iter().map(|(v, _)| f64::from(*v)).sum::<f64>() / n;
+    let var = x
+        .iter()
+        .map(|(v, _)| (f64::from(*v) - mu).powi(2))
+        .sum::<f64>()
+        / n;
+    (mu, 1. / (var + eps as f64).sqrt())
+}
+
+#[track_caller]
+fn normalize(index: usize, eps: f32, args: Vec<PtrVWrap>) -> PtrVWrap {
+    let mut a = VWrap::new(Box::new(OpNormalize { index, eps }));
+    a.set_inp(args);
+    a
+}
+
+#[track_caller]
+fn inv_std(eps: f32, args: Vec<PtrVWrap>) -> PtrVWrap {
+    let mut a = VWrap::new(Box::new(OpInvStd { eps }));
+    a.set_inp(args);
+    a
+}
+
+impl FWrap for OpNormalize {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpNormalize { index: 0, eps: 0. })
+    }
+    fn flops(&self, inputs: usize) -> u64 {
+        4 * inputs as u64 + COST_TRANSCENDENTAL
+    }
+    fn f(&self) -> EvalFn {
+        let (index, eps) = (self.index, self.eps);
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(index < x.len());
+            let (mu, inv_sigma) = norm_stats(x, eps);
+            float_like(x, (f64::from(x[index].0) - mu) * inv_sigma)
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        let (index, eps) = (self.index, self.eps);
+        Box::new(move |args: Vec<PtrVWrap>, self_ptr: &PtrVWrap| {
+            //h_i=(x_i-mu)*s, s=1/sqrt(var+eps)
+            //h_i'=s*(x_i' - mean(x') - h_i*mean(h.*x'))
+
+            assert!(index < args.len());
+
+            let inv_n = constant(ValType::F(1. / args.len() as f32));
+            let sum_dx = args.iter().map(|x| x.fwd()).reduce(Add).unwrap();
+            let sum_hdx = (0..args.len())
+                .map(|j| Mul(normalize(j, eps, args.clone()), args[j].fwd()))
+                .reduce(Add)
+                .unwrap();
+
+            Mul(
+                inv_std(eps, args.clone()),
+                Minus(
+                    args[index].fwd(),
+                    Mul(inv_n, Add(sum_dx, Mul(self_ptr.clone(), sum_hdx))),
+                ),
+            )
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        let (index, eps) = (self.index, self.eps);
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, cur: &PtrVWrap| {
+                //dh_i/dx_j = s*(delta_ij - 1/n - h_i*h_j/n)
+
+                assert!(index < inputs.len());
+
+                let n = inputs.len() as f32;
+                let s_adj = Mul(inv_std(eps, inputs.clone()), out_adj);
+                (0..inputs.len())
+                    .map(|j| {
+                        let delta = if j == index { 1. - 1. / n } else { -1. / n };
+                        let delta = constant(ValType::F(delta));
+                        let inv_n = constant(ValType::F(1. / n));
+                        let hh = Mul(cur.clone(), normalize(j, eps, inputs.clone()));
+                        Mul(Minus(delta, Mul(inv_n, hh)), s_adj.clone())
+                    })
+                    .collect()
+            },
+        )
+    }
+}
+
+impl FWrap for OpInvStd {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpInvStd { eps: 0. })
+    }
+    fn flops(&self, inputs: usize) -> u64 {
+        3 * inputs as u64 + COST_TRANSCENDENTAL
+    }
+    fn f(&self) -> EvalFn {
+        let eps = self.eps;
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(!x.is_empty());
+            float_like(x, norm_stats(x, eps).1)
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        let eps = self.eps;
+        Box::new(move |args: Vec<PtrVWrap>, self_ptr: &PtrVWrap| {
+            //s=(var+eps)^-1/2
+            //s'=-s^2*mean(h.*x')
+
+            assert!(!args.is_empty());
+
+            let minus_inv_n = constant(ValType::F(-1. / args.len() as f32));
+            let hdx = (0..args.len())
+                .map(|j| Mul(normalize(j, eps, args.clone()), args[j].fwd()))
+                .reduce(Add)
+                .unwrap();
+
+            Mul(
+                Mul(minus_inv_n, Mul(self_ptr.clone(), self_ptr.clone())),
+                hdx,
+            )
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        let eps = self.eps;
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, cur: &PtrVWrap| {
+                //ds/dx_j = -s^2*h_j/n
+
+                assert!(!inputs.is_empty());
+
+                let minus_inv_n = constant(ValType::F(-1. / inputs.len() as f32));
+                let scale = Mul(Mul(minus_inv_n, Mul(cur.clone(), cur.clone())), out_adj);
+                (0..inputs.len())
+                    .map(|j| Mul(normalize(j, eps, inputs.clone()), scale.clone()))
+                    .collect()
+            },
+        )
+    }
+}
+
+impl FWrap for OpSincSqrt {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpSincSqrt { order: 0 })
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        2 * COST_TRANSCENDENTAL
+    }
+    fn f(&self) -> EvalFn {
+        let order = self.order;
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() == 1);
+            match x[0].0 {
+                ValType::D(a) => ValType::D(special::sinc_sqrt_derivative(order, a)),
+                a => ValType::F(special::sinc_sqrt_derivative(order, a.into()) as f32),
+            }
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        let order = self.order;
+        Box::new(move |args: Vec<PtrVWrap>, _self_ptr: &PtrVWrap| {
+            assert_eq!(args.len(), 1);
+
+            Mul(sinc_sqrt(order + 1, args[0].clone()), args[0].fwd())
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        let order = self.order;
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 1);
+
+                vec![Mul(
+                    sinc_sqrt(order + 1, inputs[0].clone()),
+                    out_adj.clone(),
+                )]
+            },
+        )
+    }
+}
+
+impl FWrap for OpAcosSq {
+    fn new() -> Box<dyn FWrap>
+    where
+        Self: Sized,
+    {
+        Box::new(OpAcosSq {})
+    }
+    fn flops(&self, _inputs: usize) -> u64 {
+        2 * COST_TRANSCENDENTAL
+    }
+    fn f(&self) -> EvalFn {
+        Box::new(move |x: &[(ValType, bool)], _v: Option<ValType>| {
+            assert!(x.len() == 1);
+            let c: f64 = x[0].0.into();
+            ValType::F(c.clamp(-1., 1.).acos().powi(2) as f32)
+        })
+    }
+    fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+        Box::new(move |args: Vec<PtrVWrap>, self_ptr: &PtrVWrap| {
+            //y=acos(c)^2=t^2
+            //y'=-2t/sin(t)*c'=-2/A(y)*c' with A(s)=sin(sqrt(s))/sqrt(s)
+
+            assert_eq!(args.len(), 1);
+
+            let minus_two = constant(ValType::F(-2.));
+            Mul(
+                Div(minus_two, sinc_sqrt(0, self_ptr.clone())),
+                args[0].fwd(),
+            )
+        })
+    }
+    fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+        Box::new(
+            move |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, cur: &PtrVWrap| {
+                assert_eq!(inputs.len(), 1);
+
+                let minus_two = constant(ValType::F(-2.));
+                vec![Mul(Div(minus_two, sinc_sqrt(0, cur.clone())), out_adj)]
+            },
+        )
+    }
+}
+
+#[allow(dead_code)]
+#[track_caller]
+pub fn Mul(arg0: PtrVWrap, arg1: PtrVWrap) -> PtrVWrap {
+    let mut a = VWrap::new(OpMul::new());
+    a.set_inp(canonical_pair(arg0, arg1));
+    a
+}
+
+#[allow(dead_code)]
+#[track_caller]
+pub fn Add(arg0: PtrVWrap, arg1: PtrVWrap) -> PtrVWrap {
+    let mut a = VWrap::new(OpAdd::new());
+    a.set_inp(canonical_pair(arg0, arg1));
+    a
+}
+
+#[allow(dead_code)]
+#[track_caller]
+pub fn Minus(arg0: PtrVWrap, arg1: PtrVWrap) -> PtrVWrap {
+    let mut a = VWrap::new(OpAdd::new());
+    let temp = constant(ValType::F(-1.));
+    a.set_inp(canonical_pair(arg0, Mul(arg1, temp)));
+    a
+}
+
+#[allow(dead_code)]
+#[track_caller]
+pub fn Leaf(arg0: ValType) -> PtrVWrap {
+    let a = VWrap::new_with_val(OpLeaf::new(), arg0);
+    a
+}
+
+/// reasons a derivative target would silently get a zero or ambiguous derivative
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WrtIssue {
+    /// target is not reachable from the differentiated variable
+    NotInGraph,
+    /// target is computed from other nodes, only leaves receive adjoints
+    NotALeaf,
+    /// target is a constant node, which never receives adjoints or tangents
+    Constant,
+    /// forward mode target without .active()
+    Inactive,
+    /// target is frozen, so derivatives wrt it are zero
+    Frozen,
+    /// target holds an integer value, treated as a constant by the differentiated variable,
+    /// or differentiated as a real value in strict mode
+    Integer,
+}
+
+/// issue of a derivative target found by an entry point in strict mode
+#[derive(Debug, Clone)]
+pub struct WrtReport {
+    /// entry point that was called, e.g. "rev" or "Partials::get"
+    pub entry: &'static str,
+    /// position of the target among the targets checked by the entry point
+    pub index: usize,
+    pub target: PtrVWrap,
+    pub issue: WrtIssue,
+}
+
+impl fmt::Display for WrtReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} target {}{}: {}",
+            self.entry,
+            self.index,
+            self.target.origin(),
+            self.issue
+        )
+    }
+}
+
+/// receives the reports of strict mode, see set_strict
+pub type StrictHandler = Rc<dyn Fn(&WrtReport)>;
+
+impl fmt::Display for WrtIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            WrtIssue::NotInGraph => "target does not appear in the graph",
+            WrtIssue::NotALeaf => "target is not a leaf",
+            WrtIssue::Constant => "target is a constant",
+            WrtIssue::Inactive => "forward mode target is not active",
+            WrtIssue::Frozen => "target is frozen",
+            WrtIssue::Integer => "target holds an integer value",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+thread_local! {
+    static STRICT: RefCell<Option<StrictHandler>> = const { RefCell::new(None) };
+    static IN_ENTRY: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+    static TRACK_LOCATIONS: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+    static CANONICAL_ORDER: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+/// treatment of leaves holding ValType::I or ValType::L values in derivative graphs
+///
+/// under Promote, strict mode reports integer targets as WrtIssue::Integer to the handler
+/// given to set_strict, which may reject them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntegerSemantics {
+    /// differentiated as real values, so derivatives are floats
+    Promote,
+    /// constants with zero derivative, like frozen nodes
+    Constant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubgradientPolicy {
+    Zero,
+    /// derivative from below
+    Left,
+    /// derivative from above
+    Right,
+    /// mean of the one-sided derivatives
+    Average,
+}
+
+impl SubgradientPolicy {
+    /// derivative at a kink with one-sided derivatives left and right
+    pub fn resolve(self, left: f64, right: f64) -> f64 {
+        match self {
+            SubgradientPolicy::Zero => 0.,
+            SubgradientPolicy::Left => left,
+            SubgradientPolicy::Right => right,
+            SubgradientPolicy::Average => 0.5 * (left + right),
+        }
+    }
+}
+
+thread_local! {
+    static SUBGRADIENT: std::cell::Cell<SubgradientPolicy> =
+        const { std::cell::Cell::new(SubgradientPolicy::Zero) };
+}
+
+/// sets the subgradient policy on this thread, Zero by default
+///
+/// ops with kinks read the policy when their derivative graphs are built by fwd or rev, so
+/// graphs built earlier keep the policy they were built with
+#[allow(dead_code)]
+pub fn set_subgradient_policy(policy: SubgradientPolicy) {
+    SUBGRADIENT.with(|s| s.set(policy));
+}
+
+/// subgradient policy on this thread
+#[allow(dead_code)]
+pub fn subgradient_policy() -> SubgradientPolicy {
+    SUBGRADIENT.with(|s| s.get())
+}
+
+/// runs f, e.g. building the derivative graphs of one graph, under the given subgradient
+/// policy, restoring the previous policy afterwards, also if f panics
+#[allow(dead_code)]
+pub fn with_subgradient_policy<R>(policy: SubgradientPolicy, f: impl FnOnce() -> R) -> R {
+    struct Reset(SubgradientPolicy);
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            set_subgradient_policy(self.0);
+        }
+    }
+    let _reset = Reset(subgradient_policy());
+    set_subgradient_policy(policy);
+    f()
+}
+
+/// enables reporting of derivative targets that would silently give zero on this thread,
+/// or disables it with None
+///
+/// while enabled, fwd, rev, fwd_wrt, rev_wrt, partial and Partials::get call the handler
+/// for each such target before differentiating, see check_wrt. fwd checks the nodes marked
+/// active, or every leaf if none is, and rev every leaf. integer targets are reported as
+/// WrtIssue::Integer, as it is ambiguous whether they are meant to be differentiated; a
+/// handler rejects them by panicking
+#[allow(dead_code)]
+pub fn set_strict(handler: Option<StrictHandler>) {
+    STRICT.with(|s| *s.borrow_mut() = handler);
+}
+
+/// whether strict reporting is enabled on this thread
+#[allow(dead_code)]
+pub fn strict() -> bool {
+    STRICT.with(|s| s.borrow().is_some())
+}
+
+/// records the source location of every node constructed on this thread while enabled
+///
+/// op constructors pass on the location of their caller, so nodes built by user code point
+/// at the user's line and nodes built by derivative rules at the rule
+#[allow(dead_code)]
+pub fn set_track_locations(on: bool) {
+    TRACK_LOCATIONS.with(|t| t.set(on));
+}
+
+/// whether construction locations are recorded on this thread
+#[allow(dead_code)]
+pub fn track_locations() -> bool {
+    TRACK_LOCATIONS.with(|t| t.get())
+}
+
+/// orders the two operands of every Add, Mul and Minus constructed on this thread while
+/// enabled by their structural hash
+///
+/// expressions that only differ in the order of such operands then build the same graph,
+/// so their canonical text and structural hash agree, e.g. in golden snapshot tests. the
+/// operations are commutative in floating point, so values do not change
+#[allow(dead_code)]
+pub fn set_canonical_order(on: bool) {
+    CANONICAL_ORDER.with(|c| c.set(on));
+}
+
+/// whether operands of commutative ops are ordered on this thread
+#[allow(dead_code)]
+pub fn canonical_order() -> bool {
+    CANONICAL_ORDER.with(|c| c.get())
+}
+
+#[track_caller]
+fn tracked_location() -> Option<&'static Location<'static>> {
+    if track_locations() {
+        Some(Location::caller())
+    } else {
+        None
+    }
+}
+
+/// checks that x is a valid target for differentiating y, in forward mode if forward
+#[allow(dead_code)]
+pub fn check_wrt(y: &PtrVWrap, x: &PtrVWrap, forward: bool) -> Option<WrtIssue> {
+    if matches!(x.kind(), OpKind::Const | OpKind::Zero | OpKind::One) {
+        return Some(WrtIssue::Constant);
+    }
+    if !x.0.deref().borrow().inp.is_empty() {
+        return Some(WrtIssue::NotALeaf);
+    }
+    if !y.reachable().contains(x) {
+        return Some(WrtIssue::NotInGraph);
+    }
+    if x.is_frozen() {
+        return Some(WrtIssue::Frozen);
+    }
+    if x.is_integer() && (y.treats_as_constant(x) || strict()) {
+        return Some(WrtIssue::Integer);
+    }
+    if forward && !x.0.deref().borrow().eval_g {
+        return Some(WrtIssue::Inactive);
+    }
+    None
+}
+
+/// runs the derivative entry point of y named entry, after reporting the issues of its
+/// targets while strict mode is on
+///
+/// entry points run by another one, e.g. fwd by tangent rules or by fwd_wrt, do not report
+/// again
+fn strict_entry<R>(
+    entry: &'static str,
+    y: &PtrVWrap,
+    forward: bool,
+    targets: impl FnOnce() -> Vec<PtrVWrap>,
+    run: impl FnOnce() -> R,
+) -> R {
+    struct Reset(bool);
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            IN_ENTRY.with(|e| e.set(self.0));
+        }
+    }
+    let nested = IN_ENTRY.with(|e| e.replace(true));
+    let _reset = Reset(nested);
+    //cloned out, so the handler may itself change the strict setting
+    let handler = STRICT.with(|s| s.borrow().clone());
+    if let Some(handler) = handler.filter(|_| !nested) {
+        for (index, target) in targets().into_iter().enumerate() {
+            if let Some(issue) = check_wrt(y, &target, forward) {
+                handler(&WrtReport {
+                    entry,
+                    index,
+                    target,
+                    issue,
+                });
+            }
+        }
+    }
+    run()
+}
+
+/// mixed partial derivative d^k y/(dx_1 ... dx_k) evaluated at the current leaf values
+///
+/// reverse mode builds the derivatives wrt x_1..x_(k-1), each sweep giving the adjoints
+/// of all leaves at once, and a single tangent-linear pass wrt x_k finishes, avoiding the
+/// adjoint map for the last order. activity flags are set for that pass and restored
+/// afterwards; partials wrt leaves y does not depend on are zero
+#[allow(dead_code)]
+pub fn partial(y: &PtrVWrap, wrt: &[PtrVWrap]) -> ValType {
+    //activity is handled here, so only check the targets themselves
+    strict_entry(
+        "partial",
+        y,
+        false,
+        || wrt.to_vec(),
+        || partial_unchecked(y, wrt),
+    )
+}
+
+fn partial_unchecked(y: &PtrVWrap, wrt: &[PtrVWrap]) -> ValType {
+    let (last, rest) = match wrt.split_last() {
+        Some(x) => x,
+        None => return y.clone().apply_fwd(),
+    };
+
+    let mut d = y.clone();
+    for x in rest {
+        d = match d.rev_wrt(x) {
+            Some(adj) => adj,
+            None => return ValType::F(0.),
+        };
+    }
+
+    d.fwd_wrt(last)
+}
+
+/// mixed partial derivatives of one variable at the current leaf values, keeping the
+/// derivative graphs built on the way so partials sharing lower orders reuse them
+///
+/// mixed partials commute, so each partial is taken in the order of first appearance of
+/// the leaves in the graph: reverse mode for all but the last order, a tangent-linear pass
+/// for the last. the graphs do not depend on leaf values and stay valid when they change
+pub struct Partials {
+    root: PtrVWrap,
+    leaves: Vec<PtrVWrap>,
+    /// derivative graphs by the indices of the leaves differentiated for, None if zero
+    graphs: HashMap<Vec<usize>, Option<PtrVWrap>>,
+}
+
+impl Partials {
+    pub fn new(y: &PtrVWrap) -> Partials {
+        Partials {
+            root: y.clone(),
+            leaves: y
+                .reachable()
+                .into_iter()
+                .filter(|n| n.0.deref().borrow().inp.is_empty())
+                .collect(),
+            graphs: HashMap::new(),
+        }
+    }
+
+    /// d^n y/(dx_1^k_1 ... dx_m^k_m) for [(x_1, k_1), ..., (x_m, k_m)] with n = k_1 + ... + k_m
+    ///
+    /// partials wrt nodes that are not leaves of y are zero
+    pub fn get(&mut self, wrt: &[(PtrVWrap, usize)]) -> ValType {
+        let root = self.root.clone();
+        strict_entry(
+            "Partials::get",
+            &root,
+            false,
+            || wrt.iter().map(|(x, _)| x.clone()).collect(),
+            || self.get_unchecked(wrt),
+        )
+    }
+
+    fn get_unchecked(&mut self, wrt: &[(PtrVWrap, usize)]) -> ValType {
+        let mut order: Vec<usize> = vec![];
+        for (x, k) in wrt.iter().filter(|(_, k)| *k > 0) {
+            match self.leaves.iter().position(|l| l == x) {
+                Some(i) => order.extend(vec![i; *k]),
+                None => return ValType::F(0.),
+            }
+        }
+        order.sort_unstable();
+
+        match order.pop() {
+            None => self.root.clone().apply_fwd(),
+            Some(last) => match self.graph(&order) {
+                Some(d) => d.fwd_wrt(&self.leaves[last]),
+                None => ValType::F(0.),
+            },
+        }
+    }
+
+    /// derivative graph of y wrt the given leaves, None if it is zero
+    fn graph(&mut self, order: &[usize]) -> Option<PtrVWrap> {
+        let (last, rest) = match order.split_last() {
+            Some(x) => x,
+            None => return Some(self.root.clone()),
+        };
+        if let Some(g) = self.graphs.get(order) {
+            return g.clone();
+        }
+        let g = self
+            .graph(rest)
+            .and_then(|d| d.rev_wrt(&self.leaves[*last]));
+        self.graphs.insert(order.to_vec(), g.clone());
+        g
+    }
+
+    /// number of derivative graphs built so far
+    pub fn len(&self) -> usize {
+        self.graphs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.graphs.is_empty()
+    }
+}
+
+/// mixed partial derivative of y of order k_i in each x_i, e.g. d^3 y/(dx^2 dz) for
+/// [(x, 2), (z, 1)], evaluated at the current leaf values
+///
+/// keep a Partials to reuse the derivative graphs across calls
+#[allow(dead_code)]
+pub fn partial_multi(y: &PtrVWrap, wrt: &[(PtrVWrap, usize)]) -> ValType {
+    Partials::new(y).get(wrt)
+}
+
+/// second directional derivative v^T H v of output wrt inputs along v, evaluated at the
+/// current leaf values
+///
+/// the tangent of the tangent is built with all inputs active, and the links standing for
+/// the leaf derivatives are given the components of v instead of 1 in a single evaluation,
+/// so neither the hessian nor a hessian-vector product is formed. activity flags are
+/// restored afterwards; leaves outside inputs are held constant
+#[allow(dead_code)]
+pub fn curvature_along(output: &PtrVWrap, inputs: &[PtrVWrap], v: &[f32]) -> ValType {
+    assert_eq!(inputs.len(), v.len(), "direction length mismatch");
+
+    let d2 = with_active(std::slice::from_ref(output), inputs, || output.fwd().fwd());
+    d2.apply_fwd_with(&seed_links(&d2, inputs, v))
+}
+
+/// runs build, e.g. fwd, with exactly the inputs active among the leaves of roots, and
+/// restores the activity flags afterwards, also if build panics
+pub(crate) fn with_active<R>(
+    roots: &[PtrVWrap],
+    inputs: &[PtrVWrap],
+    build: impl FnOnce() -> R,
+) -> R {
+    struct Reset(Vec<(PtrVWrap, bool)>);
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            for (l, flag) in self.0.iter() {
+                l.0.deref().borrow_mut().eval_g = *flag;
+            }
+        }
+    }
+    let leaves: Vec<PtrVWrap> = topo_order(roots)
+        .into_iter()
+        .filter(|n| n.0.deref().borrow().inp.is_empty())
+        .collect();
+    let _reset = Reset(
+        leaves
+            .iter()
+            .map(|l| (l.clone(), l.0.deref().borrow().eval_g))
+            .collect(),
+    );
+    for l in leaves.iter() {
+        l.0.deref().borrow_mut().eval_g = inputs.contains(l);
+    }
+    build()
+}
+
+/// values for apply_fwd_with that give the links of a tangent graph the components of the
+/// direction v instead of 1, and 0 for leaves outside inputs
+pub(crate) fn seed_links(
+    tangent: &PtrVWrap,
+    inputs: &[PtrVWrap],
+    v: &[f32],
+) -> HashMap<PtrVWrap, ValType> {
+    topo_order(std::slice::from_ref(tangent))
+        .into_iter()
+        .filter(|n| n.kind() == OpKind::Link)
+        .map(|n| {
+            let leaf = &n.inputs()[0];
+            let dir = inputs.iter().zip(v).filter(|(x, _)| *x == leaf);
+            let vi: f32 = dir.map(|(_, vi)| *vi).sum();
+            (n, ValType::F(vi))
+        })
+        .collect()
+}
+
+/// direction in which a derivative graph is built
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Mode {
+    Fwd,
+    Rev,
+}
+
+/// derivative graphs memoized by node, leaf and mode, so repeated requests and higher
+/// orders reuse what was built before
+///
+/// rev of a node is built once; forward derivatives wrt a leaf also remember the tangent
+/// of every node they meet, so building f'' from f' reuses the tangents of the nodes of f
+/// already built for f'. graphs are valid for any leaf values and activity flags, but not
+/// after freezing nodes, changing the settings of the differentiated nodes or rewriting
+/// graphs (e.g. with fuse): call clear then. the cache keeps the graphs it has seen alive
+#[derive(Default)]
+pub struct DerivativeCache {
+    /// first derivative by node, leaf and mode, None if it is zero
+    derived: HashMap<(PtrVWrap, PtrVWrap, Mode), Option<PtrVWrap>>,
+    /// leaf adjoints by node
+    adjoints: HashMap<PtrVWrap, HashMap<PtrVWrap, PtrVWrap>>,
+    /// tangents of the nodes met by forward derivatives, by leaf
+    tangents: HashMap<PtrVWrap, TangentCache>,
+    hits: usize,
+}
+
+impl DerivativeCache {
+    pub fn new() -> DerivativeCache {
+        DerivativeCache::default()
+    }
+
+    /// same as x.rev(), built on the first request for x
+    pub fn rev(&mut self, x: &PtrVWrap) -> HashMap<PtrVWrap, PtrVWrap> {
+        if let Some(adj) = self.adjoints.get(x) {
+            self.hits += 1;
+            return adj.clone();
+        }
+        let adj = x.rev();
+        self.adjoints.insert(x.clone(), adj.clone());
+        adj
+    }
+
+    /// derivative graph of the given order of x wrt leaf, built in the given mode, None if
+    /// it is zero
+    ///
+    /// forward derivatives are built with leaf the only active leaf and do not read the
+    /// activity flags when evaluated, which are left as they were
+    pub fn derivative(
+        &mut self,
+        x: &PtrVWrap,
+        leaf: &PtrVWrap,
+        order: u32,
+        mode: Mode,
+    ) -> Option<PtrVWrap> {
+        let mut d = x.clone();
+        for _ in 0..order {
+            let key = (d.clone(), leaf.clone(), mode);
+            d = match self.derived.get(&key) {
+                Some(next) => {
+                    self.hits += 1;
+                    next.clone()
+                }
+                None => {
+                    let next = match mode {
+                        Mode::Rev => self.rev(&d).get(leaf).cloned(),
+                        Mode::Fwd => self.tangent(&d, leaf),
+                    };
+                    self.derived.insert(key, next.clone());
+                    next
+                }
+            }?;
+        }
+        Some(d)
+    }
+
+    /// tangent of x wrt leaf, sharing the tangents of nodes met before
+    fn tangent(&mut self, x: &PtrVWrap, leaf: &PtrVWrap) -> Option<PtrVWrap> {
+        let leaves: Vec<PtrVWrap> = x
+            .reachable()
+            .into_iter()
+            .filter(|n| n.0.deref().borrow().inp.is_empty())
+            .collect();
+        if !leaves.contains(leaf) {
+            return None;
+        }
+
+        struct Reset;
+        impl Drop for Reset {
+            fn drop(&mut self) {
+                TANGENTS.with(|c| *c.borrow_mut() = None);
+            }
+        }
+        let cache = self.tangents.remove(leaf).unwrap_or_else(|| TangentCache {
+            leaf: leaf.clone(),
+            tangents: HashMap::new(),
+            hits: 0,
+        });
+        TANGENTS.with(|c| *c.borrow_mut() = Some(cache));
+        let _reset = Reset;
+        let t = with_active(std::slice::from_ref(x), std::slice::from_ref(leaf), || {
+            x.fwd()
+        });
+        let cache = TANGENTS.with(|c| c.borrow_mut().take());
+        self.tangents
+            .insert(leaf.clone(), cache.expect("tangent cache missing"));
+        Some(t)
+    }
+
+    /// number of requests and node tangents served from the cache
+    pub fn hits(&self) -> usize {
+        self.hits + self.tangents.values().map(|c| c.hits).sum::<usize>()
+    }
+
+    /// forgets all derivative graphs
+    pub fn clear(&mut self) {
+        self.derived.clear();
+        self.adjoints.clear();
+        self.tangents.clear();
+    }
+}
+
+/// rev of a graph reusing the nodes of the adjoint graphs of earlier calls, e.g. in an
+/// optimizer loop rebuilding the gradient at every step
+///
+/// nodes built by rev are kept by the pool; on the next call those no longer referenced
+/// outside of it are overwritten in place instead of being freed and allocated again.
+/// adjoint graphs still held by the caller are left untouched
+pub struct RevPool {
+    root: PtrVWrap,
+    pool: NodePool,
+}
+
+impl RevPool {
+    pub fn new(root: &PtrVWrap) -> RevPool {
+        RevPool {
+            root: root.clone(),
+            pool: NodePool::default(),
+        }
+    }
+
+    /// same as rev of the root
+    pub fn rev(&mut self) -> HashMap<PtrVWrap, PtrVWrap> {
+        //swaps the pools back also if rev panics, so later nodes are not pooled by mistake
+        struct Reset<'a>(&'a mut NodePool, Option<NodePool>);
+        impl Drop for Reset<'_> {
+            fn drop(&mut self) {
+                let outer = self.1.take();
+                *self.0 = POOL.with(|p| p.replace(outer)).unwrap_or_default();
+            }
+        }
+        self.pool.reclaim();
+        let outer = POOL.with(|p| p.replace(Some(std::mem::take(&mut self.pool))));
+        let _reset = Reset(&mut self.pool, outer);
+        self.root.rev()
+    }
+
+    /// number of nodes held by the pool, in use or free
+    pub fn len(&self) -> usize {
+        self.pool.live.len() + self.pool.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// number of nodes built in recycled storage so far
+    pub fn reused(&self) -> usize {
+        self.pool.reused
+    }
+
+    /// releases the free nodes
+    pub fn shrink(&mut self) {
+        self.pool.free.clear();
+    }
+}
+
+/// leaf without a value met during evaluation
+#[derive(Debug, Clone, PartialEq)]
+pub struct MissingValue {
+    /// nodes from the evaluated variable down to the leaf
+    pub path: Vec<PtrVWrap>,
+}
+
+impl MissingValue {
+    pub fn leaf(&self) -> &PtrVWrap {
+        self.path.last().expect("empty path")
+    }
+}
+
+impl fmt::Display for MissingValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let leaf = self.leaf();
+        write!(f, "leaf value missing: {}{}", leaf.op_name(), leaf.origin())?;
+        write!(f, ", path from output:")?;
+        for w in self.path.windows(2) {
+            let slot = w[0].inputs().iter().position(|i| *i == w[1]);
+            write!(f, " {} input {} ->", w[0].op_name(), slot.unwrap_or(0))?;
+        }
+        write!(f, " {}", leaf.op_name())
+    }
+}
+
+impl std::error::Error for MissingValue {}
+
+/// derivative on which forward and reverse mode disagree, found by debug_check_consistency
+#[derive(Debug, Clone, PartialEq)]
+pub struct Inconsistency {
+    /// node whose local derivative disagrees, None for the derivative of the output wrt
+    /// one of the given inputs
+    pub node: Option<PtrVWrap>,
+    /// input slot of the node, or index into the given inputs
+    pub input: usize,
+    pub fwd: f32,
+    pub rev: f32,
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.node {
+            Some(n) => write!(f, "{}{} wrt input {}", n.op_name(), n.origin(), self.input)?,
+            None => write!(f, "output wrt input {}", self.input)?,
+        }
+        write!(f, ": fwd {} rev {}", self.fwd, self.rev)
+    }
+}
+
+fn consistent(a: f32, b: f32) -> bool {
+    a == b || (a.is_nan() && b.is_nan()) || (a - b).abs() <= 1e-3 * a.abs().max(b.abs()).max(1.)
+}
+
+/// compares reverse mode derivatives against forward mode ones at the current leaf values
+///
+/// the derivatives of output wrt each of inputs are compared first, then the local
+/// derivatives of every node on a path from inputs to output wrt each of its input slots,
+/// which points at the op with a faulty tangent or adjoint. the tangent and adjoint rules
+/// of a node are applied to leaves holding the values of its inputs, so the graph is never
+/// rewired, and only node values are updated as by apply_fwd. frozen nodes are skipped;
+/// returns the mismatches, empty if both modes agree
+#[allow(dead_code)]
+pub fn debug_check_consistency(output: &PtrVWrap, inputs: &[PtrVWrap]) -> Vec<Inconsistency> {
+    let mut found = vec![];
+    output.clone().apply_fwd();
+
+    for (k, x) in inputs.iter().enumerate() {
+        let fwd = f32::from(output.fwd_wrt(x));
+        let rev = output
+            .rev_wrt(x)
+            .map_or(0., |mut adj| f32::from(adj.apply_fwd()));
+        if !consistent(fwd, rev) {
+            found.push(Inconsistency {
+                node: None,
+                input: k,
+                fwd,
+                rev,
+            });
+        }
+    }
+
+    let mut depends: HashSet<PtrVWrap> = inputs.iter().cloned().collect();
+    for n in topo_order(std::slice::from_ref(output)) {
+        let inp = n.inputs();
+        if !inp.iter().any(|i| depends.contains(i)) {
+            continue;
+        }
+        depends.insert(n.clone());
+        if n.is_frozen() {
+            continue;
+        }
+
+        //the rules of the op applied to leaves holding the input values, so n keeps its inputs
+        let locals: Vec<PtrVWrap> = inp
+            .iter()
+            .map(|i| Leaf(i.0.deref().borrow().val.expect("input not evaluated")))
+            .collect();
+        let (mut tangent, mut adjoint) = {
+            let node = n.0.deref().borrow();
+            (node.raw.tangent(), node.raw.adjoint())
+        };
+        let adjoints = adjoint(locals.clone(), one(), &n);
+        for (j, mut adj) in adjoints.into_iter().enumerate() {
+            for (l, leaf) in locals.iter().enumerate() {
+                leaf.0.deref().borrow_mut().eval_g = l == j;
+            }
+            let fwd = f32::from(tangent(locals.clone(), &n).apply_fwd());
+            let rev = f32::from(adj.apply_fwd());
+            if !consistent(fwd, rev) {
+                found.push(Inconsistency {
+                    node: Some(n.clone()),
+                    input: j,
+                    fwd,
+                    rev,
+                });
+            }
+        }
+    }
+
+    found
+}
+
+/// constant node that is not collected as a leaf by rev
+#[track_caller]
+pub(crate) fn constant(arg0: ValType) -> PtrVWrap {
+    shared(ConstKey::of(arg0), || {
+        VWrap::new_with_val(OpConst::new(), arg0)
+    })
+}
+
+/// constant node, shared with the constants of equal value and type built in the same
+/// with_shared_constants call and by the same fwd or rev, as are the zeros and ones of
+/// the derivative graphs they build
+///
+/// a constant may thus appear in several graphs and must not be changed with set_val
+#[allow(dead_code)]
+#[track_caller]
+pub fn Const(v: ValType) -> PtrVWrap {
+    constant(v)
+}
+
+#[allow(dead_code)]
+#[track_caller]
+pub fn Sin(arg0: PtrVWrap) -> PtrVWrap {
+    let mut a = VWrap::new(OpSin::new());
+    a.set_inp(vec![arg0]);
+    a
+}
+
+#[allow(dead_code)]
+#[track_caller]
+pub fn Cos(arg0: PtrVWrap) -> PtrVWrap {
+    let mut a = VWrap::new(OpCos::new());
+    a.set_inp(vec![arg0]);
+    a
+}
+
+#[allow(dead_code)]
+#[track_caller]
+pub fn Tan(arg0: PtrVWrap) -> PtrVWrap {
+    let mut a = VWrap::new(OpTan::new());
+    a.set_inp(vec![arg0]);
+    a
+}
+
+/// hyperbolic tangent, with derivatives 1-tanh(x)^2 built from the node's own value
+#[allow(dead_code)]
+#[track_caller]
+pub fn Tanh(arg0: PtrVWrap) -> PtrVWrap {
+    let mut a = VWrap::new(OpTanh::new());
+    a.set_inp(vec![arg0]);
+    a
+}
+
+/// logistic function 1/(1+exp(-x)), evaluated as exp(x)/(1+exp(x)) for negative x so it
+/// never overflows, with derivatives s*(1-s) built from the node's own value s
+#[allow(dead_code)]
+#[track_caller]
+pub fn Sigmoid(arg0: PtrVWrap) -> PtrVWrap {
+    let mut a = VWrap::new(OpSigmoid::new());
+    a.set_inp(vec![arg0]);
+    a
+}
+
+/// ln(1+exp(x)), evaluated as max(x,0) + ln(1+exp(-|x|)) so it never overflows, with
+/// derivative sigmoid(x)
+#[allow(dead_code)]
+#[track_caller]
+pub fn Softplus(arg0: PtrVWrap) -> PtrVWrap {
+    let mut a = VWrap::new(OpSoftplus::new());
+    a.set_inp(vec![arg0]);
+    a
+}
+
+/// gaussian error linear unit x*cdf(x), with cdf the standard normal distribution, in its
+/// exact form; derivative cdf(x) + x*pdf(x)
+#[allow(dead_code)]
+#[track_caller]
+pub fn Gelu(arg0: PtrVWrap) -> PtrVWrap {
+    let mut a = VWrap::new(OpGelu::new());
+    a.set_inp(vec![arg0]);
+    a
+}
+
+#[allow(dead_code)]
+#[track_caller]
+pub fn Exp(arg0: PtrVWrap) -> PtrVWrap {
+    let mut a = VWrap::new(OpExp::new());
+    a.set_inp(vec![arg0]);
+    a
+}
+
+/// square root, with derivatives built from the node's own value, 1/(2*sqrt(x))
+#[allow(dead_code)]
+#[track_caller]
+pub fn Sqrt(arg0: PtrVWrap) -> PtrVWrap {
+    let mut a = VWrap::new(OpSqrt::new());
+    a.set_inp(vec![arg0]);
+    a
+}
+
+#[allow(dead_code)]
+#[track_caller]
+pub fn Ln(arg0: PtrVWrap) -> PtrVWrap {
+    let mut a = VWrap::new(OpLn::new());
+    a.set_inp(vec![arg0]);
+    a
+}
+
+#[allow(dead_code)]
+#[track_caller]
+pub fn Div(arg0: PtrVWrap, arg1: PtrVWrap) -> PtrVWrap {
+    let mut a = VWrap::new(OpDiv::new());
+    a.set_inp(vec![arg0, arg1]);
+    a
+}
+
+/// angle of the point (x, y) in (-pi, pi], with the quadrant given by the signs of both
+/// arguments; derivatives x/(x^2+y^2) wrt y and -y/(x^2+y^2) wrt x are undefined at the
+/// origin
+#[allow(dead_code)]
+#[track_caller]
+pub fn Atan2(y: PtrVWrap, x: PtrVWrap) -> PtrVWrap {
+    let mut a = VWrap::new(OpAtan2::new());
+    a.set_inp(vec![y, x]);
+    a
+}
+
+#[allow(dead_code)]
+#[track_caller]
+pub fn Pow(arg0: PtrVWrap, arg1: PtrVWrap) -> PtrVWrap {
+    let mut a = VWrap::new(OpPow::new());
+    a.set_inp(vec![arg0, arg1]);
+    a
+}
+
+/// floor-based remainder a - b*floor(a/b), taking the sign of b
+///
+/// derivative wrt b is treated as zero, see RemExact
+#[allow(dead_code)]
+#[track_caller]
+pub fn Rem(arg0: PtrVWrap, arg1: PtrVWrap) -> PtrVWrap {
+    let mut a = VWrap::new(OpRem::new());
+    a.set_inp(vec![arg0, arg1]);
+    a
+}
+
+/// floor-based remainder including the derivative -floor(a/b) wrt b
+#[allow(dead_code)]
+#[track_caller]
+pub fn RemExact(arg0: PtrVWrap, arg1: PtrVWrap) -> PtrVWrap {
+    let mut a = VWrap::new(Box::new(OpRem { divisor_grad: true }));
+    a.set_inp(vec![arg0, arg1]);
+    a
+}
+
+#[track_caller]
+fn bessel(kind: BesselKind, arg0: PtrVWrap) -> PtrVWrap {
+    let mut a = VWrap::new(Box::new(OpBessel { kind }));
+    a.set_inp(vec![arg0]);
+    a
+}
+
+/// bessel function of the first kind, order 0
+#[allow(dead_code)]
+#[track_caller]
+pub fn BesselJ0(arg0: PtrVWrap) -> PtrVWrap {
+    bessel(BesselKind::J0, arg0)
+}
+
+/// bessel function of the first kind, order 1
+#[allow(dead_code)]
+#[track_caller]
+pub fn BesselJ1(arg0: PtrVWrap) -> PtrVWrap {
+    bessel(BesselKind::J1, arg0)
+}
+
+/// bessel function of the second kind, order 0, defined for x > 0
+#[allow(dead_code)]
+#[track_caller]
+pub fn BesselY0(arg0: PtrVWrap) -> PtrVWrap {
+    bessel(BesselKind::Y0, arg0)
+}
+
+/// bessel function of the second kind, order 1, defined for x > 0
+#[allow(dead_code)]
+#[track_caller]
+pub fn BesselY1(arg0: PtrVWrap) -> PtrVWrap {
+    bessel(BesselKind::Y1, arg0)
+}
+
+/// modified bessel function of the first kind, order 0
+#[allow(dead_code)]
+#[track_caller]
+pub fn BesselI0(arg0: PtrVWrap) -> PtrVWrap {
+    bessel(BesselKind::I0, arg0)
+}
+
+/// modified bessel function of the first kind, order 1
+#[allow(dead_code)]
+#[track_caller]
+pub fn BesselI1(arg0: PtrVWrap) -> PtrVWrap {
+    bessel(BesselKind::I1, arg0)
+}
+
+/// modified bessel function of the second kind, order 0, defined for x > 0
+#[allow(dead_code)]
+#[track_caller]
+pub fn BesselK0(arg0: PtrVWrap) -> PtrVWrap {
+    bessel(BesselKind::K0, arg0)
+}
+
+/// modified bessel function of the second kind, order 1, defined for x > 0
+#[allow(dead_code)]
+#[track_caller]
+pub fn BesselK1(arg0: PtrVWrap) -> PtrVWrap {
+    bessel(BesselKind::K1, arg0)
+}
+
+/// sin(sqrt(s))/sqrt(s) as a smooth function of s, with sinh for negative s
+#[track_caller]
+pub(crate) fn SincSqrt(arg0: PtrVWrap) -> PtrVWrap {
+    sinc_sqrt(0, arg0)
+}
+
+#[track_caller]
+fn sinc_sqrt(order: u32, arg0: PtrVWrap) -> PtrVWrap {
+    let mut a = VWrap::new(Box::new(OpSincSqrt { order }));
+    a.set_inp(vec![arg0]);
+    a
+}
+
+/// acos(c)^2 with c clamped to [-1,1], smooth at c=1 and singular at c=-1
+#[track_caller]
+pub(crate) fn AcosSq(arg0: PtrVWrap) -> PtrVWrap {
+    let mut a = VWrap::new(OpAcosSq::new());
+    a.set_inp(vec![arg0]);
+    a
+}
+
+#[track_caller]
+fn sinc(order: u32, arg0: PtrVWrap) -> PtrVWrap {
+    let mut a = VWrap::new(Box::new(OpSinc { order }));
+    a.set_inp(vec![arg0]);
+    a
+}
+
+/// sin(x)/x, evaluating to 1 at x=0 with finite derivatives of all orders there
+#[allow(dead_code)]
+#[track_caller]
+pub fn Sinc(arg0: PtrVWrap) -> PtrVWrap {
+    sinc(0, arg0)
+}
+
+#[track_caller]
+fn ln_gamma(order: u32, arg0: PtrVWrap) -> PtrVWrap {
+    let mut a = VWrap::new(Box::new(OpLnGamma { order }));
+    a.set_inp(vec![arg0]);
+    a
+}
+
+/// ln|gamma(x)|
+#[allow(dead_code)]
+#[track_caller]
+pub fn LnGamma(arg0: PtrVWrap) -> PtrVWrap {
+    ln_gamma(0, arg0)
+}
+
+/// digamma function, the derivative of ln(gamma(x))
+#[allow(dead_code)]
+#[track_caller]
+pub fn Digamma(arg0: PtrVWrap) -> PtrVWrap {
+    ln_gamma(1, arg0)
+}
+
+/// KL(N(mu1, s1) || N(mu2, s2)) = ln(s2/s1) + (s1^2 + (mu1-mu2)^2)/(2 s2^2) - 1/2 as a
+/// single node, with s1, s2 the standard deviations
+#[allow(dead_code)]
+#[track_caller]
+pub fn KlNormal(mu1: PtrVWrap, s1: PtrVWrap, mu2: PtrVWrap, s2: PtrVWrap) -> PtrVWrap {
+    let mut a = VWrap::new(OpKlNormal::new());
+    a.set_inp(vec![mu1, s1, mu2, s2]);
+    a
+}
+
+/// KL(Bernoulli(p) || Bernoulli(q)) = p ln(p/q) + (1-p) ln((1-p)/(1-q)) as a single node
+#[allow(dead_code)]
+#[track_caller]
+pub fn KlBernoulli(p: PtrVWrap, q: PtrVWrap) -> PtrVWrap {
+    let mut a = VWrap::new(OpKlBernoulli::new());
+    a.set_inp(vec![p, q]);
+    a
+}
+
+/// linear interpolation a + t*(b-a)
+#[allow(dead_code)]
+#[track_caller]
+pub fn Lerp(arg0: PtrVWrap, arg1: PtrVWrap, arg2: PtrVWrap) -> PtrVWrap {
+    let mut a = VWrap::new(OpLerp::new());
+    a.set_inp(vec![arg0, arg1, arg2]);
+    a
+}
+
+/// smooth maximum tau*ln(sum(exp(x_i/tau))), approaching max(x_i) as tau goes to 0
+///
+/// the gradient wrt x_i is softmax(x/tau)_i
+#[allow(dead_code)]
+#[track_caller]
+pub fn SoftMax(args: Vec<PtrVWrap>, tau: f32) -> PtrVWrap {
+    assert!(!args.is_empty(), "SoftMax needs at least one input");
+    let mut a = VWrap::new(Box::new(OpSoftExtremum { tau, sign: 1. }));
+    a.set_inp(args);
+    a
+}
+
+/// smooth minimum -tau*ln(sum(exp(-x_i/tau))), approaching min(x_i) as tau goes to 0
+#[allow(dead_code)]
+#[track_caller]
+pub fn SoftMin(args: Vec<PtrVWrap>, tau: f32) -> PtrVWrap {
+    assert!(!args.is_empty(), "SoftMin needs at least one input");
+    let mut a = VWrap::new(Box::new(OpSoftExtremum { tau, sign: -1. }));
+    a.set_inp(args);
+    a
+}
+
+/// layer normalization gamma_i*(x_i-mean(x))/sqrt(var(x)+eps)+beta_i over a vector of nodes
+///
+/// each normalized entry is a single node with the full adjoint wrt all of x
+#[allow(dead_code)]
+#[track_caller]
+pub fn LayerNorm(
+    x: Vec<PtrVWrap>,
+    gamma: Vec<PtrVWrap>,
+    beta: Vec<PtrVWrap>,
+    eps: f32,
+) -> Vec<PtrVWrap> {
+    assert!(!x.is_empty(), "LayerNorm needs at least one input");
+    assert_eq!(x.len(), gamma.len(), "gamma length mismatch");
+    assert_eq!(x.len(), beta.len(), "beta length mismatch");
+    gamma
+        .into_iter()
+        .zip(beta)
+        .enumerate()
+        .map(|(i, (g, b))| Add(Mul(g, normalize(i, eps, x.clone())), b))
+        .collect()
+}
+
+/// inner product of two equal length vectors of nodes as a single node
+#[allow(dead_code)]
+#[track_caller]
+pub fn Dot(arg0: Vec<PtrVWrap>, arg1: Vec<PtrVWrap>) -> PtrVWrap {
+    assert_eq!(arg0.len(), arg1.len(), "Dot length mismatch");
+    let mut a = VWrap::new(OpDot::new());
+    a.set_inp(arg0.into_iter().chain(arg1).collect());
+    a
+}
+
+/// matrix product of row-major matrices of nodes, each entry being a Dot node
+#[allow(dead_code)]
+#[track_caller]
+pub fn MatMul(arg0: &[Vec<PtrVWrap>], arg1: &[Vec<PtrVWrap>]) -> Vec<Vec<PtrVWrap>> {
+    let inner = arg1.len();
+    assert!(
+        arg0.iter().all(|row| row.len() == inner),
+        "MatMul inner dimension mismatch"
+    );
+    let cols = arg1.first().map_or(0, |row| row.len());
+    assert!(
+        arg1.iter().all(|row| row.len() == cols),
+        "MatMul ragged rhs"
+    );
+    arg0.iter()
+        .map(|row| {
+            (0..cols)
+                .map(|j| Dot(row.clone(), arg1.iter().map(|r| r[j].clone()).collect()))
+                .collect()
+        })
+        .collect()
+}
+
+/// matrix product over a leading batch dimension
+///
+/// a batch of size 1 on either side is broadcast against the other
+#[allow(dead_code)]
+#[track_caller]
+pub fn BatchMatMul(
+    arg0: &[Vec<Vec<PtrVWrap>>],
+    arg1: &[Vec<Vec<PtrVWrap>>],
+) -> Vec<Vec<Vec<PtrVWrap>>> {
+    let batch = match (arg0.len(), arg1.len()) {
+        (x, y) if x == y => x,
+        (1, y) => y,
+        (x, 1) => x,
+        (x, y) => panic!("BatchMatMul batch mismatch: {} vs {}", x, y),
+    };
+    (0..batch)
+        .map(|i| {
+            MatMul(
+                &arg0[if arg0.len() == 1 { 0 } else { i }],
+                &arg1[if arg1.len() == 1 { 0 } else { i }],
+            )
+        })
+        .collect()
+}
+
+///row-major entries of a square matrix of nodes
+fn flatten_square(a: &[Vec<PtrVWrap>]) -> (usize, Vec<PtrVWrap>) {
+    let n = a.len();
+    assert!(n > 0, "empty matrix");
+    assert!(a.iter().all(|row| row.len() == n), "matrix is not square");
+    (n, a.iter().flatten().cloned().collect())
+}
+
+/// determinant of a square matrix of nodes, adjoint adj(A)^T which equals det(A)*inv(A)^T
+/// for invertible A
+#[allow(dead_code)]
+#[track_caller]
+pub fn Det(arg0: &[Vec<PtrVWrap>]) -> PtrVWrap {
+    let (n, args) = flatten_square(arg0);
+    let mut a = VWrap::new(Box::new(OpDet { n }));
+    a.set_inp(args);
+    a
+}
+
+/// inverse of a square matrix of nodes, adjoint -inv(A)^T*dB*inv(A)^T
+///
+/// entries evaluate to NaN for a singular matrix, the factorization is shared by all entries
+#[allow(dead_code)]
+#[track_caller]
+pub fn Inverse(arg0: &[Vec<PtrVWrap>]) -> Vec<Vec<PtrVWrap>> {
+    let (n, args) = flatten_square(arg0);
+    (0..n)
+        .map(|i| (0..n).map(|j| inv_entry(n, i, j, args.clone())).collect())
+        .collect()
+}
+
+/// lower cholesky factor of a symmetric positive definite matrix of nodes
+///
+/// only the lower triangle is read, an entry below the diagonal stands for both mirrored
+/// entries; entries evaluate to NaN if the matrix is not positive definite
+#[allow(dead_code)]
+#[track_caller]
+pub fn Cholesky(arg0: &[Vec<PtrVWrap>]) -> Vec<Vec<PtrVWrap>> {
+    let (n, args) = flatten_square(arg0);
+    (0..n)
+        .map(|i| {
+            (0..n)
+                .map(|j| {
+                    if j <= i {
+                        chol_entry(n, i, j, args.clone())
+                    } else {
+                        zero()
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// solution x of L x = b for a lower triangular matrix of nodes, reading only its lower triangle
+#[allow(dead_code)]
+#[track_caller]
+pub fn Solve(arg0: &[Vec<PtrVWrap>], arg1: &[PtrVWrap]) -> Vec<PtrVWrap> {
+    let (n, mut args) = flatten_square(arg0);
+    assert_eq!(arg1.len(), n, "Solve dimension mismatch");
+    args.extend(arg1.iter().cloned());
+    (0..n)
+        .map(|i| solve_entry(n, i, false, args.clone()))
+        .collect()
+}
+
+/// eigendecomposition of a symmetric matrix of nodes
+///
+/// returns the eigenvalues in ascending order and a matrix whose columns are the unit
+/// eigenvectors, each signed so its largest magnitude component is positive.
+/// only the lower triangle is read, an entry below the diagonal stands for both mirrored entries.
+/// eigenvector derivatives drop the 1/(lambda_i-lambda_j) terms of (near) degenerate pairs,
+/// so within a degenerate eigenspace the basis is held fixed
+#[allow(dead_code)]
+#[track_caller]
+pub fn SymEig(arg0: &[Vec<PtrVWrap>]) -> (Vec<PtrVWrap>, Vec<Vec<PtrVWrap>>) {
+    let (n, args) = flatten_square(arg0);
+    let vals = (0..n).map(|i| eigval(n, i, args.clone())).collect();
+    let vecs = (0..n)
+        .map(|r| (0..n).map(|i| eigvec(n, r, i, args.clone())).collect())
+        .collect();
+    (vals, vecs)
+}
+
+fn transpose(a: &[Vec<PtrVWrap>]) -> Vec<Vec<PtrVWrap>> {
+    let cols = a.first().map_or(0, |row| row.len());
+    (0..cols)
+        .map(|c| a.iter().map(|row| row[c].clone()).collect())
+        .collect()
+}
+
+/// thin singular value decomposition A = U*diag(S)*V^T of a matrix of nodes
+///
+/// singular values are in descending order, U and V hold min(rows, cols) columns.
+/// derivatives follow the usual svd adjoint, dropping the terms of (near) equal singular
+/// values. columns of U for zero singular values complete it to an orthonormal set, so
+/// ds_i/dA=u_i*v_i^T stays finite for rank deficient matrices
+#[allow(dead_code)]
+#[track_caller]
+pub fn Svd(arg0: &[Vec<PtrVWrap>]) -> (Vec<Vec<PtrVWrap>>, Vec<PtrVWrap>, Vec<Vec<PtrVWrap>>) {
+    let rows = arg0.len();
+    assert!(rows > 0, "empty matrix");
+    let cols = arg0[0].len();
+    assert!(arg0.iter().all(|row| row.len() == cols), "ragged matrix");
+    if rows < cols {
+        let (v, s, u) = Svd(&transpose(arg0));
+        return (u, s, v);
+    }
+    let args: Vec<PtrVWrap> = arg0.iter().flatten().cloned().collect();
+    let entries = |part: SvdPart, count: usize| -> Vec<Vec<PtrVWrap>> {
+        (0..count)
+            .map(|r| {
+                (0..cols)
+                    .map(|i| svd_entry(rows, cols, part, r, i, args.clone()))
+                    .collect()
+            })
+            .collect()
+    };
+    let s = (0..cols)
+        .map(|i| svd_entry(rows, cols, SvdPart::S, 0, i, args.clone()))
+        .collect();
+    (entries(SvdPart::U, rows), s, entries(SvdPart::V, cols))
+}
+
+#[track_caller]
+fn dft(re: &[PtrVWrap], im: &[PtrVWrap], inverse: bool) -> (Vec<PtrVWrap>, Vec<PtrVWrap>) {
+    assert_eq!(
+        re.len(),
+        im.len(),
+        "real and imaginary parts differ in length"
+    );
+    let args: Vec<PtrVWrap> = re.iter().chain(im.iter()).cloned().collect();
+    let part = |imag: bool| -> Vec<PtrVWrap> {
+        (0..re.len())
+            .map(|k| {
+                let mut a = VWrap::new(Box::new(OpDft { k, imag, inverse }));
+                a.set_inp(args.clone());
+                a
+            })
+            .collect()
+    };
+    (part(false), part(true))
+}
+
+///unscaled radix-2 decimation in time transform of a power of two length
+#[track_caller]
+fn fft_radix2(re: &[PtrVWrap], im: &[PtrVWrap], inverse: bool) -> (Vec<PtrVWrap>, Vec<PtrVWrap>) {
+    let n = re.len();
+    if n == 1 {
+        return (re.to_vec(), im.to_vec());
+    }
+    let half = |x: &[PtrVWrap], p: usize| -> Vec<PtrVWrap> {
+        x.iter().skip(p).step_by(2).cloned().collect()
+    };
+    let (er, ei) = fft_radix2(&half(re, 0), &half(im, 0), inverse);
+    let (or, oi) = fft_radix2(&half(re, 1), &half(im, 1), inverse);
+    let sign = if inverse { 1. } else { -1. };
+    let (mut xr, mut xi) = (er.clone(), ei.clone());
+    xr.extend(er.iter().cloned());
+    xi.extend(ei.iter().cloned());
+    for k in 0..n / 2 {
+        //X_k=E_k+w^k*O_k, X_(k+n/2)=E_k-w^k*O_k
+        let (tr, ti) = if k == 0 {
+            (or[0].clone(), oi[0].clone())
+        } else {
+            let (s, c) = (sign * 2. * std::f64::consts::PI * k as f64 / n as f64).sin_cos();
+            (
+                twiddle(c, s, false, or[k].clone(), oi[k].clone()),
+                twiddle(c, s, true, or[k].clone(), oi[k].clone()),
+            )
+        };
+        xr[k] = Add(er[k].clone(), tr.clone());
+        xi[k] = Add(ei[k].clone(), ti.clone());
+        xr[k + n / 2] = Minus(er[k].clone(), tr);
+        xi[k + n / 2] = Minus(ei[k].clone(), ti);
+    }
+    (xr, xi)
+}
+
+#[track_caller]
+fn fft(re: &[PtrVWrap], im: &[PtrVWrap], inverse: bool) -> (Vec<PtrVWrap>, Vec<PtrVWrap>) {
+    let n = re.len();
+    if !n.is_power_of_two() || im.len() != n {
+        return dft(re, im, inverse);
+    }
+    let (xr, xi) = fft_radix2(re, im, inverse);
+    if !inverse {
+        return (xr, xi);
+    }
+    let scale = 1. / n as f64;
+    xr.into_iter()
+        .zip(xi)
+        .map(|(r, i)| {
+            (
+                twiddle(scale, 0., false, r.clone(), i.clone()),
+                twiddle(scale, 0., true, r, i),
+            )
+        })
+        .unzip()
+}
+
+/// discrete fourier transform X_k = sum(x_j*exp(-2*pi*i*j*k/n)) of a complex vector of nodes
+///
+/// returns the real and imaginary parts. power of two lengths are built from radix-2
+/// butterflies with O(n*log(n)) nodes, other lengths evaluate each bin directly as one
+/// node over all inputs, O(n^2) in total, with the conjugate transform as adjoint
+#[allow(dead_code)]
+#[track_caller]
+pub fn Fft(re: &[PtrVWrap], im: &[PtrVWrap]) -> (Vec<PtrVWrap>, Vec<PtrVWrap>) {
+    fft(re, im, false)
+}
+
+/// inverse discrete fourier transform x_j = sum(X_k*exp(2*pi*i*j*k/n))/n, with the costs of Fft
+#[allow(dead_code)]
+#[track_caller]
+pub fn Ifft(re: &[PtrVWrap], im: &[PtrVWrap]) -> (Vec<PtrVWrap>, Vec<PtrVWrap>) {
+    fft(re, im, true)
+}
+
+///inclusive scan y_i = f(y_(i-1), x_i) with y_0 = x_0
+#[track_caller]
+fn scan(arg0: &[PtrVWrap], f: fn(PtrVWrap, PtrVWrap) -> PtrVWrap) -> Vec<PtrVWrap> {
+    let mut ret: Vec<PtrVWrap> = Vec::with_capacity(arg0.len());
+    for x in arg0.iter() {
+        let y = match ret.last() {
+            Some(prev) => f(prev.clone(), x.clone()),
+            None => x.clone(),
+        };
+        ret.push(y);
+    }
+    ret
+}
+
+///scan of each column (axis 0) or each row (axis 1) of a matrix
+#[track_caller]
+fn scan_axis(
+    arg0: &[Vec<PtrVWrap>],
+    axis: usize,
+    f: fn(PtrVWrap, PtrVWrap) -> PtrVWrap,
+) -> Vec<Vec<PtrVWrap>> {
+    match axis {
+        0 => transpose(
+            &transpose(arg0)
+                .iter()
+                .map(|c| scan(c, f))
+                .collect::<Vec<_>>(),
+        ),
+        1 => arg0.iter().map(|r| scan(r, f)).collect(),
+        _ => panic!("axis {} out of range for a matrix", axis),
+    }
+}
+
+/// running sums y_i = y_(i-1)+x_i with y_0 = x_0
+///
+/// the adjoint of x_j is the reversed scan of the output adjoints from j onwards
+#[allow(dead_code)]
+#[track_caller]
+pub fn CumSum(arg0: &[PtrVWrap]) -> Vec<PtrVWrap> {
+    scan(arg0, Add)
+}
+
+/// running products y_i = y_(i-1)*x_i with y_0 = x_0
+///
+/// adjoints multiply by the other factor of each step, so zero entries are handled exactly
+#[allow(dead_code)]
+#[track_caller]
+pub fn CumProd(arg0: &[PtrVWrap]) -> Vec<PtrVWrap> {
+    scan(arg0, Mul)
+}
+
+/// CumSum down each column (axis 0) or along each row (axis 1) of a matrix of nodes
+#[allow(dead_code)]
+#[track_caller]
+pub fn CumSumAxis(arg0: &[Vec<PtrVWrap>], axis: usize) -> Vec<Vec<PtrVWrap>> {
+    scan_axis(arg0, axis, Add)
+}
+
+/// CumProd down each column (axis 0) or along each row (axis 1) of a matrix of nodes
+#[allow(dead_code)]
+#[track_caller]
+pub fn CumProdAxis(arg0: &[Vec<PtrVWrap>], axis: usize) -> Vec<Vec<PtrVWrap>> {
+    scan_axis(arg0, axis, Mul)
+}
+
+#[track_caller]
+fn fma(arg0: PtrVWrap, arg1: PtrVWrap, arg2: PtrVWrap) -> PtrVWrap {
+    VWrap::new_with_input(OpFma::new(), vec![arg0, arg1, arg2])
+}
+
+#[track_caller]
+fn scale(factor: f32, arg0: PtrVWrap) -> PtrVWrap {
+    VWrap::new_with_input(Box::new(OpScale { factor }), vec![arg0])
+}
+
+/// sampled signal with values samples at the strictly increasing times, interpolated at t
+///
+/// t is clamped to the sampled range; derivatives are available wrt t and the samples
+#[allow(dead_code)]
+#[track_caller]
+pub fn Interp(t: PtrVWrap, times: &[f32], samples: &[PtrVWrap], scheme: Interpolation) -> PtrVWrap {
+    assert!(times.len() >= 2, "Interp needs at least two samples");
+    assert_eq!(times.len(), samples.len(), "Interp length mismatch");
+    assert!(
+        times.windows(2).all(|w| w[0] < w[1]),
+        "Interp times must be strictly increasing"
+    );
+    let op = OpInterp {
+        times: Arc::new(times.iter().map(|&x| x as f64).collect()),
+        scheme,
+        order: 0,
+        weight: None,
+    };
+    let mut inputs = vec![t];
+    inputs.extend(samples.iter().cloned());
+    op.build(inputs)
+}
+
+/// entries x[indices[k]], each a node over the selected entry alone
+///
+/// repeated indices are allowed; rev gives adjoints for the gathered entries only, with
+/// the contributions of repeated indices summed as in a scatter-add
+#[allow(dead_code)]
+#[track_caller]
+pub fn Gather(arg0: &[PtrVWrap], indices: &[usize]) -> Vec<PtrVWrap> {
+    indices
+        .iter()
+        .map(|&index| {
+            assert!(index < arg0.len(), "Gather index out of bounds");
+            VWrap::new_with_input(OpSelect::new(), vec![arg0[index].clone()])
+        })
+        .collect()
+}
+
+/// row index of an embedding table, one node per column
+///
+/// the row is selected when the graph is built and only its entries are inputs, so rev
+/// gives adjoints for the looked up rows alone, summing the contributions of repeated
+/// lookups, instead of a dense adjoint of the whole table
+#[allow(dead_code)]
+#[track_caller]
+pub fn Embedding(table: &[Vec<PtrVWrap>], index: usize) -> Vec<PtrVWrap> {
+    assert!(index < table.len(), "Embedding index out of bounds");
+    table[index]
+        .iter()
+        .map(|e| VWrap::new_with_input(OpSelect::new(), vec![e.clone()]))
+        .collect()
+}
+
+/// vector of length len with y_i = sum(src_k where indices[k]=i), zero where nothing lands
+///
+/// the adjoint of src_k is the output adjoint at indices[k], i.e. a gather
 #[allow(dead_code)]
-pub fn Exp(arg0: PtrVWrap) -> PtrVWrap {
-    let mut a = VWrap::new(OpExp::new());
+#[track_caller]
+pub fn ScatterAdd(src: &[PtrVWrap], indices: &[usize], len: usize) -> Vec<PtrVWrap> {
+    assert_eq!(
+        src.len(),
+        indices.len(),
+        "ScatterAdd needs one index per source"
+    );
+    assert!(
+        indices.iter().all(|&i| i < len),
+        "ScatterAdd index out of bounds"
+    );
+    (0..len)
+        .map(|i| {
+            let hits: Vec<PtrVWrap> = src
+                .iter()
+                .zip(indices.iter())
+                .filter(|(_, &k)| k == i)
+                .map(|(x, _)| x.clone())
+                .collect();
+            if hits.is_empty() {
+                zero()
+            } else {
+                sum(hits)
+            }
+        })
+        .collect()
+}
+
+#[track_caller]
+fn sum(args: Vec<PtrVWrap>) -> PtrVWrap {
+    let mut a = VWrap::new(OpSum::new());
+    a.set_inp(args);
+    a
+}
+
+/// leaves holding the given values
+#[allow(dead_code)]
+#[track_caller]
+pub fn leaf_vec(values: &[f32]) -> Vec<PtrVWrap> {
+    values.iter().map(|v| Leaf(ValType::F(*v))).collect()
+}
+
+/// sum of the nodes as a single node, a zero constant if there are none
+#[allow(dead_code)]
+#[track_caller]
+pub fn sum_nodes(nodes: &[PtrVWrap]) -> PtrVWrap {
+    if nodes.is_empty() {
+        constant(ValType::F(0.))
+    } else {
+        sum(nodes.to_vec())
+    }
+}
+
+/// inner product of two equal length slices of nodes as a single node
+#[allow(dead_code)]
+#[track_caller]
+pub fn dot_nodes(a: &[PtrVWrap], b: &[PtrVWrap]) -> PtrVWrap {
+    if a.is_empty() {
+        assert!(b.is_empty(), "Dot length mismatch");
+        constant(ValType::F(0.))
+    } else {
+        Dot(a.to_vec(), b.to_vec())
+    }
+}
+
+/// collections of nodes that keep their shape when transformed node by node
+pub trait MapNodes: Sized {
+    /// applies f to every node of the collection
+    fn map_with<F: FnMut(PtrVWrap) -> PtrVWrap>(&self, f: &mut F) -> Self;
+}
+
+impl MapNodes for PtrVWrap {
+    fn map_with<F: FnMut(PtrVWrap) -> PtrVWrap>(&self, f: &mut F) -> Self {
+        f(self.clone())
+    }
+}
+
+impl<T: MapNodes> MapNodes for Vec<T> {
+    fn map_with<F: FnMut(PtrVWrap) -> PtrVWrap>(&self, f: &mut F) -> Self {
+        self.iter().map(|x| x.map_with(f)).collect()
+    }
+}
+
+impl<T: MapNodes, const N: usize> MapNodes for [T; N] {
+    fn map_with<F: FnMut(PtrVWrap) -> PtrVWrap>(&self, f: &mut F) -> Self {
+        std::array::from_fn(|i| self[i].map_with(f))
+    }
+}
+
+/// applies f to every node of a slice, the slice form of map used with leaf_vec
+#[allow(dead_code)]
+#[track_caller]
+pub fn map_nodes<F: FnMut(PtrVWrap) -> PtrVWrap>(nodes: &[PtrVWrap], f: F) -> Vec<PtrVWrap> {
+    nodes.iter().cloned().map(f).collect()
+}
+
+/// applies a closure building a sub-expression to every node of a vector, a matrix as
+/// nested vectors or an array of nodes, in row-major order
+#[allow(dead_code)]
+#[track_caller]
+pub fn map<C: MapNodes, F: FnMut(PtrVWrap) -> PtrVWrap>(nodes: &C, mut f: F) -> C {
+    nodes.map_with(&mut f)
+}
+
+/// sum of the diagonal of a square matrix of nodes as a single node
+#[allow(dead_code)]
+#[track_caller]
+pub fn Trace(arg0: &[Vec<PtrVWrap>]) -> PtrVWrap {
+    let (n, _) = flatten_square(arg0);
+    sum((0..n).map(|i| arg0[i][i].clone()).collect())
+}
+
+/// main diagonal of a matrix of nodes
+#[allow(dead_code)]
+#[track_caller]
+pub fn Diag(arg0: &[Vec<PtrVWrap>]) -> Vec<PtrVWrap> {
+    arg0.iter()
+        .enumerate()
+        .filter_map(|(i, row)| row.get(i).cloned())
+        .collect()
+}
+
+/// square matrix with the given nodes on the diagonal and zeros elsewhere
+#[allow(dead_code)]
+#[track_caller]
+pub fn DiagEmbed(arg0: &[PtrVWrap]) -> Vec<Vec<PtrVWrap>> {
+    (0..arg0.len())
+        .map(|i| {
+            (0..arg0.len())
+                .map(|j| if i == j { arg0[i].clone() } else { zero() })
+                .collect()
+        })
+        .collect()
+}
+
+/// kronecker product of two matrices of nodes
+///
+/// entry (i*p+k, j*q+l) is a_ij*b_kl for B of shape p by q, so the adjoint of a_ij
+/// contracts block (i,j) of the output adjoint with B
+#[allow(dead_code)]
+#[track_caller]
+pub fn Kron(arg0: &[Vec<PtrVWrap>], arg1: &[Vec<PtrVWrap>]) -> Vec<Vec<PtrVWrap>> {
+    let q = arg1.first().map_or(0, |row| row.len());
+    assert!(arg1.iter().all(|row| row.len() == q), "Kron ragged rhs");
+    arg0.iter()
+        .flat_map(|a_row| {
+            arg1.iter().map(move |b_row| {
+                a_row
+                    .iter()
+                    .flat_map(|a| b_row.iter().map(move |b| Mul(a.clone(), b.clone())))
+                    .collect()
+            })
+        })
+        .collect()
+}
+
+#[track_caller]
+fn step(coeffs: &'static [f64], order: u32, arg0: PtrVWrap) -> PtrVWrap {
+    let policy = subgradient_policy();
+    let mut a = VWrap::new(Box::new(OpStep {
+        coeffs,
+        order,
+        policy,
+    }));
     a.set_inp(vec![arg0]);
     a
 }
 
-#[allow(dead_code)]
-pub fn Ln(arg0: PtrVWrap) -> PtrVWrap {
-    let mut a = VWrap::new(OpLn::new());
-    a.set_inp(vec![arg0]);
-    a
+#[track_caller]
+fn heaviside(arg0: PtrVWrap) -> PtrVWrap {
+    let policy = subgradient_policy();
+    VWrap::new_with_input(Box::new(OpHeaviside { policy }), vec![arg0])
+}
+
+/// rectified linear unit max(x, 0), with the derivative at 0 given by the subgradient policy
+#[allow(dead_code)]
+#[track_caller]
+pub fn Relu(arg0: PtrVWrap) -> PtrVWrap {
+    VWrap::new_with_input(OpRelu::new(), vec![arg0])
+}
+
+#[track_caller]
+fn sign(arg0: PtrVWrap) -> PtrVWrap {
+    let policy = subgradient_policy();
+    VWrap::new_with_input(Box::new(OpSign { policy }), vec![arg0])
+}
+
+/// absolute value |x|, with derivative sign(x)
+///
+/// the derivative at 0 is given by the subgradient policy over the one-sided derivatives
+/// -1 and 1: 0 under the default Zero and Average, which keeps L1 penalties at rest at 0
+#[allow(dead_code)]
+#[track_caller]
+pub fn Abs(arg0: PtrVWrap) -> PtrVWrap {
+    VWrap::new_with_input(OpAbs::new(), vec![arg0])
+}
+
+/// larger of a and b, with the adjoint routed to the larger operand
+///
+/// at a tie the subgradient policy resolves the share of a between 0 and 1 and b gets the
+/// rest: Right routes the adjoint to a, Left and the default Zero to b, Average splits it
+#[allow(dead_code)]
+#[track_caller]
+pub fn Max(arg0: PtrVWrap, arg1: PtrVWrap) -> PtrVWrap {
+    VWrap::new_with_input(Box::new(OpMaxMin { max: true }), vec![arg0, arg1])
+}
+
+/// smaller of a and b, with the adjoint routed to the smaller operand and ties handled as
+/// in Max
+#[allow(dead_code)]
+#[track_caller]
+pub fn Min(arg0: PtrVWrap, arg1: PtrVWrap) -> PtrVWrap {
+    VWrap::new_with_input(Box::new(OpMaxMin { max: false }), vec![arg0, arg1])
+}
+
+#[track_caller]
+fn clip_gate(lo: f32, hi: f32, arg0: PtrVWrap) -> PtrVWrap {
+    let policy = subgradient_policy();
+    VWrap::new_with_input(Box::new(OpClipGate { lo, hi, policy }), vec![arg0])
+}
+
+/// x clamped to [lo, hi]
+///
+/// the derivative is 1 inside and 0 outside of the interval, with the subgradient policy
+/// at the bounds, or 1 everywhere if pass_through (straight-through estimator), so the
+/// gradient keeps flowing through saturated values
+#[allow(dead_code)]
+#[track_caller]
+pub fn ClipWithGrad(arg0: PtrVWrap, lo: f32, hi: f32, pass_through: bool) -> PtrVWrap {
+    assert!(lo <= hi, "ClipWithGrad needs lo <= hi");
+    let op = OpClip {
+        lo,
+        hi,
+        pass_through,
+    };
+    VWrap::new_with_input(Box::new(op), vec![arg0])
+}
+
+/// hermite smoothstep 3t^2-2t^3, clamped to 0 for t<=0 and 1 for t>=1
+#[allow(dead_code)]
+#[track_caller]
+pub fn Smoothstep(arg0: PtrVWrap) -> PtrVWrap {
+    step(OpStep::SMOOTH, 0, arg0)
+}
+
+/// perlin smootherstep 6t^5-15t^4+10t^3, clamped to 0 for t<=0 and 1 for t>=1
+#[allow(dead_code)]
+#[track_caller]
+pub fn Smootherstep(arg0: PtrVWrap) -> PtrVWrap {
+    step(OpStep::SMOOTHER, 0, arg0)
+}
+
+#[cfg(test)]
+fn eq_f32(a: f32, b: f32) -> bool {
+    (a - b).abs() < 0.01
+}
+
+#[test]
+fn test_loop_fwd() {
+    let l0 = Leaf(ValType::F(2.)).active();
+
+    let mut l = l0.clone();
+    for _ in 0..10 {
+        l = Mul(l, Leaf(ValType::F(2.)));
+    }
+
+    let vl = l.apply_fwd();
+
+    dbg!(vl);
+
+    assert!(eq_f32(vl.into(), 2048.));
+
+    let mut g = l.fwd();
+    let h = g.apply_fwd();
+
+    dbg!(h);
+
+    assert!(eq_f32(h.into(), 1024.));
+}
+
+#[test]
+fn test_simple_fwd() {
+    //(3x)' = 3
+
+    let l0 = Leaf(ValType::F(4.)).active();
+    let l1 = Leaf(ValType::F(3.));
+    let a = Mul(l0.clone(), l1.clone());
+
+    let mut b = a.fwd();
+
+    let c = b.apply_fwd();
+
+    dbg!(c);
+
+    assert!(eq_f32(c.into(), 3.));
+}
+
+#[test]
+fn test_square_fwd() {
+    //(3x^2)' = 6x{x=4} = 24
+    let l0 = Leaf(ValType::F(4.)).active();
+    let l1 = Leaf(ValType::F(3.));
+    let a = Mul(Mul(l0.clone(), l0.clone()), l1);
+
+    let mut b = a.fwd();
+
+    let c = b.apply_fwd();
+
+    dbg!(&c);
+
+    // dbg!( &l0 );
+
+    assert!(eq_f32(c.into(), 24.));
+}
+
+#[test]
+fn test_square_fwd_2() {
+    //(x(4)^2)' = 16
+    let l0 = Leaf(ValType::F(4.));
+    let l1 = Leaf(ValType::F(3.)).active();
+    let a = Mul(Mul(l0.clone(), l0.clone()), l1);
+
+    let mut b = a.fwd();
+
+    let c = b.apply_fwd();
+
+    dbg!(&c);
+
+    assert!(eq_f32(c.into(), 16.));
+}
+
+#[test]
+fn test_simple_rev() {
+    //(3x)' = 3
+    let l0 = Leaf(ValType::F(4.));
+    let l1 = Leaf(ValType::F(3.));
+    let a = Mul(l0.clone(), l1.clone());
+
+    let ret = a
+        .rev()
+        .get_mut(&l0)
+        .expect("l0 adjoint missing")
+        .apply_rev();
+
+    dbg!(ret);
+
+    assert!(eq_f32(ret.into(), 3.));
+}
+
+#[test]
+fn test_simple_rev_2() {
+    //(3x^2)' = 6x{x=4} = 24
+    let l0 = Leaf(ValType::F(4.));
+    let l1 = Leaf(ValType::F(3.));
+    let a = Mul(Mul(l0.clone(), l0.clone()), l1.clone());
+
+    let ret = a
+        .rev()
+        .get_mut(&l0)
+        .expect("l0 adjoint missing")
+        .apply_rev();
+
+    dbg!(&ret);
+
+    assert!(eq_f32(ret.into(), 24.));
+}
+
+#[test]
+fn test_composite_fwd_over_fwd() {
+    //y=3*x^2 where x=4
+    //compute y'' = (6x)' = 6
+
+    let l0 = Leaf(ValType::F(4.)).active();
+    let l1 = Leaf(ValType::F(3.));
+    let a = Mul(Mul(l0.clone(), l0.clone()), l1.clone());
+
+    let mut gg = a.fwd().fwd();
+
+    let ret = gg.apply_fwd();
+
+    dbg!(&ret);
+
+    assert!(eq_f32(ret.into(), 6.));
+}
+
+#[test]
+fn test_composite_fwd_over_rev() {
+    //y=x*3 where x=4
+    //compute y'' = (3)' = 0
+
+    let l0 = Leaf(ValType::F(4.)).active();
+    let l1 = Leaf(ValType::F(3.));
+    let a = Mul(l0.clone(), l1.clone());
+
+    let mut adjoints = a.rev();
+
+    let adj = adjoints.get_mut(&l0).expect("l0 adjoint missing");
+
+    let mut g = adj.fwd();
+
+    let ret = g.apply_fwd();
+
+    dbg!(&ret);
+
+    assert!(eq_f32(ret.into(), 0.));
+}
+
+#[test]
+fn test_composite_fwd_over_rev_2() {
+    //y=3*x^2 where x=4
+    //compute y'' = (6x)' = 6
+
+    let l0 = Leaf(ValType::F(4.)).active();
+    let l1 = Leaf(ValType::F(3.));
+    let a = Mul(Mul(l0.clone(), l0.clone()), l1.clone());
+
+    let ret = a
+        .rev()
+        .get_mut(&l0)
+        .expect("l0 adjoint missing")
+        .fwd()
+        .apply_fwd();
+
+    dbg!(&ret);
+
+    assert!(eq_f32(ret.into(), 6.));
+}
+
+#[test]
+fn test_composite_rev_over_rev() {
+    //(3x^2)'' = 6
+    let l0 = Leaf(ValType::F(4.));
+    let l1 = Leaf(ValType::F(3.));
+    let a = Mul(Mul(l0.clone(), l0.clone()), l1.clone());
+
+    let ret = a
+        .rev()
+        .get_mut(&l0)
+        .expect("l0 adjoint missing")
+        .rev()
+        .get_mut(&l0)
+        .expect("l0 adjoint missing")
+        .apply_rev();
+
+    dbg!(&ret);
+
+    assert!(eq_f32(ret.into(), 6.));
+}
+
+#[test]
+fn test_composite_rev_over_fwd() {
+    //(3x^2)'' = 6
+
+    let l0 = Leaf(ValType::F(4.)).active();
+    let l1 = Leaf(ValType::F(3.));
+    let a = Mul(Mul(l0.clone(), l0.clone()), l1.clone());
+
+    let ret = a
+        .fwd()
+        .rev()
+        .get_mut(&l0)
+        .expect("l0 adjoint missing")
+        .apply_rev();
+
+    dbg!(&ret);
+
+    assert!(eq_f32(ret.into(), 6.));
 }
 
-#[allow(dead_code)]
-pub fn Div(arg0: PtrVWrap, arg1: PtrVWrap) -> PtrVWrap {
-    let mut a = VWrap::new(OpDiv::new());
-    a.set_inp(vec![arg0, arg1]);
-    a
+#[test]
+fn test_composite_rev_over_fwd_change_input() {
+    //(3x^2)'' = 6
+
+    let l0 = Leaf(ValType::F(4.)).active();
+    let mut l1 = Leaf(ValType::F(3.));
+    let a = Mul(Mul(l0.clone(), l0.clone()), l1.clone());
+
+    let mut gg = a
+        .fwd()
+        .rev()
+        .get_mut(&l0)
+        .expect("l0 adjoint missing")
+        .clone();
+
+    let ret = gg.apply_rev();
+
+    dbg!(&ret);
+
+    assert!(eq_f32(ret.into(), 6.));
+
+    //change to (7x^2)''=(14x)'=14
+    l1.set_val(ValType::F(7.));
+
+    let ret2 = gg.apply_rev();
+
+    dbg!(&ret2);
+
+    assert!(eq_f32(ret2.into(), 14.));
+}
+
+#[test]
+fn test_composite_rev_over_rev_change_input() {
+    //(3x^2)'' = 6
+
+    let l0 = Leaf(ValType::F(4.));
+    let mut l1 = Leaf(ValType::F(3.));
+    let a = Mul(Mul(l0.clone(), l0.clone()), l1.clone());
+
+    let mut gg = a
+        .rev()
+        .get_mut(&l0)
+        .expect("l0 adjoint missing")
+        .rev()
+        .get_mut(&l0)
+        .expect("l0 adjoint missing")
+        .clone();
+
+    let ret = gg.apply_rev();
+
+    dbg!(&ret);
+
+    assert!(eq_f32(ret.into(), 6.));
+
+    //change to (7x^2)''=(14x)'=14
+    l1.set_val(ValType::F(7.));
+
+    let ret2 = gg.apply_rev();
+
+    dbg!(&ret2);
+
+    assert!(eq_f32(ret2.into(), 14.));
+}
+
+#[test]
+fn test_composite_fwd_over_rev_change_input() {
+    //(3x^2)'' = 6
+
+    let l0 = Leaf(ValType::F(4.)).active();
+    let mut l1 = Leaf(ValType::F(3.));
+    let a = Mul(Mul(l0.clone(), l0.clone()), l1.clone());
+
+    let mut gg = a.rev().get_mut(&l0).expect("l0 adjoint missing").fwd();
+
+    let ret = gg.apply_fwd();
+
+    dbg!(&ret);
+
+    assert!(eq_f32(ret.into(), 6.));
+
+    //change to (7x^2)''=(14x)'=14
+    l1.set_val(ValType::F(7.));
+
+    let ret2 = gg.apply_fwd();
+
+    dbg!(&ret2);
+
+    assert!(eq_f32(ret2.into(), 14.));
+}
+
+#[test]
+fn test_composite_fwd_over_fwd_change_input() {
+    //y=3*x^2 where x=4
+    //compute y'' = (6x)' = 6
+
+    let l0 = Leaf(ValType::F(4.)).active();
+    let mut l1 = Leaf(ValType::F(3.));
+    let a = Mul(Mul(l0.clone(), l0.clone()), l1.clone());
+
+    let mut gg = a.fwd().fwd();
+
+    let ret = gg.apply_fwd();
+
+    dbg!(&ret);
+
+    assert!(eq_f32(ret.into(), 6.));
+
+    //change to (7x^2)''=(14x)'=14
+    l1.set_val(ValType::F(7.));
+
+    let ret2 = gg.apply_fwd();
+
+    dbg!(&ret2);
+
+    assert!(eq_f32(ret2.into(), 14.));
+}
+
+#[test]
+fn test_2nd_partial_derivative() {
+    //x=4
+    //y=3
+    //f=x^2 * y^2
+    //d^2(f)/(dx dy) = d(d(x^2 * y^2)/dx)/dy = d(2x*y^2)/dy = 2x*2y = 4*4*3 = 48
+
+    let l0 = Leaf(ValType::F(4.));
+    let l1 = Leaf(ValType::F(3.));
+    let a = Mul(Mul(l0.clone(), l0.clone()), Mul(l1.clone(), l1.clone()));
+
+    let mut gg = a
+        .rev()
+        .get_mut(&l0)
+        .expect("l0 adjoint missing")
+        .rev()
+        .get_mut(&l1)
+        .expect("l1 adjoint missing")
+        .clone();
+
+    let ret = gg.apply_rev();
+
+    dbg!(&ret);
+
+    assert!(eq_f32(ret.into(), 48.));
+}
+
+#[test]
+fn test_trig_sin_fwd() {
+    //y=3*sin(x) where x=2
+    //y'=3*cos(x) where x=2
+    //y''=-3*sin(x) where x=2
+
+    let l0 = Leaf(ValType::F(2.)).active();
+    let l1 = Leaf(ValType::F(3.));
+    let mut a = Mul(Sin(l0.clone()), l1.clone());
+
+    assert!(eq_f32(a.apply_fwd().into(), 3.0 * 2f32.sin()));
+
+    let mut g = a.fwd();
+
+    assert!(eq_f32(g.apply_fwd().into(), 3.0 * 2f32.cos()));
+
+    let mut gg = g.fwd();
+
+    assert!(eq_f32(gg.apply_fwd().into(), -3.0 * 2f32.sin()));
+}
+
+#[test]
+fn test_trig_sin_rev() {
+    //y=3*sin(x) where x=2
+    //y'=3*cos(x) where x=2
+    //y''=-3*sin(x) where x=2
+
+    let l0 = Leaf(ValType::F(2.)).active();
+    let l1 = Leaf(ValType::F(3.));
+    let a = Mul(Sin(l0.clone()), l1.clone());
+
+    {
+        let g = a
+            .rev()
+            .get_mut(&l0)
+            .expect("l0 adjoint missing")
+            .apply_rev();
+
+        assert!(eq_f32(g.into(), 3.0 * 2f32.cos()));
+    }
+
+    {
+        let gg = a
+            .rev()
+            .get_mut(&l0)
+            .expect("l0 adjoint missing")
+            .rev()
+            .get_mut(&l0)
+            .expect("l0 adjoint missing")
+            .apply_rev();
+
+        assert!(eq_f32(gg.into(), -3.0 * 2f32.sin()));
+    }
 }
 
-#[allow(dead_code)]
-pub fn Pow(arg0: PtrVWrap, arg1: PtrVWrap) -> PtrVWrap {
-    let mut a = VWrap::new(OpPow::new());
-    a.set_inp(vec![arg0, arg1]);
-    a
-}
+#[test]
+fn test_trig_cos_fwd() {
+    //y=3*cos(x) where x=2
+    //y'=-3*sin(x) where x=2
+    //y''=-3*cos(x) where x=2
+
+    let l0 = Leaf(ValType::F(2.)).active();
+    let l1 = Leaf(ValType::F(3.));
+    let mut a = Mul(Cos(l0.clone()), l1.clone());
+
+    assert!(eq_f32(a.apply_fwd().into(), 3.0 * 2f32.cos()));
+
+    let mut g = a.fwd();
 
-#[cfg(test)]
-fn eq_f32(a: f32, b: f32) -> bool {
-    (a - b).abs() < 0.01
+    assert!(eq_f32(g.apply_fwd().into(), -3.0 * 2f32.sin()));
+
+    let mut gg = g.fwd();
+
+    assert!(eq_f32(gg.apply_fwd().into(), -3.0 * 2f32.cos()));
 }
 
 #[test]
-fn test_loop_fwd() {
+fn test_trig_cos_rev() {
+    //y=3*cos(x) where x=2
+    //y'=-3*sin(x) where x=2
+    //y''=-3*cos(x) where x=2
+
     let l0 = Leaf(ValType::F(2.)).active();
+    let l1 = Leaf(ValType::F(3.));
+    let a = Mul(Cos(l0.clone()), l1.clone());
 
-    let mut l = l0.clone();
-    for _ in 0..10 {
-        l = Mul(l, Leaf(ValType::F(2.)));
-    }
+    {
+        let g = a
+            .rev()
+            .get_mut(&l0)
+            .expect("l0 adjoint missing")
+            .apply_rev();
 
-    let vl = l.apply_fwd();
+        assert!(eq_f32(g.into(), -3.0 * 2f32.sin()));
+    }
 
-    dbg!(vl);
+    {
+        let gg = a
+            .rev()
+            .get_mut(&l0)
+            .expect("l0 adjoint missing")
+            .rev()
+            .get_mut(&l0)
+            .expect("l0 adjoint missing")
+            .apply_rev();
 
-    assert!(eq_f32(vl.into(), 2048.));
+        assert!(eq_f32(gg.into(), -3.0 * 2f32.cos()));
+    }
+}
 
-    let mut g = l.fwd();
-    let h = g.apply_fwd();
+#[test]
+fn test_exp_fwd() {
+    //y=3*exp(4x) where x=2
+    //y'=12*exp(4x) where x=2
+    //y''=48*exp(4x) where x=2
 
-    dbg!(h);
+    let l0 = Leaf(ValType::F(2.)).active();
+    let l1 = Leaf(ValType::F(3.));
+    let l2 = Leaf(ValType::F(4.));
+    let a = Mul(Exp(Mul(l2.clone(), l0.clone())), l1.clone());
 
-    assert!(eq_f32(h.into(), 1024.));
+    {
+        assert!(eq_f32(a.fwd().apply_fwd().into(), 12. * 8f32.exp()));
+    }
+    {
+        assert!(eq_f32(a.fwd().fwd().apply_fwd().into(), 48. * 8f32.exp()));
+    }
 }
 
 #[test]
-fn test_simple_fwd() {
-    //(3x)' = 3
+fn test_exp_rev() {
+    //y=3*exp(4x) where x=2
+    //y'=12*exp(4x) where x=2
+    //y''=48*exp(4x) where x=2
 
-    let l0 = Leaf(ValType::F(4.)).active();
+    let l0 = Leaf(ValType::F(2.)).active();
     let l1 = Leaf(ValType::F(3.));
-    let a = Mul(l0.clone(), l1.clone());
+    let l2 = Leaf(ValType::F(4.));
+    let a = Mul(Exp(Mul(l2.clone(), l0.clone())), l1.clone());
 
-    let mut b = a.fwd();
+    {
+        let g = a
+            .rev()
+            .get_mut(&l0)
+            .expect("l0 adjoint missing")
+            .apply_rev();
+        assert!(eq_f32(g.into(), 12. * 8f32.exp()));
+    }
+    {
+        let gg = a
+            .rev()
+            .get_mut(&l0)
+            .expect("l0 adjoint missing")
+            .rev()
+            .get_mut(&l0)
+            .expect("l0 adjoint missing")
+            .apply_rev();
+        assert!(eq_f32(gg.into(), 48. * 8f32.exp()));
+    }
+}
 
-    let c = b.apply_fwd();
+#[test]
+fn test_div_fwd() {
+    //y=3/(4x) where x=2
+    //y'=-3/4*x^-2
+    //y''=6/4*x^-3
 
-    dbg!(c);
+    let l0 = Leaf(ValType::F(2.)).active();
+    let l1 = Leaf(ValType::F(3.));
+    let l2 = Leaf(ValType::F(4.));
+    let a = Div(l1.clone(), Mul(l2.clone(), l0.clone()));
 
-    assert!(eq_f32(c.into(), 3.));
+    {
+        assert!(eq_f32(a.fwd().apply_fwd().into(), -3. / 4. * 2f32.powi(-2)));
+    }
+    {
+        assert!(eq_f32(
+            a.fwd().fwd().apply_fwd().into(),
+            6. / 4. * 2f32.powi(-3)
+        ));
+    }
 }
 
 #[test]
-fn test_square_fwd() {
-    //(3x^2)' = 6x{x=4} = 24
-    let l0 = Leaf(ValType::F(4.)).active();
-    let l1 = Leaf(ValType::F(3.));
-    let a = Mul(Mul(l0.clone(), l0.clone()), l1);
+fn test_div_rev() {
+    //y=3/(4x) where x=2
+    //y'=-3/4*x^-2
+    //y''=6/4*x^-3
 
-    let mut b = a.fwd();
+    let l0 = Leaf(ValType::F(2.)).active();
+    let l1 = Leaf(ValType::F(3.));
+    let l2 = Leaf(ValType::F(4.));
+    let a = Div(l1.clone(), Mul(l2.clone(), l0.clone()));
 
-    let c = b.apply_fwd();
+    {
+        let g = a
+            .rev()
+            .get_mut(&l0)
+            .expect("l0 adjoint missing")
+            .apply_rev();
 
-    dbg!(&c);
+        assert!(eq_f32(g.into(), -3. / 4. * 2f32.powi(-2)));
+    }
 
-    // dbg!( &l0 );
+    {
+        let gg = a
+            .rev()
+            .get_mut(&l0)
+            .expect("l0 adjoint missing")
+            .rev()
+            .get_mut(&l0)
+            .expect("l0 adjoint missing")
+            .apply_rev();
 
-    assert!(eq_f32(c.into(), 24.));
+        assert!(eq_f32(gg.into(), 6. / 4. * 2f32.powi(-3)));
+    }
 }
 
 #[test]
-fn test_square_fwd_2() {
-    //(x(4)^2)' = 16
-    let l0 = Leaf(ValType::F(4.));
-    let l1 = Leaf(ValType::F(3.)).active();
-    let a = Mul(Mul(l0.clone(), l0.clone()), l1);
-
-    let mut b = a.fwd();
-
-    let c = b.apply_fwd();
+fn test_tan_fwd() {
+    //y=3tan(4x) where x=2
+    //y'=12/(cos(4x))^2 where x=2
 
-    dbg!(&c);
+    let l0 = Leaf(ValType::F(2.)).active();
+    let l1 = Leaf(ValType::F(3.));
+    let l2 = Leaf(ValType::F(4.));
+    let a = Mul(l1.clone(), Tan(Mul(l2.clone(), l0.clone())));
 
-    assert!(eq_f32(c.into(), 16.));
+    assert!(eq_f32(
+        a.fwd().apply_fwd().into(),
+        12. / (8f32.cos().powi(2))
+    ));
 }
 
 #[test]
-fn test_simple_rev() {
-    //(3x)' = 3
-    let l0 = Leaf(ValType::F(4.));
+fn test_tan_rev() {
+    //y=3tan(4x) where x=2
+    //y'=12/(cos(4x))^2 where x=2
+
+    let l0 = Leaf(ValType::F(2.)).active();
     let l1 = Leaf(ValType::F(3.));
-    let a = Mul(l0.clone(), l1.clone());
+    let l2 = Leaf(ValType::F(4.));
+    let a = Mul(l1.clone(), Tan(Mul(l2.clone(), l0.clone())));
 
-    let ret = a
+    let g = a
         .rev()
         .get_mut(&l0)
         .expect("l0 adjoint missing")
         .apply_rev();
 
-    dbg!(ret);
-
-    assert!(eq_f32(ret.into(), 3.));
+    assert!(eq_f32(g.into(), 12. / (8f32.cos().powi(2))));
 }
 
 #[test]
-fn test_simple_rev_2() {
-    //(3x^2)' = 6x{x=4} = 24
-    let l0 = Leaf(ValType::F(4.));
-    let l1 = Leaf(ValType::F(3.));
-    let a = Mul(Mul(l0.clone(), l0.clone()), l1.clone());
+fn test_ln_fwd() {
+    //y=ln(4x) where x=2
+    //y'=4/(4x) where x=2
 
-    let ret = a
-        .rev()
-        .get_mut(&l0)
-        .expect("l0 adjoint missing")
-        .apply_rev();
+    let l0 = Leaf(ValType::F(2.)).active();
+    let l1 = Leaf(ValType::F(4.));
+    let a = Ln(Mul(l0.clone(), l1.clone()));
 
-    dbg!(&ret);
+    let g = a.fwd().apply_fwd();
 
-    assert!(eq_f32(ret.into(), 24.));
+    assert!(eq_f32(g.into(), 4. / 8.));
 }
 
 #[test]
-fn test_composite_fwd_over_fwd() {
-    //y=3*x^2 where x=4
-    //compute y'' = (6x)' = 6
-
-    let l0 = Leaf(ValType::F(4.)).active();
-    let l1 = Leaf(ValType::F(3.));
-    let a = Mul(Mul(l0.clone(), l0.clone()), l1.clone());
-
-    let mut gg = a.fwd().fwd();
+fn test_ln_rev() {
+    //y=ln(4x) where x=2
+    //y'=4/(4x) where x=2
 
-    let ret = gg.apply_fwd();
+    let l0 = Leaf(ValType::F(2.)).active();
+    let l1 = Leaf(ValType::F(4.));
+    let a = Ln(Mul(l0.clone(), l1.clone()));
 
-    dbg!(&ret);
+    let g = a
+        .rev()
+        .get_mut(&l0)
+        .expect("l0 adjoint missing")
+        .apply_rev();
 
-    assert!(eq_f32(ret.into(), 6.));
+    assert!(eq_f32(g.into(), 4. / 8.));
 }
 
 #[test]
-fn test_composite_fwd_over_rev() {
-    //y=x*3 where x=4
-    //compute y'' = (3)' = 0
-
-    let l0 = Leaf(ValType::F(4.)).active();
-    let l1 = Leaf(ValType::F(3.));
-    let a = Mul(l0.clone(), l1.clone());
-
-    let mut adjoints = a.rev();
+fn test_pow_fwd() {
+    //y=4x^3 where x=2
+    //y'=12*x^2 where x=2
 
-    let adj = adjoints.get_mut(&l0).expect("l0 adjoint missing");
+    let l0 = Leaf(ValType::F(2.)).active();
+    let l1 = Leaf(ValType::F(4.));
+    let l2 = Leaf(ValType::F(3.));
+    let a = Mul(l1.clone(), Pow(l0.clone(), l2.clone()));
 
-    let mut g = adj.fwd();
+    assert!(eq_f32(a.fwd().apply_fwd().into(), 12. * 4.));
+}
 
-    let ret = g.apply_fwd();
+#[test]
+fn test_pow_fwd_2() {
+    //y=4^(3x) where x=2
+    //y'=ln(4)*4^(3x)*3 where x=2
 
-    dbg!(&ret);
+    let l0 = Leaf(ValType::F(2.)).active();
+    let l1 = Leaf(ValType::F(4.));
+    let l2 = Leaf(ValType::F(3.));
+    let a = Pow(l1.clone(), Mul(l2.clone(), l0.clone()));
 
-    assert!(eq_f32(ret.into(), 0.));
+    assert!(eq_f32(
+        a.fwd().apply_fwd().into(),
+        4f32.ln() * 4f32.powf(3. * 2.) * 3.
+    ));
 }
 
 #[test]
-fn test_composite_fwd_over_rev_2() {
-    //y=3*x^2 where x=4
-    //compute y'' = (6x)' = 6
+fn test_pow_rev() {
+    //y=4x^3 where x=2
+    //y'=12*x^2 where x=2
 
-    let l0 = Leaf(ValType::F(4.)).active();
-    let l1 = Leaf(ValType::F(3.));
-    let a = Mul(Mul(l0.clone(), l0.clone()), l1.clone());
+    let l0 = Leaf(ValType::F(2.)).active();
+    let l1 = Leaf(ValType::F(4.));
+    let l2 = Leaf(ValType::F(3.));
+    let a = Mul(l1.clone(), Pow(l0.clone(), l2.clone()));
 
-    let ret = a
+    let g = a
         .rev()
         .get_mut(&l0)
         .expect("l0 adjoint missing")
-        .fwd()
-        .apply_fwd();
-
-    dbg!(&ret);
+        .apply_rev();
 
-    assert!(eq_f32(ret.into(), 6.));
+    assert!(eq_f32(g.into(), 12. * 4.));
 }
 
 #[test]
-fn test_composite_rev_over_rev() {
-    //(3x^2)'' = 6
-    let l0 = Leaf(ValType::F(4.));
-    let l1 = Leaf(ValType::F(3.));
-    let a = Mul(Mul(l0.clone(), l0.clone()), l1.clone());
+fn test_pow_rev_2() {
+    //y=4^(3x) where x=2
+    //y'=ln(4)*4^(3x)*3 where x=2
 
-    let ret = a
-        .rev()
-        .get_mut(&l0)
-        .expect("l0 adjoint missing")
+    let l0 = Leaf(ValType::F(2.)).active();
+    let l1 = Leaf(ValType::F(4.));
+    let l2 = Leaf(ValType::F(3.));
+    let a = Pow(l1.clone(), Mul(l2.clone(), l0.clone()));
+
+    let g = a
         .rev()
         .get_mut(&l0)
         .expect("l0 adjoint missing")
         .apply_rev();
 
-    dbg!(&ret);
+    assert!(eq_f32(g.into(), 4f32.ln() * 4f32.powf(3. * 2.) * 3.));
+}
 
-    assert!(eq_f32(ret.into(), 6.));
+#[test]
+fn test_rem_fwd() {
+    //y=rem(3x, 4) where x=3
+    //y'=3
+
+    let l0 = Leaf(ValType::F(3.)).active();
+    let l1 = Leaf(ValType::F(3.));
+    let l2 = Leaf(ValType::F(4.));
+    let mut a = Rem(Mul(l1.clone(), l0.clone()), l2.clone());
+
+    assert!(eq_f32(a.apply_fwd().into(), 1.));
+    assert!(eq_f32(a.fwd().apply_fwd().into(), 3.));
+
+    //sign follows divisor
+    let mut b = Rem(Leaf(ValType::F(-1.)), Leaf(ValType::F(4.)));
+    assert!(eq_f32(b.apply_fwd().into(), 3.));
 }
 
 #[test]
-fn test_composite_rev_over_fwd() {
-    //(3x^2)'' = 6
+fn test_rem_rev() {
+    //y=rem(a, b) where a=7, b=3
+    //dy/da=1
+    //dy/db=0 by default, -floor(a/b)=-2 for exact version
 
-    let l0 = Leaf(ValType::F(4.)).active();
+    let l0 = Leaf(ValType::F(7.));
     let l1 = Leaf(ValType::F(3.));
-    let a = Mul(Mul(l0.clone(), l0.clone()), l1.clone());
 
-    let ret = a
-        .fwd()
-        .rev()
-        .get_mut(&l0)
-        .expect("l0 adjoint missing")
-        .apply_rev();
+    let a = Rem(l0.clone(), l1.clone());
+    let mut adj = a.rev();
+    assert!(eq_f32(
+        adj.get_mut(&l0)
+            .expect("l0 adjoint missing")
+            .apply_rev()
+            .into(),
+        1.
+    ));
+    assert!(eq_f32(
+        adj.get_mut(&l1)
+            .expect("l1 adjoint missing")
+            .apply_rev()
+            .into(),
+        0.
+    ));
 
-    dbg!(&ret);
+    let b = RemExact(l0.clone(), l1.clone());
+    let mut adj = b.rev();
+    assert!(eq_f32(
+        adj.get_mut(&l0)
+            .expect("l0 adjoint missing")
+            .apply_rev()
+            .into(),
+        1.
+    ));
+    assert!(eq_f32(
+        adj.get_mut(&l1)
+            .expect("l1 adjoint missing")
+            .apply_rev()
+            .into(),
+        -2.
+    ));
 
-    assert!(eq_f32(ret.into(), 6.));
+    let l2 = Leaf(ValType::F(3.)).active();
+    let c = RemExact(l0.clone(), l2.clone());
+    assert!(eq_f32(c.fwd().apply_fwd().into(), -2.));
 }
 
 #[test]
-fn test_composite_rev_over_fwd_change_input() {
-    //(3x^2)'' = 6
+fn test_bessel_fwd() {
+    //y=J0(x) where x=1
+    //y'=-J1(1)=-0.4401
+    let l0 = Leaf(ValType::F(1.)).active();
+    let mut a = BesselJ0(l0.clone());
+    assert!(eq_f32(a.apply_fwd().into(), 0.7652));
+    assert!(eq_f32(a.fwd().apply_fwd().into(), -0.4401));
+
+    //y=J1(x^2) where x=1
+    //y'=(J0(1)-J1(1))*2x=0.6503
+    let b = BesselJ1(Mul(l0.clone(), l0.clone()));
+    assert!(eq_f32(b.fwd().apply_fwd().into(), 0.6503));
+
+    //y=K0(x) where x=1
+    //y'=-K1(1)=-0.6019
+    let c = BesselK0(l0.clone());
+    assert!(eq_f32(c.fwd().apply_fwd().into(), -0.6019));
+
+    //second derivative of I0 is I0 - I1/x = 1.2661 - 0.5652 = 0.7009
+    let d = BesselI0(l0.clone());
+    assert!(eq_f32(d.fwd().fwd().apply_fwd().into(), 0.7009));
+
+    //J1'(0) = I1'(0) = 1/2, J1''(0) = I1''(0) = 0
+    let z = Leaf(ValType::F(0.)).active();
+    for e in [BesselJ1(z.clone()), BesselI1(z.clone())].iter() {
+        assert!(eq_f32(e.fwd().apply_fwd().into(), 0.5));
+        assert!(eq_f32(e.fwd().fwd().apply_fwd().into(), 0.));
+    }
+    assert!(eq_f32(
+        BesselJ1(z.clone()).rev()[&z].clone().apply_rev().into(),
+        0.5
+    ));
+}
 
-    let l0 = Leaf(ValType::F(4.)).active();
-    let mut l1 = Leaf(ValType::F(3.));
-    let a = Mul(Mul(l0.clone(), l0.clone()), l1.clone());
+#[test]
+fn test_bessel_rev() {
+    //y=Y0(x) where x=2
+    //dy/dx=-Y1(2)=0.1070
+    let l0 = Leaf(ValType::F(2.));
+    let a = BesselY0(l0.clone());
+    let mut adj = a.rev();
+    assert!(eq_f32(
+        adj.get_mut(&l0)
+            .expect("l0 adjoint missing")
+            .apply_rev()
+            .into(),
+        0.1070
+    ));
 
-    let mut gg = a
-        .fwd()
-        .rev()
-        .get_mut(&l0)
-        .expect("l0 adjoint missing")
-        .clone();
+    //y=I0(x) where x=2
+    //dy/dx=I1(2)=1.5906
+    let b = BesselI0(l0.clone());
+    let mut adj = b.rev();
+    assert!(eq_f32(
+        adj.get_mut(&l0)
+            .expect("l0 adjoint missing")
+            .apply_rev()
+            .into(),
+        1.5906
+    ));
 
-    let ret = gg.apply_rev();
+    //double precision is preserved
+    let mut c = BesselJ0(Leaf(ValType::D(1.)));
+    match c.apply_fwd() {
+        ValType::D(x) => assert!((x - 0.7651976866).abs() < 1e-6),
+        _ => panic!("expected f64 value"),
+    }
+}
 
-    dbg!(&ret);
+#[test]
+fn test_sinc_fwd() {
+    //y=sinc(x) where x=0
+    //y=1, y'=0, y''=-1/3
+    let l0 = Leaf(ValType::F(0.)).active();
+    let mut a = Sinc(l0.clone());
+    assert!(eq_f32(a.apply_fwd().into(), 1.));
+    assert!(eq_f32(a.fwd().apply_fwd().into(), 0.));
+    assert!(eq_f32(a.fwd().fwd().apply_fwd().into(), -1. / 3.));
+
+    //y=sinc(2x) where x=1
+    //y'=2*(2cos(2)-sin(2))/4=-0.8707
+    let l1 = Leaf(ValType::F(1.)).active();
+    let two = Leaf(ValType::F(2.));
+    let b = Sinc(Mul(two, l1.clone()));
+    assert!(eq_f32(b.fwd().apply_fwd().into(), -0.8707));
+}
 
-    assert!(eq_f32(ret.into(), 6.));
+#[test]
+fn test_ln_gamma() {
+    //y=ln(gamma(x)) where x=3
+    //y=ln(2), y'=psi(3)=1.5-euler gamma, y''=pi^2/6-1.25
+    let l0 = Leaf(ValType::F(3.)).active();
+    let mut a = LnGamma(l0.clone());
+    assert!(eq_f32(a.apply_fwd().into(), 2f32.ln()));
+    assert!(eq_f32(a.fwd().apply_fwd().into(), 0.9228));
+    assert!(eq_f32(a.fwd().fwd().apply_fwd().into(), 0.3949));
+
+    let mut d = Digamma(l0.clone());
+    assert!(eq_f32(d.apply_fwd().into(), 0.9228));
+    let mut adj = d.rev();
+    assert!(eq_f32(
+        adj.get_mut(&l0)
+            .expect("l0 adjoint missing")
+            .apply_rev()
+            .into(),
+        0.3949
+    ));
+}
 
-    //change to (7x^2)''=(14x)'=14
-    l1.set_val(ValType::F(7.));
+#[test]
+fn test_kl_normal() {
+    //y=KL(N(1, 2) || N(0, 1))=-ln(2)+(4+1)/2-1/2
+    let mu1 = Leaf(ValType::F(1.));
+    let s1 = Leaf(ValType::F(2.));
+    let mu2 = Leaf(ValType::F(0.));
+    let s2 = Leaf(ValType::F(1.));
+    let mut y = KlNormal(mu1.clone(), s1.clone(), mu2.clone(), s2.clone());
+    assert!(eq_f32(y.apply_fwd().into(), 2. - 2f32.ln()));
+
+    //dy/dmu1=1, dy/ds1=-1/2+2, dy/dmu2=-1, dy/ds2=1-5
+    let leaves = [mu1.clone(), s1.clone(), mu2.clone(), s2.clone()];
+    let mut adj = y.rev();
+    for (l, e) in leaves.iter().zip([1f32, 1.5, -1., -4.]) {
+        let d: f32 = adj.get_mut(l).expect("adjoint missing").apply_rev().into();
+        assert!(eq_f32(d, e));
+    }
+    assert!(eq_f32(y.fwd_wrt(&s1).into(), 1.5));
+    //d2y/ds2^2=-1/s2^2+3(s1^2+d^2)/s2^4
+    assert!(eq_f32(partial(&y, &[s2.clone(), s2.clone()]).into(), 14.));
+}
 
-    let ret2 = gg.apply_rev();
+#[test]
+fn test_kl_bernoulli() {
+    //y=KL(B(0.25) || B(0.5))
+    let p = Leaf(ValType::F(0.25));
+    let q = Leaf(ValType::F(0.5));
+    let mut y = KlBernoulli(p.clone(), q.clone());
+    assert!(eq_f32(
+        y.apply_fwd().into(),
+        0.25 * 0.5f32.ln() + 0.75 * 1.5f32.ln()
+    ));
+    //dy/dp=ln(p/q)-ln((1-p)/(1-q)), dy/dq=(1-p)/(1-q)-p/q
+    assert!(eq_f32(y.fwd_wrt(&p).into(), (1f32 / 3.).ln()));
+    let mut adj = y.rev();
+    let d: f32 = adj
+        .get_mut(&q)
+        .expect("q adjoint missing")
+        .apply_rev()
+        .into();
+    assert!(eq_f32(d, 1.));
+}
 
-    dbg!(&ret2);
+#[test]
+fn test_sinc_rev() {
+    //y=sinc(x) where x=2
+    //dy/dx=(2cos(2)-sin(2))/4=-0.4354
+    let l0 = Leaf(ValType::F(2.));
+    let a = Sinc(l0.clone());
+    let mut adj = a.rev();
+    assert!(eq_f32(
+        adj.get_mut(&l0)
+            .expect("l0 adjoint missing")
+            .apply_rev()
+            .into(),
+        -0.4354
+    ));
 
-    assert!(eq_f32(ret2.into(), 14.));
+    //no NaN at the origin
+    let l1 = Leaf(ValType::F(0.));
+    let b = Sinc(l1.clone());
+    let mut adj = b.rev();
+    assert!(eq_f32(
+        adj.get_mut(&l1)
+            .expect("l1 adjoint missing")
+            .apply_rev()
+            .into(),
+        0.
+    ));
 }
 
 #[test]
-fn test_composite_rev_over_rev_change_input() {
-    //(3x^2)'' = 6
+fn test_smoothstep_fwd() {
+    //y=smoothstep(x) where x=0.25
+    //y=3/16-2/64=0.15625, y'=6x(1-x)=1.125, y''=6-12x=3
+    let l0 = Leaf(ValType::F(0.25)).active();
+    let mut a = Smoothstep(l0.clone());
+    assert!(eq_f32(a.apply_fwd().into(), 0.15625));
+    assert!(eq_f32(a.fwd().apply_fwd().into(), 1.125));
+    assert!(eq_f32(a.fwd().fwd().apply_fwd().into(), 3.));
+
+    //y=smootherstep(x) where x=0.5
+    //y=0.5, y'=30x^2(x-1)^2=1.875
+    let l1 = Leaf(ValType::F(0.5)).active();
+    let mut b = Smootherstep(l1.clone());
+    assert!(eq_f32(b.apply_fwd().into(), 0.5));
+    assert!(eq_f32(b.fwd().apply_fwd().into(), 1.875));
+
+    //clamped outside [0,1]
+    let l2 = Leaf(ValType::F(1.5)).active();
+    let mut c = Smoothstep(l2.clone());
+    assert!(eq_f32(c.apply_fwd().into(), 1.));
+    assert!(eq_f32(c.fwd().apply_fwd().into(), 0.));
+}
 
-    let l0 = Leaf(ValType::F(4.));
-    let mut l1 = Leaf(ValType::F(3.));
-    let a = Mul(Mul(l0.clone(), l0.clone()), l1.clone());
+#[test]
+fn test_smoothstep_rev() {
+    //y=smoothstep(x/2) where x=1
+    //dy/dx=6*0.5*0.5/2=0.75
+    let l0 = Leaf(ValType::F(1.));
+    let half = Leaf(ValType::F(0.5));
+    let a = Smoothstep(Mul(l0.clone(), half));
+    let mut adj = a.rev();
+    assert!(eq_f32(
+        adj.get_mut(&l0)
+            .expect("l0 adjoint missing")
+            .apply_rev()
+            .into(),
+        0.75
+    ));
 
-    let mut gg = a
-        .rev()
-        .get_mut(&l0)
-        .expect("l0 adjoint missing")
-        .rev()
-        .get_mut(&l0)
-        .expect("l0 adjoint missing")
-        .clone();
+    //y=smootherstep(x) where x=-1
+    //dy/dx=0
+    let l1 = Leaf(ValType::F(-1.));
+    let b = Smootherstep(l1.clone());
+    let mut adj = b.rev();
+    assert!(eq_f32(
+        adj.get_mut(&l1)
+            .expect("l1 adjoint missing")
+            .apply_rev()
+            .into(),
+        0.
+    ));
+}
 
-    let ret = gg.apply_rev();
+#[test]
+fn test_lerp_fwd() {
+    //y=lerp(a, b, t) where a=1, b=5, t=0.25
+    //dy/da=0.75, dy/db=0.25, dy/dt=4
+    let mut a = Lerp(
+        Leaf(ValType::F(1.)),
+        Leaf(ValType::F(5.)),
+        Leaf(ValType::F(0.25)),
+    );
+    assert!(eq_f32(a.apply_fwd().into(), 2.));
+
+    let l0 = Leaf(ValType::F(1.)).active();
+    let b = Lerp(l0.clone(), Leaf(ValType::F(5.)), Leaf(ValType::F(0.25)));
+    assert!(eq_f32(b.fwd().apply_fwd().into(), 0.75));
+
+    let l2 = Leaf(ValType::F(0.25)).active();
+    let c = Lerp(Leaf(ValType::F(1.)), Leaf(ValType::F(5.)), l2.clone());
+    assert!(eq_f32(c.fwd().apply_fwd().into(), 4.));
+}
 
-    dbg!(&ret);
+#[test]
+fn test_lerp_rev() {
+    //y=lerp(a, b, t) where a=1, b=5, t=0.25
+    //dy/da=0.75, dy/db=0.25, dy/dt=4
+    let l0 = Leaf(ValType::F(1.));
+    let l1 = Leaf(ValType::F(5.));
+    let l2 = Leaf(ValType::F(0.25));
+    let a = Lerp(l0.clone(), l1.clone(), l2.clone());
+    let mut adj = a.rev();
+    assert!(eq_f32(
+        adj.get_mut(&l0)
+            .expect("l0 adjoint missing")
+            .apply_rev()
+            .into(),
+        0.75
+    ));
+    assert!(eq_f32(
+        adj.get_mut(&l1)
+            .expect("l1 adjoint missing")
+            .apply_rev()
+            .into(),
+        0.25
+    ));
+    assert!(eq_f32(
+        adj.get_mut(&l2)
+            .expect("l2 adjoint missing")
+            .apply_rev()
+            .into(),
+        4.
+    ));
+}
 
-    assert!(eq_f32(ret.into(), 6.));
+#[test]
+fn test_softmax_fwd() {
+    //y=softmax(x, 2, 3; tau=1) where x=1
+    //y=ln(e+e^2+e^3)=3.4076, y'=e/(e+e^2+e^3)=0.0900
+    let l0 = Leaf(ValType::F(1.)).active();
+    let mut a = SoftMax(
+        vec![l0.clone(), Leaf(ValType::F(2.)), Leaf(ValType::F(3.))],
+        1.,
+    );
+    assert!(eq_f32(a.apply_fwd().into(), 3.4076));
+    assert!(eq_f32(a.fwd().apply_fwd().into(), 0.0900));
+    //y''=w(1-w)=0.0819
+    assert!(eq_f32(a.fwd().fwd().apply_fwd().into(), 0.0819));
+
+    //low temperature approaches min, no overflow for large inputs
+    let mut b = SoftMin(
+        vec![Leaf(ValType::F(100.)), Leaf(ValType::F(200.)), l0.clone()],
+        0.01,
+    );
+    assert!(eq_f32(b.apply_fwd().into(), 1.));
+    assert!(eq_f32(b.fwd().apply_fwd().into(), 1.));
+}
 
-    //change to (7x^2)''=(14x)'=14
-    l1.set_val(ValType::F(7.));
+#[test]
+fn test_softmax_rev() {
+    //y=softmin(x0, x1; tau=0.5) where x0=0, x1=1
+    //dy/dx0=1/(1+e^-2)=0.8808, dy/dx1=0.1192
+    let l0 = Leaf(ValType::F(0.));
+    let l1 = Leaf(ValType::F(1.));
+    let a = SoftMin(vec![l0.clone(), l1.clone()], 0.5);
+    let mut adj = a.rev();
+    assert!(eq_f32(
+        adj.get_mut(&l0)
+            .expect("l0 adjoint missing")
+            .apply_rev()
+            .into(),
+        0.8808
+    ));
+    assert!(eq_f32(
+        adj.get_mut(&l1)
+            .expect("l1 adjoint missing")
+            .apply_rev()
+            .into(),
+        0.1192
+    ));
+}
 
-    let ret2 = gg.apply_rev();
+#[test]
+fn test_layernorm_fwd() {
+    //y=layernorm(x) with gamma=1, beta=0, eps=0 where x=(1,2,3), active x0
+    //mean=2, var=2/3, s=1.2247, h=(-1.2247,0,1.2247)
+    //dy_i/dx0=s*(delta_i0-1/3-h_i*h_0/3)=(0.2041,-0.4082,0.2041)
+    let l0 = Leaf(ValType::F(1.)).active();
+    let x = vec![l0.clone(), Leaf(ValType::F(2.)), Leaf(ValType::F(3.))];
+    let one = || Leaf(ValType::F(1.));
+    let zero = || Leaf(ValType::F(0.));
+    let mut y = LayerNorm(
+        x,
+        vec![one(), one(), one()],
+        vec![zero(), zero(), zero()],
+        0.,
+    );
+    assert!(eq_f32(y[0].apply_fwd().into(), -1.2247));
+    assert!(eq_f32(y[1].apply_fwd().into(), 0.));
+    assert!(eq_f32(y[2].apply_fwd().into(), 1.2247));
+    assert!(eq_f32(y[0].fwd().apply_fwd().into(), 0.2041));
+    assert!(eq_f32(y[1].fwd().apply_fwd().into(), -0.4082));
+    assert!(eq_f32(y[2].fwd().apply_fwd().into(), 0.2041));
+
+    //double precision is preserved, h_2=(5/3)/(sqrt(14)/3) for x=(1,2,4)
+    let x: Vec<PtrVWrap> = [1., 2., 4.].iter().map(|v| Leaf(ValType::D(*v))).collect();
+    let one = || Leaf(ValType::D(1.));
+    let zero = || Leaf(ValType::D(0.));
+    let mut y = LayerNorm(
+        x,
+        vec![one(), one(), one()],
+        vec![zero(), zero(), zero()],
+        0.,
+    );
+    match y[2].apply_fwd() {
+        ValType::D(v) => assert!((v - 5. / 14f64.sqrt()).abs() < 1e-12),
+        _ => panic!("expected f64 value"),
+    }
+}
 
-    dbg!(&ret2);
+#[test]
+fn test_layernorm_rev() {
+    //y=layernorm(x)_0 with gamma=2, beta=1 where x=(1,2,4)
+    //mean=7/3, s=0.8018, h=(-1.0690,-0.2673,1.3363)
+    //dy/dx_j=2*s*(delta_0j-1/3-h_0*h_j/3)=(0.4582,-0.6872,0.2291)
+    let l = vec![
+        Leaf(ValType::F(1.)),
+        Leaf(ValType::F(2.)),
+        Leaf(ValType::F(4.)),
+    ];
+    let two = || Leaf(ValType::F(2.));
+    let one = || Leaf(ValType::F(1.));
+    let y = LayerNorm(
+        l.clone(),
+        vec![two(), two(), two()],
+        vec![one(), one(), one()],
+        1e-5,
+    );
+    let mut adj = y[0].rev();
+    let expected = [0.4582, -0.6872, 0.2291];
+    for (leaf, e) in l.iter().zip(expected.iter()) {
+        assert!(eq_f32(
+            adj.get_mut(leaf)
+                .expect("leaf adjoint missing")
+                .apply_rev()
+                .into(),
+            *e
+        ));
+    }
 
-    assert!(eq_f32(ret2.into(), 14.));
+    //second order with gamma=1: d2y0/dx0^2=0.1964
+    let l0 = Leaf(ValType::F(1.)).active();
+    let x = vec![l0.clone(), Leaf(ValType::F(2.)), Leaf(ValType::F(4.))];
+    let y = LayerNorm(x, vec![one(), one(), one()], vec![two(), two(), two()], 0.);
+    let mut adj = y[0].rev();
+    assert!(eq_f32(
+        adj.get_mut(&l0)
+            .expect("l0 adjoint missing")
+            .fwd()
+            .apply_fwd()
+            .into(),
+        0.1964
+    ));
+}
+
+#[cfg(test)]
+fn leaf_matrix(v: &[&[f32]]) -> Vec<Vec<PtrVWrap>> {
+    v.iter()
+        .map(|row| row.iter().map(|x| Leaf(ValType::F(*x))).collect())
+        .collect()
 }
 
 #[test]
-fn test_composite_fwd_over_rev_change_input() {
-    //(3x^2)'' = 6
+fn test_matmul_fwd() {
+    //c=a*b where a=[[1,2],[3,4]], b=[[5],[6]]
+    //c=[[17],[39]], dc/da00=b00=5
+    let mut a = leaf_matrix(&[&[1., 2.], &[3., 4.]]);
+    a[0][0] = Leaf(ValType::F(1.)).active();
+    let b = leaf_matrix(&[&[5.], &[6.]]);
+    let mut c = MatMul(&a, &b);
+    assert_eq!((c.len(), c[0].len()), (2, 1));
+    assert!(eq_f32(c[0][0].apply_fwd().into(), 17.));
+    assert!(eq_f32(c[1][0].apply_fwd().into(), 39.));
+    assert!(eq_f32(c[0][0].fwd().apply_fwd().into(), 5.));
+    assert!(eq_f32(c[1][0].fwd().apply_fwd().into(), 0.));
+
+    //double precision is preserved when any input is f64
+    let mut d = Dot(
+        vec![Leaf(ValType::D(0.1)), Leaf(ValType::F(2.))],
+        vec![Leaf(ValType::D(3.)), Leaf(ValType::F(4.))],
+    );
+    match d.apply_fwd() {
+        ValType::D(v) => assert!((v - (0.1 * 3. + 8.)).abs() < 1e-12),
+        _ => panic!("expected f64 value"),
+    }
+}
 
-    let l0 = Leaf(ValType::F(4.)).active();
-    let mut l1 = Leaf(ValType::F(3.));
-    let a = Mul(Mul(l0.clone(), l0.clone()), l1.clone());
+#[test]
+fn test_batch_matmul_rev() {
+    //c_i=a_i*w with w broadcast over the batch
+    //a_0=[[1,2]], a_1=[[3,4]], w=[[5],[6]]
+    //d(c_0+c_1)/dw=[[1+3],[2+4]]
+    let a = vec![leaf_matrix(&[&[1., 2.]]), leaf_matrix(&[&[3., 4.]])];
+    let w = vec![leaf_matrix(&[&[5.], &[6.]])];
+    let mut c = BatchMatMul(&a, &w);
+    assert_eq!(c.len(), 2);
+    assert!(eq_f32(c[0][0][0].apply_fwd().into(), 17.));
+    assert!(eq_f32(c[1][0][0].apply_fwd().into(), 39.));
+
+    let y = Add(c[0][0][0].clone(), c[1][0][0].clone());
+    let mut adj = y.rev();
+    for (leaf, e) in [&w[0][0][0], &w[0][1][0]].iter().zip([4., 6.].iter()) {
+        assert!(eq_f32(
+            adj.get_mut(leaf)
+                .expect("w adjoint missing")
+                .apply_rev()
+                .into(),
+            *e
+        ));
+    }
+    assert!(eq_f32(
+        adj.get_mut(&a[1][0][1])
+            .expect("a adjoint missing")
+            .apply_rev()
+            .into(),
+        6.
+    ));
+}
 
-    let mut gg = a.rev().get_mut(&l0).expect("l0 adjoint missing").fwd();
+#[test]
+fn test_det_inverse_fwd() {
+    //A=[[2,1],[1,3]], det=5, inv=[[0.6,-0.2],[-0.2,0.4]]
+    //d det/d a00=a11=3, d inv00/d a00=-inv00*inv00=-0.36
+    let mut a = leaf_matrix(&[&[2., 1.], &[1., 3.]]);
+    a[0][0] = Leaf(ValType::F(2.)).active();
+    let mut d = Det(&a);
+    assert!(eq_f32(d.apply_fwd().into(), 5.));
+    assert!(eq_f32(d.fwd().apply_fwd().into(), 3.));
+
+    let mut inv = Inverse(&a);
+    assert!(eq_f32(inv[0][0].apply_fwd().into(), 0.6));
+    assert!(eq_f32(inv[0][1].apply_fwd().into(), -0.2));
+    assert!(eq_f32(inv[1][1].apply_fwd().into(), 0.4));
+    assert!(eq_f32(inv[0][0].fwd().apply_fwd().into(), -0.36));
+    //second derivative of det wrt a00 is 0
+    assert!(eq_f32(d.fwd().fwd().apply_fwd().into(), 0.));
+}
 
-    let ret = gg.apply_fwd();
+#[test]
+fn test_det_inverse_rev() {
+    //y=det(A) where A=[[2,1],[1,3]]
+    //dy/dA=det*inv^T=[[3,-1],[-1,2]]
+    let a = leaf_matrix(&[&[2., 1.], &[1., 3.]]);
+    let y = Det(&a);
+    let mut adj = y.rev();
+    let expected = [[3., -1.], [-1., 2.]];
+    for i in 0..2 {
+        for j in 0..2 {
+            assert!(eq_f32(
+                adj.get_mut(&a[i][j])
+                    .expect("a adjoint missing")
+                    .apply_rev()
+                    .into(),
+                expected[i][j]
+            ));
+        }
+    }
 
-    dbg!(&ret);
+    //y=inv(A)_01, dy/da_kl=-inv_0k*inv_l1
+    //dy/da_10=-inv_01*inv_01=-0.04, dy/da_00=-inv_00*inv_01=0.12
+    let inv = Inverse(&a);
+    let mut adj = inv[0][1].rev();
+    assert!(eq_f32(
+        adj.get_mut(&a[1][0])
+            .expect("a10 adjoint missing")
+            .apply_rev()
+            .into(),
+        -0.04
+    ));
+    assert!(eq_f32(
+        adj.get_mut(&a[0][0])
+            .expect("a00 adjoint missing")
+            .apply_rev()
+            .into(),
+        0.12
+    ));
 
-    assert!(eq_f32(ret.into(), 6.));
+    //singular A=[[1,2],[2,4]], dy/dA=adj(A)^T=[[4,-2],[-2,1]]
+    let a = leaf_matrix(&[&[1., 2.], &[2., 4.]]);
+    let expected = [[4., -2.], [-2., 1.]];
+    for i in 0..2 {
+        for j in 0..2 {
+            assert!(eq_f32(
+                Det(&a).rev()[&a[i][j]].clone().apply_rev().into(),
+                expected[i][j]
+            ));
+        }
+    }
 
-    //change to (7x^2)''=(14x)'=14
-    l1.set_val(ValType::F(7.));
+    //double precision is preserved
+    let a: Vec<Vec<PtrVWrap>> = [[2., 1.], [1., 3.]]
+        .iter()
+        .map(|r| r.iter().map(|v| Leaf(ValType::D(*v))).collect())
+        .collect();
+    match Inverse(&a)[0][1].apply_fwd() {
+        ValType::D(v) => assert!((v + 0.2).abs() < 1e-12),
+        _ => panic!("expected f64 value"),
+    }
+    match Det(&a).apply_fwd() {
+        ValType::D(v) => assert!((v - 5.).abs() < 1e-12),
+        _ => panic!("expected f64 value"),
+    }
+}
 
-    let ret2 = gg.apply_fwd();
+#[test]
+fn test_cholesky_fwd() {
+    //A=[[4,2],[2,3]], L=[[2,0],[1,sqrt(2)]]
+    //dL00/da00=1/(2*L00)=0.25, dL11/da10=-L10/(L00*L11)=-0.3536
+    let mut a = leaf_matrix(&[&[4., 2.], &[2., 3.]]);
+    a[0][0] = Leaf(ValType::F(4.)).active();
+    a[1][0] = Leaf(ValType::F(2.)).active();
+    let mut l = Cholesky(&a);
+    assert!(eq_f32(l[0][0].apply_fwd().into(), 2.));
+    assert!(eq_f32(l[0][1].apply_fwd().into(), 0.));
+    assert!(eq_f32(l[1][0].apply_fwd().into(), 1.));
+    assert!(eq_f32(l[1][1].apply_fwd().into(), std::f32::consts::SQRT_2));
+
+    let mut b = leaf_matrix(&[&[4., 2.], &[2., 3.]]);
+    b[0][0] = Leaf(ValType::F(4.)).active();
+    assert!(eq_f32(Cholesky(&b)[0][0].fwd().apply_fwd().into(), 0.25));
+
+    let mut c = leaf_matrix(&[&[4., 2.], &[2., 3.]]);
+    c[1][0] = Leaf(ValType::F(2.)).active();
+    let lc = Cholesky(&c);
+    assert!(eq_f32(lc[1][0].fwd().apply_fwd().into(), 0.5));
+    assert!(eq_f32(lc[1][1].fwd().apply_fwd().into(), -0.3536));
+}
 
-    dbg!(&ret2);
+#[test]
+fn test_cholesky_rev() {
+    //y=L11 where A=[[4,2],[2,3]]
+    //dy/da00=L10^2/(2*L00^2*L11)=0.0884, dy/da10=-0.3536, dy/da11=0.3536
+    //dy/da01=0 as the upper triangle is unused
+    let a = leaf_matrix(&[&[4., 2.], &[2., 3.]]);
+    let l = Cholesky(&a);
+    let mut adj = l[1][1].rev();
+    let expected = [[0.0884, 0.], [-0.3536, 0.3536]];
+    for i in 0..2 {
+        for j in 0..2 {
+            assert!(eq_f32(
+                adj.get_mut(&a[i][j])
+                    .expect("a adjoint missing")
+                    .apply_rev()
+                    .into(),
+                expected[i][j]
+            ));
+        }
+    }
+}
+
+#[test]
+fn test_solve() {
+    //L=[[2,0],[1,3]], b=[4,5], x=[2,1]
+    //dx1/db=[-1/6,1/3], dx1/dL=[[1/3,0],[-2/3,-1/3]]
+    let l = leaf_matrix(&[&[2., 0.], &[1., 3.]]);
+    let b = vec![Leaf(ValType::F(4.)), Leaf(ValType::F(5.))];
+    let mut x = Solve(&l, &b);
+    assert!(eq_f32(x[0].apply_fwd().into(), 2.));
+    assert!(eq_f32(x[1].apply_fwd().into(), 1.));
+
+    let mut adj = x[1].rev();
+    for (leaf, e) in b.iter().zip([-1. / 6., 1. / 3.].iter()) {
+        assert!(eq_f32(
+            adj.get_mut(leaf)
+                .expect("b adjoint missing")
+                .apply_rev()
+                .into(),
+            *e
+        ));
+    }
+    let expected = [[1. / 3., 0.], [-2. / 3., -1. / 3.]];
+    for i in 0..2 {
+        for j in 0..2 {
+            assert!(eq_f32(
+                adj.get_mut(&l[i][j])
+                    .expect("L adjoint missing")
+                    .apply_rev()
+                    .into(),
+                expected[i][j]
+            ));
+        }
+    }
 
-    assert!(eq_f32(ret2.into(), 14.));
+    let b1 = vec![Leaf(ValType::F(4.)), Leaf(ValType::F(5.)).active()];
+    assert!(eq_f32(Solve(&l, &b1)[1].fwd().apply_fwd().into(), 1. / 3.));
+
+    //double precision is preserved through the factor and the solve
+    let a: Vec<Vec<PtrVWrap>> = [[4., 2.], [2., 3.]]
+        .iter()
+        .map(|r| r.iter().map(|v| Leaf(ValType::D(*v))).collect())
+        .collect();
+    let mut l = Cholesky(&a);
+    match l[1][1].apply_fwd() {
+        ValType::D(v) => assert!((v - 2f64.sqrt()).abs() < 1e-12),
+        _ => panic!("expected f64 value"),
+    }
+    let mut x = Solve(&l, &[Leaf(ValType::D(4.)), Leaf(ValType::D(1.))]);
+    match x[1].apply_fwd() {
+        ValType::D(v) => assert!((v + 1. / 2f64.sqrt()).abs() < 1e-12),
+        _ => panic!("expected f64 value"),
+    }
 }
 
 #[test]
-fn test_composite_fwd_over_fwd_change_input() {
-    //y=3*x^2 where x=4
-    //compute y'' = (6x)' = 6
+fn test_sym_eig_fwd() {
+    //A=[[2,1],[1,3]], lambda=(1.3820,3.6180)
+    //v_0=(0.8507,-0.5257), v_1=(0.5257,0.8507)
+    //d/da00: lambda'=(0.7236,0.2764), v_0'=(-0.1051,-0.1701)
+    let mut a = leaf_matrix(&[&[2., 1.], &[1., 3.]]);
+    a[0][0] = Leaf(ValType::F(2.)).active();
+    let (mut vals, mut vecs) = SymEig(&a);
+    assert!(eq_f32(vals[0].apply_fwd().into(), 1.3820));
+    assert!(eq_f32(vals[1].apply_fwd().into(), 3.6180));
+    assert!(eq_f32(vecs[0][0].apply_fwd().into(), 0.8507));
+    assert!(eq_f32(vecs[1][0].apply_fwd().into(), -0.5257));
+    assert!(eq_f32(vals[0].fwd().apply_fwd().into(), 0.7236));
+    assert!(eq_f32(vals[1].fwd().apply_fwd().into(), 0.2764));
+    assert!(eq_f32(vecs[0][0].fwd().apply_fwd().into(), -0.1051));
+    assert!(eq_f32(vecs[1][0].fwd().apply_fwd().into(), -0.1701));
+
+    //double precision is preserved
+    let d: Vec<Vec<PtrVWrap>> = [[2., 1.], [1., 3.]]
+        .iter()
+        .map(|r| r.iter().map(|v| Leaf(ValType::D(*v))).collect())
+        .collect();
+    let (mut vals, mut vecs) = SymEig(&d);
+    match (vals[0].apply_fwd(), vecs[0][1].apply_fwd()) {
+        (ValType::D(l), ValType::D(v)) => {
+            assert!((l - (2.5 - 1.25f64.sqrt())).abs() < 1e-12);
+            assert!((v - 0.5257311121191336).abs() < 1e-12);
+        }
+        _ => panic!("expected f64 values"),
+    }
+}
 
-    let l0 = Leaf(ValType::F(4.)).active();
-    let mut l1 = Leaf(ValType::F(3.));
-    let a = Mul(Mul(l0.clone(), l0.clone()), l1.clone());
+#[test]
+fn test_sym_eig_rev() {
+    //y=lambda_0 where A=[[2,1],[1,3]]
+    //dy/da10=2*v_00*v_10=-0.8944, dy/da01=0 as only the lower triangle is read
+    let a = leaf_matrix(&[&[2., 1.], &[1., 3.]]);
+    let (vals, vecs) = SymEig(&a);
+    let mut adj = vals[0].rev();
+    let expected = [[0.7236, 0.], [-0.8944, 0.2764]];
+    for i in 0..2 {
+        for j in 0..2 {
+            assert!(eq_f32(
+                adj.get_mut(&a[i][j])
+                    .expect("a adjoint missing")
+                    .apply_rev()
+                    .into(),
+                expected[i][j]
+            ));
+        }
+    }
 
-    let mut gg = a.fwd().fwd();
+    //y=v_11, dy/dA lower=(-0.1051,-0.1051,0.1051)
+    let mut adj = vecs[1][1].rev();
+    let expected = [[-0.1051, 0.], [-0.1051, 0.1051]];
+    for i in 0..2 {
+        for j in 0..2 {
+            assert!(eq_f32(
+                adj.get_mut(&a[i][j])
+                    .expect("a adjoint missing")
+                    .apply_rev()
+                    .into(),
+                expected[i][j]
+            ));
+        }
+    }
 
-    let ret = gg.apply_fwd();
+    //degenerate eigenvalues give finite eigenvector derivatives
+    let b = leaf_matrix(&[&[1., 0.], &[0., 1.]]);
+    let (_, vecs) = SymEig(&b);
+    let mut adj = vecs[0][0].rev();
+    let d: f32 = adj
+        .get_mut(&b[1][0])
+        .expect("b adjoint missing")
+        .apply_rev()
+        .into();
+    assert!(d.is_finite());
+}
 
-    dbg!(&ret);
+#[test]
+fn test_svd() {
+    //A=[[3,0],[4,5]], S=(6.7082,2.2361), v_0=(0.7071,0.7071), u_0=(0.3162,0.9487)
+    let a = leaf_matrix(&[&[3., 0.], &[4., 5.]]);
+    let (mut u, mut s, mut v) = Svd(&a);
+    assert!(eq_f32(s[0].apply_fwd().into(), 6.7082));
+    assert!(eq_f32(s[1].apply_fwd().into(), 2.2361));
+    assert!(eq_f32(
+        v[0][0].apply_fwd().into(),
+        std::f32::consts::FRAC_1_SQRT_2
+    ));
+    assert!(eq_f32(
+        v[1][0].apply_fwd().into(),
+        std::f32::consts::FRAC_1_SQRT_2
+    ));
+    assert!(eq_f32(u[0][0].apply_fwd().into(), 0.3162));
+    assert!(eq_f32(u[1][0].apply_fwd().into(), 0.9487));
+    //reconstruction
+    for r in 0..2 {
+        for c in 0..2 {
+            let x: f32 = (0..2)
+                .map(|i| {
+                    let ui: f32 = u[r][i].apply_fwd().into();
+                    let si: f32 = s[i].apply_fwd().into();
+                    let vi: f32 = v[c][i].apply_fwd().into();
+                    ui * si * vi
+                })
+                .sum();
+            let e: f32 = a[r][c].clone().apply_fwd().into();
+            assert!(eq_f32(x, e));
+        }
+    }
 
-    assert!(eq_f32(ret.into(), 6.));
+    //ds_i/dA=u_i*v_i^T
+    let mut adj = s[1].rev();
+    let expected = [[0.6708, -0.6708], [-0.2236, 0.2236]];
+    for i in 0..2 {
+        for j in 0..2 {
+            assert!(eq_f32(
+                adj.get_mut(&a[i][j])
+                    .expect("a adjoint missing")
+                    .apply_rev()
+                    .into(),
+                expected[i][j]
+            ));
+        }
+    }
 
-    //change to (7x^2)''=(14x)'=14
-    l1.set_val(ValType::F(7.));
+    //wide matrix, s=|a|, ds/da=a/|a|
+    let mut w = leaf_matrix(&[&[1., 2., 3.]]);
+    w[0][2] = Leaf(ValType::F(3.)).active();
+    let (u, mut s, v) = Svd(&w);
+    assert_eq!(
+        (u.len(), u[0].len(), s.len(), v.len(), v[0].len()),
+        (1, 1, 1, 3, 1)
+    );
+    assert!(eq_f32(s[0].apply_fwd().into(), 3.7417));
+    assert!(eq_f32(s[0].fwd().apply_fwd().into(), 0.8018));
+
+    //rank deficient A=[[1,0],[0,0]], the nuclear norm s_0+s_1 has the finite gradient U*V^T=I
+    let a = leaf_matrix(&[&[1., 0.], &[0., 0.]]);
+    let (mut u, s, _) = Svd(&a);
+    assert!(eq_f32(u[1][1].apply_fwd().into(), 1.));
+    let expected = [[1., 0.], [0., 1.]];
+    for i in 0..2 {
+        for j in 0..2 {
+            let g: f32 = Add(s[0].clone(), s[1].clone()).rev()[&a[i][j]]
+                .clone()
+                .apply_rev()
+                .into();
+            assert!(eq_f32(g, expected[i][j]));
+            let g: f32 = u[0][0].rev()[&a[i][j]].clone().apply_rev().into();
+            assert!(g.is_finite());
+        }
+    }
 
-    let ret2 = gg.apply_fwd();
+    //tall rank deficient matrix, the projection term of dU stays finite
+    let a = leaf_matrix(&[&[1., 2.], &[2., 4.], &[3., 6.]]);
+    let (u, s, _) = Svd(&a);
+    let g: f32 = u[2][0].rev()[&a[1][1]].clone().apply_rev().into();
+    assert!(g.is_finite());
+    let g: f32 = s[0].rev()[&a[2][1]].clone().apply_rev().into();
+    //u_0=(1,2,3)/sqrt(14), v_0=(1,2)/sqrt(5)
+    assert!(eq_f32(g, 6. / 70f32.sqrt()));
+}
 
-    dbg!(&ret2);
+#[test]
+fn test_trace_diag() {
+    //y=tr(A*A) where A=[[1,2],[3,4]]
+    //y=29, dy/dA=2*A^T=[[2,6],[4,8]]
+    let a = leaf_matrix(&[&[1., 2.], &[3., 4.]]);
+    let mut y = Trace(&MatMul(&a, &a));
+    assert!(eq_f32(y.apply_fwd().into(), 29.));
+    let mut adj = y.rev();
+    let expected = [[2., 6.], [4., 8.]];
+    for i in 0..2 {
+        for j in 0..2 {
+            assert!(eq_f32(
+                adj.get_mut(&a[i][j])
+                    .expect("a adjoint missing")
+                    .apply_rev()
+                    .into(),
+                expected[i][j]
+            ));
+        }
+    }
 
-    assert!(eq_f32(ret2.into(), 14.));
+    //diag of a non-square matrix
+    let b = leaf_matrix(&[&[1., 2., 3.], &[4., 5., 6.]]);
+    let mut d = Diag(&b);
+    assert_eq!(d.len(), 2);
+    assert!(eq_f32(d[1].apply_fwd().into(), 5.));
+
+    //y=tr(diag_embed(v)*A) where v=(x,2), x=1
+    //y'=a00=1
+    let l0 = Leaf(ValType::F(1.)).active();
+    let mut e = DiagEmbed(&[l0.clone(), Leaf(ValType::F(2.))]);
+    assert!(eq_f32(e[0][1].apply_fwd().into(), 0.));
+    let y = Trace(&MatMul(&e, &a));
+    assert!(eq_f32(y.fwd().apply_fwd().into(), 1.));
 }
 
 #[test]
-fn test_2nd_partial_derivative() {
-    //x=4
-    //y=3
-    //f=x^2 * y^2
-    //d^2(f)/(dx dy) = d(d(x^2 * y^2)/dx)/dy = d(2x*y^2)/dy = 2x*2y = 4*4*3 = 48
+fn test_kron() {
+    //A=[[1,2]], B=[[0,1],[2,3]]
+    //kron=[[0,1,0,2],[2,3,4,6]]
+    let a = leaf_matrix(&[&[1., 2.]]);
+    let b = leaf_matrix(&[&[0., 1.], &[2., 3.]]);
+    let mut k = Kron(&a, &b);
+    assert_eq!((k.len(), k[0].len()), (2, 4));
+    let expected = [[0., 1., 0., 2.], [2., 3., 4., 6.]];
+    for r in 0..2 {
+        for c in 0..4 {
+            assert!(eq_f32(k[r][c].apply_fwd().into(), expected[r][c]));
+        }
+    }
 
-    let l0 = Leaf(ValType::F(4.));
-    let l1 = Leaf(ValType::F(3.));
-    let a = Mul(Mul(l0.clone(), l0.clone()), Mul(l1.clone(), l1.clone()));
+    //y=sum(kron(A,B)), dy/da_ij=sum(B)=6, dy/db_kl=sum(A)=3
+    let y = sum(k.concat());
+    let mut adj = y.rev();
+    assert!(eq_f32(
+        adj.get_mut(&a[0][1])
+            .expect("a adjoint missing")
+            .apply_rev()
+            .into(),
+        6.
+    ));
+    assert!(eq_f32(
+        adj.get_mut(&b[1][0])
+            .expect("b adjoint missing")
+            .apply_rev()
+            .into(),
+        3.
+    ));
+}
 
-    let mut gg = a
-        .rev()
-        .get_mut(&l0)
-        .expect("l0 adjoint missing")
-        .rev()
-        .get_mut(&l1)
-        .expect("l1 adjoint missing")
-        .clone();
+#[test]
+fn test_fft() {
+    //x=(1,2,3,4), X=(10, -2+2i, -2, -2-2i)
+    let re: Vec<PtrVWrap> = [1., 2., 3., 4.]
+        .iter()
+        .map(|x| Leaf(ValType::F(*x)))
+        .collect();
+    let im: Vec<PtrVWrap> = (0..4).map(|_| Leaf(ValType::F(0.))).collect();
+    let (mut xr, mut xi) = Fft(&re, &im);
+    let expected = [(10., 0.), (-2., 2.), (-2., 0.), (-2., -2.)];
+    for k in 0..4 {
+        assert!(eq_f32(xr[k].apply_fwd().into(), expected[k].0));
+        assert!(eq_f32(xi[k].apply_fwd().into(), expected[k].1));
+    }
 
-    let ret = gg.apply_rev();
+    //round trip
+    let (mut yr, mut yi) = Ifft(&xr, &xi);
+    for j in 0..4 {
+        assert!(eq_f32(yr[j].apply_fwd().into(), (j + 1) as f32));
+        assert!(eq_f32(yi[j].apply_fwd().into(), 0.));
+    }
 
-    dbg!(&ret);
+    //y=im(X_1), dy/dx_j=-sin(2*pi*j/4)=(0,-1,0,1), the butterflies skip exact zeros
+    let mut adj = xi[1].rev();
+    for (leaf, e) in re.iter().zip([0., -1., 0., 1.].iter()) {
+        let d: f32 = adj.get_mut(leaf).map_or(0., |x| x.apply_rev().into());
+        assert!(eq_f32(d, *e));
+    }
 
-    assert!(eq_f32(ret.into(), 48.));
+    //d re(X_1)/d im(x_1)=sin(pi/2)=1
+    let im1 = vec![
+        Leaf(ValType::F(0.)),
+        Leaf(ValType::F(0.)).active(),
+        Leaf(ValType::F(0.)),
+        Leaf(ValType::F(0.)),
+    ];
+    let (xr, _) = Fft(&re, &im1);
+    assert!(eq_f32(xr[1].fwd().apply_fwd().into(), 1.));
+
+    //direct transform for other lengths, x=(1,2,3), X_1=-1.5+0.866i, in double precision
+    let re: Vec<PtrVWrap> = [1., 2., 3.].iter().map(|x| Leaf(ValType::D(*x))).collect();
+    let im: Vec<PtrVWrap> = (0..3).map(|_| Leaf(ValType::D(0.))).collect();
+    let (mut xr, mut xi) = Fft(&re, &im);
+    match (xr[1].apply_fwd(), xi[1].apply_fwd()) {
+        (ValType::D(a), ValType::D(b)) => {
+            assert!((a + 1.5).abs() < 1e-12);
+            assert!((b - 0.75f64.sqrt()).abs() < 1e-12);
+        }
+        _ => panic!("expected f64 values"),
+    }
+    let (mut yr, _) = Ifft(&xr, &xi);
+    assert!(eq_f32(yr[2].apply_fwd().into(), 3.));
+
+    //butterflies of a length 8 transform agree with the direct one, also in double precision
+    let x: Vec<f64> = (0..16).map(|j| ((j * 7) % 5) as f64 - 1.5).collect();
+    let re: Vec<PtrVWrap> = x[..8].iter().map(|v| Leaf(ValType::D(*v))).collect();
+    let im: Vec<PtrVWrap> = x[8..].iter().map(|v| Leaf(ValType::D(*v))).collect();
+    let (fr, fi) = Fft(&re, &im);
+    let (dr, di) = dft(&re, &im, false);
+    for (mut a, mut b) in fr.into_iter().chain(fi).zip(dr.into_iter().chain(di)) {
+        match (a.apply_fwd(), b.apply_fwd()) {
+            (ValType::D(a), ValType::D(b)) => assert!((a - b).abs() < 1e-12),
+            _ => panic!("expected f64 values"),
+        }
+    }
+    //and so do their gradients
+    let (fr, _) = Fft(&re, &im);
+    let (dr, _) = dft(&re, &im, false);
+    for leaf in re.iter().chain(im.iter()) {
+        let d = |y: &PtrVWrap| -> f64 {
+            y.rev()
+                .get(leaf)
+                .map_or(0., |x| x.clone().apply_rev().into())
+        };
+        let (a, b) = (d(&fr[3]), d(&dr[3]));
+        assert!((a - b).abs() < 1e-6);
+    }
 }
 
 #[test]
-fn test_trig_sin_fwd() {
-    //y=3*sin(x) where x=2
-    //y'=3*cos(x) where x=2
-    //y''=-3*sin(x) where x=2
+fn test_cumsum_cumprod() {
+    //x=(1,2,3), cumsum=(1,3,6), cumprod=(1,2,6)
+    let x: Vec<PtrVWrap> = [1., 2., 3.].iter().map(|v| Leaf(ValType::F(*v))).collect();
+    let mut cs = CumSum(&x);
+    let mut cp = CumProd(&x);
+    for (i, (s, p)) in [(1., 1.), (3., 2.), (6., 6.)].iter().enumerate() {
+        assert!(eq_f32(cs[i].apply_fwd().into(), *s));
+        assert!(eq_f32(cp[i].apply_fwd().into(), *p));
+    }
 
-    let l0 = Leaf(ValType::F(2.)).active();
-    let l1 = Leaf(ValType::F(3.));
-    let mut a = Mul(Sin(l0.clone()), l1.clone());
+    //y=sum(cumsum(x)), dy/dx=(3,2,1)
+    let y = sum(cs.clone());
+    let mut adj = y.rev();
+    for (leaf, e) in x.iter().zip([3., 2., 1.].iter()) {
+        assert!(eq_f32(
+            adj.get_mut(leaf)
+                .expect("x adjoint missing")
+                .apply_rev()
+                .into(),
+            *e
+        ));
+    }
 
-    assert!(eq_f32(a.apply_fwd().into(), 3.0 * 2f32.sin()));
+    //y=cumprod(x)_2 where x=(2,0,3), dy/dx=(0,6,0)
+    let z: Vec<PtrVWrap> = [2., 0., 3.].iter().map(|v| Leaf(ValType::F(*v))).collect();
+    let mut adj = CumProd(&z)[2].rev();
+    for (leaf, e) in z.iter().zip([0., 6., 0.].iter()) {
+        assert!(eq_f32(
+            adj.get_mut(leaf)
+                .expect("z adjoint missing")
+                .apply_rev()
+                .into(),
+            *e
+        ));
+    }
 
-    let mut g = a.fwd();
+    //d cumprod(x)_2/d x_0=x_1*x_2=6
+    let w = vec![Leaf(ValType::F(1.)).active(), x[1].clone(), x[2].clone()];
+    assert!(eq_f32(CumProd(&w)[2].fwd().apply_fwd().into(), 6.));
+
+    //each prefix adds a single two input node
+    assert!(CumSum(&x).iter().all(|y| y.inputs().len() <= 2));
+
+    //along both axes of [[1,2],[3,4]]
+    let m = leaf_matrix(&[&[1., 2.], &[3., 4.]]);
+    let mut c0 = CumSumAxis(&m, 0);
+    let mut c1 = CumProdAxis(&m, 1);
+    assert!(eq_f32(c0[1][0].apply_fwd().into(), 4.));
+    assert!(eq_f32(c0[1][1].apply_fwd().into(), 6.));
+    assert!(eq_f32(c0[0][1].apply_fwd().into(), 2.));
+    assert!(eq_f32(c1[1][1].apply_fwd().into(), 12.));
+    assert!(eq_f32(c1[0][1].apply_fwd().into(), 2.));
+
+    //double precision is preserved by the n-ary sum
+    let d: Vec<PtrVWrap> = [0.1, 0.2, 0.3]
+        .iter()
+        .map(|v| Leaf(ValType::D(*v)))
+        .collect();
+    match sum(d).apply_fwd() {
+        ValType::D(a) => assert!((a - (0.1 + 0.2 + 0.3)).abs() < 1e-15),
+        _ => panic!("expected f64 value"),
+    }
+}
 
-    assert!(eq_f32(g.apply_fwd().into(), 3.0 * 2f32.cos()));
+#[test]
+fn test_gather_scatter_fwd() {
+    //y=gather(x, [2,0,2]) where x=(1,2,3)
+    let l0 = Leaf(ValType::F(1.));
+    let l2 = Leaf(ValType::F(3.)).active();
+    let x = vec![l0, Leaf(ValType::F(2.)), l2];
+    let mut y = Gather(&x, &[2, 0, 2]);
+    let vals: Vec<f32> = y.iter_mut().map(|v| v.apply_fwd().into()).collect();
+    assert_eq!(vals, vec![3., 1., 3.]);
+    assert!(eq_f32(y[0].fwd().apply_fwd().into(), 1.));
+    assert!(eq_f32(y[1].fwd().apply_fwd().into(), 0.));
+
+    //z=scatter_add((1,2,3), [1,1,3], 4)=(0,3,0,3)
+    let mut z = ScatterAdd(&x, &[1, 1, 3], 4);
+    let vals: Vec<f32> = z.iter_mut().map(|v| v.apply_fwd().into()).collect();
+    assert_eq!(vals, vec![0., 3., 0., 3.]);
+    assert!(eq_f32(z[3].fwd().apply_fwd().into(), 1.));
+}
 
-    let mut gg = g.fwd();
+#[test]
+fn test_gather_rev() {
+    //y=sum(gather(x, [1,1,0])) where x=(5,7,9)
+    //dy/dx=(1,2,0), repeated indices accumulate
+    let x: Vec<PtrVWrap> = [5., 7., 9.].iter().map(|&v| Leaf(ValType::F(v))).collect();
+    let y = sum(Gather(&x, &[1, 1, 0]));
+    let mut adj = y.rev();
+    for (l, e) in x.iter().zip([1., 2.].iter()) {
+        let d: f32 = adj
+            .get_mut(l)
+            .expect("leaf adjoint missing")
+            .apply_rev()
+            .into();
+        assert!(eq_f32(d, *e));
+    }
+    //x_2 is not gathered, so it is not an input of y
+    assert!(!adj.contains_key(&x[2]));
+}
 
-    assert!(eq_f32(gg.apply_fwd().into(), -3.0 * 2f32.sin()));
+#[test]
+fn test_scatter_add_rev() {
+    //y=2*z_0+3*z_1 with z=scatter_add((a,b,c), [1,0,1], 2)
+    //dy/da=3, dy/db=2, dy/dc=3
+    let x: Vec<PtrVWrap> = [1., 1., 1.].iter().map(|&v| Leaf(ValType::F(v))).collect();
+    let z = ScatterAdd(&x, &[1, 0, 1], 2);
+    let w = vec![constant(ValType::F(2.)), constant(ValType::F(3.))];
+    let y = Dot(w, z);
+    let mut adj = y.rev();
+    for (l, e) in x.iter().zip([3., 2., 3.].iter()) {
+        let d: f32 = adj
+            .get_mut(l)
+            .expect("leaf adjoint missing")
+            .apply_rev()
+            .into();
+        assert!(eq_f32(d, *e));
+    }
+}
+
+#[cfg(test)]
+fn node_count(x: &PtrVWrap) -> usize {
+    x.reachable().len()
 }
 
 #[test]
-fn test_trig_sin_rev() {
-    //y=3*sin(x) where x=2
-    //y'=3*cos(x) where x=2
-    //y''=-3*sin(x) where x=2
+fn test_fuse_fwd() {
+    //y=(a*b+c)/4 where a=2, b=3, c=4
+    //dy/da=b/4=0.75
+    let l0 = Leaf(ValType::F(2.)).active();
+    let l1 = Leaf(ValType::F(3.));
+    let l2 = Leaf(ValType::F(4.));
+    let four = constant(ValType::F(4.));
+    let mut y = Div(Add(Mul(l0.clone(), l1.clone()), l2.clone()), four);
+    assert_eq!(node_count(&y), 7);
+    assert_eq!(y.fuse(), 2);
+    assert_eq!(node_count(&y), 5);
+    assert!(eq_f32(y.apply_fwd().into(), 2.5));
+    assert!(eq_f32(y.fwd().apply_fwd().into(), 0.75));
+    //nothing left to fuse
+    assert_eq!(y.fuse(), 0);
+
+    //division by 3 or by an f64 constant is kept, as the reciprocal would round differently
+    let mut y3 = Div(l0.clone(), constant(ValType::F(3.)));
+    let yd = Div(l0.clone(), constant(ValType::D(4.)));
+    assert_eq!(y3.fuse(), 0);
+    assert_eq!(yd.fuse(), 0);
+    assert_eq!(
+        y3.apply_fwd(),
+        Div(l0.clone(), constant(ValType::F(3.))).apply_fwd()
+    );
+    //a power of two rewrites bit-exactly
+    let x = Leaf(ValType::F(0.1));
+    let mut y8 = Div(x.clone(), constant(ValType::F(-0.125)));
+    assert_eq!(y8.fuse(), 1);
+    assert_eq!(y8.apply_fwd(), ValType::F(0.1 / -0.125));
+
+    //consumers outside the fused graph drop their cached structural hash
+    let u = Add(Mul(l0.clone(), l1.clone()), l2.clone());
+    let z = Sin(u.clone());
+    let before = z.structural_hash();
+    assert_eq!(u.fuse(), 1);
+    assert_ne!(z.structural_hash(), before);
+    assert_eq!(z.structural_hash(), Sin(u.clone()).structural_hash());
+}
 
+#[test]
+fn test_fuse_rev() {
+    //y=(a*b)*(a*c) where a=2, b=3, c=4
+    //dy/da=2abc=48, d2y/da2=2bc=24
     let l0 = Leaf(ValType::F(2.)).active();
     let l1 = Leaf(ValType::F(3.));
-    let a = Mul(Sin(l0.clone()), l1.clone());
+    let l2 = Leaf(ValType::F(4.));
+    let y = Mul(Mul(l0.clone(), l1), Mul(l0.clone(), l2));
+    let mut adj = y.rev();
+    let mut d = adj.get_mut(&l0).expect("l0 adjoint missing").clone();
+    let before = node_count(&d);
+    assert!(d.fuse() > 0);
+    assert!(node_count(&d) < before);
+    assert!(eq_f32(d.apply_rev().into(), 48.));
+    assert!(eq_f32(d.fwd().apply_fwd().into(), 24.));
+}
 
-    {
-        let g = a
-            .rev()
-            .get_mut(&l0)
+#[test]
+fn test_rev_mixed_depth() {
+    //y=x*x*x+x where x=2, x reached at depths 1, 2 and 3
+    //dy/dx=3x^2+1=13
+    let l0 = Leaf(ValType::F(2.));
+    let y = Add(Mul(Mul(l0.clone(), l0.clone()), l0.clone()), l0.clone());
+    let mut adj = y.rev();
+    assert!(eq_f32(
+        adj.get_mut(&l0)
             .expect("l0 adjoint missing")
-            .apply_rev();
-
-        assert!(eq_f32(g.into(), 3.0 * 2f32.cos()));
-    }
+            .apply_rev()
+            .into(),
+        13.
+    ));
 
-    {
-        let gg = a
-            .rev()
-            .get_mut(&l0)
-            .expect("l0 adjoint missing")
-            .rev()
-            .get_mut(&l0)
-            .expect("l0 adjoint missing")
-            .apply_rev();
+    //y=kron(A,B)_00+...+kron(A,B)_13 as a left fold, dy/da_ij=sum(B)=6, dy/db_kl=sum(A)=3
+    let a = leaf_matrix(&[&[1., 2.]]);
+    let b = leaf_matrix(&[&[0., 1.], &[2., 3.]]);
+    let y = Kron(&a, &b).concat().into_iter().reduce(Add).unwrap();
+    let mut adj = y.rev();
+    assert!(eq_f32(
+        adj.get_mut(&a[0][0])
+            .expect("a adjoint missing")
+            .apply_rev()
+            .into(),
+        6.
+    ));
+    assert!(eq_f32(
+        adj.get_mut(&b[0][0])
+            .expect("b adjoint missing")
+            .apply_rev()
+            .into(),
+        3.
+    ));
+}
 
-        assert!(eq_f32(gg.into(), -3.0 * 2f32.sin()));
+#[test]
+fn test_rev_ordered() {
+    //y=c*(a+b*c) where a=1, b=2, c=3
+    //leaves come in order of first appearance: c, a, b
+    //dy/dc=a+2bc=13, dy/da=c=3, dy/db=c^2=9
+    let l0 = Leaf(ValType::F(1.));
+    let l1 = Leaf(ValType::F(2.));
+    let l2 = Leaf(ValType::F(3.));
+    let y = Mul(l2.clone(), Add(l0.clone(), Mul(l1.clone(), l2.clone())));
+    for _ in 0..2 {
+        let adj = y.rev_ordered();
+        let leaves: Vec<PtrVWrap> = adj.iter().map(|(l, _)| l.clone()).collect();
+        assert_eq!(leaves, vec![l2.clone(), l0.clone(), l1.clone()]);
+        let vals: Vec<f32> = adj
+            .into_iter()
+            .map(|(_, mut d)| d.apply_rev().into())
+            .collect();
+        assert_eq!(vals, vec![13., 3., 9.]);
     }
 }
 
 #[test]
-fn test_trig_cos_fwd() {
-    //y=3*cos(x) where x=2
-    //y'=-3*sin(x) where x=2
-    //y''=-3*cos(x) where x=2
-
-    let l0 = Leaf(ValType::F(2.)).active();
+fn test_release() {
+    //derivative graphs are freed once the adjoint map is dropped
+    let l0 = Leaf(ValType::F(2.));
     let l1 = Leaf(ValType::F(3.));
-    let mut a = Mul(Cos(l0.clone()), l1.clone());
-
-    assert!(eq_f32(a.apply_fwd().into(), 3.0 * 2f32.cos()));
-
-    let mut g = a.fwd();
-
-    assert!(eq_f32(g.apply_fwd().into(), -3.0 * 2f32.sin()));
-
-    let mut gg = g.fwd();
-
-    assert!(eq_f32(gg.apply_fwd().into(), -3.0 * 2f32.cos()));
+    let y = Mul(l0.clone(), l1.clone());
+    let adj = y.rev();
+    let d = Rc::downgrade(&adj[&l0].0);
+    assert!(d.upgrade().is_some());
+    drop(adj);
+    assert!(d.upgrade().is_none());
+
+    //a panicking adjoint builder leaves no accumulation behind
+    let mut bad = VWrap::new(OpFma::new());
+    bad.set_inp(vec![l0.clone(), l1.clone()]);
+    let z = Mul(bad.clone(), l1.clone());
+    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| z.rev()));
+    assert!(res.is_err());
+    assert!(bad.adjoint().is_none());
+    assert!([&z, &l0, &l1].iter().all(|n| n.adjoint().is_none()));
 }
 
 #[test]
-fn test_trig_cos_rev() {
-    //y=3*cos(x) where x=2
-    //y'=-3*sin(x) where x=2
-    //y''=-3*cos(x) where x=2
-
-    let l0 = Leaf(ValType::F(2.)).active();
-    let l1 = Leaf(ValType::F(3.));
-    let a = Mul(Cos(l0.clone()), l1.clone());
+fn test_drop_deep_chain() {
+    //y=x+1+1+...+1 with a million nodes, freed without recursion
+    let l0 = Leaf(ValType::F(0.));
+    let one = constant(ValType::F(1.));
+    let mut y = l0.clone();
+    for _ in 0..1_000_000 {
+        y = Add(y, one.clone());
+    }
+    let w = Rc::downgrade(&y.0);
+    drop(y);
+    assert!(w.upgrade().is_none());
+    assert_eq!(Rc::strong_count(&l0.0), 1);
+    assert_eq!(Rc::strong_count(&one.0), 1);
+}
 
-    {
-        let g = a
-            .rev()
-            .get_mut(&l0)
+#[test]
+fn test_aliased_inputs() {
+    //y=x*x where x=3, dy/dx=6, d2y/dx2=2
+    let l0 = Leaf(ValType::F(3.)).active();
+    let mut y = Mul(l0.clone(), l0.clone());
+    assert!(eq_f32(y.apply_fwd().into(), 9.));
+    let mut dy = y.fwd();
+    assert!(eq_f32(dy.apply_fwd().into(), 6.));
+    assert!(eq_f32(dy.fwd().apply_fwd().into(), 2.));
+    let mut adj = y.rev();
+    let d = adj.get_mut(&l0).expect("l0 adjoint missing");
+    assert!(eq_f32(d.apply_rev().into(), 6.));
+    let mut adj2 = d.rev();
+    assert!(eq_f32(
+        adj2.get_mut(&l0)
             .expect("l0 adjoint missing")
-            .apply_rev();
-
-        assert!(eq_f32(g.into(), -3.0 * 2f32.sin()));
-    }
+            .apply_rev()
+            .into(),
+        2.
+    ));
 
-    {
-        let gg = a
-            .rev()
-            .get_mut(&l0)
+    //z=(x*x)*(x*x) sharing one node, dz/dx=4x^3=108
+    let z = Mul(y.clone(), y.clone());
+    let mut adj = z.rev();
+    assert!(eq_f32(
+        adj.get_mut(&l0)
             .expect("l0 adjoint missing")
-            .rev()
-            .get_mut(&l0)
+            .apply_rev()
+            .into(),
+        108.
+    ));
+}
+
+#[test]
+fn test_reentrant_builders() {
+    //y=exp(x) where x=1, builders evaluate y while it is being differentiated
+    let l0 = Leaf(ValType::F(1.)).active();
+    let y = VWrap::new_with_input(OpReentrantExp::new(), vec![l0.clone()]);
+    assert!(eq_f32(y.fwd().apply_fwd().into(), std::f32::consts::E));
+    let mut adj = y.rev();
+    assert!(eq_f32(
+        adj.get_mut(&l0)
             .expect("l0 adjoint missing")
-            .apply_rev();
+            .apply_rev()
+            .into(),
+        std::f32::consts::E
+    ));
+}
 
-        assert!(eq_f32(gg.into(), -3.0 * 2f32.cos()));
-    }
+#[test]
+fn test_cost() {
+    //y=a*b+sin(a): mul, add and one transcendental
+    let l0 = Leaf(ValType::F(1.)).active();
+    let l1 = Leaf(ValType::F(2.));
+    let y = Add(Mul(l0.clone(), l1.clone()), Sin(l0.clone()));
+    assert_eq!(y.cost(), 2 + COST_TRANSCENDENTAL);
+
+    //shared subgraphs count once
+    let z = Mul(y.clone(), y.clone());
+    assert_eq!(z.cost(), y.cost() + 1);
+
+    //fusion turns the division into a scaling and mul+add into one fma
+    let four = constant(ValType::F(4.));
+    let w = Div(Add(Mul(l0.clone(), l1.clone()), l1.clone()), four);
+    assert_eq!(w.cost(), 2 + COST_DIV);
+    w.fuse();
+    assert_eq!(w.cost(), 3);
+
+    //fusion of an adjoint graph lowers its cost
+    let mut adj = y.rev();
+    let d = adj.get_mut(&l0).expect("l0 adjoint missing");
+    let before = d.cost();
+    d.fuse();
+    assert!(d.cost() < before);
 }
 
 #[test]
-fn test_exp_fwd() {
-    //y=3*exp(4x) where x=2
-    //y'=12*exp(4x) where x=2
-    //y''=48*exp(4x) where x=2
+fn test_partial() {
+    //f=x^2*y^2 where x=4, y=3
+    //df/dx=2xy^2=72, d2f/dxdy=4xy=48, d3f/dx2dy=4y=12, d2f/dy2=2x^2=32
+    let l0 = Leaf(ValType::F(4.));
+    let l1 = Leaf(ValType::F(3.)).active();
+    let f = Mul(Mul(l0.clone(), l0.clone()), Mul(l1.clone(), l1.clone()));
+    assert!(eq_f32(partial(&f, &[]).into(), 144.));
+    assert!(eq_f32(partial(&f, std::slice::from_ref(&l0)).into(), 72.));
+    assert!(eq_f32(partial(&f, &[l0.clone(), l1.clone()]).into(), 48.));
+    assert!(eq_f32(partial(&f, &[l1.clone(), l0.clone()]).into(), 48.));
+    assert!(eq_f32(
+        partial(&f, &[l0.clone(), l0.clone(), l1.clone()]).into(),
+        12.
+    ));
+    assert!(eq_f32(partial(&f, &[l1.clone(), l1.clone()]).into(), 32.));
 
-    let l0 = Leaf(ValType::F(2.)).active();
-    let l1 = Leaf(ValType::F(3.));
-    let l2 = Leaf(ValType::F(4.));
-    let a = Mul(Exp(Mul(l2.clone(), l0.clone())), l1.clone());
+    //activity flags are restored
+    assert!(l1.0.borrow().eval_g);
+    assert!(!l0.0.borrow().eval_g);
 
-    {
-        assert!(eq_f32(a.fwd().apply_fwd().into(), 12. * 8f32.exp()));
-    }
-    {
-        assert!(eq_f32(a.fwd().fwd().apply_fwd().into(), 48. * 8f32.exp()));
-    }
+    //unrelated leaves give zero
+    let other = Leaf(ValType::F(1.));
+    assert!(eq_f32(partial(&f, &[l0.clone(), other.clone()]).into(), 0.));
+    assert!(eq_f32(partial(&f, &[other]).into(), 0.));
 }
 
 #[test]
-fn test_exp_rev() {
-    //y=3*exp(4x) where x=2
-    //y'=12*exp(4x) where x=2
-    //y''=48*exp(4x) where x=2
-
-    let l0 = Leaf(ValType::F(2.)).active();
-    let l1 = Leaf(ValType::F(3.));
-    let l2 = Leaf(ValType::F(4.));
-    let a = Mul(Exp(Mul(l2.clone(), l0.clone())), l1.clone());
+fn test_trig_chain_fwd() {
+    //y=sin(x*z) + cos(x*z) where x=1, z=2
+    //dy/dx=z*(cos(xz)-sin(xz)), d2y/dzdx=cos(xz)-sin(xz)-xz*(sin(xz)+cos(xz))
+    let l0 = Leaf(ValType::F(1.)).active();
+    let l1 = Leaf(ValType::F(2.));
+    let u = Mul(l0.clone(), l1.clone());
+    let y = Add(Sin(u.clone()), Cos(u));
+    let expected = 2. * (2f32.cos() - 2f32.sin());
+    assert!(eq_f32(y.fwd().apply_fwd().into(), expected));
+    assert!(eq_f32(
+        partial(&y, std::slice::from_ref(&l0)).into(),
+        expected
+    ));
+    let mixed = 2f32.cos() - 2f32.sin() - 2. * (2f32.sin() + 2f32.cos());
+    assert!(eq_f32(partial(&y, &[l1, l0]).into(), mixed));
+}
 
-    {
-        let g = a
-            .rev()
-            .get_mut(&l0)
-            .expect("l0 adjoint missing")
-            .apply_rev();
-        assert!(eq_f32(g.into(), 12. * 8f32.exp()));
-    }
-    {
-        let gg = a
-            .rev()
-            .get_mut(&l0)
-            .expect("l0 adjoint missing")
-            .rev()
-            .get_mut(&l0)
-            .expect("l0 adjoint missing")
-            .apply_rev();
-        assert!(eq_f32(gg.into(), 48. * 8f32.exp()));
-    }
+#[test]
+fn test_check_wrt() {
+    //y=a*b+c with c a constant
+    let l0 = Leaf(ValType::F(1.)).active();
+    let l1 = Leaf(ValType::F(2.));
+    let c = constant(ValType::F(3.));
+    let ab = Mul(l0.clone(), l1.clone());
+    let y = Add(ab.clone(), c.clone());
+    assert_eq!(check_wrt(&y, &l0, true), None);
+    assert_eq!(check_wrt(&y, &l1, false), None);
+    assert_eq!(check_wrt(&y, &l1, true), Some(WrtIssue::Inactive));
+    assert_eq!(check_wrt(&y, &c, false), Some(WrtIssue::Constant));
+    assert_eq!(check_wrt(&y, &ab, false), Some(WrtIssue::NotALeaf));
+    let other = Leaf(ValType::F(0.));
+    assert_eq!(check_wrt(&y, &other, false), Some(WrtIssue::NotInGraph));
+    assert_eq!(
+        WrtIssue::Inactive.to_string(),
+        "forward mode target is not active"
+    );
+
+    //strict mode only reports, results are unchanged
+    assert!(!strict());
+    let reports: Rc<RefCell<Vec<WrtReport>>> = Rc::default();
+    let sink = reports.clone();
+    set_strict(Some(Rc::new(move |r: &WrtReport| {
+        sink.borrow_mut().push(r.clone())
+    })));
+    assert!(eq_f32(partial(&y, std::slice::from_ref(&other)).into(), 0.));
+    assert!(eq_f32(y.fwd().apply_fwd().into(), 2.));
+    //no active leaf, so l1 is reported inactive
+    assert!(eq_f32(Sin(l1.clone()).fwd().apply_fwd().into(), 0.));
+    assert!(eq_f32(y.rev()[&l1].clone().apply_rev().into(), 1.));
+    assert!(eq_f32(y.fwd_wrt(&other).into(), 0.));
+    assert!(y.rev_wrt(&ab).is_none());
+    assert!(eq_f32(Partials::new(&y).get(&[(c.clone(), 1)]).into(), 0.));
+    set_strict(None);
+    assert!(!strict());
+    let seen: Vec<(&str, WrtIssue)> = reports
+        .borrow()
+        .iter()
+        .map(|r| (r.entry, r.issue))
+        .collect();
+    assert_eq!(
+        seen,
+        vec![
+            ("partial", WrtIssue::NotInGraph),
+            ("fwd", WrtIssue::Inactive),
+            ("fwd_wrt", WrtIssue::NotInGraph),
+            ("rev_wrt", WrtIssue::NotALeaf),
+            ("Partials::get", WrtIssue::Constant),
+        ]
+    );
+    assert_eq!(
+        reports.borrow()[1].to_string(),
+        "fwd target 0: forward mode target is not active"
+    );
+}
+
+#[test]
+fn test_fwd_wrt() {
+    //y=x0*x1+sin(x0) where x0=2, x1=3, no leaf marked active
+    let l0 = Leaf(ValType::F(2.));
+    let mut l1 = Leaf(ValType::F(3.)).active();
+    let y = Add(Mul(l0.clone(), l1.clone()), Sin(l0.clone()));
+    let d0: f32 = y.fwd_wrt(&l0).into();
+    assert!(eq_f32(d0, 3. + 2f32.cos()));
+    let d1: f32 = y.fwd_wrt(&l1).into();
+    assert!(eq_f32(d1, 2.));
+    let other = Leaf(ValType::F(1.));
+    assert!(eq_f32(y.fwd_wrt(&other).into(), 0.));
+
+    //user activity flags are left untouched
+    assert!(!l0.0.borrow().eval_g);
+    assert!(l1.0.borrow().eval_g);
+
+    //also when building the tangent panics
+    let mut bad = VWrap::new(OpFma::new());
+    bad.set_inp(vec![l0.clone(), l1.clone()]);
+    let z = Mul(bad, l0.clone());
+    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| z.fwd_wrt(&l0)));
+    assert!(res.is_err());
+    assert!(!l0.0.borrow().eval_g);
+    assert!(l1.0.borrow().eval_g);
+    l1.inactive();
 }
 
 #[test]
-fn test_div_fwd() {
-    //y=3/(4x) where x=2
-    //y'=-3/4*x^-2
-    //y''=6/4*x^-3
+fn test_fwd_pruning() {
+    //y=x*c where c=sin(c_i*(...)+1) is built from inactive leaves only
+    let x = Leaf(ValType::F(2.)).active();
+    let mut c = Leaf(ValType::F(1.));
+    for i in 0..50 {
+        c = Sin(Add(
+            Mul(Leaf(ValType::F(i as f32)), c),
+            Leaf(ValType::F(1.)),
+        ));
+    }
+    let y = Mul(x.clone(), c.clone());
+    let cv: f32 = c.apply_fwd().into();
+    let mut t = y.fwd();
+    let d: f32 = t.apply_fwd().into();
+    assert!(eq_f32(d, cv));
+    //x'*c+x*0 where the zero is shared by the whole constant region
+    assert!(node_count(&t) - node_count(&y) < 5);
+
+    //activity is fixed when the tangent is built
+    let x2 = Leaf(ValType::F(3.));
+    let y2 = Mul(x.clone(), x2.clone());
+    let mut t = y2.fwd();
+    x2.clone().active();
+    let d: f32 = t.apply_fwd().into();
+    assert!(eq_f32(d, 3.));
+
+    //a panicking tangent rule leaves no activity analysis behind for later calls
+    let a = Leaf(ValType::F(0.5)).active();
+    let mut b = Leaf(ValType::F(0.25));
+    let broken = VWrap::new_with_input(OpSin::new(), vec![a, b.clone()]);
+    assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| broken.fwd())).is_err());
+    b.active();
+    let d: f32 = Sin(b.clone()).fwd().apply_fwd().into();
+    assert!(eq_f32(d, 0.25f32.cos()));
+}
 
-    let l0 = Leaf(ValType::F(2.)).active();
+#[test]
+fn test_rev_lazy() {
+    //y=l0*l1+sin(l1)*l2 where l0=2, l1=3, l2=4
+    let l0 = Leaf(ValType::F(2.));
     let l1 = Leaf(ValType::F(3.));
     let l2 = Leaf(ValType::F(4.));
-    let a = Div(l1.clone(), Mul(l2.clone(), l0.clone()));
+    let y = Add(
+        Mul(l0.clone(), l1.clone()),
+        Mul(Sin(l1.clone()), l2.clone()),
+    );
+    let mut lazy = y.rev_lazy();
+    assert!(lazy.is_empty());
+
+    //the adjoint of l0 does not involve the sin(l1)*l2 branch
+    let mut a0 = lazy.get(&l0).expect("l0 adjoint missing");
+    assert!(eq_f32(a0.apply_rev().into(), 3.));
+    assert!(!a0.reachable().contains(&l2));
+
+    let a1: f32 = lazy
+        .get(&l1)
+        .expect("l1 adjoint missing")
+        .apply_rev()
+        .into();
+    assert!(eq_f32(a1, 2. + 3f32.cos() * 4.));
+    let a2: f32 = lazy
+        .get(&l2)
+        .expect("l2 adjoint missing")
+        .apply_rev()
+        .into();
+    assert!(eq_f32(a2, 3f32.sin()));
+    assert!(lazy.get(&Leaf(ValType::F(0.))).is_none());
+    assert!(lazy.get(&l0).is_some());
+    assert_eq!(lazy.len(), 4);
+
+    //matches the eager adjoints
+    let mut adj = y.rev();
+    let e1: f32 = adj
+        .get_mut(&l1)
+        .expect("l1 adjoint missing")
+        .apply_rev()
+        .into();
+    assert!(eq_f32(a1, e1));
+}
 
-    {
-        assert!(eq_f32(a.fwd().apply_fwd().into(), -3. / 4. * 2f32.powi(-2)));
-    }
-    {
-        assert!(eq_f32(
-            a.fwd().fwd().apply_fwd().into(),
-            6. / 4. * 2f32.powi(-3)
-        ));
+#[test]
+fn test_leaf_vec_helpers() {
+    //y=sum(sin(x_i))+x.w where x=(0.5,1,2), w=(1,2,3)
+    let x = leaf_vec(&[0.5, 1., 2.]);
+    let w = leaf_vec(&[1., 2., 3.]);
+    assert_eq!(x.len(), 3);
+    let mut y = Add(sum_nodes(&map_nodes(&x, Sin)), dot_nodes(&x, &w));
+    let expected = 0.5f32.sin() + 1f32.sin() + 2f32.sin() + 0.5 + 2. + 6.;
+    assert!(eq_f32(y.apply_fwd().into(), expected));
+
+    //dy/dx_i=cos(x_i)+w_i
+    let mut adj = y.rev();
+    for ((xi, wi), v) in x.iter().zip([1f32, 2., 3.]).zip([0.5f32, 1., 2.]) {
+        let a: f32 = adj
+            .get_mut(xi)
+            .expect("x adjoint missing")
+            .apply_rev()
+            .into();
+        assert!(eq_f32(a, v.cos() + wi));
     }
+
+    //empty collections give zero
+    assert!(eq_f32(sum_nodes(&[]).apply_fwd().into(), 0.));
+    assert!(eq_f32(dot_nodes(&[], &[]).apply_fwd().into(), 0.));
 }
 
 #[test]
-fn test_div_rev() {
-    //y=3/(4x) where x=2
-    //y'=-3/4*x^-2
-    //y''=6/4*x^-3
-
-    let l0 = Leaf(ValType::F(2.)).active();
-    let l1 = Leaf(ValType::F(3.));
-    let l2 = Leaf(ValType::F(4.));
-    let a = Div(l1.clone(), Mul(l2.clone(), l0.clone()));
+fn test_map() {
+    //loss=sum((w*x_i-t_i)^2) where w=2, x=(1,2,3), t=(1,5,5)
+    let w = Leaf(ValType::F(2.));
+    let x = leaf_vec(&[1., 2., 3.]);
+    let t = leaf_vec(&[1., 5., 5.]);
+    let pred = map(&x, |xi| Mul(w.clone(), xi));
+    let r: Vec<PtrVWrap> = pred
+        .iter()
+        .zip(t.iter())
+        .map(|(p, ti)| Minus(p.clone(), ti.clone()))
+        .collect();
+    let sq = map(&r, |ri| Mul(ri.clone(), ri));
+    let mut loss = sum_nodes(&sq);
+    //residuals (1,-1,1)
+    assert!(eq_f32(loss.apply_fwd().into(), 3.));
+    //dloss/dw=sum(2*r_i*x_i)=2-4+6
+    let mut adj = loss.rev();
+    let dw: f32 = adj
+        .get_mut(&w)
+        .expect("w adjoint missing")
+        .apply_rev()
+        .into();
+    assert!(eq_f32(dw, 4.));
+
+    //nested collections keep their shape and are visited in row-major order
+    let m = vec![leaf_vec(&[0., 1.]), leaf_vec(&[2., 3.])];
+    let mut visited = vec![];
+    let e = map(&m, |v| {
+        visited.push(v.clone());
+        Exp(v)
+    });
+    assert_eq!(
+        visited,
+        vec![
+            m[0][0].clone(),
+            m[0][1].clone(),
+            m[1][0].clone(),
+            m[1][1].clone()
+        ]
+    );
+    assert_eq!(e.len(), 2);
+    assert!(eq_f32(e[1][0].clone().apply_fwd().into(), 2f32.exp()));
+    let a = map(&[w.clone(), w], Sin);
+    assert!(eq_f32(a[1].clone().apply_fwd().into(), 2f32.sin()));
+}
 
-    {
-        let g = a
-            .rev()
-            .get_mut(&l0)
-            .expect("l0 adjoint missing")
-            .apply_rev();
+#[test]
+fn test_interp_linear() {
+    //samples y=(0,2,3) at times (0,1,3), t=2: y=2.5
+    let t = Leaf(ValType::F(2.));
+    let y = leaf_vec(&[0., 2., 3.]);
+    let mut v = Interp(t.clone(), &[0., 1., 3.], &y, Interpolation::Linear);
+    assert!(eq_f32(v.apply_fwd().into(), 2.5));
+
+    //dv/dt=(3-2)/2, dv/dy=(0,0.5,0.5)
+    let mut adj = v.rev();
+    let dt: f32 = adj
+        .get_mut(&t)
+        .expect("t adjoint missing")
+        .apply_rev()
+        .into();
+    assert!(eq_f32(dt, 0.5));
+    for (yi, e) in y.iter().zip([0f32, 0.5, 0.5]) {
+        let d: f32 = adj
+            .get_mut(yi)
+            .expect("y adjoint missing")
+            .apply_rev()
+            .into();
+        assert!(eq_f32(d, e));
+    }
+    let mut fwd = v.fwd_wrt(&t);
+    assert!(eq_f32(fwd.into(), 0.5));
+
+    //clamped outside of the samples
+    let mut t2 = t.clone();
+    t2.set_val(ValType::F(5.));
+    assert!(eq_f32(v.apply_fwd().into(), 3.));
+    fwd = v.fwd_wrt(&t);
+    assert!(eq_f32(fwd.into(), 0.));
+}
 
-        assert!(eq_f32(g.into(), -3. / 4. * 2f32.powi(-2)));
+#[test]
+fn test_interp_cubic() {
+    //samples of t^2 at uniform times reproduce the quadratic in the interior
+    //catmull-rom tangent at 1 is (4-0)/2=2, at 2 is (9-1)/2=4, the exact slopes
+    let times = [0f32, 1., 2., 3.];
+    let y = leaf_vec(&[0., 1., 4., 9.]);
+    let t = Leaf(ValType::F(1.5));
+    let mut v = Interp(t.clone(), &times, &y, Interpolation::Cubic);
+    assert!(eq_f32(v.apply_fwd().into(), 2.25));
+    let d: f32 = v.fwd_wrt(&t).into();
+    assert!(eq_f32(d, 3.));
+    //second derivative through rev of the tangent
+    assert!(eq_f32(partial(&v, &[t.clone(), t.clone()]).into(), 2.));
+
+    //weights wrt the samples sum to one and match a perturbation of each sample
+    let mut adj = v.rev();
+    let mut total = 0.;
+    for (i, yi) in y.iter().enumerate() {
+        let w: f32 = adj
+            .get_mut(yi)
+            .expect("y adjoint missing")
+            .apply_rev()
+            .into();
+        let mut yc = yi.clone();
+        let base: f32 = v.apply_fwd().into();
+        yc.set_val(ValType::F([0., 1., 4., 9.][i] + 1.));
+        let bumped: f32 = v.apply_fwd().into();
+        yc.set_val(ValType::F([0., 1., 4., 9.][i]));
+        assert!(eq_f32(w, bumped - base));
+        total += w;
     }
+    assert!(eq_f32(total, 1.));
+}
 
-    {
-        let gg = a
-            .rev()
-            .get_mut(&l0)
-            .expect("l0 adjoint missing")
-            .rev()
-            .get_mut(&l0)
-            .expect("l0 adjoint missing")
-            .apply_rev();
+#[test]
+fn test_freeze() {
+    //y=a*b+sin(a) where a=1, b=2, with b frozen
+    let mut a = Leaf(ValType::F(1.));
+    let mut b = Leaf(ValType::F(2.));
+    let y = Add(Mul(a.clone(), b.clone()), Sin(a.clone()));
+    b.freeze();
+    assert!(b.is_frozen());
+
+    let adj = y.rev();
+    assert!(!adj.contains_key(&b));
+    let d: f32 = adj[&a].clone().apply_rev().into();
+    assert!(eq_f32(d, 2. + 1f32.cos()));
+    assert!(y.rev_wrt(&b).is_none());
+
+    //active frozen leaves get no tangent
+    b.active();
+    a.active();
+    let mut t = y.fwd();
+    assert!(eq_f32(t.apply_fwd().into(), 2. + 1f32.cos()));
+    assert!(eq_f32(y.fwd_wrt(&b).into(), 0.));
+    assert_eq!(check_wrt(&y, &b, false), Some(WrtIssue::Frozen));
+
+    //frozen intermediate nodes stop derivatives like a constant
+    let mut s = Sin(a.clone());
+    let z = Mul(s.clone(), a.clone());
+    s.freeze();
+    assert!(eq_f32(z.fwd_wrt(&a).into(), 1f32.sin()));
+
+    //unfrozen nodes take part in graphs built afterwards
+    b.unfreeze();
+    let mut adj = y.rev();
+    let d: f32 = adj
+        .get_mut(&b)
+        .expect("b adjoint missing")
+        .apply_rev()
+        .into();
+    assert!(eq_f32(d, 1.));
+}
 
-        assert!(eq_f32(gg.into(), 6. / 4. * 2f32.powi(-3)));
+#[test]
+fn test_grad_accumulation() {
+    //minibatches y_k=a*x_k+b with x=(1,2,3), a=2, b=1
+    let mut a = Leaf(ValType::F(2.)).retain_grad();
+    let b = Leaf(ValType::F(1.));
+    let mut c = Leaf(ValType::F(0.)).retain_grad();
+    assert_eq!(a.grad(), Some(0.));
+    assert_eq!(b.grad(), None);
+    for x in [1f32, 2., 3.] {
+        let y = Add(Mul(a.clone(), Leaf(ValType::F(x))), b.clone());
+        let mut adj = y.rev();
+        adj.get_mut(&a).expect("a adjoint missing").apply_rev();
+        adj.get_mut(&b).expect("b adjoint missing").apply_rev();
     }
+    //da=sum(x_k), leaves without retain_grad keep no buffer
+    assert!(eq_f32(a.grad().unwrap(), 6.));
+    assert_eq!(b.grad(), None);
+    assert_eq!(c.grad(), Some(0.));
+
+    //adjoints evaluated inside another graph, here d(dy/da)/dc=2a, do not add to the buffer
+    let y = Mul(a.clone(), Mul(a.clone(), c.clone()));
+    let adj = y.rev();
+    let mut d2 = adj[&a].rev();
+    assert!(eq_f32(
+        d2.get_mut(&c)
+            .expect("c adjoint missing")
+            .apply_rev()
+            .into(),
+        4.
+    ));
+    assert!(eq_f32(c.grad().unwrap(), 4.));
+    assert!(eq_f32(a.grad().unwrap(), 6.));
+
+    a.zero_grad();
+    c.zero_grad();
+    assert_eq!(a.grad(), Some(0.));
+    let mut adj = Mul(a.clone(), a.clone()).rev();
+    adj.get_mut(&a).expect("a adjoint missing").apply_rev();
+    assert!(eq_f32(a.grad().unwrap(), 4.));
 }
 
 #[test]
-fn test_tan_fwd() {
-    //y=3tan(4x) where x=2
-    //y'=12/(cos(4x))^2 where x=2
+fn test_mixed_precision() {
+    //y=x*1e8+x*1-x*1e8, the adjoint sum 1e8+1-1e8 cancels in f32
+    let x = Leaf(ValType::F(0.5));
+    let terms = [1e8f32, 1., -1e8]
+        .iter()
+        .map(|c| Mul(x.clone(), Leaf(ValType::F(*c))))
+        .collect::<Vec<_>>();
+    let mut y = terms.into_iter().reduce(Add).unwrap();
+    let mut adj = y.rev();
+    let d: f32 = adj
+        .get_mut(&x)
+        .expect("x adjoint missing")
+        .apply_rev()
+        .into();
+    assert!(eq_f32(d, 0.));
+
+    //summed in f64 the small contribution survives, values stay f32
+    y.mixed_precision();
+    let mut adj = y.rev();
+    let d: f32 = adj
+        .get_mut(&x)
+        .expect("x adjoint missing")
+        .apply_rev()
+        .into();
+    assert!(eq_f32(d, 1.));
+    assert!(matches!(y.apply_fwd(), ValType::F(_)));
+    assert!(eq_f32(
+        y.rev_wrt(&x).expect("x adjoint missing").apply_rev().into(),
+        1.
+    ));
+}
 
-    let l0 = Leaf(ValType::F(2.)).active();
-    let l1 = Leaf(ValType::F(3.));
-    let l2 = Leaf(ValType::F(4.));
-    let a = Mul(l1.clone(), Tan(Mul(l2.clone(), l0.clone())));
+#[test]
+fn test_compensated_summation() {
+    //y=sum(x*c_k) over 1e8, 1, -1e8 and many 0.25, exact adjoint 1+1000*0.25=251
+    let x = Leaf(ValType::F(0.5));
+    let mut c = vec![1e8f32, 1., -1e8];
+    c.extend(vec![0.25; 1000]);
+    let mut y = c
+        .iter()
+        .map(|c| Mul(x.clone(), Leaf(ValType::F(*c))))
+        .reduce(Add)
+        .unwrap();
+    let mut adj = y.rev();
+    let d: f32 = adj
+        .get_mut(&x)
+        .expect("x adjoint missing")
+        .apply_rev()
+        .into();
+    assert!((d - 251.).abs() > 1.);
+
+    y.compensated_summation();
+    let mut adj = y.rev();
+    let mut a = adj.get_mut(&x).expect("x adjoint missing").clone();
+    assert!(eq_f32(a.apply_rev().into(), 251.));
+    assert!(a.op_name().contains("OpKahanSum"));
+}
 
+#[test]
+fn test_subgradient_policy() {
+    //y=relu(x) where x=0, one-sided derivatives 0 and 1
+    let x = Leaf(ValType::F(0.));
+    let y = Relu(x.clone());
+    let d = |policy| {
+        with_subgradient_policy(policy, || {
+            let mut adj = y.rev();
+            f32::from(adj.get_mut(&x).expect("x adjoint missing").apply_rev())
+        })
+    };
+    assert!(eq_f32(d(SubgradientPolicy::Zero), 0.));
+    assert!(eq_f32(d(SubgradientPolicy::Left), 0.));
+    assert!(eq_f32(d(SubgradientPolicy::Right), 1.));
+    assert!(eq_f32(d(SubgradientPolicy::Average), 0.5));
+    assert_eq!(subgradient_policy(), SubgradientPolicy::Zero);
+    //restored when f panics
+    let res = std::panic::catch_unwind(|| {
+        with_subgradient_policy(SubgradientPolicy::Right, || panic!("interrupted"))
+    });
+    assert!(res.is_err());
+    assert_eq!(subgradient_policy(), SubgradientPolicy::Zero);
+
+    //away from the kink the policy does not matter
+    let mut x1 = x.clone();
+    x1.set_val(ValType::F(-2.));
+    assert!(eq_f32(Relu(x.clone()).apply_fwd().into(), 0.));
+    assert!(eq_f32(d(SubgradientPolicy::Right), 0.));
+
+    //smoothstep'' jumps from 0 to 6 at t=0 and from -6 to 0 at t=1
+    let mut t = Leaf(ValType::F(0.)).active();
+    let s = Smoothstep(t.clone());
+    let dd = |policy| with_subgradient_policy(policy, || f32::from(s.fwd().fwd().apply_fwd()));
+    assert!(eq_f32(dd(SubgradientPolicy::Zero), 0.));
+    assert!(eq_f32(dd(SubgradientPolicy::Right), 6.));
+    assert!(eq_f32(dd(SubgradientPolicy::Average), 3.));
+    t.set_val(ValType::F(1.));
+    assert!(eq_f32(dd(SubgradientPolicy::Left), -6.));
+
+    //graphs keep the policy they were built with
+    set_subgradient_policy(SubgradientPolicy::Right);
+    let mut g = y.rev();
+    set_subgradient_policy(SubgradientPolicy::Zero);
+    x1.set_val(ValType::F(0.));
     assert!(eq_f32(
-        a.fwd().apply_fwd().into(),
-        12. / (8f32.cos().powi(2))
+        g.get_mut(&x).expect("x adjoint missing").apply_rev().into(),
+        1.
     ));
 }
 
 #[test]
-fn test_tan_rev() {
-    //y=3tan(4x) where x=2
-    //y'=12/(cos(4x))^2 where x=2
+fn test_debug_check_consistency() {
+    //y=sin(a)*b/exp(a) + a^b
+    let a = Leaf(ValType::F(0.7));
+    let b = Leaf(ValType::F(1.3));
+    let y = Add(
+        Mul(Sin(a.clone()), Div(b.clone(), Exp(a.clone()))),
+        Pow(a.clone(), b.clone()),
+    );
+    assert!(debug_check_consistency(&y, &[a.clone(), b.clone()]).is_empty());
+
+    //adjoint of sin given as sin(x) instead of cos(x)
+    #[derive(Debug)]
+    struct OpBadSin {}
+    impl FWrap for OpBadSin {
+        fn new() -> Box<dyn FWrap> {
+            Box::new(OpBadSin {})
+        }
+        fn f(&self) -> EvalFn {
+            OpSin {}.f()
+        }
+        fn tangent(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, &PtrVWrap) -> PtrVWrap> {
+            OpSin {}.tangent()
+        }
+        fn adjoint(&self) -> Box<dyn FnMut(Vec<PtrVWrap>, PtrVWrap, &PtrVWrap) -> Vec<PtrVWrap>> {
+            Box::new(
+                |inputs: Vec<PtrVWrap>, out_adj: PtrVWrap, _cur: &PtrVWrap| {
+                    vec![Mul(Sin(inputs[0].clone()), out_adj)]
+                },
+            )
+        }
+    }
+    let s = VWrap::new_with_input(OpBadSin::new(), vec![Mul(a.clone(), b.clone())]);
+    let z = Add(Exp(s.clone()), b.clone());
+    let hash = z.structural_hash();
+    let found = debug_check_consistency(&z, &[a.clone(), b.clone()]);
+    //both end-to-end derivatives and the local derivative of the faulty op
+    assert_eq!(found.len(), 3);
+    assert_eq!(found[0].node, None);
+    assert_eq!(found[2].node, Some(s.clone()));
+    assert_eq!(found[2].input, 0);
+    assert!(eq_f32(found[2].fwd, 0.91f32.cos()));
+    assert!(eq_f32(found[2].rev, 0.91f32.sin()));
+    assert!(found[2].to_string().starts_with("OpBadSin wrt input 0"));
+    //the graph is left as it was, down to the cached structural hashes
+    assert!(s.0.borrow().structural.is_some());
+    assert_eq!(z.structural_hash(), hash);
+    assert_eq!(s.inputs().len(), 1);
+    assert!(s.inputs()[0] != a && s.inputs()[0].inputs().contains(&a));
+    assert!(eq_f32(
+        z.clone().apply_fwd().into(),
+        0.91f32.sin().exp() + 1.3
+    ));
+}
 
-    let l0 = Leaf(ValType::F(2.)).active();
-    let l1 = Leaf(ValType::F(3.));
-    let l2 = Leaf(ValType::F(4.));
-    let a = Mul(l1.clone(), Tan(Mul(l2.clone(), l0.clone())));
+#[test]
+fn test_print_tree() {
+    //y=a*b + sin(a*b)
+    let a = Leaf(ValType::F(2.));
+    let b = Leaf(ValType::F(0.5));
+    let m = Mul(a.clone(), b.clone());
+    let mut y = Add(m.clone(), Sin(m.clone()));
+    assert_eq!(
+        y.tree_string(),
+        concat!(
+            "n0 OpAdd\n",
+            "|- n1 OpMul\n",
+            "|  |- n2 OpLeaf = F(2.0)\n",
+            "|  `- n3 OpLeaf = F(0.5)\n",
+            "`- n4 OpSin\n",
+            "   `- @n1\n",
+        )
+    );
+    y.apply_fwd();
+    let text = y.tree_string();
+    assert!(text.starts_with("n0 OpAdd = F(1.841"));
+    assert!(text.contains("|- n1 OpMul = F(1.0)\n"));
+    y.print_tree();
+}
 
-    let g = a
-        .rev()
-        .get_mut(&l0)
-        .expect("l0 adjoint missing")
-        .apply_rev();
+#[test]
+fn test_node_metadata() {
+    let a = Leaf(ValType::F(1.)).with_meta("name", "a");
+    let b = Leaf(ValType::F(2.));
+    assert_eq!(a.location(), None);
+
+    set_track_locations(true);
+    let line = line!() + 1;
+    let mut y = Mul(a.clone(), b.clone()).with_meta("layer", "1");
+    let r = Relu(y.clone());
+    set_track_locations(false);
+    let loc = y.location().expect("location not recorded");
+    assert!(loc.file().ends_with("core.rs"));
+    assert_eq!(loc.line(), line);
+    assert_eq!(r.location().map(|l| l.line()), Some(line + 1));
+    assert_eq!(Sin(a.clone()).location(), None);
+
+    y.with_meta("layer", "2").with_meta("note", "x");
+    assert_eq!(y.meta("layer"), Some("2".to_string()));
+    assert_eq!(y.meta("missing"), None);
+    assert_eq!(y.metadata().len(), 2);
+    assert_eq!(a.origin(), " (name=a)");
+    let origin = format!(" (created at {}, layer=2, note=x)", loc);
+    assert_eq!(y.origin(), origin);
+    assert!(y
+        .tree_string()
+        .starts_with(&format!("n0 OpMul{}\n", origin)));
+    let e = Inconsistency {
+        node: Some(y.clone()),
+        input: 0,
+        fwd: 1.,
+        rev: 2.,
+    };
+    assert!(e
+        .to_string()
+        .starts_with(&format!("OpMul{} wrt input 0", origin)));
+}
 
-    assert!(eq_f32(g.into(), 12. / (8f32.cos().powi(2))));
+#[test]
+fn test_missing_leaf() {
+    let a = Leaf(ValType::F(1.));
+    let b = Leaf(ValType::F(2.)).with_meta("name", "b");
+    let mut y = Add(a.clone(), Mul(Sin(a.clone()), b.clone()));
+    assert!(y.try_apply_fwd().is_ok());
+
+    b.0.deref().borrow_mut().val = None;
+    let e = y.try_apply_fwd().expect_err("b has no value");
+    assert_eq!(e.leaf(), &b);
+    assert_eq!(e.path.len(), 3);
+    assert_eq!(
+        e.to_string(),
+        "leaf value missing: OpLeaf (name=b), path from output: OpAdd input 1 -> OpMul input 1 -> OpLeaf"
+    );
+    let msg = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| y.apply_fwd()))
+        .expect_err("apply_fwd should panic")
+        .downcast::<String>()
+        .expect("panic message");
+    assert!(msg.contains("(name=b)"));
 }
 
 #[test]
-fn test_ln_fwd() {
-    //y=ln(4x) where x=2
-    //y'=4/(4x) where x=2
+fn test_partial_multi() {
+    //f=x^3*sin(z)*w
+    let x = Leaf(ValType::F(1.5));
+    let z = Leaf(ValType::F(0.4));
+    let w = Leaf(ValType::F(2.));
+    let f = Mul(
+        Mul(Pow(x.clone(), constant(ValType::F(3.))), Sin(z.clone())),
+        w.clone(),
+    );
+    let (xv, zv, wv) = (1.5f32, 0.4f32, 2f32);
+
+    //d^3f/dx^2dz=6x*cos(z)*w
+    let d: f32 = partial_multi(&f, &[(x.clone(), 2), (z.clone(), 1)]).into();
+    assert!(eq_f32(d, 6. * xv * zv.cos() * wv));
+    //order of the targets does not matter
+    let d: f32 = partial_multi(&f, &[(z.clone(), 1), (x.clone(), 2)]).into();
+    assert!(eq_f32(d, 6. * xv * zv.cos() * wv));
+    assert!(eq_f32(
+        partial_multi(&f, &[(x.clone(), 2), (z.clone(), 1)]).into(),
+        partial(&f, &[z.clone(), x.clone(), x.clone()]).into()
+    ));
 
-    let l0 = Leaf(ValType::F(2.)).active();
-    let l1 = Leaf(ValType::F(4.));
-    let a = Ln(Mul(l0.clone(), l1.clone()));
+    let mut p = Partials::new(&f);
+    //d^5f/dx^3dzdw=6*cos(z)
+    assert!(eq_f32(
+        p.get(&[(x.clone(), 3), (z.clone(), 1), (w.clone(), 1)])
+            .into(),
+        6. * zv.cos()
+    ));
+    let built = p.len();
+    //d^2f/dz^2=-x^3*sin(z)*w and d^2f/dxdz=3x^2*cos(z)*w, the latter from cached graphs
+    assert!(eq_f32(
+        p.get(&[(z.clone(), 2)]).into(),
+        -xv.powi(3) * zv.sin() * wv
+    ));
+    let built_z = p.len();
+    assert!(built_z > built);
+    assert!(eq_f32(
+        p.get(&[(x.clone(), 1), (z.clone(), 1)]).into(),
+        3. * xv * xv * zv.cos() * wv
+    ));
+    assert_eq!(p.len(), built_z);
+    //zero order is the value, derivatives beyond the degree in x vanish
+    assert!(eq_f32(
+        p.get(&[(x.clone(), 0)]).into(),
+        xv.powi(3) * zv.sin() * wv
+    ));
+    assert!(eq_f32(p.get(&[(x.clone(), 4)]).into(), 0.));
+    assert!(eq_f32(p.get(&[(Leaf(ValType::F(1.)), 1)]).into(), 0.));
 
-    let g = a.fwd().apply_fwd();
+    //graphs stay valid for new leaf values
+    let mut x1 = x.clone();
+    x1.set_val(ValType::F(2.));
+    assert!(eq_f32(
+        p.get(&[(x.clone(), 2), (z.clone(), 1)]).into(),
+        6. * 2. * zv.cos() * wv
+    ));
+}
 
-    assert!(eq_f32(g.into(), 4. / 8.));
+#[test]
+fn test_apply_fwd_with() {
+    //y=a*sin(b)+b
+    let a = Leaf(ValType::F(2.)).retain_grad();
+    let b = Leaf(ValType::F(0.5));
+    let s = Sin(b.clone());
+    let y = Add(Mul(a.clone(), s.clone()), b.clone());
+
+    let values: HashMap<PtrVWrap, ValType> =
+        vec![(a.clone(), ValType::F(3.))].into_iter().collect();
+    assert!(eq_f32(
+        y.apply_fwd_with(&values).into(),
+        3. * 0.5f32.sin() + 0.5
+    ));
+    //nothing was stored
+    assert_eq!(y.0.deref().borrow().val, None);
+    assert_eq!(s.0.deref().borrow().val, None);
+    assert!(eq_f32(a.0.deref().borrow().val.unwrap().into(), 2.));
+    assert!(eq_f32(
+        y.clone().apply_fwd().into(),
+        2. * 0.5f32.sin() + 0.5
+    ));
+
+    //an interior override cuts off its inputs
+    let values: HashMap<PtrVWrap, ValType> =
+        vec![(s.clone(), ValType::F(0.25))].into_iter().collect();
+    assert!(eq_f32(y.apply_fwd_with(&values).into(), 2. * 0.25 + 0.5));
+
+    //dy/da=sin(b) at b=0 and b=0.5, the grad buffer is left alone
+    let adj = y.rev_wrt(&a).expect("a adjoint missing");
+    let values: HashMap<PtrVWrap, ValType> =
+        vec![(b.clone(), ValType::F(0.))].into_iter().collect();
+    assert!(eq_f32(adj.apply_rev_with(&values).into(), 0.));
+    assert!(eq_f32(
+        adj.apply_rev_with(&HashMap::new()).into(),
+        0.5f32.sin()
+    ));
+    assert_eq!(a.grad(), Some(0.));
+
+    //a leaf without a value and without an override is reported
+    b.0.deref().borrow_mut().val = None;
+    let y2 = y.clone();
+    let msg = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        y2.apply_fwd_with(&HashMap::new())
+    }))
+    .expect_err("b has no value")
+    .downcast::<String>()
+    .expect("panic message");
+    assert!(msg.ends_with("OpAdd input 1 -> OpLeaf"));
+    let values: HashMap<PtrVWrap, ValType> =
+        vec![(b.clone(), ValType::F(0.))].into_iter().collect();
+    assert!(eq_f32(y.apply_fwd_with(&values).into(), 0.));
 }
 
 #[test]
-fn test_ln_rev() {
-    //y=ln(4x) where x=2
-    //y'=4/(4x) where x=2
+fn test_integer_semantics() {
+    //mixed arithmetic promotes, integers of the same width stay exact
+    let i = |v| Leaf(ValType::I(v));
+    assert_eq!(
+        Add(i(2), Leaf(ValType::F(0.5))).apply_fwd(),
+        ValType::F(2.5)
+    );
+    assert_eq!(Add(i(2), i(3)).apply_fwd(), ValType::I(5));
+    assert_eq!(Mul(Leaf(ValType::L(4)), i(3)).apply_fwd(), ValType::L(12));
+    assert_eq!(
+        Mul(Leaf(ValType::D(0.5)), i(3)).apply_fwd(),
+        ValType::D(1.5)
+    );
+    assert_eq!(Minus(i(7), i(2)).apply_fwd(), ValType::F(5.));
+
+    //y=x^2-x at x=3
+    let x = i(3);
+    let mut y = Minus(Mul(x.clone(), x.clone()), x.clone());
+    assert!(eq_f32(y.clone().apply_fwd().into(), 6.));
+    assert!(eq_f32(partial(&y, std::slice::from_ref(&x)).into(), 5.));
+    let mut adj = y.rev();
+    assert!(eq_f32(
+        adj.get_mut(&x)
+            .expect("x adjoint missing")
+            .apply_rev()
+            .into(),
+        5.
+    ));
+    assert_eq!(check_wrt(&y, &x, false), None);
+    set_strict(Some(Rc::new(|r: &WrtReport| {
+        if r.issue == WrtIssue::Integer {
+            panic!("{}", r);
+        }
+    })));
+    assert_eq!(check_wrt(&y, &x, false), Some(WrtIssue::Integer));
+    //a strict handler rejects integer targets
+    let msg = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| y.rev()))
+        .expect_err("rev should panic")
+        .downcast::<String>()
+        .expect("panic message");
+    assert_eq!(*msg, "rev target 0: target holds an integer value");
+    let rejected = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        partial(&y, std::slice::from_ref(&x))
+    }));
+    assert!(rejected.is_err());
+    set_strict(None);
+
+    y.integer_semantics(IntegerSemantics::Constant);
+    assert!(eq_f32(partial(&y, std::slice::from_ref(&x)).into(), 0.));
+    assert!(!y.rev().contains_key(&x));
+    assert_eq!(check_wrt(&y, &x, false), Some(WrtIssue::Integer));
+    //float leaves are differentiated as before
+    let z = Leaf(ValType::F(3.));
+    let mut w = Mul(z.clone(), x.clone()).integer_semantics(IntegerSemantics::Constant);
+    assert!(eq_f32(w.apply_fwd().into(), 9.));
+    assert!(eq_f32(partial(&w, std::slice::from_ref(&z)).into(), 3.));
+}
 
-    let l0 = Leaf(ValType::F(2.)).active();
-    let l1 = Leaf(ValType::F(4.));
-    let a = Ln(Mul(l0.clone(), l1.clone()));
+#[test]
+fn test_rev_pool() {
+    //y=sin(a*b)+a*a
+    let a = Leaf(ValType::F(0.5));
+    let b = Leaf(ValType::F(2.));
+    let y = Add(Sin(Mul(a.clone(), b.clone())), Mul(a.clone(), a.clone()));
+    let mut pool = RevPool::new(&y);
+    assert!(pool.is_empty());
+    let grads = |adj: &HashMap<PtrVWrap, PtrVWrap>| -> (f32, f32) {
+        (
+            adj[&a].clone().apply_rev().into(),
+            adj[&b].clone().apply_rev().into(),
+        )
+    };
+    let expected = (2f32 * 1f32.cos() + 1., 0.5 * 1f32.cos());
+
+    let adj = pool.rev();
+    let built = pool.len();
+    assert!(built > 0);
+    let first = grads(&adj);
+    assert!(eq_f32(first.0, expected.0) && eq_f32(first.1, expected.1));
+
+    //graphs still held are not recycled
+    let kept = pool.rev();
+    assert_eq!(pool.reused(), 0);
+    assert_eq!(grads(&kept), first);
+
+    //dropped graphs are, and the new ones are the same
+    drop(adj);
+    drop(kept);
+    a.clone().set_val(ValType::F(1.));
+    let adj = pool.rev();
+    assert!(pool.reused() >= built);
+    assert_eq!(pool.len(), 2 * built);
+    let again = grads(&adj);
+    assert!(eq_f32(again.0, 2. * 2f32.cos() + 2.));
+    assert!(eq_f32(again.1, 2f32.cos()));
+
+    //nodes built outside of the pool are not pooled
+    let _ = y.rev();
+    assert_eq!(pool.len(), 2 * built);
+    pool.shrink();
+    assert_eq!(pool.len(), built);
+
+    //a panicking rev hands the pool back and leaves later nodes unpooled
+    let mut bad = VWrap::new(OpFma::new());
+    bad.set_inp(vec![a.clone(), b.clone()]);
+    let mut pool = RevPool::new(&Mul(bad, a.clone()));
+    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| pool.rev()));
+    assert!(res.is_err());
+    assert!(POOL.with(|p| p.borrow().is_none()));
+    let built = pool.len();
+    let _ = y.rev();
+    assert_eq!(pool.len(), built);
+}
 
-    let g = a
-        .rev()
-        .get_mut(&l0)
-        .expect("l0 adjoint missing")
-        .apply_rev();
+#[test]
+fn test_derivative_cache() {
+    //y=sin(exp(x*x)) at x=0.5
+    let x = Leaf(ValType::F(0.5));
+    let u = Exp(Mul(x.clone(), x.clone()));
+    let y = Sin(u.clone());
+    let mut cache = DerivativeCache::new();
+
+    let mut d3 = cache
+        .derivative(&y, &x, 3, Mode::Fwd)
+        .expect("nonzero derivative");
+    let expected: f32 = partial(&y, &[x.clone(), x.clone(), x.clone()]).into();
+    assert!(eq_f32(d3.apply_fwd().into(), expected));
+    //tangents of the nodes of y were reused for the second and third derivatives
+    let shared = cache.hits();
+    assert!(shared > 0);
+    //activity flags are left as they were
+    assert!(!x.0.deref().borrow().eval_g);
+
+    //lower orders come from the cache
+    let d2 = cache
+        .derivative(&y, &x, 2, Mode::Fwd)
+        .expect("nonzero derivative");
+    assert_eq!(cache.hits(), shared + 2);
+    let mut d2_again = cache.derivative(&y, &x, 2, Mode::Fwd).unwrap();
+    assert!(d2 == d2_again);
+    let expected: f32 = partial(&y, &[x.clone(), x.clone()]).into();
+    assert!(eq_f32(d2_again.apply_fwd().into(), expected));
+
+    //reverse mode and repeated rev
+    let mut r2 = cache
+        .derivative(&y, &x, 2, Mode::Rev)
+        .expect("nonzero derivative");
+    assert!(eq_f32(r2.apply_rev().into(), expected));
+    let adj = cache.rev(&y);
+    assert!(adj[&x] == cache.rev(&y)[&x]);
+    assert!(cache
+        .derivative(&y, &Leaf(ValType::F(1.)), 1, Mode::Rev)
+        .is_none());
+    assert!(cache
+        .derivative(&u, &Leaf(ValType::F(1.)), 1, Mode::Fwd)
+        .is_none());
+    assert!(cache.derivative(&y, &x, 0, Mode::Fwd).unwrap() == y);
+
+    cache.clear();
+    assert!(cache.derivative(&y, &x, 2, Mode::Fwd).unwrap() != d2);
+
+    //z=a*b+sin(a) at a=1, b=2: the tangent cached for a is unaffected by building the one
+    //for b or by later changes of activity
+    let a = Leaf(ValType::F(1.));
+    let mut b = Leaf(ValType::F(2.));
+    let z = Add(Mul(a.clone(), b.clone()), Sin(a.clone()));
+    let da = cache
+        .derivative(&z, &a, 1, Mode::Fwd)
+        .expect("nonzero derivative");
+    let mut db = cache
+        .derivative(&z, &b, 1, Mode::Fwd)
+        .expect("nonzero derivative");
+    assert!(eq_f32(db.apply_fwd().into(), 1.));
+    let mut da_again = cache.derivative(&z, &a, 1, Mode::Fwd).unwrap();
+    assert!(da == da_again);
+    assert!(eq_f32(da_again.apply_fwd().into(), 2. + 1f32.cos()));
+    b.active();
+    assert!(eq_f32(da_again.apply_fwd().into(), 2. + 1f32.cos()));
+    b.inactive();
+
+    //a panicking tangent rule leaves no seeded tangents behind for later fwd calls
+    let broken = VWrap::new_with_input(OpSin::new(), vec![a.clone(), b.clone()]);
+    let built = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        cache.derivative(&broken, &a, 1, Mode::Fwd)
+    }));
+    assert!(built.is_err());
+    assert!(!a.0.deref().borrow().eval_g);
+    b.active();
+    assert!(eq_f32(Sin(b.clone()).fwd().apply_fwd().into(), 2f32.cos()));
+    b.inactive();
+}
 
-    assert!(eq_f32(g.into(), 4. / 8.));
+#[test]
+fn test_embedding() {
+    //table of 1000 rows of 2, y=e(3).(1,2) + e(3)_0*e(7)_1
+    let table: Vec<Vec<PtrVWrap>> = (0..1000)
+        .map(|r| leaf_vec(&[r as f32, -(r as f32)]))
+        .collect();
+    let e3 = Embedding(&table, 3);
+    let e7 = Embedding(&table, 7);
+    let w = leaf_vec(&[1., 2.]);
+    let mut y = Add(dot_nodes(&e3, &w), Mul(e3[0].clone(), e7[1].clone()));
+    assert!(eq_f32(y.apply_fwd().into(), 3. - 6. - 21.));
+
+    //only the looked up rows get adjoints, the repeated row sums its contributions
+    let mut adj = y.rev();
+    let touched: Vec<&PtrVWrap> = table
+        .iter()
+        .flatten()
+        .filter(|l| adj.contains_key(*l))
+        .collect();
+    assert_eq!(touched.len(), 3);
+    //dy/dt30=1+t71=-6, dy/dt31=2, dy/dt71=t30=3
+    let mut d = |l: &PtrVWrap| f32::from(adj.get_mut(l).expect("adjoint missing").apply_rev());
+    assert!(eq_f32(d(&table[3][0]), -6.));
+    assert!(eq_f32(d(&table[3][1]), 2.));
+    assert!(eq_f32(d(&table[7][1]), 3.));
+
+    let mut t = table[3][1].clone().active();
+    assert!(eq_f32(y.fwd().apply_fwd().into(), 2.));
+    t.inactive();
 }
 
 #[test]
-fn test_pow_fwd() {
-    //y=4x^3 where x=2
-    //y'=12*x^2 where x=2
+fn test_clip_with_grad() {
+    let x = Leaf(ValType::F(0.5));
+    let mut x1 = x.clone();
+    let clip = ClipWithGrad(x.clone(), -1., 1., false);
+    let ste = ClipWithGrad(x.clone(), -1., 1., true);
+    let d = |y: &PtrVWrap| f32::from(partial(y, std::slice::from_ref(&x)));
+    let dr = |y: &PtrVWrap| f32::from(y.rev_wrt(&x).expect("x adjoint missing").apply_rev());
+
+    //inside the interval both are the identity
+    assert!(eq_f32(clip.clone().apply_fwd().into(), 0.5));
+    assert!(eq_f32(d(&clip), 1.));
+    assert!(eq_f32(d(&ste), 1.));
+
+    //saturated: y=1, the straight-through derivative is still 1
+    x1.set_val(ValType::F(3.));
+    assert!(eq_f32(clip.clone().apply_fwd().into(), 1.));
+    assert!(eq_f32(ste.clone().apply_fwd().into(), 1.));
+    assert!(eq_f32(d(&clip), 0.));
+    assert!(eq_f32(dr(&clip), 0.));
+    assert!(eq_f32(d(&ste), 1.));
+    assert!(eq_f32(dr(&ste), 1.));
+    x1.set_val(ValType::F(-3.));
+    assert!(eq_f32(clip.clone().apply_fwd().into(), -1.));
+    assert!(eq_f32(dr(&clip), 0.));
+
+    //at the bounds the subgradient policy decides
+    x1.set_val(ValType::F(1.));
+    let at = |policy| with_subgradient_policy(policy, || dr(&clip));
+    assert!(eq_f32(at(SubgradientPolicy::Zero), 0.));
+    assert!(eq_f32(at(SubgradientPolicy::Left), 1.));
+    assert!(eq_f32(at(SubgradientPolicy::Right), 0.));
+    x1.set_val(ValType::F(-1.));
+    assert!(eq_f32(at(SubgradientPolicy::Right), 1.));
+    assert!(eq_f32(at(SubgradientPolicy::Average), 0.5));
+
+    //piecewise linear, so second derivatives vanish
+    x1.set_val(ValType::F(0.2));
+    assert!(eq_f32(partial(&clip, &[x.clone(), x.clone()]).into(), 0.));
+    assert!(eq_f32(partial(&ste, &[x.clone(), x.clone()]).into(), 0.));
+}
 
-    let l0 = Leaf(ValType::F(2.)).active();
-    let l1 = Leaf(ValType::F(4.));
-    let l2 = Leaf(ValType::F(3.));
-    let a = Mul(l1.clone(), Pow(l0.clone(), l2.clone()));
+#[test]
+fn test_eval_as_f64() {
+    //y=(x+1e8)-1e8 loses x entirely in f32
+    let x = Leaf(ValType::F(1.));
+    let c = Leaf(ValType::F(1e8));
+    let mut y = Minus(Add(x.clone(), c.clone()), c.clone());
+    assert_eq!(f32::from(y.apply_fwd()), 0.);
+    assert_eq!(y.eval_as_f64(), 1.);
+    //the stored values are untouched
+    assert_eq!(x.0.borrow().val, Some(ValType::F(1.)));
+    assert_eq!(y.0.borrow().val, Some(ValType::F(0.)));
+
+    //z=y*y, dz/dx=2y is 0 in f32 and 2 in f64
+    let z = Mul(y.clone(), y.clone());
+    let mut dz = z.rev_wrt(&x).expect("x adjoint missing");
+    assert_eq!(f32::from(dz.apply_rev()), 0.);
+    assert!((dz.eval_as_f64() - 2.).abs() < 1e-9);
+    assert!((Sin(y.clone()).eval_as_f64() - 1f64.sin()).abs() < 1e-12);
+}
 
-    assert!(eq_f32(a.fwd().apply_fwd().into(), 12. * 4.));
+#[test]
+fn test_curvature_along() {
+    //f=x^2*y + sin(y), H=[[2y, 2x], [2x, -sin(y)]]
+    let x = Leaf(ValType::F(1.5));
+    let y = Leaf(ValType::F(0.7)).active();
+    let f = Add(Mul(Mul(x.clone(), x.clone()), y.clone()), Sin(y.clone()));
+    let (a, b) = (0.3f32, -2.);
+    let expect = 2. * 0.7 * a * a + 4. * 1.5 * a * b - 0.7f32.sin() * b * b;
+    let inputs = [x.clone(), y.clone()];
+    assert!(eq_f32(curvature_along(&f, &inputs, &[a, b]).into(), expect));
+    //along a coordinate axis it is the diagonal of the hessian
+    assert!(eq_f32(curvature_along(&f, &inputs, &[1., 0.]).into(), 1.4));
+    //y held constant
+    assert!(eq_f32(
+        curvature_along(&f, std::slice::from_ref(&x), &[a]).into(),
+        2. * 0.7 * a * a
+    ));
+
+    //activity flags are restored
+    assert!(!x.0.borrow().eval_g);
+    assert!(y.0.borrow().eval_g);
 }
 
 #[test]
-fn test_pow_fwd_2() {
-    //y=4^(3x) where x=2
-    //y'=ln(4)*4^(3x)*3 where x=2
+fn test_rev_retained() {
+    //y=a*sin(b)
+    let a = Leaf(ValType::F(2.));
+    let b = Leaf(ValType::F(0.5));
+    let s = Sin(b.clone());
+    let mut y = Mul(a.clone(), s.clone());
+    y.apply_fwd();
+    let adj = y.rev_retained();
+    assert_eq!(adj.len(), 4);
+    assert_eq!(adj.iter().next().map(|(n, _)| n), Some(&y));
+    assert!(adj.get(&s).is_some());
+
+    //dy/dy=1, dy/dsin(b)=a, dy/da=sin(b), dy/db=a*cos(b)
+    let values: HashMap<PtrVWrap, f32> = adj
+        .values()
+        .into_iter()
+        .map(|(n, v)| (n, v.into()))
+        .collect();
+    assert!(eq_f32(values[&y], 1.));
+    assert!(eq_f32(values[&s], 2.));
+    assert!(eq_f32(values[&a], 0.5f32.sin()));
+    assert!(eq_f32(values[&b], 2. * 0.5f32.cos()));
+    //leaf adjoints agree with rev
+    let mut db = y.rev()[&b].clone();
+    assert!(eq_f32(db.apply_rev().into(), values[&b]));
+
+    //values follow the leaves
+    let mut b1 = b.clone();
+    b1.set_val(ValType::F(0.));
+    y.apply_fwd();
+    let small: Vec<PtrVWrap> = adj
+        .values()
+        .into_iter()
+        .filter(|(_, v)| f32::from(*v).abs() < 1e-6)
+        .map(|(n, _)| n)
+        .collect();
+    assert_eq!(small, vec![a.clone()]);
+    assert_eq!((&adj).into_iter().count(), 4);
+}
 
-    let l0 = Leaf(ValType::F(2.)).active();
-    let l1 = Leaf(ValType::F(4.));
-    let l2 = Leaf(ValType::F(3.));
-    let a = Pow(l1.clone(), Mul(l2.clone(), l0.clone()));
+#[test]
+fn test_sqrt() {
+    //y=sqrt(3x) where x=12
+    //y'=3/(2*sqrt(3x))=1/4, y''=-9/(4*(3x)^(3/2))=-1/96
+    let x = Leaf(ValType::F(12.)).active();
+    let mut y = Sqrt(Mul(constant(ValType::F(3.)), x.clone()));
+    assert!(eq_f32(y.apply_fwd().into(), 6.));
+    assert!(eq_f32(y.fwd().apply_fwd().into(), 0.25));
+    assert!(eq_f32(y.fwd().fwd().apply_fwd().into(), -1. / 96.));
+    let mut g = y.rev()[&x].clone();
+    assert!(eq_f32(g.apply_rev().into(), 0.25));
+    assert!(eq_f32(
+        partial(&y, &[x.clone(), x.clone()]).into(),
+        -1. / 96.
+    ));
+    assert_eq!(y.eval_as_f64(), 6.);
+}
 
+#[test]
+fn test_tanh() {
+    //y=tanh(2x) where x=0.3
+    //y'=2*(1-t^2), y''=-8*t*(1-t^2) with t=tanh(0.6)
+    let x = Leaf(ValType::F(0.3)).active();
+    let mut y = Tanh(Mul(constant(ValType::F(2.)), x.clone()));
+    let t = 0.6f32.tanh();
+    assert!(eq_f32(y.apply_fwd().into(), t));
+    assert!(eq_f32(y.fwd().apply_fwd().into(), 2. * (1. - t * t)));
     assert!(eq_f32(
-        a.fwd().apply_fwd().into(),
-        4f32.ln() * 4f32.powf(3. * 2.) * 3.
+        y.fwd().fwd().apply_fwd().into(),
+        -8. * t * (1. - t * t)
+    ));
+    let mut g = y.rev()[&x].clone();
+    assert!(eq_f32(g.apply_rev().into(), 2. * (1. - t * t)));
+    assert!(eq_f32(
+        partial(&y, &[x.clone(), x.clone()]).into(),
+        -8. * t * (1. - t * t)
+    ));
+
+    //saturates without overflow
+    let mut x1 = x.clone();
+    x1.set_val(ValType::F(100.));
+    assert_eq!(f32::from(y.apply_fwd()), 1.);
+    assert_eq!(f32::from(g.apply_rev()), 0.);
+}
+
+#[test]
+fn test_softplus() {
+    //y=softplus(x) where x=0.5
+    //y'=s, y''=s*(1-s) with s=sigmoid(x)
+    let x = Leaf(ValType::F(0.5)).active();
+    let mut y = Softplus(x.clone());
+    let s = 1. / (1. + (-0.5f32).exp());
+    assert!(eq_f32(y.apply_fwd().into(), 0.5f32.exp().ln_1p()));
+    assert!(eq_f32(y.fwd().apply_fwd().into(), s));
+    assert!(eq_f32(y.fwd().fwd().apply_fwd().into(), s * (1. - s)));
+    let mut g = y.rev()[&x].clone();
+    assert!(eq_f32(g.apply_rev().into(), s));
+    assert!(eq_f32(
+        partial(&y, &[x.clone(), x.clone()]).into(),
+        s * (1. - s)
     ));
+
+    //exp(x) overflows f32 for x > 89 but the split evaluation does not
+    let mut x1 = x.clone();
+    x1.set_val(ValType::F(200.));
+    assert_eq!(f32::from(y.apply_fwd()), 200.);
+    assert_eq!(f32::from(g.apply_rev()), 1.);
+    x1.set_val(ValType::F(-200.));
+    assert_eq!(f32::from(y.apply_fwd()), 0.);
+    assert_eq!(f32::from(g.apply_rev()), 0.);
 }
 
 #[test]
-fn test_pow_rev() {
-    //y=4x^3 where x=2
-    //y'=12*x^2 where x=2
+fn test_gelu() {
+    //y=x*cdf(x) where x=1
+    //y'=cdf(x)+x*pdf(x), y''=(2-x^2)*pdf(x)
+    let x = Leaf(ValType::F(1.)).active();
+    let mut y = Gelu(x.clone());
+    let (cdf, pdf) = (0.8413447f32, 0.2419707f32);
+    assert!(eq_f32(y.apply_fwd().into(), cdf));
+    assert!(eq_f32(y.fwd().apply_fwd().into(), cdf + pdf));
+    assert!(eq_f32(y.fwd().fwd().apply_fwd().into(), pdf));
+    let mut g = y.rev()[&x].clone();
+    assert!(eq_f32(g.apply_rev().into(), cdf + pdf));
+    assert!(eq_f32(partial(&y, &[x.clone(), x.clone()]).into(), pdf));
+
+    //identity for large x, zero for very negative x
+    let mut x1 = x.clone();
+    x1.set_val(ValType::F(10.));
+    assert!(eq_f32(y.apply_fwd().into(), 10.));
+    assert!(eq_f32(g.apply_rev().into(), 1.));
+    x1.set_val(ValType::F(-10.));
+    assert!(eq_f32(y.apply_fwd().into(), 0.));
+    assert!(eq_f32(g.apply_rev().into(), 0.));
+}
 
-    let l0 = Leaf(ValType::F(2.)).active();
-    let l1 = Leaf(ValType::F(4.));
-    let l2 = Leaf(ValType::F(3.));
-    let a = Mul(l1.clone(), Pow(l0.clone(), l2.clone()));
+#[test]
+fn test_atan2() {
+    use std::f32::consts::PI;
+
+    //y=atan2(b,a) at (a,b)=(-1,1), second quadrant
+    //dy/db=a/r2, dy/da=-b/r2 with r2=a^2+b^2=2
+    let a = Leaf(ValType::F(-1.));
+    let b = Leaf(ValType::F(1.));
+    let mut y = Atan2(b.clone(), a.clone());
+    assert!(eq_f32(y.apply_fwd().into(), 3. * PI / 4.));
+    let adj = y.rev();
+    assert!(eq_f32(adj[&b].clone().apply_rev().into(), -0.5));
+    assert!(eq_f32(adj[&a].clone().apply_rev().into(), -0.5));
+    a.clone().active();
+    assert!(eq_f32(y.fwd().apply_fwd().into(), -0.5));
+    //d2y/da2=2ab/r2^2
+    assert!(eq_f32(partial(&y, &[a.clone(), a.clone()]).into(), -0.5));
+    assert!(eq_f32(partial(&y, &[a.clone(), b.clone()]).into(), 0.));
+
+    //finite on the vertical axis, where b/a is not
+    a.clone().set_val(ValType::F(0.));
+    b.clone().set_val(ValType::F(-2.));
+    assert!(eq_f32(y.apply_fwd().into(), -PI / 2.));
+    assert!(eq_f32(adj[&a].clone().apply_rev().into(), 0.5));
+    assert!(eq_f32(adj[&b].clone().apply_rev().into(), 0.));
+}
 
-    let g = a
-        .rev()
-        .get_mut(&l0)
-        .expect("l0 adjoint missing")
-        .apply_rev();
+#[test]
+fn test_const() {
+    //constants built by rev of a sum of quotients, each adjoint needing 1 and -1
+    let x: Vec<PtrVWrap> = (1..=8).map(|i| Leaf(ValType::F(i as f32))).collect();
+    let y = x[1..].iter().fold(x[0].clone(), |acc, xi| {
+        Add(acc, Div(xi.clone(), x[0].clone()))
+    });
+    let adj = y.rev();
+    let roots: Vec<PtrVWrap> = x.iter().map(|xi| adj[xi].clone()).collect();
+    let constants: Vec<PtrVWrap> = topo_order(&roots)
+        .into_iter()
+        .filter(|n| n.kind() == OpKind::Const)
+        .collect();
+    let values: HashSet<String> = constants
+        .iter()
+        .map(|n| format!("{:?}", n.fixed_value()))
+        .collect();
+    assert_eq!(constants.len(), values.len());
+    //dy/dx0 = 1 - sum(x_i)/x0^2
+    let expected = 1. - (2..=8).sum::<i32>() as f32;
+    assert!(eq_f32(roots[0].clone().apply_rev().into(), expected));
+
+    //user constants are shared within with_shared_constants only
+    let (a, b, c) = with_shared_constants(|| {
+        (
+            Const(ValType::F(2.)),
+            Const(ValType::F(2.)),
+            Const(ValType::D(2.)),
+        )
+    });
+    assert!(a == b);
+    assert!(a != c);
+    assert!(Const(ValType::F(2.)) != a);
+    assert!(with_shared_constants(
+        || Const(ValType::F(0.)) != Const(ValType::F(-0.))
+    ));
+    let mut z = Mul(a, x[0].clone());
+    assert!(eq_f32(z.apply_fwd().into(), 2.));
+}
 
-    assert!(eq_f32(g.into(), 12. * 4.));
+#[test]
+fn test_zero_adjoint_skip() {
+    //y=rem(a, b*exp(c)) + a*b, the divisor gets a zero adjoint from Rem
+    let a = Leaf(ValType::F(7.));
+    let b = Leaf(ValType::F(2.));
+    let c = Leaf(ValType::F(0.));
+    let y = Add(
+        Rem(a.clone(), Mul(b.clone(), Exp(c.clone()))),
+        Mul(a.clone(), b.clone()),
+    );
+    for sum in [AdjointSum::Chain, AdjointSum::F64] {
+        y.0.deref().borrow_mut().adj_sum = sum;
+        let adj = y.rev();
+        //no tangent rule is applied below the divisor, and no zero is added to a sum
+        assert!(adj[&c].is_zero());
+        let roots: Vec<PtrVWrap> = [&a, &b].iter().map(|l| adj[*l].clone()).collect();
+        assert!(topo_order(&roots).iter().all(|n| !n.is_zero()));
+        assert!(eq_f32(adj[&a].clone().apply_rev().into(), 3.));
+        assert!(eq_f32(adj[&b].clone().apply_rev().into(), 7.));
+        assert!(eq_f32(adj[&c].clone().apply_rev().into(), 0.));
+    }
 }
 
 #[test]
-fn test_pow_rev_2() {
-    //y=4^(3x) where x=2
-    //y'=ln(4)*4^(3x)*3 where x=2
+fn test_shared_one_zero() {
+    //high fan-in: y=sum(x_i*x_0) + sum(x_i), with x_i unused by a second output
+    let x: Vec<PtrVWrap> = (1..=16).map(|i| Leaf(ValType::F(i as f32))).collect();
+    let y = x.iter().fold(Leaf(ValType::F(0.)), |acc, xi| {
+        Add(Add(acc, Mul(xi.clone(), x[0].clone())), xi.clone())
+    });
+    let adj = y.rev();
+    let roots: Vec<PtrVWrap> = x.iter().map(|xi| adj[xi].clone()).collect();
+    let order = topo_order(&roots);
+    for kind in [OpKind::One, OpKind::Zero] {
+        assert!(order.iter().filter(|n| n.kind() == kind).count() <= 1);
+    }
+    //dy/dx0 = sum(x_i) + x0 + 1, dy/dxi = x0 + 1
+    assert!(eq_f32(roots[0].clone().apply_rev().into(), 136. + 2.));
+    assert!(eq_f32(roots[5].clone().apply_rev().into(), 2.));
+
+    //tangents of inactive subgraphs and of constants share a single zero
+    x[0].clone().active();
+    let t = y.fwd();
+    assert!(
+        topo_order(std::slice::from_ref(&t))
+            .iter()
+            .filter(|n| n.kind() == OpKind::Zero)
+            .count()
+            <= 1
+    );
+
+    //leaves sharing the seed as adjoint keep their own grad buffers
+    let mut a = Leaf(ValType::F(1.)).retain_grad();
+    let mut b = Leaf(ValType::F(2.)).retain_grad();
+    let s = Add(a.clone(), b.clone());
+    let adj = s.rev();
+    adj[&a].clone().apply_rev();
+    adj[&b].clone().apply_rev();
+    adj[&b].clone().apply_rev();
+    assert_eq!(a.grad(), Some(1.));
+    assert_eq!(b.grad(), Some(2.));
+    a.zero_grad();
+    b.zero_grad();
+}
 
-    let l0 = Leaf(ValType::F(2.)).active();
-    let l1 = Leaf(ValType::F(4.));
-    let l2 = Leaf(ValType::F(3.));
-    let a = Pow(l1.clone(), Mul(l2.clone(), l0.clone()));
+#[test]
+fn test_sigmoid() {
+    //y=sigmoid(x) where x=0.5
+    //y'=s*(1-s), y''=s*(1-s)*(1-2s)
+    let x = Leaf(ValType::F(0.5)).active();
+    let mut y = Sigmoid(x.clone());
+    let s = 1. / (1. + (-0.5f32).exp());
+    assert!(eq_f32(y.apply_fwd().into(), s));
+    assert!(eq_f32(y.fwd().apply_fwd().into(), s * (1. - s)));
+    assert!(eq_f32(
+        y.fwd().fwd().apply_fwd().into(),
+        s * (1. - s) * (1. - 2. * s)
+    ));
+    let mut g = y.rev()[&x].clone();
+    assert!(eq_f32(g.apply_rev().into(), s * (1. - s)));
+
+    //exp(-x) overflows f32 for x < -89 but the split evaluation does not
+    let mut x1 = x.clone();
+    for (v, expect) in [(-100f32, 0f32), (-20., (-20f32).exp()), (100., 1.)] {
+        x1.set_val(ValType::F(v));
+        let val: f32 = y.apply_fwd().into();
+        assert!(val.is_finite());
+        assert!((val - expect).abs() < 1e-9);
+        assert!(f32::from(g.apply_rev()).is_finite());
+    }
+    x1.set_val(ValType::F(-100.));
+    let d2: f32 = partial(&y, &[x.clone(), x.clone()]).into();
+    assert!(d2.is_finite() && d2.abs() < 1e-30);
+}
 
-    let g = a
-        .rev()
-        .get_mut(&l0)
-        .expect("l0 adjoint missing")
-        .apply_rev();
+#[test]
+fn test_abs() {
+    //y=|x| + |2x| where x=-1.5, y'=-3
+    let x = Leaf(ValType::F(-1.5)).active();
+    let mut y = Add(
+        Abs(x.clone()),
+        Abs(Mul(constant(ValType::F(2.)), x.clone())),
+    );
+    assert!(eq_f32(y.apply_fwd().into(), 4.5));
+    assert!(eq_f32(y.fwd().apply_fwd().into(), -3.));
+    let mut g = y.rev()[&x].clone();
+    assert!(eq_f32(g.apply_rev().into(), -3.));
+    assert!(eq_f32(partial(&y, &[x.clone(), x.clone()]).into(), 0.));
+
+    //at the kink the subgradient policy decides, 0 by default
+    let mut x1 = x.clone();
+    x1.set_val(ValType::F(0.));
+    assert_eq!(f32::from(y.apply_fwd()), 0.);
+    assert_eq!(f32::from(g.apply_rev()), 0.);
+    let at = |policy| {
+        with_subgradient_policy(policy, || {
+            let d: f32 = y.rev()[&x].clone().apply_rev().into();
+            (d, f32::from(y.fwd().apply_fwd()))
+        })
+    };
+    assert_eq!(at(SubgradientPolicy::Left), (-3., -3.));
+    assert_eq!(at(SubgradientPolicy::Right), (3., 3.));
+    assert_eq!(at(SubgradientPolicy::Average), (0., 0.));
+    //graphs built earlier keep their policy
+    assert_eq!(f32::from(g.apply_rev()), 0.);
+}
 
-    assert!(eq_f32(g.into(), 4f32.ln() * 4f32.powf(3. * 2.) * 3.));
+#[test]
+fn test_max_min() {
+    //hinge loss max(0, 1-y) where y=0.25 and its mirror min(y, 1)
+    let y = Leaf(ValType::F(0.25));
+    let one = constant(ValType::F(1.));
+    let mut hinge = Max(constant(ValType::F(0.)), Minus(one.clone(), y.clone()));
+    let mut m = Min(y.clone(), one.clone());
+    assert!(eq_f32(hinge.apply_fwd().into(), 0.75));
+    assert!(eq_f32(m.apply_fwd().into(), 0.25));
+    let mut dh = hinge.rev()[&y].clone();
+    let mut dm = m.rev()[&y].clone();
+    assert!(eq_f32(dh.apply_rev().into(), -1.));
+    assert!(eq_f32(dm.apply_rev().into(), 1.));
+    assert!(eq_f32(
+        partial(&hinge, std::slice::from_ref(&y)).into(),
+        -1.
+    ));
+
+    //the other branch wins, y=3
+    let mut y1 = y.clone();
+    y1.set_val(ValType::F(3.));
+    assert_eq!(f32::from(hinge.apply_fwd()), 0.);
+    assert_eq!(f32::from(m.apply_fwd()), 1.);
+    assert_eq!(f32::from(dh.apply_rev()), 0.);
+    assert_eq!(f32::from(dm.apply_rev()), 0.);
+
+    //ties: max(a, b) at a=b, the shares of the adjoint always sum to 1
+    let a = Leaf(ValType::F(2.));
+    let b = Leaf(ValType::F(2.));
+    let shares = |policy, max: bool| {
+        with_subgradient_policy(policy, || {
+            let y = if max {
+                Max(a.clone(), b.clone())
+            } else {
+                Min(a.clone(), b.clone())
+            };
+            let adj = y.rev();
+            let da: f32 = adj[&a].clone().apply_rev().into();
+            let db: f32 = adj[&b].clone().apply_rev().into();
+            (da, db)
+        })
+    };
+    for max in [true, false] {
+        assert_eq!(shares(SubgradientPolicy::Zero, max), (0., 1.));
+        assert_eq!(shares(SubgradientPolicy::Left, max), (0., 1.));
+        assert_eq!(shares(SubgradientPolicy::Right, max), (1., 0.));
+        assert_eq!(shares(SubgradientPolicy::Average, max), (0.5, 0.5));
+    }
+
+    //integers stay integers
+    let i = Max(Leaf(ValType::I(3)), Leaf(ValType::I(-4))).apply_fwd();
+    assert_eq!(i, ValType::I(3));
+}
+
+#[test]
+fn test_canonical_order() {
+    use crate::snapshot::to_canonical;
+
+    let a = Leaf(ValType::F(2.)).with_meta("name", "a");
+    let b = Leaf(ValType::F(0.5)).with_meta("name", "b");
+    //f=a*sin(b)+b-a written in two ways
+    let f = |swap: bool| {
+        if swap {
+            Add(Minus(b.clone(), a.clone()), Mul(Sin(b.clone()), a.clone()))
+        } else {
+            Add(Mul(a.clone(), Sin(b.clone())), Minus(b.clone(), a.clone()))
+        }
+    };
+    let (f0, f1) = (f(false), f(true));
+    assert_ne!(f0.structural_hash(), f1.structural_hash());
+
+    set_canonical_order(true);
+    assert!(canonical_order());
+    let (mut g0, mut g1) = (f(false), f(true));
+    set_canonical_order(false);
+    assert_eq!(g0.structural_hash(), g1.structural_hash());
+    assert_eq!(
+        to_canonical(std::slice::from_ref(&g0)),
+        to_canonical(std::slice::from_ref(&g1))
+    );
+    assert_eq!(g0.apply_fwd(), g1.apply_fwd());
+    assert!(eq_f32(g0.apply_fwd().into(), 2. * 0.5f32.sin() - 1.5));
+    //Minus keeps its meaning whichever operand goes first
+    let mut d = g0.rev()[&a].clone();
+    assert!(eq_f32(d.apply_rev().into(), 0.5f32.sin() - 1.));
+
+    //the hash is kept by interior nodes, leaves follow their values
+    assert_eq!(
+        g0.0.borrow().structural.map(|(_, h)| h),
+        Some(g0.structural_hash())
+    );
+    let before = Sin(b.clone()).structural_hash();
+    let mut b1 = b.clone();
+    b1.set_val(ValType::F(1.));
+    assert_ne!(Sin(b.clone()).structural_hash(), before);
 }
@@ -0,0 +1,197 @@
+//! data columns bound to leaves of an objective graph
+//!
+//! the objective is built once for a single sample, with a DataLeaf standing for each
+//! column of the data; BatchObjective evaluates it for every row of the bound minibatch
+//! and averages loss and gradients, so successive batches only rebind the columns
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::core::{Leaf, PtrVWrap};
+use crate::graph::Workspace;
+use crate::param::ParamSet;
+use crate::valtype::ValType;
+
+/// leaf taking the values of a column of data, one row at a time
+///
+/// clones share the bound column, so a clone kept by the caller rebinds the leaf seen by
+/// a BatchObjective. the leaf is frozen: derivatives wrt data are never built
+#[derive(Clone)]
+pub struct DataLeaf {
+    leaf: PtrVWrap,
+    column: Rc<RefCell<Vec<f32>>>,
+}
+
+impl DataLeaf {
+    /// data leaf with an empty column
+    #[track_caller]
+    pub fn new() -> DataLeaf {
+        DataLeaf {
+            leaf: Leaf(ValType::F(0.)).freeze(),
+            column: Default::default(),
+        }
+    }
+
+    /// node to build the objective with, holding the value of the current row
+    pub fn leaf(&self) -> &PtrVWrap {
+        &self.leaf
+    }
+
+    /// replaces the column with the values of the next batch
+    pub fn bind(&mut self, column: &[f32]) {
+        *self.column.borrow_mut() = column.to_vec();
+    }
+
+    /// number of rows of the bound column
+    pub fn len(&self) -> usize {
+        self.column.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.column.borrow().is_empty()
+    }
+
+    fn set_row(&mut self, row: usize) {
+        let v = self.column.borrow()[row];
+        self.leaf.set_val(ValType::F(v));
+    }
+}
+
+impl Default for DataLeaf {
+    #[track_caller]
+    fn default() -> DataLeaf {
+        DataLeaf::new()
+    }
+}
+
+/// mean of a per-sample objective over the rows of the columns bound to its data leaves
+///
+/// the gradient graphs wrt the parameters are built once and reevaluated for every row;
+/// parameter values are read at each evaluation
+pub struct BatchObjective {
+    objective: PtrVWrap,
+    data: Vec<DataLeaf>,
+    grads: Vec<Option<PtrVWrap>>,
+    ws: Workspace,
+}
+
+impl BatchObjective {
+    pub fn new(objective: &PtrVWrap, params: &ParamSet, data: &[DataLeaf]) -> BatchObjective {
+        let adj = objective.rev();
+        BatchObjective {
+            objective: objective.clone(),
+            data: data.to_vec(),
+            grads: params
+                .leaves()
+                .iter()
+                .map(|l| adj.get(l).cloned())
+                .collect(),
+            ws: Workspace::new(),
+        }
+    }
+
+    /// number of rows of the bound batch, panics if the columns differ in length
+    pub fn rows(&self) -> usize {
+        let rows = self.data.first().map_or(0, DataLeaf::len);
+        assert!(
+            self.data.iter().all(|d| d.len() == rows),
+            "data columns differ in length"
+        );
+        rows
+    }
+
+    /// mean loss over the batch
+    pub fn loss(&mut self) -> f32 {
+        let rows = self.rows();
+        let mut sum = 0f64;
+        for row in 0..rows {
+            self.set_row(row);
+            sum += f64::from(self.ws.apply_fwd(&self.objective));
+        }
+        (sum / rows.max(1) as f64) as f32
+    }
+
+    /// mean loss and mean gradient wrt the parameters, in registration order, over the batch
+    pub fn loss_and_grads(&mut self) -> (f32, Vec<f32>) {
+        let rows = self.rows();
+        let mut loss = 0f64;
+        let mut grads = vec![0f64; self.grads.len()];
+        for row in 0..rows {
+            self.set_row(row);
+            let ws = &mut self.ws;
+            loss += f64::from(ws.apply_fwd(&self.objective));
+            for (acc, g) in grads.iter_mut().zip(self.grads.iter()) {
+                if let Some(g) = g {
+                    *acc += f64::from(ws.apply_rev(g));
+                }
+            }
+        }
+        let n = rows.max(1) as f64;
+        (
+            (loss / n) as f32,
+            grads.into_iter().map(|g| (g / n) as f32).collect(),
+        )
+    }
+
+    fn set_row(&mut self, row: usize) {
+        for d in self.data.iter_mut() {
+            d.set_row(row);
+        }
+    }
+}
+
+#[cfg(test)]
+fn eq_f32(a: f32, b: f32) -> bool {
+    (a - b).abs() < 0.01
+}
+
+#[test]
+fn test_batch_objective() {
+    use crate::core::{Add, Minus, Mul};
+    use crate::optim::{Optimizer, Sgd};
+
+    //per-sample loss (w*x+b-y)^2
+    let mut p = ParamSet::new();
+    let w = p.add("w", 1.);
+    let b = p.add("b", 0.);
+    let (mut x, mut y) = (DataLeaf::new(), DataLeaf::new());
+    let r = Minus(Add(Mul(w, x.leaf().clone()), b), y.leaf().clone());
+    let loss = Mul(r.clone(), r);
+    let mut obj = BatchObjective::new(&loss, &p, &[x.clone(), y.clone()]);
+    assert_eq!(obj.rows(), 0);
+
+    //residuals -1, -2, -3: mean loss 14/3, dw=2*mean(r*x)=-28/3, db=2*mean(r)=-4
+    x.bind(&[1., 2., 3.]);
+    y.bind(&[2., 4., 6.]);
+    assert_eq!(obj.rows(), 3);
+    let (l, g) = obj.loss_and_grads();
+    assert!(eq_f32(l, 14. / 3.));
+    assert!(eq_f32(g[0], -28. / 3.));
+    assert!(eq_f32(g[1], -4.));
+
+    //next batch, same graph
+    x.bind(&[0., 1.]);
+    y.bind(&[1., 1.]);
+    let (l, g) = obj.loss_and_grads();
+    assert!(eq_f32(l, 0.5));
+    assert!(eq_f32(g[0], 0.));
+    assert!(eq_f32(g[1], -1.));
+    assert!(eq_f32(obj.loss(), l));
+
+    //minibatch descent on y=2x+1
+    let batches = [[0f32, 1.], [2., 3.], [-1., 0.5]];
+    let mut sgd = Sgd { lr: 0.05 };
+    for _ in 0..200 {
+        for batch in batches.iter() {
+            x.bind(batch);
+            y.bind(&[2. * batch[0] + 1., 2. * batch[1] + 1.]);
+            let (_, g) = obj.loss_and_grads();
+            let mut values = p.values();
+            sgd.step(&mut values, &g);
+            p.set_values(&values);
+        }
+    }
+    let v = p.values();
+    assert!(eq_f32(v[0], 2.));
+    assert!(eq_f32(v[1], 1.));
+}
@@ -0,0 +1,570 @@
+//! probability distributions as graph expressions
+//!
+//! parameters are arbitrary nodes, usually leaves of a ParamSet, and log densities and kl
+//! divergences are built from them as ordinary graphs, so variational objectives can be
+//! differentiated wrt the parameters with fwd and rev
+//!
+//! samples are reparameterized where possible: the noise is drawn from an Rng and embedded
+//! as a constant, so the sample carries derivatives wrt the parameters through the
+//! reparameterization, and is a plain constant otherwise
+//!
+//! samples without a reparameterization can be drawn through a StochasticGraph, which
+//! records their log densities so surrogate_loss can add the score-function (REINFORCE)
+//! terms to a cost
+//!
+//! expectations over small discrete distributions are taken exactly with Expect instead
+
+#![allow(non_snake_case)]
+
+use crate::core::{
+    constant, Add, Digamma, Div, Dot, KlBernoulli, KlNormal, Ln, LnGamma, Minus, Mul, PtrVWrap,
+};
+use crate::valtype::ValType;
+
+/// xorshift64* generator for the noise of samples
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        //a zero state would stay zero, so the one seed mapping to it gets a fixed state
+        let state = match seed ^ 0x9e37_79b9_7f4a_7c15 {
+            0 => 0x2545_f491_4f6c_dd1d,
+            s => s,
+        };
+        Rng { state }
+    }
+
+    /// uniform in (0, 1)
+    pub fn uniform(&mut self) -> f64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        let r = self.state.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        ((r >> 11) as f64 + 0.5) / (1u64 << 53) as f64
+    }
+
+    /// standard normal by box-muller
+    pub fn normal(&mut self) -> f64 {
+        let (u, v) = (self.uniform(), self.uniform());
+        (-2. * u.ln()).sqrt() * (2. * std::f64::consts::PI * v).cos()
+    }
+
+    /// gamma with the given shape and unit rate by marsaglia-tsang
+    pub fn gamma(&mut self, shape: f64) -> f64 {
+        assert!(shape > 0., "gamma shape must be positive");
+        if shape < 1. {
+            //gamma(a) = gamma(a+1) * u^(1/a)
+            return self.gamma(shape + 1.) * self.uniform().powf(1. / shape);
+        }
+        let d = shape - 1. / 3.;
+        let c = 1. / (9. * d).sqrt();
+        loop {
+            let x = self.normal();
+            let v = (1. + c * x).powi(3);
+            if v <= 0. {
+                continue;
+            }
+            let u = self.uniform();
+            if u.ln() < 0.5 * x * x + d - d * v + d * v.ln() {
+                return d * v;
+            }
+        }
+    }
+}
+
+/// distribution whose parameters are graph nodes
+pub trait Distribution {
+    /// log density (or mass) at x
+    fn log_prob(&self, x: &PtrVWrap) -> PtrVWrap;
+
+    /// sample at the current parameter values, differentiable wrt the parameters where the
+    /// distribution admits a reparameterization
+    fn sample(&self, rng: &mut Rng) -> PtrVWrap;
+
+    /// whether sample carries the full derivatives wrt all parameters
+    fn reparameterized(&self) -> bool {
+        false
+    }
+
+    /// kl divergence from other to self, KL(self || other)
+    fn kl(&self, other: &Self) -> PtrVWrap;
+}
+
+#[track_caller]
+fn c(v: f32) -> PtrVWrap {
+    constant(ValType::F(v))
+}
+
+/// current value of a parameter node
+fn value(p: &PtrVWrap) -> f64 {
+    p.clone().apply_fwd().into()
+}
+
+/// ln(B(a, b)) = ln(gamma(a)) + ln(gamma(b)) - ln(gamma(a+b))
+#[track_caller]
+fn ln_beta(a: &PtrVWrap, b: &PtrVWrap) -> PtrVWrap {
+    Minus(
+        Add(LnGamma(a.clone()), LnGamma(b.clone())),
+        LnGamma(Add(a.clone(), b.clone())),
+    )
+}
+
+/// normal with mean loc and standard deviation scale
+#[derive(Debug, Clone)]
+pub struct Normal {
+    pub loc: PtrVWrap,
+    pub scale: PtrVWrap,
+}
+
+impl Normal {
+    pub fn new(loc: PtrVWrap, scale: PtrVWrap) -> Normal {
+        Normal { loc, scale }
+    }
+}
+
+impl Distribution for Normal {
+    fn log_prob(&self, x: &PtrVWrap) -> PtrVWrap {
+        //-(x-mu)^2/(2 s^2) - ln(s) - ln(2 pi)/2
+        let z = Div(Minus(x.clone(), self.loc.clone()), self.scale.clone());
+        let half_ln_2pi = 0.5 * (2. * std::f32::consts::PI).ln();
+        Minus(
+            Mul(c(-0.5), Mul(z.clone(), z)),
+            Add(Ln(self.scale.clone()), c(half_ln_2pi)),
+        )
+    }
+
+    /// loc + scale * eps with eps ~ N(0, 1)
+    fn sample(&self, rng: &mut Rng) -> PtrVWrap {
+        let eps = c(rng.normal() as f32);
+        Add(self.loc.clone(), Mul(self.scale.clone(), eps))
+    }
+
+    fn reparameterized(&self) -> bool {
+        true
+    }
+
+    fn kl(&self, other: &Normal) -> PtrVWrap {
+        KlNormal(
+            self.loc.clone(),
+            self.scale.clone(),
+            other.loc.clone(),
+            other.scale.clone(),
+        )
+    }
+}
+
+/// bernoulli with probability probs of 1
+#[derive(Debug, Clone)]
+pub struct Bernoulli {
+    pub probs: PtrVWrap,
+}
+
+impl Bernoulli {
+    pub fn new(probs: PtrVWrap) -> Bernoulli {
+        Bernoulli { probs }
+    }
+}
+
+impl Distribution for Bernoulli {
+    fn log_prob(&self, x: &PtrVWrap) -> PtrVWrap {
+        //x ln(p) + (1-x) ln(1-p)
+        Add(
+            Mul(x.clone(), Ln(self.probs.clone())),
+            Mul(
+                Minus(c(1.), x.clone()),
+                Ln(Minus(c(1.), self.probs.clone())),
+            ),
+        )
+    }
+
+    /// constant 0 or 1, discrete samples carry no derivatives
+    fn sample(&self, rng: &mut Rng) -> PtrVWrap {
+        let one = rng.uniform() < value(&self.probs);
+        c(if one { 1. } else { 0. })
+    }
+
+    fn kl(&self, other: &Bernoulli) -> PtrVWrap {
+        KlBernoulli(self.probs.clone(), other.probs.clone())
+    }
+}
+
+/// gamma with shape concentration and inverse scale rate
+#[derive(Debug, Clone)]
+pub struct Gamma {
+    pub concentration: PtrVWrap,
+    pub rate: PtrVWrap,
+}
+
+impl Gamma {
+    pub fn new(concentration: PtrVWrap, rate: PtrVWrap) -> Gamma {
+        Gamma {
+            concentration,
+            rate,
+        }
+    }
+}
+
+impl Distribution for Gamma {
+    fn log_prob(&self, x: &PtrVWrap) -> PtrVWrap {
+        //a ln(b) + (a-1) ln(x) - b x - ln(gamma(a))
+        let (a, b) = (&self.concentration, &self.rate);
+        Minus(
+            Add(
+                Mul(a.clone(), Ln(b.clone())),
+                Mul(Minus(a.clone(), c(1.)), Ln(x.clone())),
+            ),
+            Add(Mul(b.clone(), x.clone()), LnGamma(a.clone())),
+        )
+    }
+
+    /// g / rate with g ~ gamma(concentration, 1), differentiable wrt rate only
+    fn sample(&self, rng: &mut Rng) -> PtrVWrap {
+        let g = rng.gamma(value(&self.concentration));
+        Div(c(g as f32), self.rate.clone())
+    }
+
+    fn kl(&self, other: &Gamma) -> PtrVWrap {
+        //(a1-a2) psi(a1) - ln(gamma(a1)) + ln(gamma(a2)) + a2 (ln(b1) - ln(b2))
+        //  + a1 (b2-b1)/b1
+        let (a1, b1) = (&self.concentration, &self.rate);
+        let (a2, b2) = (&other.concentration, &other.rate);
+        Add(
+            Add(
+                Mul(Minus(a1.clone(), a2.clone()), Digamma(a1.clone())),
+                Minus(LnGamma(a2.clone()), LnGamma(a1.clone())),
+            ),
+            Add(
+                Mul(a2.clone(), Minus(Ln(b1.clone()), Ln(b2.clone()))),
+                Div(Mul(a1.clone(), Minus(b2.clone(), b1.clone())), b1.clone()),
+            ),
+        )
+    }
+}
+
+/// beta on (0, 1) with shape parameters alpha and beta
+#[derive(Debug, Clone)]
+pub struct Beta {
+    pub alpha: PtrVWrap,
+    pub beta: PtrVWrap,
+}
+
+impl Beta {
+    pub fn new(alpha: PtrVWrap, beta: PtrVWrap) -> Beta {
+        Beta { alpha, beta }
+    }
+}
+
+impl Distribution for Beta {
+    fn log_prob(&self, x: &PtrVWrap) -> PtrVWrap {
+        //(a-1) ln(x) + (b-1) ln(1-x) - ln(B(a, b))
+        let (a, b) = (&self.alpha, &self.beta);
+        Minus(
+            Add(
+                Mul(Minus(a.clone(), c(1.)), Ln(x.clone())),
+                Mul(Minus(b.clone(), c(1.)), Ln(Minus(c(1.), x.clone()))),
+            ),
+            ln_beta(a, b),
+        )
+    }
+
+    /// constant g1/(g1+g2) with g1, g2 gamma distributed, carrying no derivatives
+    fn sample(&self, rng: &mut Rng) -> PtrVWrap {
+        let g1 = rng.gamma(value(&self.alpha));
+        let g2 = rng.gamma(value(&self.beta));
+        c((g1 / (g1 + g2)) as f32)
+    }
+
+    fn kl(&self, other: &Beta) -> PtrVWrap {
+        //ln(B(a2, b2)) - ln(B(a1, b1)) + (a1-a2) psi(a1) + (b1-b2) psi(b1)
+        //  + (a2-a1+b2-b1) psi(a1+b1)
+        let (a1, b1) = (&self.alpha, &self.beta);
+        let (a2, b2) = (&other.alpha, &other.beta);
+        let da = Minus(a1.clone(), a2.clone());
+        let db = Minus(b1.clone(), b2.clone());
+        Add(
+            Add(
+                Minus(ln_beta(a2, b2), ln_beta(a1, b1)),
+                Add(
+                    Mul(da.clone(), Digamma(a1.clone())),
+                    Mul(db.clone(), Digamma(b1.clone())),
+                ),
+            ),
+            Mul(
+                Minus(c(0.), Add(da, db)),
+                Digamma(Add(a1.clone(), b1.clone())),
+            ),
+        )
+    }
+}
+
+/// expectation sum(p_i v_i) of values under the discrete distribution probs as a single
+/// node, differentiable wrt both the probabilities and the values
+///
+/// probs are used as given, normalizing them (e.g. by a softmax) is up to the caller
+#[allow(dead_code)]
+#[track_caller]
+pub fn Expect(probs: &[PtrVWrap], values: &[PtrVWrap]) -> PtrVWrap {
+    assert!(!probs.is_empty(), "Expect needs at least one outcome");
+    assert_eq!(probs.len(), values.len(), "Expect length mismatch");
+    Dot(probs.to_vec(), values.to_vec())
+}
+
+/// sample drawn without a reparameterization, with the log density of the drawn value
+#[derive(Debug, Clone)]
+pub struct StochasticNode {
+    /// drawn value, a constant
+    pub value: PtrVWrap,
+    /// log density of value as a graph over the distribution parameters
+    pub log_prob: PtrVWrap,
+}
+
+/// samples of a stochastic computation, recording the log densities of the samples that
+/// carry no derivatives
+#[derive(Debug, Clone, Default)]
+pub struct StochasticGraph {
+    nodes: Vec<StochasticNode>,
+    /// subtracted from the cost in the score-function terms to reduce their variance
+    pub baseline: f32,
+}
+
+impl StochasticGraph {
+    pub fn new() -> StochasticGraph {
+        Default::default()
+    }
+
+    /// sample of d, reparameterized if d supports it and recorded as a StochasticNode
+    /// otherwise
+    pub fn sample<D: Distribution>(&mut self, d: &D, rng: &mut Rng) -> PtrVWrap {
+        let x = d.sample(rng);
+        if d.reparameterized() {
+            return x;
+        }
+        //detached so derivatives only come from the score-function terms
+        let v = c(value(&x) as f32);
+        self.nodes.push(StochasticNode {
+            value: v.clone(),
+            log_prob: d.log_prob(&v),
+        });
+        v
+    }
+
+    pub fn nodes(&self) -> &[StochasticNode] {
+        &self.nodes
+    }
+
+    /// cost + (cost - baseline) * sum(log_prob - log_prob_value) with the factors other than
+    /// log_prob held constant at their current values
+    ///
+    /// the value equals cost, and rev gives the pathwise derivatives of cost plus the
+    /// score-function estimate (cost - baseline) * sum(grad log_prob) for the recorded samples
+    pub fn surrogate_loss(&self, cost: &PtrVWrap) -> PtrVWrap {
+        if self.nodes.is_empty() {
+            return cost.clone();
+        }
+        let weight = value(cost) as f32 - self.baseline;
+        let lp = self
+            .nodes
+            .iter()
+            .map(|n| n.log_prob.clone())
+            .reduce(Add)
+            .expect("no stochastic nodes");
+        let lp_value = value(&lp) as f32;
+        Add(cost.clone(), Mul(c(weight), Minus(lp, c(lp_value))))
+    }
+}
+
+#[cfg(test)]
+use crate::core::{partial, Leaf};
+
+#[cfg(test)]
+fn eq_f32(a: f32, b: f32) -> bool {
+    (a - b).abs() < 0.01
+}
+
+#[cfg(test)]
+fn eval(mut n: PtrVWrap) -> f32 {
+    n.apply_fwd().into()
+}
+
+#[cfg(test)]
+fn dy(y: &PtrVWrap, x: &PtrVWrap) -> f32 {
+    partial(y, std::slice::from_ref(x)).into()
+}
+
+#[test]
+fn test_normal() {
+    //N(1, 2) at x=2: -1/8 - ln(2) - ln(2 pi)/2
+    let mu = Leaf(ValType::F(1.));
+    let s = Leaf(ValType::F(2.));
+    let x = Leaf(ValType::F(2.));
+    let n = Normal::new(mu.clone(), s.clone());
+    let lp = n.log_prob(&x);
+    assert!(eq_f32(eval(lp.clone()), -1.7371));
+    //d/dmu=(x-mu)/s^2, d/ds=(x-mu)^2/s^3-1/s
+    assert!(eq_f32(dy(&lp, &mu), 0.25));
+    assert!(eq_f32(dy(&lp, &s), -0.375));
+
+    //kl(N(1,2) || N(0,1)) = -ln(2) + (4+1)/2 - 1/2, d/dmu=mu, d/ds=-1/s+s
+    let p = Normal::new(Leaf(ValType::F(0.)), Leaf(ValType::F(1.)));
+    let kl = n.kl(&p);
+    assert!(eq_f32(eval(kl.clone()), 2. - 2f32.ln()));
+    assert!(eq_f32(dy(&kl, &mu), 1.));
+    assert!(eq_f32(dy(&kl, &s), 1.5));
+    assert!(eq_f32(eval(n.kl(&n)), 0.));
+
+    //reparameterized samples: d/dmu=1, d/ds=eps=(x-mu)/s
+    let mut rng = Rng::new(7);
+    let mut mean = 0.;
+    for _ in 0..2000 {
+        let x = n.sample(&mut rng);
+        let v = eval(x.clone());
+        mean += v / 2000.;
+        assert!(eq_f32(dy(&x, &mu), 1.));
+        assert!(eq_f32(dy(&x, &s), (v - 1.) / 2.));
+    }
+    assert!((mean - 1.).abs() < 0.15);
+}
+
+#[test]
+fn test_bernoulli() {
+    let p = Leaf(ValType::F(0.25));
+    let b = Bernoulli::new(p.clone());
+    assert!(eq_f32(eval(b.log_prob(&c(1.))), 0.25f32.ln()));
+    assert!(eq_f32(eval(b.log_prob(&c(0.))), 0.75f32.ln()));
+    //d/dp at x=0 is -1/(1-p)
+    assert!(eq_f32(dy(&b.log_prob(&c(0.)), &p), -4. / 3.));
+
+    let q = Bernoulli::new(Leaf(ValType::F(0.5)));
+    let kl = b.kl(&q);
+    assert!(eq_f32(
+        eval(kl.clone()),
+        0.25 * 0.5f32.ln() + 0.75 * 1.5f32.ln()
+    ));
+    //d/dp = ln(p/q) - ln((1-p)/(1-q))
+    assert!(eq_f32(dy(&kl, &p), (1f32 / 3.).ln()));
+
+    let mut rng = Rng::new(3);
+    let ones: f32 = (0..2000).map(|_| eval(b.sample(&mut rng))).sum();
+    assert!((ones / 2000. - 0.25).abs() < 0.05);
+}
+
+#[test]
+fn test_gamma() {
+    //gamma(2, 3) at x=1: 2 ln(3) - 3 - ln(gamma(2))
+    let a = Leaf(ValType::F(2.));
+    let b = Leaf(ValType::F(3.));
+    let x = Leaf(ValType::F(1.));
+    let g = Gamma::new(a.clone(), b.clone());
+    let lp = g.log_prob(&x);
+    assert!(eq_f32(eval(lp.clone()), 2. * 3f32.ln() - 3.));
+    //d/da=ln(b)+ln(x)-psi(a), d/db=a/b-x
+    assert!(eq_f32(dy(&lp, &a), 3f32.ln() - 0.4228));
+    assert!(eq_f32(dy(&lp, &b), -1. / 3.));
+
+    let h = Gamma::new(Leaf(ValType::F(1.)), Leaf(ValType::F(1.)));
+    let kl = g.kl(&h);
+    //(2-1) psi(2) - ln(gamma(2)) + ln(gamma(1)) + ln(3) + 2 (1-3)/3
+    assert!(eq_f32(eval(kl.clone()), 0.4228 + 3f32.ln() - 4. / 3.));
+    assert!(eq_f32(eval(g.kl(&g)), 0.));
+    //d/db = 1/b - a/b^2
+    assert!(eq_f32(dy(&kl, &b), 1. / 3. - 2. / 9.));
+
+    //mean a/b, samples scale as 1/b
+    let mut rng = Rng::new(11);
+    let mut mean = 0.;
+    for _ in 0..2000 {
+        let x = g.sample(&mut rng);
+        let v = eval(x.clone());
+        mean += v / 2000.;
+        assert!(eq_f32(dy(&x, &b), -v / 3.));
+    }
+    assert!((mean - 2. / 3.).abs() < 0.05);
+}
+
+#[test]
+fn test_beta() {
+    //beta(2, 3) at x=0.5: ln(0.5) + 2 ln(0.5) + ln(12)
+    let a = Leaf(ValType::F(2.));
+    let b = Leaf(ValType::F(3.));
+    let x = Leaf(ValType::F(0.5));
+    let d = Beta::new(a.clone(), b.clone());
+    let lp = d.log_prob(&x);
+    assert!(eq_f32(eval(lp.clone()), 3. * 0.5f32.ln() + 12f32.ln()));
+    //d/da = ln(x) - psi(a) + psi(a+b) = ln(0.5) - 0.4228 + 1.5061
+    assert!(eq_f32(dy(&lp, &a), 0.5f32.ln() + 1.0833));
+
+    //kl to the uniform beta(1, 1) is the negative entropy
+    let u = Beta::new(Leaf(ValType::F(1.)), Leaf(ValType::F(1.)));
+    assert!(eq_f32(eval(d.kl(&u)), 0.2349));
+    assert!(eq_f32(eval(d.kl(&d)), 0.));
+    assert!(eq_f32(eval(u.kl(&u)), 0.));
+
+    let mut rng = Rng::new(5);
+    let mean: f32 = (0..2000).map(|_| eval(d.sample(&mut rng))).sum::<f32>() / 2000.;
+    assert!((mean - 0.4).abs() < 0.03);
+}
+
+#[test]
+fn test_rng_zero_state() {
+    //the seed cancelling the mixing constant still gives a varying sequence
+    let mut rng = Rng::new(0x9e37_79b9_7f4a_7c15);
+    let u: Vec<f64> = (0..4).map(|_| rng.uniform()).collect();
+    assert!(u.iter().all(|&x| x > 0. && x < 1.));
+    assert!(u.windows(2).all(|w| w[0] != w[1]));
+}
+
+#[test]
+fn test_surrogate_loss() {
+    //cost=x*x0 with x~Bernoulli(p), p=0.3, x0=2: E[cost]=2p, d/dp=2, d/dx0=p
+    let p = Leaf(ValType::F(0.3));
+    let x0 = Leaf(ValType::F(2.));
+    let b = Bernoulli::new(p.clone());
+    let mut rng = Rng::new(1);
+    let (mut gp, mut gx) = (0., 0.);
+    for _ in 0..4000 {
+        let mut g = StochasticGraph::new();
+        let x = g.sample(&b, &mut rng);
+        assert_eq!(g.nodes().len(), 1);
+        let cost = Mul(x, x0.clone());
+        let s = g.surrogate_loss(&cost);
+        assert!(eq_f32(eval(s.clone()), eval(cost)));
+        gp += dy(&s, &p) / 4000.;
+        gx += dy(&s, &x0) / 4000.;
+    }
+    assert!((gp - 2.).abs() < 0.2);
+    assert!((gx - 0.3).abs() < 0.05);
+
+    //reparameterized samples are not recorded and keep their pathwise derivatives
+    let mu = Leaf(ValType::F(1.));
+    let n = Normal::new(mu.clone(), Leaf(ValType::F(0.5)));
+    let mut g = StochasticGraph::new();
+    g.baseline = 1.;
+    let x = g.sample(&n, &mut rng);
+    assert!(g.nodes().is_empty());
+    let cost = Mul(x.clone(), x.clone());
+    let s = g.surrogate_loss(&cost);
+    assert!(eq_f32(dy(&s, &mu), 2. * eval(x)));
+}
+
+#[test]
+fn test_expect() {
+    //E=0.2*x+0.3*x^2+0.5 where x=2
+    let p = [
+        Leaf(ValType::F(0.2)),
+        Leaf(ValType::F(0.3)),
+        Leaf(ValType::F(0.5)),
+    ];
+    let x = Leaf(ValType::F(2.));
+    let v = [x.clone(), Mul(x.clone(), x.clone()), c(1.)];
+    let e = Expect(&p, &v);
+    assert!(eq_f32(eval(e.clone()), 2.1));
+    //dE/dp_i=v_i, dE/dx=0.2+0.6x, d2E/dx2=0.6
+    for (pi, vi) in [2f32, 4., 1.].iter().enumerate() {
+        assert!(eq_f32(dy(&e, &p[pi]), *vi));
+    }
+    assert!(eq_f32(dy(&e, &x), 1.4));
+    assert!(eq_f32(partial(&e, &[x.clone(), x]).into(), 0.6));
+}
@@ -0,0 +1,243 @@
+//! statically typed expressions
+//!
+//! an expression such as `ExprMul<ExprLeaf, ExprSin<ExprLeaf>>` is a plain value whose
+//! type encodes the formula, so it is evaluated without building a graph for it, while
+//! lower builds the equivalent dynamic graph when higher derivatives or the other graph
+//! tools are needed
+//!
+//! values and local derivatives of each operation come from the core op itself: its
+//! graph and the adjoint graph built by rev are recorded once per op and thread and
+//! evaluated on f64, so both front-ends share one set of derivative rules
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops;
+
+use crate::core::{self, constant, PtrVWrap};
+use crate::scalar::eval_roots;
+use crate::valtype::ValType;
+
+/// graph of a core op over its own leaves, followed by the adjoints of the leaves
+struct Rule {
+    leaves: Vec<PtrVWrap>,
+    roots: Vec<PtrVWrap>,
+}
+
+thread_local! {
+    static RULES: RefCell<HashMap<&'static str, Rule>> = RefCell::new(HashMap::new());
+}
+
+/// value and partial derivatives of the core op built by build at x
+fn apply_rule(name: &'static str, build: fn(&[PtrVWrap]) -> PtrVWrap, x: &[f64]) -> Vec<f64> {
+    RULES.with(|r| {
+        let mut rules = r.borrow_mut();
+        let rule = rules.entry(name).or_insert_with(|| {
+            let leaves: Vec<PtrVWrap> = x.iter().map(|_| core::Leaf(ValType::D(0.))).collect();
+            let y = build(&leaves);
+            let adjoints: Vec<PtrVWrap> = leaves
+                .iter()
+                .map(|l| y.rev_wrt(l).unwrap_or_else(|| constant(ValType::D(0.))))
+                .collect();
+            let mut roots = vec![y];
+            roots.extend(adjoints);
+            Rule { leaves, roots }
+        });
+        eval_roots(&rule.roots, &rule.leaves, x)
+            .unwrap_or_else(|e| panic!("{} has no scalar rule: {}", name, e))
+    })
+}
+
+/// value and gradient wrt N variables
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Dual<const N: usize> {
+    pub val: f64,
+    pub grad: [f64; N],
+}
+
+impl<const N: usize> Dual<N> {
+    fn constant(val: f64) -> Self {
+        Dual { val, grad: [0.; N] }
+    }
+
+    /// core op built by build applied to args, with the chain rule over its partials
+    fn apply(name: &'static str, build: fn(&[PtrVWrap]) -> PtrVWrap, args: &[Self]) -> Self {
+        let x: Vec<f64> = args.iter().map(|a| a.val).collect();
+        let r = apply_rule(name, build, &x);
+        let mut grad = [0.; N];
+        for (a, d) in args.iter().zip(r[1..].iter()) {
+            grad.iter_mut()
+                .zip(a.grad.iter())
+                .for_each(|(g, ag)| *g += d * ag);
+        }
+        Dual { val: r[0], grad }
+    }
+}
+
+macro_rules! dual_op {
+    ($trait:ident, $method:ident, $build:path) => {
+        impl<const N: usize> ops::$trait for Dual<N> {
+            type Output = Self;
+            fn $method(self, o: Self) -> Self {
+                Dual::apply(
+                    stringify!($build),
+                    |x| $build(x[0].clone(), x[1].clone()),
+                    &[self, o],
+                )
+            }
+        }
+    };
+}
+
+dual_op!(Add, add, core::Add);
+dual_op!(Mul, mul, core::Mul);
+dual_op!(Div, div, core::Div);
+
+/// formula whose structure is known at compile time
+pub trait Expr: Copy {
+    /// value and gradient wrt x
+    fn dual<const N: usize>(&self, x: &[f64; N]) -> Dual<N>;
+
+    /// equivalent dynamic graph where ExprLeaf(i) is leaves[i]
+    fn lower(&self, leaves: &[PtrVWrap]) -> PtrVWrap;
+
+    /// value at x
+    fn eval<const N: usize>(&self, x: &[f64; N]) -> f64 {
+        self.dual(x).val
+    }
+
+    /// gradient wrt x
+    fn grad<const N: usize>(&self, x: &[f64; N]) -> [f64; N] {
+        self.dual(x).grad
+    }
+}
+
+/// variable x[i]
+#[derive(Debug, Clone, Copy)]
+pub struct ExprLeaf(pub usize);
+
+impl Expr for ExprLeaf {
+    fn dual<const N: usize>(&self, x: &[f64; N]) -> Dual<N> {
+        let mut grad = [0.; N];
+        grad[self.0] = 1.;
+        Dual {
+            val: x[self.0],
+            grad,
+        }
+    }
+    fn lower(&self, leaves: &[PtrVWrap]) -> PtrVWrap {
+        leaves[self.0].clone()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ExprConst(pub f64);
+
+impl Expr for ExprConst {
+    fn dual<const N: usize>(&self, _x: &[f64; N]) -> Dual<N> {
+        Dual::constant(self.0)
+    }
+    fn lower(&self, _leaves: &[PtrVWrap]) -> PtrVWrap {
+        constant(ValType::F(self.0 as f32))
+    }
+}
+
+macro_rules! binary_expr {
+    ($name:ident, $op:tt, $build:path) => {
+        #[derive(Debug, Clone, Copy)]
+        pub struct $name<A, B>(pub A, pub B);
+
+        impl<A: Expr, B: Expr> Expr for $name<A, B> {
+            fn dual<const N: usize>(&self, x: &[f64; N]) -> Dual<N> {
+                self.0.dual(x) $op self.1.dual(x)
+            }
+            fn lower(&self, leaves: &[PtrVWrap]) -> PtrVWrap {
+                $build(self.0.lower(leaves), self.1.lower(leaves))
+            }
+        }
+    };
+}
+
+binary_expr!(ExprAdd, +, core::Add);
+binary_expr!(ExprMul, *, core::Mul);
+binary_expr!(ExprDiv, /, core::Div);
+
+macro_rules! unary_expr {
+    ($name:ident, $build:path) => {
+        #[derive(Debug, Clone, Copy)]
+        pub struct $name<A>(pub A);
+
+        impl<A: Expr> Expr for $name<A> {
+            fn dual<const N: usize>(&self, x: &[f64; N]) -> Dual<N> {
+                Dual::apply(
+                    stringify!($build),
+                    |x| $build(x[0].clone()),
+                    &[self.0.dual(x)],
+                )
+            }
+            fn lower(&self, leaves: &[PtrVWrap]) -> PtrVWrap {
+                $build(self.0.lower(leaves))
+            }
+        }
+    };
+}
+
+unary_expr!(ExprSin, core::Sin);
+unary_expr!(ExprCos, core::Cos);
+unary_expr!(ExprTan, core::Tan);
+unary_expr!(ExprExp, core::Exp);
+unary_expr!(ExprLn, core::Ln);
+
+#[cfg(test)]
+fn eq_f32(a: f32, b: f32) -> bool {
+    (a - b).abs() < 0.01
+}
+
+#[test]
+fn test_expr_grad() {
+    //y=x0*sin(x1)/exp(x0)+ln(x1)*2 where x0=1, x1=0.5
+    let y = ExprAdd(
+        ExprDiv(
+            ExprMul(ExprLeaf(0), ExprSin(ExprLeaf(1))),
+            ExprExp(ExprLeaf(0)),
+        ),
+        ExprMul(ExprLn(ExprLeaf(1)), ExprConst(2.)),
+    );
+    let x = [1., 0.5];
+    let d = y.dual(&x);
+    let e = (-1f64).exp();
+    assert!(eq_f32(
+        y.eval(&x) as f32,
+        (0.5f64.sin() * e + 2. * 0.5f64.ln()) as f32
+    ));
+    //dy/dx0=sin(x1)(1-x0)/exp(x0), dy/dx1=x0*cos(x1)/exp(x0)+2/x1
+    assert!(eq_f32(d.grad[0] as f32, 0.));
+    assert!(eq_f32(d.grad[1] as f32, (0.5f64.cos() * e + 4.) as f32));
+
+    //one rule per core op, recorded on first use and shared by later evaluations
+    y.dual(&[2., 1.5]);
+    assert_eq!(RULES.with(|r| r.borrow().len()), 6);
+    let a = Dual {
+        val: 3.,
+        grad: [1., 0.],
+    };
+    let b = Dual {
+        val: 2.,
+        grad: [0., 1.],
+    };
+    assert_eq!((a / b).grad, [0.5, -0.75]);
+
+    //lowered graph gives the same derivatives
+    let leaves = [core::Leaf(ValType::F(1.)), core::Leaf(ValType::F(0.5))];
+    let mut g = y.lower(&leaves);
+    assert!(eq_f32(g.apply_fwd().into(), y.eval(&x) as f32));
+    let mut adj = g.rev();
+    for (l, d) in leaves.iter().zip(d.grad.iter()) {
+        let a: f32 = adj.get_mut(l).expect("adjoint missing").apply_rev().into();
+        assert!(eq_f32(a, *d as f32));
+    }
+
+    //tan and cos in a single variable
+    let t = ExprMul(ExprTan(ExprLeaf(0)), ExprCos(ExprLeaf(0)));
+    let d = t.grad(&[0.3]);
+    assert!(eq_f32(d[0] as f32, 0.3f32.cos()));
+}
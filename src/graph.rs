@@ -0,0 +1,755 @@
+//! frozen run-time representation of graphs built from PtrVWrap nodes
+//!
+//! compilation orders the nodes once, resolves inputs to buffer slots and builds the
+//! adjoint graphs of the chosen leaves, so repeated evaluation skips graph traversal and
+//! never touches the builder graph
+//!
+//! values are cached between runs and only nodes downstream of leaves whose value changed
+//! are recomputed
+//!
+//! nodes are grouped into levels whose members only depend on earlier levels, so a level
+//! can be split across threads, each writing its own partition of the value buffer
+//!
+//! for graphs too large to hold every value, an out-of-core run keeps a bounded number of
+//! values resident and spills the rest to a temporary file
+//!
+//! Workspace offers the same reuse for evaluating builder graphs in place
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::core::{topo_order, EvalFn, PtrVWrap};
+use crate::valtype::ValType;
+
+/// levels narrower than this are evaluated on the calling thread
+const PARALLEL_MIN_WIDTH: usize = 64;
+
+struct CompiledNode {
+    /// buffer slots of the input dependencies
+    inp: Vec<usize>,
+    /// value stored on the builder node when compiled, used by constants
+    val: Option<ValType>,
+    /// activity flag of the builder node
+    active: bool,
+    /// position among the run-time inputs for compiled leaves
+    input: Option<usize>,
+}
+
+/// immutable topology compiled from the graph of one output
+///
+/// nodes are stored in evaluation order; the nodes needed for the output come first,
+/// followed by those only needed for the gradient, each part sorted by level
+pub struct CompiledGraph {
+    nodes: Vec<CompiledNode>,
+    /// evaluation function of each node
+    fns: Vec<EvalFn>,
+    /// one value buffer slot per node, reused between runs
+    vals: Vec<ValType>,
+    /// slot of each input leaf, None for leaves the output does not depend on
+    leaves: Vec<Option<usize>>,
+    output: usize,
+    /// number of nodes evaluated for the output alone
+    eval_len: usize,
+    /// slot of the adjoint of each input leaf
+    grads: Vec<Option<usize>>,
+    /// contiguous slot ranges of mutually independent nodes
+    levels: Vec<(usize, usize)>,
+    /// slots of the nodes consuming each node
+    dependents: Vec<Vec<usize>>,
+    /// nodes whose cached value is out of date
+    dirty: Vec<bool>,
+    /// leaf values of the previous run
+    last_inputs: Vec<ValType>,
+    /// number of nodes evaluated by the previous run
+    recomputed: usize,
+    /// number of values written to disk by the previous out-of-core run
+    spilled: usize,
+}
+
+/// bytes per spilled value: a type tag and 8 bytes of payload
+const SPILL_RECORD: u64 = 9;
+
+/// temporary file holding spilled values at fixed offsets by slot, removed on drop
+struct SpillFile {
+    path: std::path::PathBuf,
+    file: File,
+}
+
+impl SpillFile {
+    fn create() -> io::Result<SpillFile> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "dynagrad-spill-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        Ok(SpillFile { path, file })
+    }
+
+    fn write(&mut self, slot: usize, v: ValType) -> io::Result<()> {
+        let (tag, bits) = match v {
+            ValType::F(x) => (0u8, x.to_bits() as u64),
+            ValType::D(x) => (1, x.to_bits()),
+            ValType::I(x) => (2, x as u32 as u64),
+            ValType::L(x) => (3, x as u64),
+        };
+        let mut rec = [0u8; SPILL_RECORD as usize];
+        rec[0] = tag;
+        rec[1..].copy_from_slice(&bits.to_le_bytes());
+        self.file
+            .seek(SeekFrom::Start(slot as u64 * SPILL_RECORD))?;
+        self.file.write_all(&rec)
+    }
+
+    fn read(&mut self, slot: usize) -> io::Result<ValType> {
+        let mut rec = [0u8; SPILL_RECORD as usize];
+        self.file
+            .seek(SeekFrom::Start(slot as u64 * SPILL_RECORD))?;
+        self.file.read_exact(&mut rec)?;
+        let mut bits = [0u8; 8];
+        bits.copy_from_slice(&rec[1..]);
+        let bits = u64::from_le_bytes(bits);
+        match rec[0] {
+            0 => Ok(ValType::F(f32::from_bits(bits as u32))),
+            1 => Ok(ValType::D(f64::from_bits(bits))),
+            2 => Ok(ValType::I(bits as u32 as i32)),
+            3 => Ok(ValType::L(bits as i64)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "bad spill record",
+            )),
+        }
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// evaluates the dirty nodes of one level starting at slot start, whose inputs all lie in done
+fn eval_level(
+    nodes: &[CompiledNode],
+    start: usize,
+    fns: &mut [EvalFn],
+    vals: &mut [ValType],
+    dirty: &mut [bool],
+    done: &[ValType],
+    inputs: &[ValType],
+) -> usize {
+    let mut count = 0;
+    let mut args = vec![];
+    for (((node, f), v), d) in nodes[start..]
+        .iter()
+        .zip(fns.iter_mut())
+        .zip(vals.iter_mut())
+        .zip(dirty.iter_mut())
+    {
+        if !*d {
+            continue;
+        }
+        args.clear();
+        args.extend(node.inp.iter().map(|&j| (done[j], nodes[j].active)));
+        *v = f(&args, node.input.map(|k| inputs[k]).or(node.val));
+        *d = false;
+        count += 1;
+    }
+    count
+}
+
+impl CompiledGraph {
+    /// compiles the graph of output, with the given leaves as run-time inputs
+    ///
+    /// other leaves and constants keep the values they hold when compiled
+    pub fn new(output: &PtrVWrap, leaves: &[PtrVWrap]) -> CompiledGraph {
+        let adjoints: HashMap<PtrVWrap, PtrVWrap> = output.rev_ordered().into_iter().collect();
+        let grad_roots: Vec<PtrVWrap> = leaves
+            .iter()
+            .filter_map(|l| adjoints.get(l).cloned())
+            .collect();
+
+        let mut order = topo_order(std::slice::from_ref(output));
+        let eval_len = order.len();
+        let mut slot: HashMap<PtrVWrap, usize> = order
+            .iter()
+            .enumerate()
+            .map(|(i, n)| (n.clone(), i))
+            .collect();
+        for n in topo_order(&grad_roots) {
+            if !slot.contains_key(&n) {
+                slot.insert(n.clone(), order.len());
+                order.push(n);
+            }
+        }
+
+        //level = 1 + deepest input level, stable sort keeps the output part first
+        let mut level = vec![0; order.len()];
+        for (i, n) in order.iter().enumerate() {
+            level[i] = n
+                .inputs()
+                .iter()
+                .map(|x| level[slot[x]] + 1)
+                .max()
+                .unwrap_or(0);
+        }
+        let mut perm: Vec<usize> = (0..order.len()).collect();
+        perm.sort_by_key(|&i| (i >= eval_len, level[i]));
+        let order: Vec<PtrVWrap> = perm.iter().map(|&i| order[i].clone()).collect();
+        let slot: HashMap<PtrVWrap, usize> = order
+            .iter()
+            .enumerate()
+            .map(|(i, n)| (n.clone(), i))
+            .collect();
+        let mut levels: Vec<(usize, usize)> = vec![];
+        for (i, &p) in perm.iter().enumerate() {
+            let key = (p >= eval_len, level[p]);
+            match levels.last_mut() {
+                Some((start, end)) if (perm[*start] >= eval_len, level[perm[*start]]) == key => {
+                    *end = i + 1
+                }
+                _ => levels.push((i, i + 1)),
+            }
+        }
+
+        let nodes: Vec<CompiledNode> = order
+            .iter()
+            .map(|n| CompiledNode {
+                inp: n.inputs().iter().map(|i| slot[i]).collect(),
+                val: n.0.borrow().val,
+                active: n.0.borrow().eval_g,
+                input: leaves.iter().position(|l| l == n),
+            })
+            .collect();
+
+        let mut dependents = vec![vec![]; nodes.len()];
+        for (i, n) in nodes.iter().enumerate() {
+            for &j in n.inp.iter() {
+                dependents[j].push(i);
+            }
+        }
+
+        CompiledGraph {
+            fns: order.iter().map(|n| n.eval_fn()).collect(),
+            vals: vec![ValType::F(0.); nodes.len()],
+            dirty: vec![true; nodes.len()],
+            dependents,
+            levels,
+            last_inputs: vec![],
+            recomputed: 0,
+            spilled: 0,
+            nodes,
+            leaves: leaves.iter().map(|l| slot.get(l).cloned()).collect(),
+            output: slot[output],
+            eval_len,
+            grads: leaves
+                .iter()
+                .map(|l| adjoints.get(l).map(|a| slot[a]))
+                .collect(),
+        }
+    }
+
+    /// marks a node and everything downstream of it as out of date
+    fn mark_dirty(&mut self, slot: usize) {
+        let mut stack = vec![slot];
+        while let Some(i) = stack.pop() {
+            if !self.dirty[i] {
+                self.dirty[i] = true;
+                stack.extend(self.dependents[i].iter().cloned());
+            }
+        }
+    }
+
+    /// brings the first len nodes up to date with the given leaf values, splitting wide
+    /// levels over the given number of threads
+    fn run(&mut self, inputs: &[ValType], len: usize, threads: usize) {
+        assert_eq!(
+            inputs.len(),
+            self.leaves.len(),
+            "expected one value per compiled leaf"
+        );
+        if self.last_inputs.len() == inputs.len() {
+            let changed: Vec<usize> = inputs
+                .iter()
+                .zip(self.last_inputs.iter())
+                .zip(self.leaves.iter())
+                .filter(|((new, old), _)| new != old)
+                .filter_map(|(_, l)| *l)
+                .collect();
+            for l in changed {
+                self.mark_dirty(l);
+            }
+        }
+        self.last_inputs = inputs.to_vec();
+
+        self.recomputed = 0;
+        for &(start, end) in self.levels.iter().take_while(|(start, _)| *start < len) {
+            let end = end.min(len);
+            let nodes = &self.nodes[..end];
+            let (done, vals) = self.vals[..end].split_at_mut(start);
+            let fns = &mut self.fns[start..end];
+            let dirty = &mut self.dirty[start..end];
+            let width = end - start;
+
+            if threads <= 1 || width < PARALLEL_MIN_WIDTH {
+                self.recomputed += eval_level(nodes, start, fns, vals, dirty, done, inputs);
+                continue;
+            }
+
+            //each thread owns a disjoint partition of the level's values
+            let chunk = (width + threads - 1) / threads;
+            let done: &[ValType] = done;
+            self.recomputed += std::thread::scope(|s| {
+                let handles: Vec<_> = fns
+                    .chunks_mut(chunk)
+                    .zip(vals.chunks_mut(chunk))
+                    .zip(dirty.chunks_mut(chunk))
+                    .enumerate()
+                    .map(|(c, ((fns, vals), dirty))| {
+                        s.spawn(move || {
+                            eval_level(nodes, start + c * chunk, fns, vals, dirty, done, inputs)
+                        })
+                    })
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|h| h.join().expect("evaluation thread panicked"))
+                    .sum::<usize>()
+            });
+        }
+    }
+
+    /// value of the output for the given leaf values
+    pub fn eval(&mut self, inputs: &[ValType]) -> ValType {
+        self.run(inputs, self.eval_len, 1);
+        self.vals[self.output]
+    }
+
+    /// gradient of the output wrt each compiled leaf for the given leaf values
+    pub fn grad(&mut self, inputs: &[ValType]) -> Vec<ValType> {
+        self.grad_parallel(inputs, 1)
+    }
+
+    /// same as grad, with independent nodes of wide levels (e.g. the adjoint branches of a
+    /// sum over data points) evaluated on up to threads threads
+    pub fn grad_parallel(&mut self, inputs: &[ValType], threads: usize) -> Vec<ValType> {
+        self.run(inputs, self.nodes.len(), threads);
+        self.grads
+            .iter()
+            .map(|g| g.map_or(ValType::F(0.), |g| self.vals[g]))
+            .collect()
+    }
+
+    /// same as grad, holding at most budget values in memory
+    ///
+    /// values are dropped after their last use; when more are still needed, the one needed
+    /// furthest in the future is written to a temporary file and read back on use. the
+    /// cached values used by eval and grad are left untouched
+    pub fn grad_with_budget(
+        &mut self,
+        inputs: &[ValType],
+        budget: usize,
+    ) -> io::Result<Vec<ValType>> {
+        assert_eq!(
+            inputs.len(),
+            self.leaves.len(),
+            "expected one value per compiled leaf"
+        );
+        assert!(budget > 0, "budget must hold at least one value");
+        let len = self.nodes.len();
+
+        //positions where each value is needed, the gradients are needed at the end
+        let mut uses: Vec<Vec<usize>> = self
+            .dependents
+            .iter()
+            .map(|d| {
+                let mut d = d.clone();
+                d.sort_unstable();
+                d.dedup();
+                d
+            })
+            .collect();
+        for g in self.grads.iter().flatten() {
+            uses[*g].push(len);
+        }
+        let mut next = vec![0; len];
+
+        let mut resident: HashMap<usize, ValType> = HashMap::new();
+        let mut spill: Option<SpillFile> = None;
+        self.spilled = 0;
+
+        let mut args = vec![];
+        for i in 0..len {
+            let node = &self.nodes[i];
+            args.clear();
+            for &j in node.inp.iter() {
+                let v = match resident.get(&j) {
+                    Some(v) => *v,
+                    None => spill
+                        .as_mut()
+                        .expect("value neither resident nor spilled")
+                        .read(j)?,
+                };
+                args.push((v, self.nodes[j].active));
+            }
+            let v = (self.fns[i])(&args, node.input.map(|k| inputs[k]).or(node.val));
+            if !uses[i].is_empty() {
+                resident.insert(i, v);
+            }
+
+            //advance past this use and drop values with no uses left
+            for &j in node.inp.iter() {
+                while next[j] < uses[j].len() && uses[j][next[j]] <= i {
+                    next[j] += 1;
+                }
+                if next[j] == uses[j].len() {
+                    resident.remove(&j);
+                }
+            }
+
+            while resident.len() > budget {
+                let (&far, &v) = resident
+                    .iter()
+                    .max_by_key(|(j, _)| (uses[**j][next[**j]], **j))
+                    .unwrap();
+                if spill.is_none() {
+                    spill = Some(SpillFile::create()?);
+                }
+                spill.as_mut().unwrap().write(far, v)?;
+                resident.remove(&far);
+                self.spilled += 1;
+            }
+        }
+
+        self.grads
+            .iter()
+            .map(|g| match g {
+                None => Ok(ValType::F(0.)),
+                Some(g) => match resident.get(g) {
+                    Some(v) => Ok(*v),
+                    None => spill
+                        .as_mut()
+                        .expect("value neither resident nor spilled")
+                        .read(*g),
+                },
+            })
+            .collect()
+    }
+
+    /// number of values written to disk by the last grad_with_budget
+    pub fn spilled(&self) -> usize {
+        self.spilled
+    }
+
+    /// number of nodes evaluated by the last eval or grad, the rest were up to date
+    pub fn recomputed(&self) -> usize {
+        self.recomputed
+    }
+
+    /// number of compiled nodes, including the adjoint graphs
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+}
+
+/// evaluation order, functions and input slots of one builder graph
+struct Plan {
+    nodes: Vec<PtrVWrap>,
+    fns: Vec<EvalFn>,
+    inp: Vec<Vec<usize>>,
+}
+
+/// reusable state for repeatedly evaluating builder graphs in place
+///
+/// the first evaluation of a root records its topological order and the boxed evaluation
+/// functions of its nodes; later calls reuse them along with the value and argument
+/// buffers, and evaluate shared nodes once per call. leaf values and activity flags are
+/// read on every call, but the recorded topology is not: call clear after rewriting a
+/// graph (e.g. with fuse). the workspace keeps the graphs it has seen alive
+#[derive(Default)]
+pub struct Workspace {
+    plans: HashMap<PtrVWrap, Plan>,
+    vals: Vec<ValType>,
+    args: Vec<(ValType, bool)>,
+}
+
+impl Workspace {
+    pub fn new() -> Workspace {
+        Workspace::default()
+    }
+
+    /// same result and stored node values as x.apply_fwd()
+    pub fn apply_fwd(&mut self, x: &PtrVWrap) -> ValType {
+        let Workspace { plans, vals, args } = self;
+        let plan = plans.entry(x.clone()).or_insert_with(|| {
+            let nodes = topo_order(std::slice::from_ref(x));
+            let slot: HashMap<PtrVWrap, usize> = nodes
+                .iter()
+                .enumerate()
+                .map(|(i, n)| (n.clone(), i))
+                .collect();
+            Plan {
+                fns: nodes.iter().map(|n| n.eval_fn()).collect(),
+                inp: nodes
+                    .iter()
+                    .map(|n| n.inputs().iter().map(|i| slot[i]).collect())
+                    .collect(),
+                nodes,
+            }
+        });
+
+        vals.clear();
+        for i in 0..plan.nodes.len() {
+            args.clear();
+            args.extend(
+                plan.inp[i]
+                    .iter()
+                    .map(|&j| (vals[j], plan.nodes[j].0.borrow().eval_g)),
+            );
+            let n = &plan.nodes[i];
+            let v = (plan.fns[i])(args, n.0.borrow().val);
+            n.0.borrow_mut().val = Some(v);
+            vals.push(v);
+        }
+        *vals.last().expect("empty plan")
+    }
+
+    /// same result, stored node values and grad buffer updates as x.apply_rev()
+    pub fn apply_rev(&mut self, x: &PtrVWrap) -> ValType {
+        let v = self.apply_fwd(x);
+        x.accumulate_grad(v);
+        v
+    }
+
+    /// forgets all recorded graphs
+    pub fn clear(&mut self) {
+        self.plans.clear();
+    }
+
+    /// number of graphs with a recorded plan
+    pub fn len(&self) -> usize {
+        self.plans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.plans.is_empty()
+    }
+}
+
+#[cfg(test)]
+fn eq_f32(a: f32, b: f32) -> bool {
+    (a - b).abs() < 0.01
+}
+
+#[test]
+fn test_compiled_eval_grad() {
+    use crate::core::{Leaf, Mul, Sin};
+
+    //y=sin(a*b) where a=1, b=2
+    //dy/da=b*cos(ab)=-0.8323, dy/db=a*cos(ab)=-0.4161
+    let l0 = Leaf(ValType::F(1.));
+    let l1 = Leaf(ValType::F(2.));
+    let y = Sin(Mul(l0.clone(), l1.clone()));
+    let mut g = CompiledGraph::new(&y, &[l0.clone(), l1.clone()]);
+    assert!(!g.is_empty());
+
+    let v: f32 = g.eval(&[ValType::F(1.), ValType::F(2.)]).into();
+    assert!(eq_f32(v, 0.9093));
+    let d: Vec<f32> = g
+        .grad(&[ValType::F(1.), ValType::F(2.)])
+        .into_iter()
+        .map(|x| x.into())
+        .collect();
+    assert!(eq_f32(d[0], -0.8323));
+    assert!(eq_f32(d[1], -0.4161));
+
+    //new inputs reuse the compiled topology, a=0.5, b=1: y=sin(0.5), dy/da=cos(0.5)
+    let v: f32 = g.eval(&[ValType::F(0.5), ValType::F(1.)]).into();
+    assert!(eq_f32(v, 0.4794));
+    let d: f32 = g.grad(&[ValType::F(0.5), ValType::F(1.)])[0].into();
+    assert!(eq_f32(d, 0.8776));
+
+    //the builder graph is untouched
+    let mut y = y;
+    assert!(eq_f32(y.apply_fwd().into(), 0.9093));
+}
+
+#[test]
+fn test_compiled_fixed_leaves() {
+    use crate::core::{Add, Leaf, Mul};
+
+    //y=a*a+c with only a compiled as input, c=3 stays fixed
+    //unused leaves get a zero gradient
+    let l0 = Leaf(ValType::F(1.));
+    let l1 = Leaf(ValType::F(3.));
+    let unused = Leaf(ValType::F(7.));
+    let y = Add(Mul(l0.clone(), l0.clone()), l1);
+    let mut g = CompiledGraph::new(&y, &[l0, unused]);
+    let v: f32 = g.eval(&[ValType::F(2.), ValType::F(0.)]).into();
+    assert!(eq_f32(v, 7.));
+    let d: Vec<f32> = g
+        .grad(&[ValType::F(2.), ValType::F(0.)])
+        .into_iter()
+        .map(|x| x.into())
+        .collect();
+    assert_eq!(d, vec![4., 0.]);
+}
+
+#[test]
+fn test_compiled_incremental() {
+    use crate::core::{Add, Leaf, Mul, Sin};
+
+    //y=sin(a)+b*c, changing b only recomputes b, b*c and the sum
+    let l0 = Leaf(ValType::F(1.));
+    let l1 = Leaf(ValType::F(2.));
+    let l2 = Leaf(ValType::F(3.));
+    let y = Add(Sin(l0.clone()), Mul(l1.clone(), l2.clone()));
+    let mut g = CompiledGraph::new(&y, &[l0, l1, l2]);
+
+    let v: f32 = g
+        .eval(&[ValType::F(1.), ValType::F(2.), ValType::F(3.)])
+        .into();
+    assert!(eq_f32(v, 6.8415));
+    assert_eq!(g.recomputed(), 6);
+
+    let v: f32 = g
+        .eval(&[ValType::F(1.), ValType::F(4.), ValType::F(3.)])
+        .into();
+    assert!(eq_f32(v, 12.8415));
+    assert_eq!(g.recomputed(), 3);
+
+    //unchanged inputs recompute nothing
+    g.eval(&[ValType::F(1.), ValType::F(4.), ValType::F(3.)]);
+    assert_eq!(g.recomputed(), 0);
+
+    //the gradient reuses the cached forward values, dy/da=cos(1), dy/db=c, dy/dc=b
+    let d: Vec<f32> = g
+        .grad(&[ValType::F(1.), ValType::F(4.), ValType::F(3.)])
+        .into_iter()
+        .map(|x| x.into())
+        .collect();
+    assert!(eq_f32(d[0], 0.5403));
+    assert!(eq_f32(d[1], 3.));
+    assert!(eq_f32(d[2], 4.));
+    let total = g.recomputed();
+    assert!(total < g.len());
+
+    //changing a leaves the b and c gradient nodes alone
+    let d: f32 = g.grad(&[ValType::F(0.), ValType::F(4.), ValType::F(3.)])[0].into();
+    assert!(eq_f32(d, 1.));
+    //a, sin(a), y, cos(a) and dy/da
+    assert_eq!(g.recomputed(), 5);
+}
+
+#[test]
+fn test_compiled_parallel() {
+    use crate::core::{Add, Leaf, Mul, Sin};
+
+    //y=sum(sin(w*x_k)) over 512 data points as a balanced tree of adds
+    //dy/dw=sum(x_k*cos(w*x_k))
+    let w = Leaf(ValType::F(0.5));
+    let xs: Vec<f32> = (0..512).map(|k| k as f32 * 0.01).collect();
+    let mut terms: Vec<PtrVWrap> = xs
+        .iter()
+        .map(|&x| Sin(Mul(w.clone(), Leaf(ValType::F(x)))))
+        .collect();
+    while terms.len() > 1 {
+        terms = terms
+            .chunks(2)
+            .map(|p| Add(p[0].clone(), p[1].clone()))
+            .collect();
+    }
+    let y = terms.pop().unwrap();
+    let expected: f32 = xs.iter().map(|x| x * (0.5 * x).cos()).sum();
+
+    let mut g = CompiledGraph::new(&y, std::slice::from_ref(&w));
+    let serial: f32 = g.grad(&[ValType::F(0.5)])[0].into();
+    let mut g = CompiledGraph::new(&y, &[w]);
+    let parallel: f32 = g.grad_parallel(&[ValType::F(0.5)], 4)[0].into();
+    assert!((serial - expected).abs() < 0.05);
+    assert_eq!(serial, parallel);
+    assert_eq!(g.recomputed(), g.len());
+}
+
+#[test]
+fn test_compiled_out_of_core() {
+    use crate::core::{Add, Leaf, Mul, Sin};
+
+    //unrolled x_(t+1)=x_t+0.01*sin(a*x_t) for 300 steps, gradient wrt a and x_0
+    let a = Leaf(ValType::F(1.5));
+    let x0 = Leaf(ValType::F(0.3));
+    let h = Leaf(ValType::F(0.01));
+    let mut x = x0.clone();
+    for _ in 0..300 {
+        x = Add(x.clone(), Mul(h.clone(), Sin(Mul(a.clone(), x))));
+    }
+    let mut g = CompiledGraph::new(&x, &[a, x0]);
+    let inputs = [ValType::F(1.5), ValType::F(0.3)];
+    let full = g.grad(&inputs);
+
+    //a small budget forces spills yet gives the same gradient
+    let d = g
+        .grad_with_budget(&inputs, 8)
+        .expect("out-of-core run failed");
+    assert!(g.spilled() > 0);
+    assert_eq!(d, full);
+
+    //a budget covering the live values never touches the disk
+    let d = g
+        .grad_with_budget(&inputs, g.len())
+        .expect("out-of-core run failed");
+    assert_eq!(g.spilled(), 0);
+    assert_eq!(d, full);
+}
+
+#[test]
+fn test_workspace() {
+    use crate::core::{Add, Exp, Leaf, Mul};
+
+    //y=s*s+s with s=exp(a*b) shared, a=1, b=2
+    let mut l0 = Leaf(ValType::F(1.)).active();
+    let mut l1 = Leaf(ValType::F(2.));
+    let s = Exp(Mul(l0.clone(), l1.clone()));
+    let mut y = Add(Mul(s.clone(), s.clone()), s.clone());
+    let mut dy = y.fwd();
+
+    let mut ws = Workspace::new();
+    for a in [1., 0.5, -2.] {
+        l0.set_val(ValType::F(a));
+        let v: f32 = ws.apply_fwd(&y).into();
+        let d: f32 = ws.apply_fwd(&dy).into();
+        assert_eq!(v, y.apply_fwd().into());
+        assert_eq!(d, dy.apply_fwd().into());
+        //dy/da=(2s+1)*b*s
+        let sv = (2. * a).exp();
+        assert!(eq_f32(d, (2. * sv + 1.) * 2. * sv));
+    }
+    assert_eq!(ws.len(), 2);
+
+    //intermediate values are stored on the nodes as with apply_fwd
+    let sv: f32 = s.0.borrow().val.expect("value missing").into();
+    assert!(eq_f32(sv, (-4f32).exp()));
+    ws.clear();
+    assert!(ws.is_empty());
+
+    //grad buffers accumulate as with apply_rev
+    l1.retain_grad();
+    let mut adj = y.rev()[&l1].clone();
+    let d: f32 = ws.apply_rev(&adj).into();
+    assert_eq!(l1.grad(), Some(d));
+    adj.apply_rev();
+    assert_eq!(l1.grad(), Some(2. * d));
+    ws.apply_fwd(&adj);
+    assert_eq!(l1.grad(), Some(2. * d));
+}
@@ -5,12 +5,76 @@
 #[macro_use]
 extern crate lazy_static;
 
+mod codegen;
 mod core;
+mod data;
+mod dist;
+mod expr;
+mod graph;
+mod linalg;
+mod logspace;
+mod optim;
+mod param;
+mod pipeline;
+mod quat;
+mod ricci;
+mod scalar;
+mod snapshot;
+mod so3;
+mod soft;
+mod special;
 mod valtype;
+mod viewer;
 
 mod interface {
-    pub use crate::core::{Add, Cos, Div, Exp, Leaf, Ln, Mul, Pow, Sin, Tan};
+    pub use crate::codegen::{to_rust, to_sympy, to_sympy_grad, CodegenError, Float};
+    pub use crate::core::{
+        canonical_order, check_wrt, curvature_along, debug_check_consistency, dot_nodes, leaf_vec,
+        map, map_nodes, partial, set_canonical_order, set_strict, set_subgradient_policy,
+        set_track_locations, strict, subgradient_policy, sum_nodes, track_locations,
+        with_shared_constants, with_subgradient_policy, Abs, Add, Atan2, BatchMatMul, BesselI0,
+        BesselI1, BesselJ0, BesselJ1, BesselK0, BesselK1, BesselY0, BesselY1, Cholesky,
+        ClipWithGrad, Const, Cos, CumProd, CumProdAxis, CumSum, CumSumAxis, DerivativeCache, Det,
+        Diag, DiagEmbed, Digamma, Div, Dot, Embedding, Exp, Fft, Gather, Gelu, Ifft, Inconsistency,
+        IntegerSemantics, Interp, Interpolation, Inverse, KlBernoulli, KlNormal, Kron, LayerNorm,
+        LazyRev, Leaf, Lerp, Ln, LnGamma, MapNodes, MatMul, Max, Min, MissingValue, Mode, Mul,
+        Partials, Pow, Relu, Rem, RemExact, RetainedAdjoints, RevPool, ScatterAdd, Sigmoid, Sin,
+        Sinc, Smootherstep, Smoothstep, SoftMax, SoftMin, Softplus, Solve, Sqrt, StrictHandler,
+        SubgradientPolicy, Svd, SymEig, Tan, Tanh, Trace, WrtIssue, WrtReport, COST_DIV,
+        COST_TRANSCENDENTAL,
+    };
+    pub use crate::data::{BatchObjective, DataLeaf};
+    pub use crate::dist::{
+        Bernoulli, Beta, Distribution, Expect, Gamma, Normal, Rng, StochasticGraph, StochasticNode,
+    };
+    pub use crate::expr::{
+        Dual, Expr, ExprAdd, ExprConst, ExprCos, ExprDiv, ExprExp, ExprLeaf, ExprLn, ExprMul,
+        ExprSin, ExprTan,
+    };
+    pub use crate::graph::{CompiledGraph, Workspace};
+    pub use crate::logspace::{
+        FromLog, LogAdd, LogDiv, LogDot, LogMul, LogPow, LogSub, LogSum, ToLog,
+    };
+    pub use crate::optim::{
+        augmented_lagrangian, fit, levenberg_marquardt, projected_gradient, taylor2, trust_region,
+        Adam, AugmentedLagrangian, Hvp, JacobianOperator, LevenbergMarquardt, Optimizer,
+        QuadraticModel, Sgd, StepInfo, TrustRegion,
+    };
+    pub use crate::param::{flat_grad, grad_stats, GradStats, ParamGrad, ParamSet};
+    pub use crate::pipeline::{split_graph, Stage};
+    pub use crate::quat::{Quat, QuatConj, QuatMul, QuatNormalize, QuatRotate};
+    pub use crate::ricci::{
+        ricci, Contraction, ContractionPlan, ContractionStep, IndexPos, RicciError, RicciErrorKind,
+        RicciExpr, RicciTerm, TensorFactor, TensorIndex,
+    };
+    pub use crate::scalar::{eval_scalar, grad_scalar, Scalar, ScalarError};
+    pub use crate::snapshot::{
+        assert_snapshot, check_snapshot, to_canonical, SnapshotMismatch, UPDATE_SNAPSHOTS,
+    };
+    pub use crate::so3::{So3Exp, So3Log};
+    pub use crate::soft::{GumbelSoftmax, SoftRank, SoftSort, SoftTopK};
     pub use crate::valtype::ValType;
+    pub use crate::viewer::{to_html, write_html};
 }
 
 pub use interface::*;
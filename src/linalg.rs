@@ -0,0 +1,380 @@
+//! dense numerical linear algebra on row-major f64 buffers used by matrix ops
+
+/// lu factorization with partial pivoting
+///
+/// returns the packed factors, the row permutation and its sign, or None if singular
+pub fn lu(a: &[f64], n: usize) -> Option<(Vec<f64>, Vec<usize>, f64)> {
+    assert_eq!(a.len(), n * n);
+    let mut m = a.to_vec();
+    let mut perm: Vec<usize> = (0..n).collect();
+    let mut sign = 1.;
+    for k in 0..n {
+        let p = (k..n)
+            .max_by(|&x, &y| m[x * n + k].abs().total_cmp(&m[y * n + k].abs()))
+            .unwrap();
+        if m[p * n + k] == 0. {
+            return None;
+        }
+        if p != k {
+            for c in 0..n {
+                m.swap(k * n + c, p * n + c);
+            }
+            perm.swap(k, p);
+            sign = -sign;
+        }
+        for r in (k + 1)..n {
+            let f = m[r * n + k] / m[k * n + k];
+            m[r * n + k] = f;
+            for c in (k + 1)..n {
+                m[r * n + c] -= f * m[k * n + c];
+            }
+        }
+    }
+    Some((m, perm, sign))
+}
+
+/// determinant, 0 for singular matrices
+pub fn det(a: &[f64], n: usize) -> f64 {
+    match lu(a, n) {
+        Some((m, _, sign)) => (0..n).map(|i| m[i * n + i]).product::<f64>() * sign,
+        None => 0.,
+    }
+}
+
+/// inverse, filled with NaN for singular matrices
+pub fn inverse(a: &[f64], n: usize) -> Vec<f64> {
+    let (m, perm, _) = match lu(a, n) {
+        Some(x) => x,
+        None => return vec![f64::NAN; n * n],
+    };
+    let mut inv = vec![0.; n * n];
+    for col in 0..n {
+        //solve L U x = P e_col
+        let mut x: Vec<f64> = perm
+            .iter()
+            .map(|&p| if p == col { 1. } else { 0. })
+            .collect();
+        for i in 0..n {
+            for k in 0..i {
+                x[i] -= m[i * n + k] * x[k];
+            }
+        }
+        for i in (0..n).rev() {
+            for k in (i + 1)..n {
+                x[i] -= m[i * n + k] * x[k];
+            }
+            x[i] /= m[i * n + i];
+        }
+        for i in 0..n {
+            inv[i * n + col] = x[i];
+        }
+    }
+    inv
+}
+
+/// adjugate, the transposed cofactor matrix, finite also for singular matrices
+///
+/// det(A)*inv(A) for invertible matrices, otherwise signed minor determinants
+pub fn adjugate(a: &[f64], n: usize) -> Vec<f64> {
+    assert_eq!(a.len(), n * n);
+    if n == 1 {
+        return vec![1.];
+    }
+    let d = det(a, n);
+    if d != 0. {
+        return inverse(a, n).iter().map(|x| x * d).collect();
+    }
+    let mut adj = vec![0.; n * n];
+    let mut minor = Vec::with_capacity((n - 1) * (n - 1));
+    for i in 0..n {
+        for j in 0..n {
+            //adj_ij is the signed determinant of A without row j and column i
+            minor.clear();
+            for r in (0..n).filter(|&r| r != j) {
+                minor.extend((0..n).filter(|&c| c != i).map(|c| a[r * n + c]));
+            }
+            let sign = if (i + j) % 2 == 0 { 1. } else { -1. };
+            adj[i * n + j] = sign * det(&minor, n - 1);
+        }
+    }
+    adj
+}
+
+/// lower cholesky factor of a symmetric positive definite matrix, reading only the lower triangle
+///
+/// filled with NaN if the matrix is not positive definite
+pub fn cholesky(a: &[f64], n: usize) -> Vec<f64> {
+    assert_eq!(a.len(), n * n);
+    let mut l = vec![0.; n * n];
+    for j in 0..n {
+        let d = a[j * n + j] - (0..j).map(|k| l[j * n + k] * l[j * n + k]).sum::<f64>();
+        if d <= 0. {
+            return vec![f64::NAN; n * n];
+        }
+        l[j * n + j] = d.sqrt();
+        for i in (j + 1)..n {
+            let s = a[i * n + j] - (0..j).map(|k| l[i * n + k] * l[j * n + k]).sum::<f64>();
+            l[i * n + j] = s / l[j * n + j];
+        }
+    }
+    l
+}
+
+/// forward substitution for L x = b, reading only the lower triangle of L
+pub fn solve_lower(l: &[f64], b: &[f64], n: usize) -> Vec<f64> {
+    assert_eq!(l.len(), n * n);
+    assert_eq!(b.len(), n);
+    let mut x = b.to_vec();
+    for i in 0..n {
+        for k in 0..i {
+            x[i] -= l[i * n + k] * x[k];
+        }
+        x[i] /= l[i * n + i];
+    }
+    x
+}
+
+/// back substitution for L^T x = b, reading only the lower triangle of L
+pub fn solve_lower_transpose(l: &[f64], b: &[f64], n: usize) -> Vec<f64> {
+    assert_eq!(l.len(), n * n);
+    assert_eq!(b.len(), n);
+    let mut x = b.to_vec();
+    for i in (0..n).rev() {
+        for k in (i + 1)..n {
+            x[i] -= l[k * n + i] * x[k];
+        }
+        x[i] /= l[i * n + i];
+    }
+    x
+}
+
+/// eigendecomposition of a symmetric matrix by cyclic jacobi rotations, reading only the lower triangle
+///
+/// returns eigenvalues in ascending order and the row-major matrix whose columns are the
+/// corresponding unit eigenvectors, each signed so its largest magnitude component is positive
+pub fn sym_eig(a: &[f64], n: usize) -> (Vec<f64>, Vec<f64>) {
+    assert_eq!(a.len(), n * n);
+    let mut m: Vec<f64> = (0..n * n)
+        .map(|idx| {
+            let (r, c) = (idx / n, idx % n);
+            if c <= r {
+                a[idx]
+            } else {
+                a[c * n + r]
+            }
+        })
+        .collect();
+    let mut v: Vec<f64> = (0..n * n)
+        .map(|idx| if idx / n == idx % n { 1. } else { 0. })
+        .collect();
+    let scale: f64 = m.iter().map(|x| x * x).sum();
+    for _sweep in 0..100 {
+        let off: f64 = (0..n)
+            .flat_map(|p| ((p + 1)..n).map(move |q| (p, q)))
+            .map(|(p, q)| m[p * n + q] * m[p * n + q])
+            .sum();
+        if off <= 1e-30 * scale {
+            break;
+        }
+        for p in 0..n {
+            for q in (p + 1)..n {
+                let apq = m[p * n + q];
+                if apq == 0. {
+                    continue;
+                }
+                let theta = (m[q * n + q] - m[p * n + p]) / (2. * apq);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.).sqrt());
+                let c = 1. / (t * t + 1.).sqrt();
+                let s = t * c;
+                for k in 0..n {
+                    let (akp, akq) = (m[k * n + p], m[k * n + q]);
+                    m[k * n + p] = c * akp - s * akq;
+                    m[k * n + q] = s * akp + c * akq;
+                }
+                for k in 0..n {
+                    let (apk, aqk) = (m[p * n + k], m[q * n + k]);
+                    m[p * n + k] = c * apk - s * aqk;
+                    m[q * n + k] = s * apk + c * aqk;
+                }
+                for k in 0..n {
+                    let (vkp, vkq) = (v[k * n + p], v[k * n + q]);
+                    v[k * n + p] = c * vkp - s * vkq;
+                    v[k * n + q] = s * vkp + c * vkq;
+                }
+            }
+        }
+    }
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|&x, &y| m[x * n + x].total_cmp(&m[y * n + y]));
+    let vals = order.iter().map(|&i| m[i * n + i]).collect();
+    let mut vecs = vec![0.; n * n];
+    for (col, &i) in order.iter().enumerate() {
+        let big = (0..n)
+            .max_by(|&x, &y| v[x * n + i].abs().total_cmp(&v[y * n + i].abs()))
+            .unwrap();
+        let sign = if v[big * n + i] < 0. { -1. } else { 1. };
+        for r in 0..n {
+            vecs[r * n + col] = sign * v[r * n + i];
+        }
+    }
+    (vals, vecs)
+}
+
+/// thin svd of a rows by cols matrix, rows >= cols, from the eigendecomposition of A^T A
+///
+/// returns U (rows by cols), the singular values in descending order and V (cols by cols),
+/// all row-major. singular values up to 1e-7 of the largest are set to zero and their
+/// columns of U completed to an orthonormal set
+pub fn svd(a: &[f64], rows: usize, cols: usize) -> (Vec<f64>, Vec<f64>, Vec<f64>) {
+    assert_eq!(a.len(), rows * cols);
+    assert!(rows >= cols);
+    let mut ata = vec![0.; cols * cols];
+    for i in 0..cols {
+        for j in 0..cols {
+            ata[i * cols + j] = (0..rows).map(|k| a[k * cols + i] * a[k * cols + j]).sum();
+        }
+    }
+    let (vals, vecs) = sym_eig(&ata, cols);
+    let order: Vec<usize> = (0..cols).rev().collect();
+    let mut s: Vec<f64> = order.iter().map(|&i| vals[i].max(0.).sqrt()).collect();
+    let v: Vec<f64> = (0..cols * cols)
+        .map(|idx| vecs[(idx / cols) * cols + order[idx % cols]])
+        .collect();
+    let s_max = s.first().copied().unwrap_or(0.);
+    let mut u = vec![0.; rows * cols];
+    let mut basis: Vec<Vec<f64>> = vec![];
+    let mut zeros = vec![];
+    for i in 0..cols {
+        if s[i] > 1e-7 * s_max {
+            let col: Vec<f64> = (0..rows)
+                .map(|r| {
+                    (0..cols)
+                        .map(|k| a[r * cols + k] * v[k * cols + i])
+                        .sum::<f64>()
+                        / s[i]
+                })
+                .collect();
+            for r in 0..rows {
+                u[r * cols + i] = col[r];
+            }
+            basis.push(col);
+        } else {
+            s[i] = 0.;
+            zeros.push(i);
+        }
+    }
+    for i in zeros {
+        //the unit vector with the largest component orthogonal to the basis
+        let residual = |e: usize| -> Vec<f64> {
+            let mut x = vec![0.; rows];
+            x[e] = 1.;
+            for b in basis.iter() {
+                let d = b[e];
+                for r in 0..rows {
+                    x[r] -= d * b[r];
+                }
+            }
+            x
+        };
+        let norm = |x: &[f64]| x.iter().map(|y| y * y).sum::<f64>().sqrt();
+        let col = (0..rows)
+            .map(residual)
+            .max_by(|x, y| norm(x).total_cmp(&norm(y)))
+            .unwrap();
+        let len = norm(&col);
+        let col: Vec<f64> = col.iter().map(|x| x / len).collect();
+        for r in 0..rows {
+            u[r * cols + i] = col[r];
+        }
+        basis.push(col);
+    }
+    (u, s, v)
+}
+
+#[test]
+fn test_lu_inverse() {
+    let a = [0., 2., 1., 1., 1., 0., 3., 0., 1.];
+    assert!((det(&a, 3) + 5.).abs() < 1e-12);
+    let inv = inverse(&a, 3);
+    for i in 0..3 {
+        for j in 0..3 {
+            let v: f64 = (0..3).map(|k| a[i * 3 + k] * inv[k * 3 + j]).sum();
+            assert!((v - if i == j { 1. } else { 0. }).abs() < 1e-12);
+        }
+    }
+    assert_eq!(det(&[1., 2., 2., 4.], 2), 0.);
+    assert!(inverse(&[1., 2., 2., 4.], 2)[0].is_nan());
+
+    //adjugate agrees with det*inv and stays finite when singular
+    let adj = adjugate(&a, 3);
+    for (x, y) in adj.iter().zip(inv.iter()) {
+        assert!((x + 5. * y).abs() < 1e-12);
+    }
+    assert_eq!(adjugate(&[1., 2., 2., 4.], 2), vec![4., -2., -2., 1.]);
+    assert_eq!(adjugate(&[0., 0., 0., 0.], 2), vec![0., 0., 0., 0.]);
+}
+
+#[test]
+fn test_svd() {
+    //rank deficient, the zero singular value gets an orthonormal U column
+    let (u, s, v) = svd(&[1., 0., 0., 0.], 2, 2);
+    assert_eq!(s, vec![1., 0.]);
+    assert_eq!(u, vec![1., 0., 0., 1.]);
+    assert_eq!(v, vec![1., 0., 0., 1.]);
+
+    let a = [1., 2., 2., 4., 3., 6.];
+    let (u, s, v) = svd(&a, 3, 2);
+    assert!((s[0] - 70f64.sqrt()).abs() < 1e-12);
+    assert_eq!(s[1], 0.);
+    for i in 0..2 {
+        for j in 0..2 {
+            let d: f64 = (0..3).map(|r| u[r * 2 + i] * u[r * 2 + j]).sum();
+            assert!((d - if i == j { 1. } else { 0. }).abs() < 1e-12);
+        }
+    }
+    for r in 0..3 {
+        for c in 0..2 {
+            let x: f64 = (0..2).map(|i| u[r * 2 + i] * s[i] * v[c * 2 + i]).sum();
+            assert!((x - a[r * 2 + c]).abs() < 1e-12);
+        }
+    }
+}
+
+#[test]
+fn test_cholesky_solve() {
+    //upper triangle is ignored
+    let a = [4., 100., 2., 3.];
+    let l = cholesky(&a, 2);
+    assert_eq!(l[0], 2.);
+    assert_eq!(l[1], 0.);
+    assert_eq!(l[2], 1.);
+    assert!((l[3] - 2f64.sqrt()).abs() < 1e-12);
+    assert!(cholesky(&[1., 2., 2., 1.], 2)[0].is_nan());
+    assert_eq!(solve_lower(&[2., 7., 1., 3.], &[4., 5.], 2), vec![2., 1.]);
+    //[[2, 1], [0, 3]] x = (5, 3)
+    assert_eq!(
+        solve_lower_transpose(&[2., 7., 1., 3.], &[5., 3.], 2),
+        vec![2., 1.]
+    );
+}
+
+#[test]
+fn test_sym_eig() {
+    let a = [2., 0., 1., 3.];
+    let (vals, vecs) = sym_eig(&a, 2);
+    assert!((vals[0] - 1.381966).abs() < 1e-6);
+    assert!((vals[1] - 3.618034).abs() < 1e-6);
+    assert!((vecs[0] - 0.850651).abs() < 1e-6);
+    assert!((vecs[2] + 0.525731).abs() < 1e-6);
+    assert!((vecs[1] - 0.525731).abs() < 1e-6);
+
+    //A v = lambda v for a 3x3
+    let a = [4., 1., 2., 1., 3., 0., 2., 0., 5.];
+    let (vals, vecs) = sym_eig(&a, 3);
+    for i in 0..3 {
+        for r in 0..3 {
+            let av: f64 = (0..3).map(|k| a[r * 3 + k] * vecs[k * 3 + i]).sum();
+            assert!((av - vals[i] * vecs[r * 3 + i]).abs() < 1e-9);
+        }
+    }
+}
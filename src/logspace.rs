@@ -0,0 +1,131 @@
+//! arithmetic on logarithms of positive values
+//!
+//! a node holding ln(x) stands for x, so products, quotients and powers become sums,
+//! differences and scalings, and sums go through log-sum-exp, which never overflows;
+//! likelihoods far outside the range of f32 stay representable
+//!
+//! derivatives are wrt the log values, e.g. d LogSum(l)/d l_i = exp(l_i - LogSum(l)), and
+//! derivatives wrt x follow from the chain rule through ToLog
+
+#![allow(non_snake_case)]
+
+use crate::core::{constant, Add, Exp, Ln, Minus, Mul, PtrVWrap, SoftMax};
+use crate::valtype::ValType;
+
+/// ln(x) for x > 0
+#[allow(dead_code)]
+#[track_caller]
+pub fn ToLog(x: PtrVWrap) -> PtrVWrap {
+    Ln(x)
+}
+
+/// x = exp(l), which may overflow or underflow
+#[allow(dead_code)]
+#[track_caller]
+pub fn FromLog(l: PtrVWrap) -> PtrVWrap {
+    Exp(l)
+}
+
+/// ln(a*b) = ln(a) + ln(b)
+#[allow(dead_code)]
+#[track_caller]
+pub fn LogMul(la: PtrVWrap, lb: PtrVWrap) -> PtrVWrap {
+    Add(la, lb)
+}
+
+/// ln(a/b) = ln(a) - ln(b)
+#[allow(dead_code)]
+#[track_caller]
+pub fn LogDiv(la: PtrVWrap, lb: PtrVWrap) -> PtrVWrap {
+    Minus(la, lb)
+}
+
+/// ln(a^k) = k*ln(a)
+#[allow(dead_code)]
+#[track_caller]
+pub fn LogPow(la: PtrVWrap, k: f32) -> PtrVWrap {
+    Mul(constant(ValType::F(k)), la)
+}
+
+/// ln(a+b) = ln(exp(ln(a)) + exp(ln(b))), shifted by the larger operand
+#[allow(dead_code)]
+#[track_caller]
+pub fn LogAdd(la: PtrVWrap, lb: PtrVWrap) -> PtrVWrap {
+    SoftMax(vec![la, lb], 1.)
+}
+
+/// ln(a-b) = ln(a) + ln(1 - exp(ln(b) - ln(a))) for a > b
+#[allow(dead_code)]
+#[track_caller]
+pub fn LogSub(la: PtrVWrap, lb: PtrVWrap) -> PtrVWrap {
+    let ratio = Exp(Minus(lb, la.clone()));
+    Add(la, Ln(Minus(constant(ValType::F(1.)), ratio)))
+}
+
+/// ln(sum(x_i)) from ln(x_i)
+#[allow(dead_code)]
+#[track_caller]
+pub fn LogSum(l: &[PtrVWrap]) -> PtrVWrap {
+    assert!(!l.is_empty(), "LogSum needs at least one input");
+    SoftMax(l.to_vec(), 1.)
+}
+
+/// ln(sum(a_i*b_i)) from ln(a_i) and ln(b_i), e.g. a likelihood marginalized over states
+#[allow(dead_code)]
+#[track_caller]
+pub fn LogDot(la: &[PtrVWrap], lb: &[PtrVWrap]) -> PtrVWrap {
+    assert_eq!(la.len(), lb.len(), "LogDot length mismatch");
+    let terms: Vec<PtrVWrap> = la
+        .iter()
+        .zip(lb.iter())
+        .map(|(a, b)| LogMul(a.clone(), b.clone()))
+        .collect();
+    LogSum(&terms)
+}
+
+#[cfg(test)]
+fn eq_f32(a: f32, b: f32) -> bool {
+    (a - b).abs() < 0.01
+}
+
+#[test]
+fn test_log_space() {
+    use crate::core::{partial, Leaf};
+
+    //product of 100 probabilities 1e-5 underflows in f32 but not as a sum of logs
+    let p = Leaf(ValType::F(1e-5));
+    let lp = ToLog(p.clone());
+    let mut direct = (0..99).fold(p.clone(), |acc, _| Mul(acc, p.clone()));
+    assert_eq!(f32::from(direct.apply_fwd()), 0.);
+    let mut l = (0..99).fold(lp.clone(), |acc, _| LogMul(acc, lp.clone()));
+    assert!((f32::from(l.apply_fwd()) - 100. * 1e-5f32.ln()).abs() < 0.1);
+    //d ln(p^100)/dp = 100/p
+    let d: f32 = partial(&l, std::slice::from_ref(&p)).into();
+    assert!((d / 1e7 - 1.).abs() < 1e-3);
+
+    //ln(e^1000 + e^1000) = 1000 + ln(2) with weights 1/2
+    let a = Leaf(ValType::F(1000.));
+    let b = Leaf(ValType::F(1000.));
+    let mut s = LogAdd(a.clone(), b.clone());
+    assert!(eq_f32(s.apply_fwd().into(), 1000. + 2f32.ln()));
+    assert!(eq_f32(partial(&s, std::slice::from_ref(&a)).into(), 0.5));
+    let mut t = LogSub(s.clone(), b.clone());
+    assert!(eq_f32(t.apply_fwd().into(), 1000.));
+
+    //ln(2*3 + 4*5) = ln(26), powers and quotients
+    let la: Vec<PtrVWrap> = [2f32, 4.]
+        .iter()
+        .map(|v| Leaf(ValType::F(v.ln())))
+        .collect();
+    let lb: Vec<PtrVWrap> = [3f32, 5.]
+        .iter()
+        .map(|v| Leaf(ValType::F(v.ln())))
+        .collect();
+    let mut d = LogDot(&la, &lb);
+    assert!(eq_f32(d.apply_fwd().into(), 26f32.ln()));
+    //d/d ln(a_0) = a_0*b_0/26
+    assert!(eq_f32(partial(&d, &la[..1]).into(), 6. / 26.));
+    let mut q = FromLog(LogDiv(LogPow(la[1].clone(), 2.), lb[0].clone()));
+    assert!(eq_f32(q.apply_fwd().into(), 16. / 3.));
+    assert!(eq_f32(LogSum(&la).apply_fwd().into(), 6f32.ln()));
+}
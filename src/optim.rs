@@ -0,0 +1,914 @@
+//! optimizers and a training loop over a ParamSet
+//!
+//! first order optimizers update parameter values from gradients, second order drivers
+//! build derivative graphs of a fixed objective graph once and reevaluate them as the
+//! parameter values change
+
+use std::collections::HashMap;
+
+use crate::core::{dot_nodes, seed_links, sum_nodes, with_active, Add, Leaf, Mul, PtrVWrap};
+use crate::graph::Workspace;
+use crate::linalg;
+use crate::param::ParamSet;
+use crate::valtype::ValType;
+
+/// update rule applied to the parameter values given their gradient
+pub trait Optimizer {
+    fn step(&mut self, values: &mut [f32], grads: &[f32]);
+}
+
+/// plain gradient descent
+#[derive(Debug, Clone, Copy)]
+pub struct Sgd {
+    pub lr: f32,
+}
+
+impl Optimizer for Sgd {
+    fn step(&mut self, values: &mut [f32], grads: &[f32]) {
+        for (v, g) in values.iter_mut().zip(grads) {
+            *v -= self.lr * g;
+        }
+    }
+}
+
+/// adam with bias corrected moment estimates
+#[derive(Debug, Clone)]
+pub struct Adam {
+    pub lr: f32,
+    pub beta1: f32,
+    pub beta2: f32,
+    pub eps: f32,
+    t: i32,
+    m: Vec<f32>,
+    v: Vec<f32>,
+}
+
+impl Adam {
+    /// adam with the usual defaults beta1=0.9, beta2=0.999, eps=1e-8
+    pub fn new(lr: f32) -> Adam {
+        Adam {
+            lr,
+            beta1: 0.9,
+            beta2: 0.999,
+            eps: 1e-8,
+            t: 0,
+            m: vec![],
+            v: vec![],
+        }
+    }
+}
+
+impl Optimizer for Adam {
+    fn step(&mut self, values: &mut [f32], grads: &[f32]) {
+        if self.m.len() != values.len() {
+            self.m = vec![0.; values.len()];
+            self.v = vec![0.; values.len()];
+        }
+        self.t += 1;
+        let c1 = 1. - self.beta1.powi(self.t);
+        let c2 = 1. - self.beta2.powi(self.t);
+        for ((x, g), (m, v)) in values
+            .iter_mut()
+            .zip(grads)
+            .zip(self.m.iter_mut().zip(self.v.iter_mut()))
+        {
+            *m = self.beta1 * *m + (1. - self.beta1) * g;
+            *v = self.beta2 * *v + (1. - self.beta2) * g * g;
+            *x -= self.lr * (*m / c1) / ((*v / c2).sqrt() + self.eps);
+        }
+    }
+}
+
+/// state passed to fit callbacks after the gradient of an epoch is evaluated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StepInfo {
+    pub epoch: usize,
+    pub loss: f32,
+    /// euclidean norm of the gradient
+    pub grad_norm: f32,
+}
+
+/// runs epochs optimizer steps on params and returns the loss of every epoch
+///
+/// objective builds the loss graph from the parameters at the start of each epoch, and the
+/// callbacks run before the update of each epoch
+pub fn fit<F: FnMut(&ParamSet) -> PtrVWrap>(
+    mut objective: F,
+    params: &mut ParamSet,
+    optimizer: &mut dyn Optimizer,
+    epochs: usize,
+    callbacks: &mut [&mut dyn FnMut(&StepInfo)],
+) -> Vec<f32> {
+    let mut losses = Vec::with_capacity(epochs);
+    for epoch in 0..epochs {
+        let mut y = objective(params);
+        let loss: f32 = y.apply_fwd().into();
+        let grads = params.grads(&y);
+        let info = StepInfo {
+            epoch,
+            loss,
+            grad_norm: grads.iter().map(|g| g * g).sum::<f32>().sqrt(),
+        };
+        for c in callbacks.iter_mut() {
+            c(&info);
+        }
+        let mut values = params.values();
+        optimizer.step(&mut values, &grads);
+        params.set_values(&values);
+        losses.push(loss);
+    }
+    losses
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+fn norm(a: &[f32]) -> f32 {
+    dot(a, a).sqrt()
+}
+
+/// gradient and hessian-vector products of an output wrt a set of leaves
+///
+/// the product is the gradient of grad(output).v, with v held in leaves of its own, so
+/// the derivative graphs are built once, the second order ones on the first product, and
+/// reevaluated for every new point and direction
+pub struct Hvp {
+    output: PtrVWrap,
+    leaves: Vec<PtrVWrap>,
+    grad: Vec<Option<PtrVWrap>>,
+    dir: Vec<PtrVWrap>,
+    hv: Option<Vec<Option<PtrVWrap>>>,
+    ws: Workspace,
+}
+
+impl Hvp {
+    pub fn new(output: &PtrVWrap, leaves: &[PtrVWrap]) -> Hvp {
+        let adj = output.rev();
+        Hvp {
+            output: output.clone(),
+            leaves: leaves.to_vec(),
+            grad: leaves.iter().map(|l| adj.get(l).cloned()).collect(),
+            dir: leaves.iter().map(|_| Leaf(ValType::F(0.))).collect(),
+            hv: None,
+            ws: Workspace::new(),
+        }
+    }
+
+    /// value of the output at the current leaf values
+    pub fn value(&mut self) -> f32 {
+        self.ws.apply_fwd(&self.output).into()
+    }
+
+    /// gradient at the current leaf values
+    pub fn grad(&mut self) -> Vec<f32> {
+        let ws = &mut self.ws;
+        self.grad
+            .iter()
+            .map(|g| g.as_ref().map_or(0., |g| ws.apply_rev(g).into()))
+            .collect()
+    }
+
+    /// hessian times v at the current leaf values
+    pub fn apply(&mut self, v: &[f32]) -> Vec<f32> {
+        assert_eq!(v.len(), self.dir.len(), "direction length mismatch");
+        for (d, x) in self.dir.iter_mut().zip(v) {
+            d.set_val(ValType::F(*x));
+        }
+        let Hvp {
+            leaves,
+            grad,
+            dir,
+            hv,
+            ws,
+            ..
+        } = self;
+        let hv = hv.get_or_insert_with(|| {
+            let (g, d): (Vec<PtrVWrap>, Vec<PtrVWrap>) = grad
+                .iter()
+                .zip(dir.iter())
+                .filter_map(|(g, d)| g.clone().map(|g| (g, d.clone())))
+                .unzip();
+            let adj = dot_nodes(&g, &d).rev();
+            leaves.iter().map(|l| adj.get(l).cloned()).collect()
+        });
+        hv.iter()
+            .map(|h| h.as_ref().map_or(0., |h| ws.apply_rev(h).into()))
+            .collect()
+    }
+}
+
+/// second order taylor model f(x0+dx) ~ value + grad.dx + dx.hessian.dx/2 of an output
+/// around a point, as built by taylor2
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuadraticModel {
+    /// point x0 of the expansion
+    pub center: Vec<f32>,
+    pub value: f32,
+    pub grad: Vec<f32>,
+    /// rows of the hessian
+    pub hessian: Vec<Vec<f32>>,
+}
+
+impl QuadraticModel {
+    /// model value at center + dx
+    pub fn eval(&self, dx: &[f32]) -> f32 {
+        assert_eq!(dx.len(), self.grad.len(), "step length mismatch");
+        let hdx: Vec<f32> = self.hessian.iter().map(|row| dot(row, dx)).collect();
+        self.value + dot(&self.grad, dx) + 0.5 * dot(dx, &hdx)
+    }
+}
+
+/// value, gradient and hessian of output wrt inputs at point, packaged as a quadratic model
+///
+/// the point is substituted for the input values while evaluating, so the values stored
+/// in the graph are left as they are. the hessian takes a reverse sweep per input over the
+/// adjoints; inputs output does not depend on get zero derivatives
+pub fn taylor2(output: &PtrVWrap, inputs: &[PtrVWrap], point: &[f32]) -> QuadraticModel {
+    assert_eq!(inputs.len(), point.len(), "point length mismatch");
+    let at: HashMap<PtrVWrap, ValType> = inputs
+        .iter()
+        .cloned()
+        .zip(point.iter().map(|v| ValType::F(*v)))
+        .collect();
+    let adj = output.rev();
+    let mut grad = vec![0.; inputs.len()];
+    let mut hessian = vec![vec![0.; inputs.len()]; inputs.len()];
+    for (i, x) in inputs.iter().enumerate() {
+        let g = match adj.get(x) {
+            Some(g) => g,
+            None => continue,
+        };
+        grad[i] = g.apply_rev_with(&at).into();
+        let adj2 = g.rev();
+        for (j, z) in inputs.iter().enumerate() {
+            if let Some(h) = adj2.get(z) {
+                hessian[i][j] = h.apply_rev_with(&at).into();
+            }
+        }
+    }
+    QuadraticModel {
+        center: point.to_vec(),
+        value: output.apply_fwd_with(&at).into(),
+        grad,
+        hessian,
+    }
+}
+
+/// jacobian of a set of outputs wrt a set of leaves as a linear operator, for iterative
+/// solvers that only need products with it
+///
+/// matvec evaluates the tangents of the outputs with the leaf derivatives seeded by the
+/// direction, rmatvec the gradients of u.outputs with u held in leaves of its own; both
+/// are built once and reevaluated at the current leaf values, and the matrix is never
+/// formed
+pub struct JacobianOperator {
+    inputs: Vec<PtrVWrap>,
+    tangents: Vec<PtrVWrap>,
+    cotangent: Vec<PtrVWrap>,
+    vjp: Vec<Option<PtrVWrap>>,
+    ws: Workspace,
+}
+
+impl JacobianOperator {
+    pub fn new(outputs: &[PtrVWrap], inputs: &[PtrVWrap]) -> JacobianOperator {
+        let tangents = with_active(outputs, inputs, || {
+            outputs.iter().map(|y| y.fwd()).collect::<Vec<_>>()
+        });
+        let cotangent: Vec<PtrVWrap> = outputs.iter().map(|_| Leaf(ValType::F(0.))).collect();
+        let adj = dot_nodes(outputs, &cotangent).rev();
+        JacobianOperator {
+            inputs: inputs.to_vec(),
+            tangents,
+            vjp: inputs.iter().map(|x| adj.get(x).cloned()).collect(),
+            cotangent,
+            ws: Workspace::new(),
+        }
+    }
+
+    /// number of outputs
+    pub fn rows(&self) -> usize {
+        self.tangents.len()
+    }
+
+    /// number of inputs
+    pub fn cols(&self) -> usize {
+        self.inputs.len()
+    }
+
+    /// jacobian times v, the derivative of the outputs along v
+    pub fn matvec(&mut self, v: &[f32]) -> Vec<f32> {
+        assert_eq!(v.len(), self.cols(), "vector length mismatch");
+        self.tangents
+            .iter()
+            .map(|t| t.apply_fwd_with(&seed_links(t, &self.inputs, v)).into())
+            .collect()
+    }
+
+    /// transposed jacobian times u, the gradient of u.outputs
+    pub fn rmatvec(&mut self, u: &[f32]) -> Vec<f32> {
+        assert_eq!(u.len(), self.rows(), "vector length mismatch");
+        for (c, x) in self.cotangent.iter_mut().zip(u) {
+            c.set_val(ValType::F(*x));
+        }
+        let ws = &mut self.ws;
+        self.vjp
+            .iter()
+            .map(|g| g.as_ref().map_or(0., |g| ws.apply_rev(g).into()))
+            .collect()
+    }
+}
+
+/// settings of the trust region newton method
+#[derive(Debug, Clone, Copy)]
+pub struct TrustRegion {
+    /// initial trust region radius
+    pub radius: f32,
+    pub max_radius: f32,
+    /// minimum ratio of actual to predicted reduction for accepting a step
+    pub eta: f32,
+    /// gradient norm at which to stop
+    pub tol: f32,
+    /// conjugate gradient iterations per step
+    pub max_cg: usize,
+}
+
+impl Default for TrustRegion {
+    fn default() -> TrustRegion {
+        TrustRegion {
+            radius: 1.,
+            max_radius: 100.,
+            eta: 0.1,
+            tol: 1e-4,
+            max_cg: 50,
+        }
+    }
+}
+
+/// step p minimizing g.p+p.Hp/2 within |p| <= radius by steihaug conjugate gradient
+fn steihaug(hvp: &mut Hvp, g: &[f32], radius: f32, tol: f32, max_cg: usize) -> Vec<f32> {
+    //tau >= 0 with |z+tau*d| = radius
+    let to_boundary = |z: &[f32], d: &[f32]| {
+        let (a, b, c) = (dot(d, d), 2. * dot(z, d), dot(z, z) - radius * radius);
+        let tau = (-b + (b * b - 4. * a * c).max(0.).sqrt()) / (2. * a);
+        z.iter()
+            .zip(d)
+            .map(|(z, d)| z + tau * d)
+            .collect::<Vec<f32>>()
+    };
+
+    let mut z = vec![0.; g.len()];
+    let mut r = g.to_vec();
+    let mut d: Vec<f32> = g.iter().map(|x| -x).collect();
+    for _ in 0..max_cg {
+        let hd = hvp.apply(&d);
+        let dhd = dot(&d, &hd);
+        if dhd <= 0. {
+            //negative curvature, follow d to the boundary
+            return to_boundary(&z, &d);
+        }
+        let alpha = dot(&r, &r) / dhd;
+        let z_next: Vec<f32> = z.iter().zip(&d).map(|(z, d)| z + alpha * d).collect();
+        if norm(&z_next) >= radius {
+            return to_boundary(&z, &d);
+        }
+        let r_next: Vec<f32> = r.iter().zip(&hd).map(|(r, h)| r + alpha * h).collect();
+        if norm(&r_next) < tol {
+            return z_next;
+        }
+        let beta = dot(&r_next, &r_next) / dot(&r, &r);
+        d = r_next.iter().zip(&d).map(|(r, d)| -r + beta * d).collect();
+        z = z_next;
+        r = r_next;
+    }
+    z
+}
+
+/// minimizes output over params by a trust region newton method, with steps solved by
+/// steihaug conjugate gradient on hessian-vector products
+///
+/// runs at most iters steps and returns the output value after each accepted or rejected
+/// step; params hold the final point
+pub fn trust_region(
+    output: &PtrVWrap,
+    params: &mut ParamSet,
+    opts: &TrustRegion,
+    iters: usize,
+) -> Vec<f32> {
+    let mut hvp = Hvp::new(output, params.leaves());
+    trust_region_hvp(&mut hvp, params, opts, iters)
+}
+
+/// trust_region on the derivative graphs of hvp, built for the leaves of params
+fn trust_region_hvp(
+    hvp: &mut Hvp,
+    params: &mut ParamSet,
+    opts: &TrustRegion,
+    iters: usize,
+) -> Vec<f32> {
+    let mut radius = opts.radius;
+    let mut f = hvp.value();
+    let mut losses = vec![];
+    for _ in 0..iters {
+        let g = hvp.grad();
+        if norm(&g) < opts.tol {
+            break;
+        }
+        let p = steihaug(hvp, &g, radius, opts.tol, opts.max_cg);
+        let hp = hvp.apply(&p);
+        let predicted = -(dot(&g, &p) + 0.5 * dot(&p, &hp));
+
+        let x = params.values();
+        let trial: Vec<f32> = x.iter().zip(&p).map(|(x, p)| x + p).collect();
+        params.set_values(&trial);
+        let f_trial = hvp.value();
+        let rho = if predicted > 0. {
+            (f - f_trial) / predicted
+        } else {
+            -1.
+        };
+
+        if rho < 0.25 {
+            radius *= 0.25;
+        } else if rho > 0.75 && norm(&p) >= 0.99 * radius {
+            radius = (2. * radius).min(opts.max_radius);
+        }
+        if rho > opts.eta {
+            f = f_trial;
+        } else {
+            params.set_values(&x);
+        }
+        losses.push(f);
+    }
+    losses
+}
+
+/// settings of the levenberg-marquardt solver
+#[derive(Debug, Clone, Copy)]
+pub struct LevenbergMarquardt {
+    /// initial damping
+    pub lambda: f64,
+    /// factor the damping is multiplied by after a rejected step and divided by after an
+    /// accepted one
+    pub factor: f64,
+    /// norm of J^T r at which to stop
+    pub tol: f64,
+}
+
+impl Default for LevenbergMarquardt {
+    fn default() -> LevenbergMarquardt {
+        LevenbergMarquardt {
+            lambda: 1e-3,
+            factor: 10.,
+            tol: 1e-6,
+        }
+    }
+}
+
+/// minimizes the sum of squared residuals over params by damped gauss-newton steps
+/// (J^T J + lambda diag(J^T J)) dx = -J^T r
+///
+/// the jacobian rows are built once by a reverse sweep per residual and reevaluated at each
+/// point; runs at most iters steps and returns half the sum of squares after each step,
+/// with params holding the final point
+pub fn levenberg_marquardt(
+    residuals: &[PtrVWrap],
+    params: &mut ParamSet,
+    opts: &LevenbergMarquardt,
+    iters: usize,
+) -> Vec<f32> {
+    let n = params.len();
+    let rows: Vec<Vec<Option<PtrVWrap>>> = residuals
+        .iter()
+        .map(|r| {
+            let adj = r.rev();
+            params
+                .leaves()
+                .iter()
+                .map(|l| adj.get(l).cloned())
+                .collect()
+        })
+        .collect();
+    let mut ws = Workspace::new();
+    let cost = |ws: &mut Workspace| -> f64 {
+        residuals
+            .iter()
+            .map(|r| {
+                let v: f64 = ws.apply_fwd(r).into();
+                0.5 * v * v
+            })
+            .sum()
+    };
+
+    let mut lambda = opts.lambda;
+    let mut c = cost(&mut ws);
+    let mut costs = vec![];
+    for _ in 0..iters {
+        //J^T J and J^T r at the current point
+        let mut jtj = vec![0.; n * n];
+        let mut jtr = vec![0.; n];
+        for (r, row) in residuals.iter().zip(rows.iter()) {
+            let rv: f64 = ws.apply_fwd(r).into();
+            let j: Vec<f64> = row
+                .iter()
+                .map(|a| a.as_ref().map_or(0., |a| ws.apply_rev(a).into()))
+                .collect();
+            for (a, ja) in j.iter().enumerate() {
+                jtr[a] += ja * rv;
+                for (b, jb) in j.iter().enumerate() {
+                    jtj[a * n + b] += ja * jb;
+                }
+            }
+        }
+        if jtr.iter().map(|x| x * x).sum::<f64>().sqrt() < opts.tol {
+            break;
+        }
+
+        let x = params.values();
+        let mut accepted = false;
+        //raise the damping until the step reduces the cost
+        while !accepted && lambda < 1e12 {
+            let mut m = jtj.clone();
+            for a in 0..n {
+                m[a * n + a] += lambda * jtj[a * n + a].max(1e-12);
+            }
+            let l = linalg::cholesky(&m, n);
+            let neg: Vec<f64> = jtr.iter().map(|x| -x).collect();
+            let dx = linalg::solve_lower_transpose(&l, &linalg::solve_lower(&l, &neg, n), n);
+            let trial: Vec<f32> = x.iter().zip(&dx).map(|(x, d)| x + *d as f32).collect();
+            params.set_values(&trial);
+            let c_trial = cost(&mut ws);
+            if c_trial < c {
+                c = c_trial;
+                lambda /= opts.factor;
+                accepted = true;
+            } else {
+                params.set_values(&x);
+                lambda *= opts.factor;
+            }
+        }
+        costs.push(c as f32);
+        if !accepted {
+            break;
+        }
+    }
+    costs
+}
+
+/// minimizes output over params within lower <= x <= upper by gradient steps projected
+/// back onto the bounds
+///
+/// stops early once a step moves no parameter by more than tol; returns the output value
+/// after each step, with params holding the final point
+pub fn projected_gradient(
+    output: &PtrVWrap,
+    params: &mut ParamSet,
+    lower: &[f32],
+    upper: &[f32],
+    lr: f32,
+    tol: f32,
+    iters: usize,
+) -> Vec<f32> {
+    assert_eq!(lower.len(), params.len(), "bound count mismatch");
+    assert_eq!(upper.len(), params.len(), "bound count mismatch");
+    let mut hvp = Hvp::new(output, params.leaves());
+    let clamp = |x: &mut [f32]| {
+        for ((x, lo), hi) in x.iter_mut().zip(lower).zip(upper) {
+            *x = x.max(*lo).min(*hi);
+        }
+    };
+    let mut x = params.values();
+    clamp(&mut x);
+    params.set_values(&x);
+
+    let mut losses = vec![];
+    for _ in 0..iters {
+        let g = hvp.grad();
+        let mut next: Vec<f32> = x.iter().zip(&g).map(|(x, g)| x - lr * g).collect();
+        clamp(&mut next);
+        let moved = x
+            .iter()
+            .zip(&next)
+            .fold(0f32, |m, (a, b)| m.max((a - b).abs()));
+        params.set_values(&next);
+        x = next;
+        losses.push(hvp.value());
+        if moved <= tol {
+            break;
+        }
+    }
+    losses
+}
+
+/// settings of the augmented lagrangian method
+#[derive(Debug, Clone, Copy)]
+pub struct AugmentedLagrangian {
+    /// initial penalty weight
+    pub mu: f32,
+    /// factor the penalty grows by when the violation does not shrink enough
+    pub growth: f32,
+    /// largest constraint violation accepted as feasible
+    pub tol: f32,
+    /// settings and step count of the trust region method minimizing each subproblem
+    pub inner: TrustRegion,
+    pub inner_iters: usize,
+}
+
+impl Default for AugmentedLagrangian {
+    fn default() -> AugmentedLagrangian {
+        AugmentedLagrangian {
+            mu: 10.,
+            growth: 10.,
+            tol: 1e-4,
+            inner: TrustRegion::default(),
+            inner_iters: 100,
+        }
+    }
+}
+
+/// minimizes output over params subject to constraints[i] = 0
+///
+/// each outer iteration minimizes output + sum(l_i c_i) + mu/2 sum(c_i^2) with the trust
+/// region method, then updates the multipliers l_i += mu c_i; the graph of this function
+/// and its derivative graphs are built once with the multipliers and penalty held in
+/// leaves. returns the output value after each outer iteration and stops once no
+/// constraint is violated by more than tol
+pub fn augmented_lagrangian(
+    output: &PtrVWrap,
+    constraints: &[PtrVWrap],
+    params: &mut ParamSet,
+    opts: &AugmentedLagrangian,
+    outer: usize,
+) -> Vec<f32> {
+    let multipliers: Vec<PtrVWrap> = constraints.iter().map(|_| Leaf(ValType::F(0.))).collect();
+    let half_mu = Leaf(ValType::F(0.5 * opts.mu));
+    let squares: Vec<PtrVWrap> = constraints
+        .iter()
+        .map(|c| Mul(c.clone(), c.clone()))
+        .collect();
+    let lagrangian = Add(
+        Add(output.clone(), dot_nodes(&multipliers, constraints)),
+        Mul(half_mu.clone(), sum_nodes(&squares)),
+    );
+
+    let mut hvp = Hvp::new(&lagrangian, params.leaves());
+    let mut ws = Workspace::new();
+    let mut mu = opts.mu;
+    let mut violation = f32::INFINITY;
+    let mut losses = vec![];
+    for _ in 0..outer {
+        trust_region_hvp(&mut hvp, params, &opts.inner, opts.inner_iters);
+        let c: Vec<f32> = constraints.iter().map(|c| ws.apply_fwd(c).into()).collect();
+        losses.push(ws.apply_fwd(output).into());
+
+        let v = c.iter().fold(0f32, |m, x| m.max(x.abs()));
+        if v <= opts.tol {
+            break;
+        }
+        for (l, ci) in multipliers.iter().zip(&c) {
+            let lv: f32 = l.fixed_value().expect("multiplier value missing").into();
+            l.clone().set_val(ValType::F(lv + mu * ci));
+        }
+        if v > 0.25 * violation {
+            mu *= opts.growth;
+            half_mu.clone().set_val(ValType::F(0.5 * mu));
+        }
+        violation = v;
+    }
+    losses
+}
+
+#[cfg(test)]
+fn eq_f32(a: f32, b: f32) -> bool {
+    (a - b).abs() < 0.01
+}
+
+#[test]
+fn test_fit() {
+    use crate::core::{Add, Leaf, Minus, Mul};
+    use crate::valtype::ValType;
+
+    //loss=(a-3)^2+(b+1)^2
+    let objective = |p: &ParamSet| {
+        let da = Minus(p.leaves()[0].clone(), Leaf(ValType::F(3.)));
+        let db = Add(p.leaves()[1].clone(), Leaf(ValType::F(1.)));
+        Add(Mul(da.clone(), da), Mul(db.clone(), db))
+    };
+
+    let mut p = ParamSet::new();
+    p.add("a", 0.);
+    p.add("b", 0.);
+    let mut steps = vec![];
+    let mut log = |s: &StepInfo| steps.push(*s);
+    let losses = fit(objective, &mut p, &mut Sgd { lr: 0.1 }, 50, &mut [&mut log]);
+    assert_eq!(losses.len(), 50);
+    assert!(eq_f32(losses[0], 10.));
+    assert!(losses.windows(2).all(|w| w[1] <= w[0]));
+    assert_eq!(steps.len(), 50);
+    //gradient (2(a-3), 2(b+1)) at the start
+    assert!(eq_f32(steps[0].grad_norm, 40f32.sqrt()));
+    let v = p.values();
+    assert!(eq_f32(v[0], 3.));
+    assert!(eq_f32(v[1], -1.));
+
+    let mut p = ParamSet::new();
+    p.add("a", 0.);
+    p.add("b", 0.);
+    fit(objective, &mut p, &mut Adam::new(0.1), 300, &mut []);
+    let v = p.values();
+    assert!(eq_f32(v[0], 3.));
+    assert!(eq_f32(v[1], -1.));
+}
+
+#[test]
+fn test_hvp() {
+    use crate::core::{Add, Mul, Sin};
+
+    //y=a^2*b+sin(b) where a=1, b=2
+    //H=[[2b, 2a], [2a, -sin(b)]]
+    let mut p = ParamSet::new();
+    let a = p.add("a", 1.);
+    let b = p.add("b", 2.);
+    let y = Add(Mul(Mul(a.clone(), a), b.clone()), Sin(b));
+    let mut h = Hvp::new(&y, p.leaves());
+    assert!(eq_f32(h.value(), 2. + 2f32.sin()));
+    let g = h.grad();
+    assert!(eq_f32(g[0], 4.));
+    assert!(eq_f32(g[1], 1. + 2f32.cos()));
+    let hv = h.apply(&[1., 0.]);
+    assert!(eq_f32(hv[0], 4.));
+    assert!(eq_f32(hv[1], 2.));
+    let hv = h.apply(&[0., 1.]);
+    assert!(eq_f32(hv[1], -(2f32.sin())));
+
+    //new parameter values are picked up, a=0, b=1
+    p.set_values(&[0., 1.]);
+    let hv = h.apply(&[1., 1.]);
+    assert!(eq_f32(hv[0], 2.));
+    assert!(eq_f32(hv[1], -(1f32.sin())));
+}
+
+#[test]
+fn test_taylor2() {
+    use crate::core::{Add, Minus, Mul, Sin};
+
+    //y=a^2*b+sin(b) at a=1, b=2
+    let a = Leaf(ValType::F(-3.));
+    let b = Leaf(ValType::F(5.));
+    let c = Leaf(ValType::F(4.));
+    let y = Add(Mul(Mul(a.clone(), a.clone()), b.clone()), Sin(b.clone()));
+    let m = taylor2(&y, &[a.clone(), b.clone(), c], &[1., 2., 0.]);
+    assert!(eq_f32(m.value, 2. + 2f32.sin()));
+    assert!(eq_f32(m.grad[0], 4.));
+    assert!(eq_f32(m.grad[1], 1. + 2f32.cos()));
+    assert_eq!(m.grad[2], 0.);
+    assert!(eq_f32(m.hessian[0][0], 4.));
+    assert!(eq_f32(m.hessian[0][1], 2.));
+    assert!(eq_f32(m.hessian[1][0], 2.));
+    assert!(eq_f32(m.hessian[1][1], -(2f32.sin())));
+    assert_eq!(m.hessian[2], vec![0.; 3]);
+    assert_eq!(m.eval(&[0., 0., 0.]), m.value);
+    //the leaf values are left as they are
+    assert_eq!(a.fixed_value(), Some(ValType::F(-3.)));
+
+    //exact for a quadratic, q=3a^2+ab-2b+1
+    let q = Add(
+        Add(
+            Mul(Leaf(ValType::F(3.)), Mul(a.clone(), a.clone())),
+            Mul(a.clone(), b.clone()),
+        ),
+        Minus(Leaf(ValType::F(1.)), Mul(Leaf(ValType::F(2.)), b.clone())),
+    );
+    let m = taylor2(&q, &[a, b], &[0.5, -1.]);
+    let f = |a: f32, b: f32| 3. * a * a + a * b - 2. * b + 1.;
+    assert!(eq_f32(m.value, f(0.5, -1.)));
+    assert!(eq_f32(m.eval(&[1.5, 3.]), f(2., 2.)));
+    assert!(eq_f32(m.eval(&[-2., 0.25]), f(-1.5, -0.75)));
+}
+
+#[test]
+fn test_jacobian_operator() {
+    use crate::core::{Sin, Tanh};
+
+    //y0=a*b, y1=sin(a)+b*tanh(c), J=[[b, a], [cos(a), tanh(c)]] at a=1, b=2
+    let a = Leaf(ValType::F(1.));
+    let b = Leaf(ValType::F(2.)).active();
+    let c = Leaf(ValType::F(3.));
+    let y = [
+        Mul(a.clone(), b.clone()),
+        Add(Sin(a.clone()), Mul(b.clone(), Tanh(c.clone()))),
+    ];
+    let mut j = JacobianOperator::new(&y, &[a.clone(), b.clone()]);
+    assert_eq!((j.rows(), j.cols()), (2, 2));
+    //c is not an input and held constant
+    let t = 3f32.tanh();
+    let jv = j.matvec(&[1., 0.]);
+    assert!(eq_f32(jv[0], 2.));
+    assert!(eq_f32(jv[1], 1f32.cos()));
+    let jv = j.matvec(&[0.5, -1.]);
+    assert!(eq_f32(jv[0], 0.));
+    assert!(eq_f32(jv[1], 0.5 * 1f32.cos() - t));
+    let jtu = j.rmatvec(&[1., 2.]);
+    assert!(eq_f32(jtu[0], 2. + 2. * 1f32.cos()));
+    assert!(eq_f32(jtu[1], 1. + 2. * t));
+    //activity flags are restored
+    assert!(b.0.borrow().eval_g && !a.0.borrow().eval_g);
+
+    //new leaf values are picked up, a=0, b=3; u.Jv equals Jtu.v
+    let mut a1 = a.clone();
+    a1.set_val(ValType::F(0.));
+    let mut b1 = b.clone();
+    b1.set_val(ValType::F(3.));
+    let (u, v) = ([0.3, -0.7], [1.1, 0.4]);
+    let jv = j.matvec(&v);
+    let jtu = j.rmatvec(&u);
+    assert!(eq_f32(jv[0], 3.3));
+    assert!(eq_f32(dot(&u, &jv), dot(&jtu, &v)));
+}
+
+#[test]
+fn test_trust_region() {
+    use crate::core::{Add, Minus, Mul};
+
+    //rosenbrock (1-a)^2+100(b-a^2)^2 from (-1.2, 1)
+    let mut p = ParamSet::new();
+    let a = p.add("a", -1.2);
+    let b = p.add("b", 1.);
+    let da = Minus(Leaf(ValType::F(1.)), a.clone());
+    let db = Minus(b, Mul(a.clone(), a));
+    let y = Add(
+        Mul(da.clone(), da),
+        Mul(Leaf(ValType::F(100.)), Mul(db.clone(), db)),
+    );
+    let losses = trust_region(&y, &mut p, &TrustRegion::default(), 100);
+    assert!(losses.len() < 100);
+    assert!(losses.windows(2).all(|w| w[1] <= w[0]));
+    let v = p.values();
+    assert!(eq_f32(v[0], 1.));
+    assert!(eq_f32(v[1], 1.));
+}
+
+#[test]
+fn test_levenberg_marquardt() {
+    use crate::core::{Exp, Minus, Mul};
+
+    //fit y=a*exp(b*t) to samples of a=2, b=-0.5 starting from a=1, b=0
+    let mut p = ParamSet::new();
+    let a = p.add("a", 1.);
+    let b = p.add("b", 0.);
+    let residuals: Vec<PtrVWrap> = (0..8)
+        .map(|i| {
+            let t = i as f32 * 0.5;
+            let y = 2. * (-0.5 * t).exp();
+            let model = Mul(a.clone(), Exp(Mul(b.clone(), Leaf(ValType::F(t)))));
+            Minus(model, Leaf(ValType::F(y)))
+        })
+        .collect();
+    let costs = levenberg_marquardt(&residuals, &mut p, &LevenbergMarquardt::default(), 50);
+    assert!(!costs.is_empty());
+    assert!(costs.windows(2).all(|w| w[1] <= w[0]));
+    assert!(*costs.last().unwrap() < 1e-6);
+    let v = p.values();
+    assert!(eq_f32(v[0], 2.));
+    assert!(eq_f32(v[1], -0.5));
+}
+
+#[test]
+fn test_projected_gradient() {
+    use crate::core::Minus;
+
+    //(a-3)^2+(b+1)^2 within a in [0,2], b in [0,5] has its minimum at (2,0)
+    let mut p = ParamSet::new();
+    let a = p.add("a", 1.);
+    let b = p.add("b", 1.);
+    let da = Minus(a, Leaf(ValType::F(3.)));
+    let db = Add(b, Leaf(ValType::F(1.)));
+    let y = Add(Mul(da.clone(), da), Mul(db.clone(), db));
+    let losses = projected_gradient(&y, &mut p, &[0., 0.], &[2., 5.], 0.1, 1e-6, 200);
+    assert!(losses.len() < 200);
+    let v = p.values();
+    assert!(eq_f32(v[0], 2.));
+    assert!(eq_f32(v[1], 0.));
+    assert!(eq_f32(*losses.last().unwrap(), 2.));
+}
+
+#[test]
+fn test_augmented_lagrangian() {
+    use crate::core::Minus;
+
+    //a^2+2b^2 subject to a+b=1 has its minimum at (2/3, 1/3)
+    let mut p = ParamSet::new();
+    let a = p.add("a", 0.);
+    let b = p.add("b", 0.);
+    let y = Add(
+        Mul(a.clone(), a.clone()),
+        Mul(Leaf(ValType::F(2.)), Mul(b.clone(), b.clone())),
+    );
+    let c = Minus(Add(a, b), Leaf(ValType::F(1.)));
+    let losses = augmented_lagrangian(&y, &[c], &mut p, &AugmentedLagrangian::default(), 20);
+    assert!(losses.len() < 20);
+    let v = p.values();
+    assert!(eq_f32(v[0], 2. / 3.));
+    assert!(eq_f32(v[1], 1. / 3.));
+    assert!(eq_f32(*losses.last().unwrap(), 2. / 3.));
+}
@@ -0,0 +1,366 @@
+//! named collections of parameter leaves
+
+use std::collections::HashMap;
+
+use crate::core::{Leaf, PtrVWrap};
+use crate::valtype::ValType;
+
+/// leaves registered under unique names, in registration order
+#[derive(Default)]
+pub struct ParamSet {
+    names: Vec<String>,
+    leaves: Vec<PtrVWrap>,
+}
+
+/// v in the variant of old, rounded for integers, ValType::F if there is no old value
+fn same_variant(old: Option<ValType>, v: f64) -> ValType {
+    match old {
+        Some(ValType::D(_)) => ValType::D(v),
+        Some(ValType::I(_)) => ValType::I(v.round() as i32),
+        Some(ValType::L(_)) => ValType::L(v.round() as i64),
+        _ => ValType::F(v as f32),
+    }
+}
+
+impl ParamSet {
+    pub fn new() -> ParamSet {
+        Default::default()
+    }
+
+    /// registers a new leaf holding value and returns it
+    pub fn add(&mut self, name: &str, value: f32) -> PtrVWrap {
+        let l = Leaf(ValType::F(value));
+        self.insert(name, l.clone());
+        l
+    }
+
+    /// registers an existing leaf
+    pub fn insert(&mut self, name: &str, leaf: PtrVWrap) {
+        assert!(
+            self.get(name).is_none(),
+            "parameter {} already registered",
+            name
+        );
+        self.names.push(name.to_string());
+        self.leaves.push(leaf);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&PtrVWrap> {
+        self.names
+            .iter()
+            .position(|n| n == name)
+            .map(|i| &self.leaves[i])
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    pub fn leaves(&self) -> &[PtrVWrap] {
+        &self.leaves
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    /// current values in registration order
+    pub fn values(&self) -> Vec<f32> {
+        self.leaves
+            .iter()
+            .map(|l| l.fixed_value().expect("parameter value missing").into())
+            .collect()
+    }
+
+    /// sets the values in registration order, keeping the variant each parameter holds
+    pub fn set_values(&mut self, values: &[f32]) {
+        assert_eq!(values.len(), self.leaves.len(), "parameter count mismatch");
+        for (l, v) in self.leaves.iter_mut().zip(values) {
+            let v = same_variant(l.fixed_value(), f64::from(*v));
+            l.set_val(v);
+        }
+    }
+
+    /// current values in registration order as a flat vector, e.g. the starting point of
+    /// an external solver
+    pub fn to_flat(&self) -> Vec<f64> {
+        self.leaves
+            .iter()
+            .map(|l| l.fixed_value().expect("parameter value missing").into())
+            .collect()
+    }
+
+    /// sets the values in registration order from a flat vector, keeping the variant each
+    /// parameter holds, so ValType::D parameters keep f64 precision
+    pub fn set_from_flat(&mut self, values: &[f64]) {
+        assert_eq!(values.len(), self.leaves.len(), "parameter count mismatch");
+        for (l, v) in self.leaves.iter_mut().zip(values) {
+            let v = same_variant(l.fixed_value(), *v);
+            l.set_val(v);
+        }
+    }
+
+    /// derivatives of output wrt the parameters at their current values, in registration
+    /// order, zero for parameters output does not depend on
+    pub fn grads(&self, output: &PtrVWrap) -> Vec<f32> {
+        let mut adj = output.rev();
+        self.leaves
+            .iter()
+            .map(|l| match adj.get_mut(l) {
+                Some(a) => a.apply_rev().into(),
+                None => 0.,
+            })
+            .collect()
+    }
+
+    /// enables the grad buffers of all parameters, see PtrVWrap::retain_grad
+    pub fn retain_grads(&mut self) {
+        for l in self.leaves.iter_mut() {
+            l.retain_grad();
+        }
+    }
+
+    /// resets the grad buffers of all parameters
+    pub fn zero_grads(&mut self) {
+        for l in self.leaves.iter_mut() {
+            l.zero_grad();
+        }
+    }
+}
+
+/// gradient of output wrt the parameters as a flat vector in registration order, zero for
+/// parameters output does not depend on, matching to_flat and set_from_flat
+///
+/// no value is stored in the graph and grad buffers are left untouched, so the function
+/// can be called by an external solver at any point it chooses
+pub fn flat_grad(output: &PtrVWrap, params: &ParamSet) -> Vec<f64> {
+    let adj = output.rev();
+    params
+        .leaves()
+        .iter()
+        .map(|l| {
+            adj.get(l)
+                .map_or(0., |a| a.apply_rev_with(&HashMap::new()).into())
+        })
+        .collect()
+}
+
+/// gradient of one parameter as reported by grad_stats
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParamGrad {
+    pub name: String,
+    /// content of the grad buffer, None without retain_grad
+    pub grad: Option<f32>,
+    /// l2 norm of the gradient, |grad| for a scalar parameter and 0 without a grad buffer
+    pub norm: f32,
+}
+
+/// summary of the evaluated gradients of a ParamSet
+#[derive(Debug, Clone, PartialEq)]
+pub struct GradStats {
+    /// per parameter, in registration order
+    pub params: Vec<ParamGrad>,
+    /// l2 norm of the finite gradients
+    pub norm: f32,
+    /// largest and smallest finite gradient, None if there is none
+    pub max: Option<f32>,
+    pub min: Option<f32>,
+    /// number of gradients that are NaN
+    pub nan: usize,
+    /// number of gradients that are infinite
+    pub inf: usize,
+    /// number of parameters without a grad buffer
+    pub missing: usize,
+}
+
+/// norms, extremes and NaN counts of the grad buffers of the parameters in one call
+///
+/// the buffers hold the adjoints evaluated since the last zero_grads, so call retain_grads
+/// before building the adjoints; non-finite gradients are counted and left out of the
+/// global norm and extremes, which therefore stay usable for telemetry
+pub fn grad_stats(params: &ParamSet) -> GradStats {
+    let mut stats = GradStats {
+        params: Vec::with_capacity(params.len()),
+        norm: 0.,
+        max: None,
+        min: None,
+        nan: 0,
+        inf: 0,
+        missing: 0,
+    };
+    let mut sq = 0f64;
+    for (name, l) in params.names.iter().zip(params.leaves.iter()) {
+        let grad = l.grad();
+        match grad {
+            None => stats.missing += 1,
+            Some(g) if g.is_nan() => stats.nan += 1,
+            Some(g) if g.is_infinite() => stats.inf += 1,
+            Some(g) => {
+                sq += f64::from(g) * f64::from(g);
+                stats.max = Some(stats.max.map_or(g, |m| m.max(g)));
+                stats.min = Some(stats.min.map_or(g, |m| m.min(g)));
+            }
+        }
+        stats.params.push(ParamGrad {
+            name: name.clone(),
+            grad,
+            norm: grad.map_or(0., f32::abs),
+        });
+    }
+    stats.norm = sq.sqrt() as f32;
+    stats
+}
+
+#[cfg(test)]
+fn eq_f32(a: f32, b: f32) -> bool {
+    (a - b).abs() < 0.01
+}
+
+#[test]
+fn test_param_set() {
+    use crate::core::{Add, Mul, Sin};
+
+    //y=a*b+sin(a) where a=1, b=2
+    let mut p = ParamSet::new();
+    assert!(p.is_empty());
+    let a = p.add("a", 1.);
+    let b = p.add("b", 2.);
+    let c = p.add("unused", 5.);
+    let y = Add(Mul(a.clone(), b.clone()), Sin(a.clone()));
+    assert_eq!(p.len(), 3);
+    assert_eq!(p.names(), &["a", "b", "unused"]);
+    assert_eq!(p.get("b"), Some(&b));
+    assert!(p.get("d").is_none());
+    assert_eq!(p.values(), vec![1., 2., 5.]);
+
+    let g = p.grads(&y);
+    assert!(eq_f32(g[0], 2. + 1f32.cos()));
+    assert!(eq_f32(g[1], 1.));
+    assert!(eq_f32(g[2], 0.));
+
+    //new values are picked up by the next gradient
+    p.set_values(&[0., 3., 5.]);
+    assert_eq!(p.values(), vec![0., 3., 5.]);
+    let g = p.grads(&y);
+    assert!(eq_f32(g[0], 4.));
+    assert!(eq_f32(g[1], 0.));
+
+    let mut q = ParamSet::new();
+    q.insert("c", c.clone());
+    assert_eq!(q.leaves(), &[c]);
+
+    //f64 and integer parameters keep their variant
+    let d = Leaf(ValType::D(0.));
+    let n = Leaf(ValType::I(0));
+    q.insert("d", d.clone());
+    q.insert("n", n.clone());
+    q.set_values(&[1., 0.5, 2.]);
+    assert_eq!(d.fixed_value(), Some(ValType::D(0.5)));
+    assert_eq!(n.fixed_value(), Some(ValType::I(2)));
+    q.set_from_flat(&[1., 0.25, 3.]);
+    assert_eq!(d.fixed_value(), Some(ValType::D(0.25)));
+    assert_eq!(n.fixed_value(), Some(ValType::I(3)));
+}
+
+#[test]
+fn test_flat_grad() {
+    use crate::core::{Add, Minus, Mul};
+
+    //rosenbrock (1-a)^2 + 100(b-a^2)^2, with b held in f64
+    let mut p = ParamSet::new();
+    let a = p.add("a", -1.2);
+    let b = Leaf(ValType::D(1.));
+    p.insert("b", b.clone());
+    p.add("unused", 3.);
+    let one = Leaf(ValType::F(1.));
+    let r0 = Minus(one, a.clone());
+    let r1 = Minus(b.clone(), Mul(a.clone(), a.clone()));
+    let y = Add(
+        Mul(r0.clone(), r0),
+        Mul(Leaf(ValType::F(100.)), Mul(r1.clone(), r1)),
+    );
+
+    //dy/da = -2(1-a) - 400a(b-a^2), dy/db = 200(b-a^2)
+    let g = flat_grad(&y, &p);
+    assert_eq!(g.len(), 3);
+    assert!((g[0] + 215.6).abs() < 1e-3);
+    assert!((g[1] + 88.).abs() < 1e-3);
+    assert_eq!(g[2], 0.);
+    assert_eq!(a.grad(), None);
+
+    //an external solver only sees flat vectors: gradient descent with backtracking
+    let mut x = p.to_flat();
+    assert_eq!(x[1], 1.);
+    let f = |p: &mut ParamSet, x: &[f64]| {
+        p.set_from_flat(x);
+        f64::from(y.apply_fwd_with(&HashMap::new()))
+    };
+    let mut step = 1e-3;
+    for _ in 0..5000 {
+        let g = flat_grad(&y, &p);
+        let fx = f(&mut p, &x);
+        let next: Vec<f64> = x.iter().zip(g.iter()).map(|(x, g)| x - step * g).collect();
+        if f(&mut p, &next) < fx {
+            x = next;
+            step *= 1.2;
+        } else {
+            step *= 0.5;
+        }
+        p.set_from_flat(&x);
+    }
+    assert!((x[0] - 1.).abs() < 0.05);
+    assert!((x[1] - 1.).abs() < 0.1);
+    assert_eq!(x[2], 3.);
+    assert!(matches!(b.fixed_value(), Some(ValType::D(_))));
+}
+
+#[test]
+fn test_grad_stats() {
+    use crate::core::{Add, Div, Mul};
+
+    //y=3a-4b+c/d where d=0
+    let mut p = ParamSet::new();
+    let a = p.add("a", 1.);
+    let b = p.add("b", 2.);
+    let c = p.add("c", 0.);
+    let d = p.add("d", 0.);
+    p.add("e", 1.);
+    p.retain_grads();
+    let y = Add(
+        Add(
+            Mul(Leaf(ValType::F(3.)), a.clone()),
+            Mul(Leaf(ValType::F(-4.)), b.clone()),
+        ),
+        Div(c.clone(), d.clone()),
+    );
+    let mut q = ParamSet::new();
+    q.insert("x", Leaf(ValType::F(0.)));
+
+    let g = p.grads(&y);
+    assert!(eq_f32(g[0], 3.));
+    let s = grad_stats(&p);
+    //dy/dc=1/d is infinite, dy/dd=-c/d^2 is NaN, e does not appear in y
+    assert_eq!(s.params[1].name, "b");
+    assert_eq!(s.params[1].grad, Some(-4.));
+    assert!(eq_f32(s.params[1].norm, 4.));
+    assert_eq!(s.params[4].grad, Some(0.));
+    assert!(eq_f32(s.norm, 5.));
+    assert_eq!(s.max, Some(3.));
+    assert_eq!(s.min, Some(-4.));
+    assert_eq!((s.nan, s.inf, s.missing), (1, 1, 0));
+
+    //buffers accumulate until zero_grads
+    p.grads(&y);
+    assert!(eq_f32(grad_stats(&p).norm, 10.));
+    p.zero_grads();
+    let s = grad_stats(&p);
+    assert_eq!((s.norm, s.max, s.nan), (0., Some(0.), 0));
+
+    let s = grad_stats(&q);
+    assert_eq!((s.missing, s.max), (1, None));
+    assert_eq!(s.params[0].grad, None);
+}
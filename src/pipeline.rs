@@ -0,0 +1,218 @@
+//! splitting of a graph into stages for pipelined or distributed evaluation
+//!
+//! each stage computes its cut nodes from the cut nodes of earlier stages it reads, its
+//! interface inputs, and from the leaves of the graph. values and adjoints cross stage
+//! boundaries as plain ValType buffers in the order of the interface lists, so a stage can
+//! run wherever the values of its inputs and the leaves are available
+
+use std::collections::{HashMap, HashSet};
+
+use crate::core::{dot_nodes, topo_order, Leaf, PtrVWrap};
+use crate::valtype::ValType;
+
+/// part of a graph between cuts, see split_graph
+pub struct Stage {
+    inputs: Vec<PtrVWrap>,
+    outputs: Vec<PtrVWrap>,
+    leaves: Vec<PtrVWrap>,
+    /// leaves holding the adjoints of the outputs while evaluating the backward pass
+    seeds: Vec<PtrVWrap>,
+    /// adjoint graphs of inputs and leaves, None if zero
+    input_adj: Vec<Option<PtrVWrap>>,
+    leaf_adj: Vec<Option<PtrVWrap>>,
+}
+
+impl Stage {
+    /// cut nodes of earlier stages read by this stage
+    pub fn inputs(&self) -> &[PtrVWrap] {
+        &self.inputs
+    }
+
+    /// nodes computed by this stage, its cut nodes or the output for the last stage
+    pub fn outputs(&self) -> &[PtrVWrap] {
+        &self.outputs
+    }
+
+    /// leaves of the graph read by this stage
+    pub fn leaves(&self) -> &[PtrVWrap] {
+        &self.leaves
+    }
+
+    fn at(&self, inputs: &[ValType]) -> HashMap<PtrVWrap, ValType> {
+        assert_eq!(
+            inputs.len(),
+            self.inputs.len(),
+            "stage input count mismatch"
+        );
+        self.inputs
+            .iter()
+            .cloned()
+            .zip(inputs.iter().cloned())
+            .collect()
+    }
+
+    /// values of the outputs given the values of the inputs and the current leaf values
+    ///
+    /// no value is stored in the graph
+    pub fn forward(&self, inputs: &[ValType]) -> Vec<ValType> {
+        let at = self.at(inputs);
+        self.outputs.iter().map(|o| o.apply_fwd_with(&at)).collect()
+    }
+
+    /// adjoints of the inputs and of the leaves of the stage given the values of the inputs
+    /// and the adjoints of the outputs
+    ///
+    /// a leaf read by several stages gets the sum of their leaf adjoints
+    pub fn backward(
+        &self,
+        inputs: &[ValType],
+        output_adj: &[ValType],
+    ) -> (Vec<ValType>, Vec<ValType>) {
+        assert_eq!(
+            output_adj.len(),
+            self.outputs.len(),
+            "stage output count mismatch"
+        );
+        let mut at = self.at(inputs);
+        at.extend(self.seeds.iter().cloned().zip(output_adj.iter().cloned()));
+        let eval = |adj: &[Option<PtrVWrap>]| {
+            adj.iter()
+                .map(|a| a.as_ref().map_or(ValType::F(0.), |a| a.apply_rev_with(&at)))
+                .collect()
+        };
+        (eval(&self.input_adj), eval(&self.leaf_adj))
+    }
+}
+
+/// nodes reachable from roots without passing through stop, stop nodes included
+fn reach(roots: &[PtrVWrap], stop: &HashSet<PtrVWrap>) -> HashSet<PtrVWrap> {
+    let mut seen: HashSet<PtrVWrap> = roots.iter().cloned().collect();
+    let mut stack = roots.to_vec();
+    while let Some(n) = stack.pop() {
+        if stop.contains(&n) && !roots.contains(&n) {
+            continue;
+        }
+        for i in n.inputs() {
+            if seen.insert(i.clone()) {
+                stack.push(i);
+            }
+        }
+    }
+    seen
+}
+
+/// splits the graph of output into stages ending at the given groups of cut nodes
+///
+/// stage k computes cuts[k] and the last stage computes output. a stage reads the cut
+/// nodes of earlier stages it depends on as inputs and recomputes any other node it
+/// shares with an earlier stage; cut nodes must not depend on cut nodes of later stages.
+/// the adjoint graphs of every stage are built here, once
+pub fn split_graph(output: &PtrVWrap, cuts: &[Vec<PtrVWrap>]) -> Vec<Stage> {
+    let mut groups: Vec<Vec<PtrVWrap>> = cuts.to_vec();
+    groups.push(vec![output.clone()]);
+    let stage_of: HashMap<PtrVWrap, usize> = cuts
+        .iter()
+        .enumerate()
+        .flat_map(|(k, g)| g.iter().map(move |c| (c.clone(), k)))
+        .collect();
+    let cut_nodes: HashSet<PtrVWrap> = stage_of.keys().cloned().collect();
+
+    let mut stages = vec![];
+    for (k, outputs) in groups.into_iter().enumerate() {
+        let nodes = reach(&outputs, &cut_nodes);
+        let mut inputs = vec![];
+        let mut leaves = vec![];
+        for n in topo_order(&outputs) {
+            if !nodes.contains(&n) || outputs.contains(&n) {
+                continue;
+            }
+            match stage_of.get(&n) {
+                Some(&j) if j < k => inputs.push(n),
+                Some(&j) => panic!("cut of stage {} depends on a cut of stage {}", k, j),
+                None if n.is_variable() => leaves.push(n),
+                None => {}
+            }
+        }
+
+        let seeds: Vec<PtrVWrap> = outputs.iter().map(|_| Leaf(ValType::F(0.))).collect();
+        let root = dot_nodes(&outputs, &seeds);
+        //the sweep stops at the interface inputs, whose inputs belong to earlier stages
+        let boundary: HashSet<PtrVWrap> = inputs.iter().cloned().collect();
+        let roots = std::slice::from_ref(&root);
+        let swept = reach(roots, &boundary);
+        let order: Vec<PtrVWrap> = topo_order(roots)
+            .into_iter()
+            .filter(|n| swept.contains(n) && !seeds.contains(n))
+            .collect();
+        let adj: HashMap<PtrVWrap, PtrVWrap> = root
+            .adjoint_sweep(order, false, &boundary)
+            .into_iter()
+            .collect();
+
+        stages.push(Stage {
+            input_adj: inputs.iter().map(|n| adj.get(n).cloned()).collect(),
+            leaf_adj: leaves.iter().map(|n| adj.get(n).cloned()).collect(),
+            inputs,
+            outputs,
+            leaves,
+            seeds,
+        });
+    }
+    stages
+}
+
+#[cfg(test)]
+fn eq_f32(a: f32, b: f32) -> bool {
+    (a - b).abs() < 0.01
+}
+
+#[test]
+fn test_split_graph() {
+    use crate::core::{Add, Mul, Sin};
+
+    //h=sin(a*x), z=[h*w, h+w], y=z0*z1+h, cut at h and at z
+    let x = Leaf(ValType::F(0.5));
+    let a = Leaf(ValType::F(2.));
+    let w = Leaf(ValType::F(3.));
+    let h = Sin(Mul(a.clone(), x.clone()));
+    let z = vec![Mul(h.clone(), w.clone()), Add(h.clone(), w.clone())];
+    let mut y = Add(Mul(z[0].clone(), z[1].clone()), h.clone());
+    let stages = split_graph(&y, &[vec![h.clone()], z.clone()]);
+    assert_eq!(stages.len(), 3);
+    assert!(stages[0].inputs().is_empty());
+    assert_eq!(stages[0].leaves(), &[a.clone(), x.clone()]);
+    assert_eq!(stages[1].inputs(), std::slice::from_ref(&h));
+    assert_eq!(stages[1].leaves(), std::slice::from_ref(&w));
+    //the skip connection from h is an input of the last stage as well
+    assert_eq!(stages[2].inputs(), &[h.clone(), z[0].clone(), z[1].clone()]);
+
+    //forward pass exchanging buffers keyed by node
+    let mut vals: HashMap<PtrVWrap, ValType> = HashMap::new();
+    for s in stages.iter() {
+        let inputs: Vec<ValType> = s.inputs().iter().map(|n| vals[n]).collect();
+        let out = s.forward(&inputs);
+        vals.extend(s.outputs().iter().cloned().zip(out));
+    }
+    assert_eq!(vals[&y], y.apply_fwd());
+
+    //backward pass in reverse stage order, summing adjoints of shared interface nodes
+    let mut adj: HashMap<PtrVWrap, f32> = HashMap::new();
+    adj.insert(y.clone(), 1.);
+    let mut grads: HashMap<PtrVWrap, f32> = HashMap::new();
+    for s in stages.iter().rev() {
+        let inputs: Vec<ValType> = s.inputs().iter().map(|n| vals[n]).collect();
+        let out_adj: Vec<ValType> = s.outputs().iter().map(|n| ValType::F(adj[n])).collect();
+        let (da, dl) = s.backward(&inputs, &out_adj);
+        for (n, d) in s.inputs().iter().zip(da) {
+            *adj.entry(n.clone()).or_insert(0.) += f32::from(d);
+        }
+        for (n, d) in s.leaves().iter().zip(dl) {
+            *grads.entry(n.clone()).or_insert(0.) += f32::from(d);
+        }
+    }
+    let full = y.rev();
+    for l in [&x, &a, &w] {
+        let expect: f32 = full[l].clone().apply_rev().into();
+        assert!(eq_f32(grads[l], expect));
+    }
+}
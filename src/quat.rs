@@ -0,0 +1,158 @@
+//! quaternions as 4-vectors of nodes [w, x, y, z] with w the scalar part
+//!
+//! all ops are compositions of scalar nodes, so derivatives of any order are exact
+
+#![allow(non_snake_case)]
+
+use crate::core::{constant, Add, Div, Dot, Minus, Mul, Pow, PtrVWrap};
+use crate::valtype::ValType;
+
+/// quaternion [w, x, y, z]
+pub type Quat = [PtrVWrap; 4];
+
+#[track_caller]
+fn cross(a: [&PtrVWrap; 3], b: [&PtrVWrap; 3]) -> [PtrVWrap; 3] {
+    let m = |x: &PtrVWrap, y: &PtrVWrap| Mul(x.clone(), y.clone());
+    [
+        Minus(m(a[1], b[2]), m(a[2], b[1])),
+        Minus(m(a[2], b[0]), m(a[0], b[2])),
+        Minus(m(a[0], b[1]), m(a[1], b[0])),
+    ]
+}
+
+/// hamilton product p*q
+#[allow(dead_code)]
+#[track_caller]
+pub fn QuatMul(p: &Quat, q: &Quat) -> Quat {
+    let m = |i: usize, j: usize| Mul(p[i].clone(), q[j].clone());
+    [
+        Minus(Minus(m(0, 0), m(1, 1)), Add(m(2, 2), m(3, 3))),
+        Add(Add(m(0, 1), m(1, 0)), Minus(m(2, 3), m(3, 2))),
+        Add(Minus(m(0, 2), m(1, 3)), Add(m(2, 0), m(3, 1))),
+        Add(Add(m(0, 3), m(1, 2)), Minus(m(3, 0), m(2, 1))),
+    ]
+}
+
+/// conjugate [w, -x, -y, -z]
+#[allow(dead_code)]
+#[track_caller]
+pub fn QuatConj(q: &Quat) -> Quat {
+    let neg = |x: &PtrVWrap| Mul(constant(ValType::F(-1.)), x.clone());
+    [q[0].clone(), neg(&q[1]), neg(&q[2]), neg(&q[3])]
+}
+
+/// q/|q|
+#[allow(dead_code)]
+#[track_caller]
+pub fn QuatNormalize(q: &Quat) -> Quat {
+    let norm = Pow(Dot(q.to_vec(), q.to_vec()), constant(ValType::F(0.5)));
+    [
+        Div(q[0].clone(), norm.clone()),
+        Div(q[1].clone(), norm.clone()),
+        Div(q[2].clone(), norm.clone()),
+        Div(q[3].clone(), norm),
+    ]
+}
+
+/// rotate v by the unit quaternion q, i.e. the vector part of q*[0,v]*conj(q)
+///
+/// q is not normalized here, pass it through QuatNormalize when it is a free parameter
+#[allow(dead_code)]
+#[track_caller]
+pub fn QuatRotate(q: &Quat, v: &[PtrVWrap; 3]) -> [PtrVWrap; 3] {
+    //t = 2 u x v, v' = v + w t + u x t
+    let u = [&q[1], &q[2], &q[3]];
+    let uv = cross(u, [&v[0], &v[1], &v[2]]);
+    let t: Vec<PtrVWrap> = uv.iter().map(|x| Add(x.clone(), x.clone())).collect();
+    let ut = cross(u, [&t[0], &t[1], &t[2]]);
+    let r = |i: usize| {
+        Add(
+            Add(v[i].clone(), Mul(q[0].clone(), t[i].clone())),
+            ut[i].clone(),
+        )
+    };
+    [r(0), r(1), r(2)]
+}
+
+#[cfg(test)]
+fn eq_f32(a: f32, b: f32) -> bool {
+    (a - b).abs() < 0.01
+}
+
+#[cfg(test)]
+#[track_caller]
+fn quat(v: [f32; 4]) -> Quat {
+    use crate::core::Leaf;
+    [
+        Leaf(ValType::F(v[0])),
+        Leaf(ValType::F(v[1])),
+        Leaf(ValType::F(v[2])),
+        Leaf(ValType::F(v[3])),
+    ]
+}
+
+#[test]
+fn test_quat_mul() {
+    //i*j=k, j*i=-k
+    let i = quat([0., 1., 0., 0.]);
+    let j = quat([0., 0., 1., 0.]);
+    let mut k = QuatMul(&i, &j);
+    let mut mk = QuatMul(&j, &i);
+    let expected = [0., 0., 0., 1.];
+    for c in 0..4 {
+        assert!(eq_f32(k[c].apply_fwd().into(), expected[c]));
+        assert!(eq_f32(mk[c].apply_fwd().into(), -expected[c]));
+    }
+
+    //q*conj(q)=|q|^2
+    let q = quat([1., 2., 3., 4.]);
+    let mut n = QuatMul(&q, &QuatConj(&q));
+    assert!(eq_f32(n[0].apply_fwd().into(), 30.));
+    assert!(eq_f32(n[3].apply_fwd().into(), 0.));
+}
+
+#[test]
+fn test_quat_normalize() {
+    //n=q/|q| where q=(3,4,0,0)
+    //dn0/dq0=(1-n0^2)/|q|=0.128, dn1/dq0=-n0*n1/|q|=-0.096
+    let mut q = quat([3., 4., 0., 0.]);
+    q[0] = crate::core::Leaf(ValType::F(3.)).active();
+    let mut n = QuatNormalize(&q);
+    assert!(eq_f32(n[0].apply_fwd().into(), 0.6));
+    assert!(eq_f32(n[1].apply_fwd().into(), 0.8));
+    assert!(eq_f32(n[0].fwd().apply_fwd().into(), 0.128));
+    assert!(eq_f32(n[1].fwd().apply_fwd().into(), -0.096));
+
+    //same derivatives in reverse mode
+    let q0 = q[0].clone();
+    let mut adj = n[1].rev();
+    assert!(eq_f32(
+        adj.get_mut(&q0)
+            .expect("q0 adjoint missing")
+            .apply_rev()
+            .into(),
+        -0.096
+    ));
+}
+
+#[test]
+fn test_quat_rotate() {
+    //90 degrees about z maps (1,0,0) to (0,1,0)
+    let h = std::f32::consts::FRAC_1_SQRT_2;
+    let mut q = quat([h, 0., 0., h]);
+    let v = [
+        crate::core::Leaf(ValType::F(1.)),
+        crate::core::Leaf(ValType::F(0.)),
+        crate::core::Leaf(ValType::F(0.)),
+    ];
+    let mut r = QuatRotate(&q, &v);
+    let expected = [0., 1., 0.];
+    for c in 0..3 {
+        assert!(eq_f32(r[c].apply_fwd().into(), expected[c]));
+    }
+
+    //r_x=(1-2(y^2+z^2))*v_x+..., dr_x/dz=-4z=-2.8284 at v=(1,0,0)
+    q[3] = crate::core::Leaf(ValType::F(h)).active();
+    let r = QuatRotate(&q, &v);
+    assert!(eq_f32(r[0].fwd().apply_fwd().into(), -2.8284));
+}
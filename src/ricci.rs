@@ -0,0 +1,1929 @@
+//! parsing of tensor expressions written in Ricci calculus (index) notation
+//!
+//! an expression is a sum of terms, each term a product of indexed tensors,
+//! with repeated upper/lower index pairs implying summation (Einstein convention), eg:
+//!
+//! `A^i_j B^j_k - C^i_k`
+//!
+//! tensor names start with an uppercase letter followed by any letters or digits, so adjacent
+//! factors need to be separated by whitespace or `*` (eg: `Riem X`).
+//! indices may be given after `^`/`_` (optionally grouped in braces, eg: `A^{ij}_{kl}`)
+//! or directly with unicode superscript/subscript characters (eg: `Aⁱʲₖₗ`).
+//! indices are single lowercase letters or digits, except within braces containing whitespace
+//! where each whitespace separated word is an index (eg: `Riem^{mu nu}_{rho sigma}`),
+//! and latex style `\mu` which is always a single index.
+//! the kronecker delta is written as `δ`, eg: `δ^i_j`
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+/// variance of an index
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum IndexPos {
+    Upper,
+    Lower,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TensorIndex {
+    pub name: String,
+    pub pos: IndexPos,
+}
+
+/// indexed tensor, indices are kept in order of appearance
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TensorFactor {
+    pub name: String,
+    pub indices: Vec<TensorIndex>,
+}
+
+/// product of factors
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RicciTerm {
+    pub coeff: i32,
+    pub factors: Vec<TensorFactor>,
+}
+
+/// sum of terms
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RicciExpr {
+    pub terms: Vec<RicciTerm>,
+}
+
+/// pairwise contraction of two operands, ids below the factor count refer to factors of the term,
+/// id `factor count + k` refers to the result of step k
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContractionStep {
+    pub lhs: usize,
+    pub rhs: usize,
+    /// indices remaining in the result, sorted by name
+    pub indices: Vec<String>,
+    /// number of elements of the result
+    pub size: usize,
+    /// multiply-adds needed for the step
+    pub flops: usize,
+}
+
+/// order of pairwise contractions for evaluating a term
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContractionPlan {
+    pub steps: Vec<ContractionStep>,
+    /// total number of elements of all intermediate results
+    pub size: usize,
+    pub flops: usize,
+}
+
+/// name of the kronecker delta
+pub const DELTA: &str = "δ";
+
+/// factor count up to which the optimal order is searched exhaustively
+const CONTRACTION_DP_MAX_FACTORS: usize = 10;
+
+/// summed index pair within a term, locations are given as (factor, index slot)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Contraction {
+    pub index: String,
+    pub upper: (usize, usize),
+    pub lower: (usize, usize),
+}
+
+/// maps unicode superscript characters to their base index character
+fn superscript(c: char) -> Option<char> {
+    let r = match c {
+        '⁰' => '0',
+        '¹' => '1',
+        '²' => '2',
+        '³' => '3',
+        '⁴' => '4',
+        '⁵' => '5',
+        '⁶' => '6',
+        '⁷' => '7',
+        '⁸' => '8',
+        '⁹' => '9',
+        'ᵃ' => 'a',
+        'ᵇ' => 'b',
+        'ᶜ' => 'c',
+        'ᵈ' => 'd',
+        'ᵉ' => 'e',
+        'ᶠ' => 'f',
+        'ᵍ' => 'g',
+        'ʰ' => 'h',
+        'ⁱ' => 'i',
+        'ʲ' => 'j',
+        'ᵏ' => 'k',
+        'ˡ' => 'l',
+        'ᵐ' => 'm',
+        'ⁿ' => 'n',
+        'ᵒ' => 'o',
+        'ᵖ' => 'p',
+        'ʳ' => 'r',
+        'ˢ' => 's',
+        'ᵗ' => 't',
+        'ᵘ' => 'u',
+        'ᵛ' => 'v',
+        'ʷ' => 'w',
+        'ˣ' => 'x',
+        'ʸ' => 'y',
+        'ᶻ' => 'z',
+        'ᵅ' => 'α',
+        'ᵝ' => 'β',
+        'ᵞ' => 'γ',
+        'ᵟ' => 'δ',
+        'ᵋ' => 'ε',
+        'ᶿ' => 'θ',
+        'ᶥ' => 'ι',
+        'ᵠ' => 'φ',
+        'ᵡ' => 'χ',
+        _ => {
+            return None;
+        }
+    };
+    Some(r)
+}
+
+/// maps unicode subscript characters to their base index character
+fn subscript(c: char) -> Option<char> {
+    let r = match c {
+        '₀' => '0',
+        '₁' => '1',
+        '₂' => '2',
+        '₃' => '3',
+        '₄' => '4',
+        '₅' => '5',
+        '₆' => '6',
+        '₇' => '7',
+        '₈' => '8',
+        '₉' => '9',
+        'ₐ' => 'a',
+        'ₑ' => 'e',
+        'ₕ' => 'h',
+        'ᵢ' => 'i',
+        'ⱼ' => 'j',
+        'ₖ' => 'k',
+        'ₗ' => 'l',
+        'ₘ' => 'm',
+        'ₙ' => 'n',
+        'ₒ' => 'o',
+        'ₚ' => 'p',
+        'ᵣ' => 'r',
+        'ₛ' => 's',
+        'ₜ' => 't',
+        'ᵤ' => 'u',
+        'ᵥ' => 'v',
+        'ₓ' => 'x',
+        'ᵦ' => 'β',
+        'ᵧ' => 'γ',
+        'ᵨ' => 'ρ',
+        'ᵩ' => 'φ',
+        'ᵪ' => 'χ',
+        _ => {
+            return None;
+        }
+    };
+    Some(r)
+}
+
+fn is_index_char(c: char) -> bool {
+    //modifier letters count as lowercase, exclude them so they are handled as super/subscripts
+    (c.is_lowercase() || c.is_ascii_digit()) && superscript(c).is_none() && subscript(c).is_none()
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_uppercase() || c == 'δ'
+}
+
+fn is_name_continuation_char(c: char) -> bool {
+    c.is_alphanumeric() && superscript(c).is_none() && subscript(c).is_none()
+}
+
+/// kind of problem found in an expression
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RicciErrorKind {
+    UnexpectedChar,
+    MissingFactor,
+    MissingIndex,
+    InvalidIndex,
+    UnclosedBrace,
+    /// index appears more than twice within a term
+    IndexRepeated,
+    /// index appears twice within a term without being one upper and one lower
+    SameVariance,
+    /// free indices of a term differ from those of the first term
+    FreeIndexMismatch,
+    /// derivative target is not a single tensor
+    NotSingleFactor,
+    /// index of a derivative target is also a free index of the expression
+    IndexClash,
+    /// coefficient of combined like terms does not fit in an i32
+    CoefficientOverflow,
+}
+
+/// problem found in an expression, located by byte offset into the input
+#[derive(Debug, Clone, PartialEq)]
+pub struct RicciError {
+    pub kind: RicciErrorKind,
+    pub offset: usize,
+    /// offending character or index, if any
+    pub found: Option<String>,
+}
+
+impl fmt::Display for RicciError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self.kind {
+            RicciErrorKind::UnexpectedChar => "unexpected character",
+            RicciErrorKind::MissingFactor => "missing factor",
+            RicciErrorKind::MissingIndex => "missing index",
+            RicciErrorKind::InvalidIndex => "invalid index character",
+            RicciErrorKind::UnclosedBrace => "unclosed brace",
+            RicciErrorKind::IndexRepeated => "index appears more than twice in a term",
+            RicciErrorKind::SameVariance => {
+                "contracted index must appear once upper and once lower"
+            }
+            RicciErrorKind::FreeIndexMismatch => "free indices differ from the first term",
+            RicciErrorKind::NotSingleFactor => "expected a single tensor",
+            RicciErrorKind::IndexClash => "index clashes with a free index of the expression",
+            RicciErrorKind::CoefficientOverflow => "coefficient of combined terms overflows",
+        };
+        match &self.found {
+            Some(c) => write!(f, "{} '{}' at byte {}", msg, c, self.offset),
+            None => write!(f, "{} at byte {}", msg, self.offset),
+        }
+    }
+}
+
+impl std::error::Error for RicciError {}
+
+struct Parser {
+    chars: Vec<(usize, char)>,
+    len: usize,
+    cur: usize,
+    errors: Vec<RicciError>,
+    /// byte offsets of the indices, by term and factor
+    spans: Vec<Vec<Vec<usize>>>,
+    /// byte offsets of the terms
+    term_offsets: Vec<usize>,
+}
+
+impl Parser {
+    fn new(s: &str) -> Parser {
+        Parser {
+            chars: s.char_indices().collect(),
+            len: s.len(),
+            cur: 0,
+            errors: vec![],
+            spans: vec![],
+            term_offsets: vec![],
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.cur).map(|x| x.1)
+    }
+
+    /// byte offset of the current character
+    fn offset(&self) -> usize {
+        self.chars.get(self.cur).map(|x| x.0).unwrap_or(self.len)
+    }
+
+    fn error(&mut self, kind: RicciErrorKind, offset: usize, found: Option<String>) {
+        self.errors.push(RicciError {
+            kind,
+            offset,
+            found,
+        });
+    }
+
+    /// records an error at the current character
+    fn error_here(&mut self, kind: RicciErrorKind) {
+        let (offset, found) = (self.offset(), self.peek().map(String::from));
+        self.error(kind, offset, found);
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            if c.is_whitespace() {
+                self.cur += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn parse_expr(&mut self) -> RicciExpr {
+        let mut terms = vec![];
+
+        self.skip_whitespace();
+
+        let mut sign = 1;
+        match self.peek() {
+            Some('-') => {
+                sign = -1;
+                self.cur += 1;
+            }
+            Some('+') => {
+                self.cur += 1;
+            }
+            _ => {}
+        }
+
+        loop {
+            self.skip_whitespace();
+            let offset = self.offset();
+            let coeff = sign * self.parse_coeff();
+            let (factors, spans) = self.parse_term();
+            if factors.is_empty() {
+                self.error_here(RicciErrorKind::MissingFactor);
+            } else {
+                terms.push(RicciTerm { coeff, factors });
+                self.spans.push(spans);
+                self.term_offsets.push(offset);
+            }
+
+            //terms end at a sign or the end of input, anything else is consumed by parse_term
+            match self.peek() {
+                Some('+') => {
+                    sign = 1;
+                }
+                Some('-') => {
+                    sign = -1;
+                }
+                _ => {
+                    break;
+                }
+            }
+            self.cur += 1;
+        }
+
+        RicciExpr { terms }
+    }
+
+    /// optional integer multiplier at the start of a term
+    fn parse_coeff(&mut self) -> i32 {
+        let mut ret: Option<i32> = None;
+        while let Some(c) = self.peek() {
+            match c.to_digit(10) {
+                Some(d) if c.is_ascii_digit() => {
+                    let x = ret.unwrap_or(0);
+                    ret = Some(x.saturating_mul(10).saturating_add(d as i32));
+                    self.cur += 1;
+                }
+                _ => {
+                    break;
+                }
+            }
+        }
+        ret.unwrap_or(1)
+    }
+
+    fn parse_term(&mut self) -> (Vec<TensorFactor>, Vec<Vec<usize>>) {
+        let mut factors = vec![];
+        let mut spans = vec![];
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                Some('*') => {
+                    if factors.is_empty() {
+                        self.error_here(RicciErrorKind::MissingFactor);
+                    }
+                    self.cur += 1;
+                    self.skip_whitespace();
+                    match self.peek() {
+                        Some(c) if is_name_char(c) => {}
+                        _ => {
+                            self.error_here(RicciErrorKind::MissingFactor);
+                        }
+                    }
+                }
+                Some(c) if is_name_char(c) => {
+                    let (f, s) = self.parse_factor();
+                    factors.push(f);
+                    spans.push(s);
+                }
+                Some('+') | Some('-') | None => {
+                    break;
+                }
+                Some(_) => {
+                    self.error_here(RicciErrorKind::UnexpectedChar);
+                    self.cur += 1;
+                }
+            }
+        }
+        (factors, spans)
+    }
+
+    fn parse_factor(&mut self) -> (TensorFactor, Vec<usize>) {
+        let mut name = String::new();
+        name.push(self.peek().expect("tensor name missing"));
+        self.cur += 1;
+        while let Some(c) = self.peek() {
+            if is_name_continuation_char(c) {
+                name.push(c);
+                self.cur += 1;
+            } else {
+                break;
+            }
+        }
+
+        let mut indices = vec![];
+        let mut spans = vec![];
+
+        while let Some(c) = self.peek() {
+            match c {
+                '^' | '_' => {
+                    let pos = if c == '^' {
+                        IndexPos::Upper
+                    } else {
+                        IndexPos::Lower
+                    };
+                    self.cur += 1;
+                    for (name, offset) in self.parse_group() {
+                        indices.push(TensorIndex { name, pos });
+                        spans.push(offset);
+                    }
+                }
+                c if superscript(c).is_some() => {
+                    indices.push(TensorIndex {
+                        name: superscript(c).unwrap().to_string(),
+                        pos: IndexPos::Upper,
+                    });
+                    spans.push(self.offset());
+                    self.cur += 1;
+                }
+                c if subscript(c).is_some() => {
+                    indices.push(TensorIndex {
+                        name: subscript(c).unwrap().to_string(),
+                        pos: IndexPos::Lower,
+                    });
+                    spans.push(self.offset());
+                    self.cur += 1;
+                }
+                _ => {
+                    break;
+                }
+            }
+        }
+
+        (TensorFactor { name, indices }, spans)
+    }
+
+    /// latex style index following a backslash, eg: `\mu`
+    fn parse_command(&mut self) -> Option<String> {
+        let start = self.offset();
+        self.cur += 1;
+        let mut word = String::new();
+        while let Some(c) = self.peek() {
+            if c.is_alphabetic() {
+                word.push(c);
+                self.cur += 1;
+            } else {
+                break;
+            }
+        }
+        if word.is_empty() {
+            self.error(RicciErrorKind::InvalidIndex, start, Some("\\".to_string()));
+            None
+        } else {
+            Some(word)
+        }
+    }
+
+    /// run of index characters with their byte offsets
+    fn parse_run(&mut self) -> Vec<(char, usize)> {
+        let mut ret = vec![];
+        while let Some(c) = self.peek() {
+            if is_index_char(c) {
+                ret.push((c, self.offset()));
+                self.cur += 1;
+            } else {
+                break;
+            }
+        }
+        ret
+    }
+
+    /// indices following a `^` or `_`, either a braced group or a run of index characters
+    fn parse_group(&mut self) -> Vec<(String, usize)> {
+        let mut ret = vec![];
+        if self.peek() == Some('{') {
+            let brace = self.offset();
+            self.cur += 1;
+
+            //words are split into single character indices unless whitespace delimited
+            let mut words: Vec<Vec<(char, usize)>> = vec![];
+            let mut commands = vec![];
+            let mut delimited = false;
+            loop {
+                match self.peek() {
+                    Some('}') => {
+                        self.cur += 1;
+                        break;
+                    }
+                    Some(c) if c.is_whitespace() => {
+                        delimited = true;
+                        self.cur += 1;
+                    }
+                    Some('\\') => {
+                        let offset = self.offset();
+                        if let Some(x) = self.parse_command() {
+                            commands.push((words.len(), x, offset));
+                            words.push(vec![]);
+                        }
+                    }
+                    Some(c) if is_index_char(c) => {
+                        let run = self.parse_run();
+                        words.push(run);
+                    }
+                    Some('+') | Some('-') | Some('*') | Some('^') | Some('_') | Some('{')
+                    | None => {
+                        //likely a forgotten closing brace, leave the rest to the caller
+                        self.error(RicciErrorKind::UnclosedBrace, brace, Some("{".to_string()));
+                        break;
+                    }
+                    Some(c) if is_name_char(c) => {
+                        self.error(RicciErrorKind::UnclosedBrace, brace, Some("{".to_string()));
+                        break;
+                    }
+                    Some(_) => {
+                        self.error_here(RicciErrorKind::InvalidIndex);
+                        self.cur += 1;
+                    }
+                }
+            }
+
+            let mut commands = commands.into_iter().peekable();
+            for (idx, w) in words.into_iter().enumerate() {
+                if commands.peek().map(|x| x.0) == Some(idx) {
+                    let (_, x, offset) = commands.next().unwrap();
+                    ret.push((x, offset));
+                } else if delimited {
+                    ret.push((w.iter().map(|x| x.0).collect(), w[0].1));
+                } else {
+                    ret.extend(w.into_iter().map(|(c, offset)| (c.to_string(), offset)));
+                }
+            }
+        } else if self.peek() == Some('\\') {
+            let offset = self.offset();
+            if let Some(x) = self.parse_command() {
+                ret.push((x, offset));
+            } else {
+                return ret;
+            }
+        } else {
+            ret.extend(
+                self.parse_run()
+                    .into_iter()
+                    .map(|(c, offset)| (c.to_string(), offset)),
+            );
+        }
+        if ret.is_empty() {
+            self.error_here(RicciErrorKind::MissingIndex);
+        }
+        ret
+    }
+
+    /// checks summation convention of each term and consistency of free indices across terms
+    fn validate(&mut self, e: &RicciExpr) {
+        let mut first_free: Option<Vec<TensorIndex>> = None;
+
+        for (t_idx, t) in e.terms.iter().enumerate() {
+            //occurrences of each index in order of first appearance
+            let mut order = vec![];
+            let mut occurrences: HashMap<&str, Vec<(IndexPos, usize)>> = HashMap::new();
+            for (f_idx, f) in t.factors.iter().enumerate() {
+                for (i_idx, i) in f.indices.iter().enumerate() {
+                    let offset = self.spans[t_idx][f_idx][i_idx];
+                    let v = occurrences.entry(i.name.as_str()).or_insert_with(|| {
+                        order.push(i.name.as_str());
+                        vec![]
+                    });
+                    v.push((i.pos, offset));
+                }
+            }
+            for name in order.iter() {
+                let v = &occurrences[name];
+                if v.len() > 2 {
+                    self.error(
+                        RicciErrorKind::IndexRepeated,
+                        v[2].1,
+                        Some(name.to_string()),
+                    );
+                } else if v.len() == 2 && v[0].0 == v[1].0 {
+                    self.error(RicciErrorKind::SameVariance, v[1].1, Some(name.to_string()));
+                }
+            }
+
+            let mut free = t.free_indices();
+            free.sort_by(|a, b| {
+                (&a.name, a.pos == IndexPos::Lower).cmp(&(&b.name, b.pos == IndexPos::Lower))
+            });
+            match first_free {
+                None => {
+                    first_free = Some(free);
+                }
+                Some(ref x) => {
+                    if *x != free {
+                        let offset = self.term_offsets[t_idx];
+                        self.error(RicciErrorKind::FreeIndexMismatch, offset, None);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl RicciTerm {
+    /// index pairs summed over, in order of first appearance
+    pub fn contractions(&self) -> Vec<Contraction> {
+        let mut ret: Vec<Contraction> = vec![];
+        let mut pending: HashMap<&str, (IndexPos, (usize, usize))> = HashMap::new();
+        for (f_idx, f) in self.factors.iter().enumerate() {
+            for (i_idx, i) in f.indices.iter().enumerate() {
+                match pending.remove(i.name.as_str()) {
+                    Some((pos, loc)) if pos != i.pos => {
+                        let (upper, lower) = if pos == IndexPos::Upper {
+                            (loc, (f_idx, i_idx))
+                        } else {
+                            ((f_idx, i_idx), loc)
+                        };
+                        ret.push(Contraction {
+                            index: i.name.clone(),
+                            upper,
+                            lower,
+                        });
+                    }
+                    Some(x) => {
+                        //not a valid contraction, keep the first occurrence
+                        pending.insert(&i.name, x);
+                    }
+                    None => {
+                        pending.insert(&i.name, (i.pos, (f_idx, i_idx)));
+                    }
+                }
+            }
+        }
+        ret.sort_by_key(|x| x.upper.min(x.lower));
+        ret
+    }
+
+    /// indices occurring once in the term, in order of appearance
+    pub fn free_indices(&self) -> Vec<TensorIndex> {
+        let mut count = HashMap::new();
+        for f in self.factors.iter() {
+            for i in f.indices.iter() {
+                *count.entry(i.name.as_str()).or_insert(0) += 1;
+            }
+        }
+        self.factors
+            .iter()
+            .flat_map(|f| f.indices.iter())
+            .filter(|i| count[i.name.as_str()] == 1)
+            .cloned()
+            .collect()
+    }
+}
+
+/// n-th name in the standard sequence of dummy indices (a, b, .., z, a1, b1, ..),
+/// skipping the reserved names
+fn dummy_name(n: usize, reserved: &HashSet<String>) -> String {
+    let mut count = 0;
+    for i in 0.. {
+        let letter = (b'a' + (i % 26) as u8) as char;
+        let name = if i < 26 {
+            letter.to_string()
+        } else {
+            format!("{}{}", letter, i / 26)
+        };
+        if reserved.contains(&name) {
+            continue;
+        }
+        if count == n {
+            return name;
+        }
+        count += 1;
+    }
+    unreachable!()
+}
+
+/// limit on the number of factor orderings tried when breaking ties during canonicalization
+const CANONICAL_MAX_CANDIDATES: usize = 720;
+
+/// all orderings of v
+fn permutations<T: Clone>(v: &[T]) -> Vec<Vec<T>> {
+    if v.len() <= 1 {
+        return vec![v.to_vec()];
+    }
+    let mut ret = vec![];
+    for i in 0..v.len() {
+        let mut rest = v.to_vec();
+        let x = rest.remove(i);
+        for mut p in permutations(&rest) {
+            p.insert(0, x.clone());
+            ret.push(p);
+        }
+    }
+    ret
+}
+
+impl RicciTerm {
+    /// renames dummy indices in order of first appearance
+    fn rename_dummies(&self, reserved: &HashSet<String>) -> RicciTerm {
+        let mut count: HashMap<&str, usize> = HashMap::new();
+        for i in self.factors.iter().flat_map(|f| f.indices.iter()) {
+            *count.entry(i.name.as_str()).or_insert(0) += 1;
+        }
+        let mut map: HashMap<&str, String> = HashMap::new();
+        let factors = self
+            .factors
+            .iter()
+            .map(|f| TensorFactor {
+                name: f.name.clone(),
+                indices: f
+                    .indices
+                    .iter()
+                    .map(|i| {
+                        let name = if count[i.name.as_str()] > 1 {
+                            let n = map.len();
+                            map.entry(i.name.as_str())
+                                .or_insert_with(|| dummy_name(n, reserved))
+                                .clone()
+                        } else {
+                            i.name.clone()
+                        };
+                        TensorIndex { name, pos: i.pos }
+                    })
+                    .collect(),
+            })
+            .collect();
+        RicciTerm {
+            coeff: self.coeff,
+            factors,
+        }
+    }
+
+    /// term with factors sorted and dummy indices renamed to the standard sequence,
+    /// avoiding the reserved names
+    ///
+    /// factors are ordered by name and free index structure, remaining ties are broken
+    /// by choosing the ordering giving the smallest renamed term
+    pub fn canonical(&self, reserved: &HashSet<String>) -> RicciTerm {
+        let free: HashSet<String> = self.free_indices().into_iter().map(|x| x.name).collect();
+
+        //sort key independent of dummy index names
+        let key = |f: &TensorFactor| {
+            (
+                f.name.clone(),
+                f.indices
+                    .iter()
+                    .map(|i| {
+                        if free.contains(&i.name) {
+                            (i.pos, Some(i.name.clone()))
+                        } else {
+                            (i.pos, None)
+                        }
+                    })
+                    .collect::<Vec<_>>(),
+            )
+        };
+
+        let mut factors = self.factors.clone();
+        factors.sort_by_key(|f| key(f));
+
+        //groups of factors with equal keys
+        let mut groups: Vec<Vec<TensorFactor>> = vec![];
+        for f in factors.into_iter() {
+            match groups.last_mut() {
+                Some(g) if key(&g[0]) == key(&f) => {
+                    g.push(f);
+                }
+                _ => {
+                    groups.push(vec![f]);
+                }
+            }
+        }
+
+        //candidate orderings from permuting factors within each group
+        let mut candidates: Vec<Vec<TensorFactor>> = vec![vec![]];
+        for g in groups.iter() {
+            let count = (1..=g.len()).try_fold(candidates.len(), |acc, x| acc.checked_mul(x));
+            if g.len() == 1 || !matches!(count, Some(x) if x <= CANONICAL_MAX_CANDIDATES) {
+                //keep the sorted order of the group
+                for c in candidates.iter_mut() {
+                    c.extend(g.iter().cloned());
+                }
+                continue;
+            }
+            let perms = permutations(g);
+            let mut next = vec![];
+            for c in candidates.iter() {
+                for p in perms.iter() {
+                    let mut x = c.clone();
+                    x.extend(p.iter().cloned());
+                    next.push(x);
+                }
+            }
+            candidates = next;
+        }
+
+        candidates
+            .into_iter()
+            .map(|factors| {
+                RicciTerm {
+                    coeff: self.coeff,
+                    factors,
+                }
+                .rename_dummies(reserved)
+            })
+            .min_by(|a, b| a.factors.cmp(&b.factors))
+            .expect("no candidate ordering")
+    }
+}
+
+fn delta(upper: &str, lower: &str) -> TensorFactor {
+    TensorFactor {
+        name: DELTA.to_string(),
+        indices: vec![
+            TensorIndex {
+                name: upper.to_string(),
+                pos: IndexPos::Upper,
+            },
+            TensorIndex {
+                name: lower.to_string(),
+                pos: IndexPos::Lower,
+            },
+        ],
+    }
+}
+
+impl RicciTerm {
+    fn index_names(&self) -> HashSet<String> {
+        self.factors
+            .iter()
+            .flat_map(|f| f.indices.iter().map(|i| i.name.clone()))
+            .collect()
+    }
+
+    /// renames dummy indices found in avoid to unused names
+    fn rename_dummies_avoiding(&self, avoid: &HashSet<String>) -> RicciTerm {
+        let free: HashSet<String> = self.free_indices().into_iter().map(|x| x.name).collect();
+        let mut used = self.index_names();
+        used.extend(avoid.iter().cloned());
+
+        let mut map: HashMap<String, String> = HashMap::new();
+        for name in self.index_names().into_iter() {
+            if avoid.contains(&name) && !free.contains(&name) {
+                let fresh = dummy_name(0, &used);
+                used.insert(fresh.clone());
+                map.insert(name, fresh);
+            }
+        }
+
+        let mut ret = self.clone();
+        for i in ret.factors.iter_mut().flat_map(|f| f.indices.iter_mut()) {
+            if let Some(x) = map.get(&i.name) {
+                i.name = x.clone();
+            }
+        }
+        ret
+    }
+
+    /// removes kronecker deltas summed with another factor by renaming the index of that factor
+    pub fn contract_deltas(&self) -> RicciTerm {
+        let mut ret = self.clone();
+        'search: loop {
+            for d in 0..ret.factors.len() {
+                let f = &ret.factors[d];
+                if f.name != DELTA || f.indices.len() != 2 || f.indices[0].pos == f.indices[1].pos {
+                    continue;
+                }
+                if f.indices[0].name == f.indices[1].name {
+                    //trace of delta is the dimension, keep it
+                    continue;
+                }
+                for slot in 0..2 {
+                    let x = f.indices[slot].clone();
+                    let y = f.indices[1 - slot].name.clone();
+                    let found = ret.factors.iter().enumerate().find_map(|(o, g)| {
+                        if o == d {
+                            return None;
+                        }
+                        g.indices
+                            .iter()
+                            .position(|i| i.name == x.name && i.pos != x.pos)
+                            .map(|k| (o, k))
+                    });
+                    if let Some((o, k)) = found {
+                        ret.factors[o].indices[k].name = y;
+                        ret.factors.remove(d);
+                        continue 'search;
+                    }
+                }
+            }
+            break;
+        }
+        ret
+    }
+}
+
+/// growable set of small integers
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BitSet(Vec<u64>);
+
+impl BitSet {
+    fn new(len: usize) -> Self {
+        BitSet(vec![0; (len + 63) / 64])
+    }
+
+    fn single(len: usize, i: usize) -> Self {
+        let mut ret = BitSet::new(len);
+        ret.insert(i);
+        ret
+    }
+
+    fn insert(&mut self, i: usize) {
+        self.0[i / 64] |= 1 << (i % 64);
+    }
+
+    fn contains(&self, i: usize) -> bool {
+        self.0[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    fn union(&self, other: &BitSet) -> BitSet {
+        BitSet(
+            self.0
+                .iter()
+                .zip(other.0.iter())
+                .map(|(a, b)| a | b)
+                .collect(),
+        )
+    }
+
+    fn intersect(&self, other: &BitSet) -> BitSet {
+        BitSet(
+            self.0
+                .iter()
+                .zip(other.0.iter())
+                .map(|(a, b)| a & b)
+                .collect(),
+        )
+    }
+
+    fn ones(&self) -> impl Iterator<Item = usize> + '_ {
+        self.0.iter().enumerate().flat_map(|(w, x)| {
+            (0..64)
+                .filter(move |b| x & (1 << b) != 0)
+                .map(move |b| w * 64 + b)
+        })
+    }
+}
+
+/// operand bookkeeping for planning contractions of a term
+struct ContractionCtx<'a> {
+    /// distinct index names in sorted order
+    names: Vec<&'a str>,
+    /// set of index names used by each factor
+    factor_indices: Vec<BitSet>,
+    /// index names occurring only once in the term
+    free: BitSet,
+    dims: &'a HashMap<String, usize>,
+}
+
+impl<'a> ContractionCtx<'a> {
+    fn new(t: &'a RicciTerm, dims: &'a HashMap<String, usize>) -> Self {
+        let mut total: HashMap<&str, usize> = HashMap::new();
+        for i in t.factors.iter().flat_map(|f| f.indices.iter()) {
+            *total.entry(i.name.as_str()).or_insert(0) += 1;
+        }
+        let mut names: Vec<&str> = total.keys().copied().collect();
+        names.sort_unstable();
+        let pos: HashMap<&str, usize> = names.iter().enumerate().map(|(k, v)| (*v, k)).collect();
+        let factor_indices = t
+            .factors
+            .iter()
+            .map(|f| {
+                let mut s = BitSet::new(names.len());
+                for i in f.indices.iter() {
+                    s.insert(pos[i.name.as_str()]);
+                }
+                s
+            })
+            .collect();
+        let mut free = BitSet::new(names.len());
+        for (k, v) in names.iter().enumerate() {
+            if total[v] == 1 {
+                free.insert(k);
+            }
+        }
+        ContractionCtx {
+            names,
+            factor_indices,
+            free,
+            dims,
+        }
+    }
+
+    /// index names used by the factors in the set
+    fn within(&self, set: &BitSet) -> BitSet {
+        set.ones().fold(BitSet::new(self.names.len()), |acc, x| {
+            acc.union(&self.factor_indices[x])
+        })
+    }
+
+    /// index names used by the factors outside of the set
+    fn outside(&self, set: &BitSet) -> BitSet {
+        let mut rest = BitSet::new(self.factor_indices.len());
+        for x in 0..self.factor_indices.len() {
+            if !set.contains(x) {
+                rest.insert(x);
+            }
+        }
+        self.within(&rest)
+    }
+
+    /// indices of the combined factors in the set still needed outside of it
+    fn open(&self, set: &BitSet) -> BitSet {
+        self.within(set)
+            .intersect(&self.outside(set).union(&self.free))
+    }
+
+    fn size(&self, indices: &BitSet) -> usize {
+        indices.ones().fold(1usize, |acc, i| {
+            let name = self.names[i];
+            let d = *self
+                .dims
+                .get(name)
+                .unwrap_or_else(|| panic!("dimension missing for index {}", name));
+            acc.saturating_mul(d)
+        })
+    }
+
+    /// (result size, flops) of contracting two disjoint sets
+    fn cost(&self, a: &BitSet, b: &BitSet) -> (usize, usize) {
+        let (oa, ob) = (self.open(a), self.open(b));
+        (
+            self.size(&self.open(&a.union(b))),
+            self.size(&oa.union(&ob)),
+        )
+    }
+
+    fn step(&self, lhs: usize, rhs: usize, a: &BitSet, b: &BitSet) -> ContractionStep {
+        let (size, flops) = self.cost(a, b);
+        ContractionStep {
+            lhs,
+            rhs,
+            indices: self
+                .open(&a.union(b))
+                .ones()
+                .map(|x| self.names[x].to_string())
+                .collect(),
+            size,
+            flops,
+        }
+    }
+
+    /// steps combining operands (id, set) in the given pairing order
+    fn plan(&self, pairs: Vec<(usize, usize)>, mut sets: Vec<BitSet>) -> ContractionPlan {
+        let mut steps = vec![];
+        for (l, r) in pairs.into_iter() {
+            steps.push(self.step(l, r, &sets[l], &sets[r]));
+            let s = sets[l].union(&sets[r]);
+            sets.push(s);
+        }
+        ContractionPlan {
+            size: steps
+                .iter()
+                .fold(0usize, |acc, x| acc.saturating_add(x.size)),
+            flops: steps
+                .iter()
+                .fold(0usize, |acc, x| acc.saturating_add(x.flops)),
+            steps,
+        }
+    }
+
+    fn singles(&self) -> Vec<BitSet> {
+        let n = self.factor_indices.len();
+        (0..n).map(|x| BitSet::single(n, x)).collect()
+    }
+
+    /// dynamic programming over subsets of factors, for at most
+    /// CONTRACTION_DP_MAX_FACTORS factors
+    fn optimal(&self) -> ContractionPlan {
+        let n = self.factor_indices.len();
+        assert!(n <= CONTRACTION_DP_MAX_FACTORS);
+        let full: usize = (1 << n) - 1;
+
+        //open indices and their size for every subset, built up from the subset without its lowest factor
+        let mut within = vec![BitSet::new(self.names.len()); 1 << n];
+        for set in 1..=full {
+            let low = set.trailing_zeros() as usize;
+            within[set] = within[set & (set - 1)].union(&self.factor_indices[low]);
+        }
+        let open: Vec<BitSet> = (0..=full)
+            .map(|set| within[set].intersect(&within[full ^ set].union(&self.free)))
+            .collect();
+        let open_size: Vec<usize> = open.iter().map(|x| self.size(x)).collect();
+
+        //best (size, flops) and split of each subset
+        let mut best: Vec<Option<((usize, usize), usize)>> = vec![None; 1 << n];
+        for i in 0..n {
+            best[1 << i] = Some(((0, 0), 0));
+        }
+        for set in 1..=full {
+            if set.count_ones() < 2 {
+                continue;
+            }
+            let low = set & set.wrapping_neg();
+            //enumerate splits with the lowest factor in the first part to skip mirrored splits
+            let mut a = (set - 1) & set;
+            while a > 0 {
+                if a & low != 0 {
+                    let b = set ^ a;
+                    let (ca, _) = best[a].expect("subset cost missing");
+                    let (cb, _) = best[b].expect("subset cost missing");
+                    let size = open_size[set];
+                    let flops = self.size(&open[a].union(&open[b]));
+                    let c = (
+                        ca.0.saturating_add(cb.0).saturating_add(size),
+                        ca.1.saturating_add(cb.1).saturating_add(flops),
+                    );
+                    if best[set].map_or(true, |x| c < x.0) {
+                        best[set] = Some((c, a));
+                    }
+                }
+                a = (a - 1) & set;
+            }
+        }
+
+        fn build(
+            set: usize,
+            best: &[Option<((usize, usize), usize)>],
+            pairs: &mut Vec<(usize, usize)>,
+            next: &mut usize,
+        ) -> usize {
+            if set.count_ones() == 1 {
+                return set.trailing_zeros() as usize;
+            }
+            let a = best[set].expect("subset cost missing").1;
+            let l = build(a, best, pairs, next);
+            let r = build(set ^ a, best, pairs, next);
+            pairs.push((l, r));
+            *next += 1;
+            *next - 1
+        }
+
+        let mut pairs = vec![];
+        let mut next = n;
+        build(full, &best, &mut pairs, &mut next);
+        self.plan(pairs, self.singles())
+    }
+
+    /// repeatedly contracts the pair giving the smallest result
+    fn greedy(&self) -> ContractionPlan {
+        let n = self.factor_indices.len();
+        let mut sets = self.singles();
+        let mut open: Vec<BitSet> = sets.iter().map(|x| self.open(x)).collect();
+        //number of live operands each index is open in
+        let mut holders = vec![0usize; self.names.len()];
+        for i in open.iter().flat_map(|x| x.ones()) {
+            holders[i] += 1;
+        }
+        //indices of a pair of operands still open after combining them
+        let merged = |a: &BitSet, b: &BitSet, holders: &[usize]| {
+            let mut ret = a.union(b);
+            for i in a.intersect(b).ones() {
+                if holders[i] == 2 {
+                    ret.0[i / 64] &= !(1 << (i % 64));
+                }
+            }
+            ret
+        };
+        let mut alive: Vec<usize> = (0..n).collect();
+        let mut pairs = vec![];
+        while alive.len() > 1 {
+            let mut pick = (0, 1);
+            let mut pick_cost = None;
+            for x in 0..alive.len() {
+                for y in x + 1..alive.len() {
+                    let (a, b) = (&open[alive[x]], &open[alive[y]]);
+                    let c = (self.size(&merged(a, b, &holders)), self.size(&a.union(b)));
+                    if pick_cost.map_or(true, |p| c < p) {
+                        pick = (x, y);
+                        pick_cost = Some(c);
+                    }
+                }
+            }
+            let (l, r) = (alive[pick.0], alive[pick.1]);
+            pairs.push((l, r));
+            let s = sets[l].union(&sets[r]);
+            sets.push(s);
+            let o = merged(&open[l], &open[r], &holders);
+            for i in open[l].ones().chain(open[r].ones()) {
+                holders[i] -= 1;
+            }
+            for i in o.ones() {
+                holders[i] += 1;
+            }
+            open.push(o);
+            alive.remove(pick.1);
+            alive.remove(pick.0);
+            alive.push(sets.len() - 1);
+        }
+        self.plan(pairs, self.singles())
+    }
+}
+
+impl RicciTerm {
+    /// order of pairwise contractions minimizing the total size of intermediate results
+    /// (ties broken by flops), given the dimension of each index
+    ///
+    /// searches all orders for up to 10 factors, larger terms use a greedy order
+    pub fn contraction_plan(&self, dims: &HashMap<String, usize>) -> ContractionPlan {
+        let ctx = ContractionCtx::new(self, dims);
+        if self.factors.len() <= CONTRACTION_DP_MAX_FACTORS {
+            ctx.optimal()
+        } else {
+            ctx.greedy()
+        }
+    }
+
+    /// left to right order of pairwise contractions, for comparison
+    pub fn naive_contraction_plan(&self, dims: &HashMap<String, usize>) -> ContractionPlan {
+        let ctx = ContractionCtx::new(self, dims);
+        let n = self.factors.len();
+        let pairs = (1..n)
+            .map(|x| (if x == 1 { 0 } else { n + x - 2 }, x))
+            .collect();
+        ctx.plan(pairs, ctx.singles())
+    }
+}
+
+impl RicciExpr {
+    /// parses an expression without checking index consistency
+    pub fn parse(s: &str) -> Result<RicciExpr, Vec<RicciError>> {
+        let mut p = Parser::new(s);
+        let e = p.parse_expr();
+        if p.errors.is_empty() {
+            Ok(e)
+        } else {
+            Err(p.errors)
+        }
+    }
+
+    /// free indices of the result, in order of appearance in the first term
+    pub fn free_indices(&self) -> Vec<TensorIndex> {
+        self.terms
+            .first()
+            .map(|t| t.free_indices())
+            .unwrap_or_default()
+    }
+
+    /// summed index pairs of each term
+    pub fn contractions(&self) -> Vec<Vec<Contraction>> {
+        self.terms.iter().map(|t| t.contractions()).collect()
+    }
+
+    /// number of upper and lower free indices of the result
+    pub fn valence(&self) -> (usize, usize) {
+        let free = self.free_indices();
+        let upper = free.iter().filter(|x| x.pos == IndexPos::Upper).count();
+        (upper, free.len() - upper)
+    }
+
+    /// rank of the result, a scalar has rank 0
+    pub fn rank(&self) -> usize {
+        self.free_indices().len()
+    }
+
+    /// removes kronecker deltas summed with another factor of each term
+    pub fn contract_deltas(&self) -> RicciExpr {
+        RicciExpr {
+            terms: self.terms.iter().map(|t| t.contract_deltas()).collect(),
+        }
+    }
+
+    /// derivative with respect to a tensor component written as a single factor, eg: `B^m_n`
+    ///
+    /// each occurrence of the tensor with matching index variance is replaced by kronecker deltas
+    /// (product rule), then deltas are contracted where possible.
+    /// the indices of the target appear as free indices of the result with opposite variance,
+    /// so they must be distinct and must not be free indices of the expression
+    pub fn derivative(&self, wrt: &str) -> Result<RicciExpr, Vec<RicciError>> {
+        let mut p = Parser::new(wrt);
+        let e = p.parse_expr();
+        if !p.errors.is_empty() {
+            return Err(p.errors);
+        }
+        if e.terms.len() != 1 || e.terms[0].factors.len() != 1 || e.terms[0].coeff != 1 {
+            return Err(vec![RicciError {
+                kind: RicciErrorKind::NotSingleFactor,
+                offset: 0,
+                found: None,
+            }]);
+        }
+        let target = &e.terms[0].factors[0];
+
+        let free: HashSet<String> = self.free_indices().into_iter().map(|x| x.name).collect();
+        let mut seen = HashSet::new();
+        let mut errors = vec![];
+        for (k, i) in target.indices.iter().enumerate() {
+            let offset = p.spans[0][0][k];
+            if !seen.insert(i.name.clone()) {
+                errors.push(RicciError {
+                    kind: RicciErrorKind::IndexRepeated,
+                    offset,
+                    found: Some(i.name.clone()),
+                });
+            } else if free.contains(&i.name) {
+                errors.push(RicciError {
+                    kind: RicciErrorKind::IndexClash,
+                    offset,
+                    found: Some(i.name.clone()),
+                });
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let mut terms = vec![];
+        for t in self.terms.iter() {
+            let t = t.rename_dummies_avoiding(&seen);
+            for (k, f) in t.factors.iter().enumerate() {
+                let matched = f.name == target.name
+                    && f.indices.len() == target.indices.len()
+                    && f.indices
+                        .iter()
+                        .zip(target.indices.iter())
+                        .all(|(a, b)| a.pos == b.pos);
+                if !matched {
+                    continue;
+                }
+                let mut factors = t.factors.clone();
+                factors.remove(k);
+                for (a, b) in f.indices.iter().zip(target.indices.iter()) {
+                    //d(A^a)/d(A^i) = δ^a_i, d(A_a)/d(A_i) = δ^i_a
+                    match a.pos {
+                        IndexPos::Upper => factors.push(delta(&a.name, &b.name)),
+                        IndexPos::Lower => factors.push(delta(&b.name, &a.name)),
+                    }
+                }
+                terms.push(RicciTerm {
+                    coeff: t.coeff,
+                    factors,
+                });
+            }
+        }
+
+        Ok(RicciExpr { terms }.contract_deltas())
+    }
+
+    /// normalized form where structurally equal expressions compare equal
+    ///
+    /// terms are canonicalized, sorted and like terms are combined; fails if the coefficient
+    /// of combined terms overflows, with the combined term as found
+    pub fn canonical(&self) -> Result<RicciExpr, RicciError> {
+        let reserved: HashSet<String> = self
+            .terms
+            .iter()
+            .flat_map(|t| t.free_indices().into_iter().map(|x| x.name))
+            .collect();
+
+        let mut terms: Vec<RicciTerm> = self.terms.iter().map(|t| t.canonical(&reserved)).collect();
+        terms.sort_by(|a, b| a.factors.cmp(&b.factors));
+
+        let mut combined: Vec<RicciTerm> = vec![];
+        for t in terms.into_iter() {
+            match combined.last_mut() {
+                Some(x) if x.factors == t.factors => {
+                    x.coeff = x.coeff.checked_add(t.coeff).ok_or_else(|| RicciError {
+                        kind: RicciErrorKind::CoefficientOverflow,
+                        offset: 0,
+                        found: Some(format!(
+                            "{}",
+                            RicciExpr {
+                                terms: vec![t.clone()]
+                            }
+                        )),
+                    })?;
+                }
+                _ => {
+                    combined.push(t);
+                }
+            }
+        }
+        combined.retain(|t| t.coeff != 0);
+
+        Ok(RicciExpr { terms: combined })
+    }
+}
+
+fn fmt_indices(f: &mut fmt::Formatter<'_>, indices: &[TensorIndex]) -> fmt::Result {
+    //consecutive indices of the same variance are grouped
+    let mut i = 0;
+    while i < indices.len() {
+        let pos = indices[i].pos;
+        let mut j = i;
+        while j < indices.len() && indices[j].pos == pos {
+            j += 1;
+        }
+        //multi-character indices need whitespace delimiting
+        let group = if indices[i..j].iter().any(|x| x.name.chars().count() > 1) {
+            let words: Vec<&str> = indices[i..j].iter().map(|x| x.name.as_str()).collect();
+            if words.len() == 1 {
+                format!("{} ", words[0])
+            } else {
+                words.join(" ")
+            }
+        } else {
+            indices[i..j].iter().map(|x| x.name.as_str()).collect()
+        };
+        match pos {
+            IndexPos::Upper => write!(f, "^{{{}}}", group)?,
+            IndexPos::Lower => write!(f, "_{{{}}}", group)?,
+        }
+        i = j;
+    }
+    Ok(())
+}
+
+impl fmt::Display for TensorFactor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        fmt_indices(f, &self.indices)
+    }
+}
+
+impl fmt::Display for RicciExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.terms.is_empty() {
+            return write!(f, "0");
+        }
+        for (idx, t) in self.terms.iter().enumerate() {
+            if idx == 0 {
+                if t.coeff < 0 {
+                    write!(f, "-")?;
+                }
+            } else if t.coeff < 0 {
+                write!(f, " - ")?;
+            } else {
+                write!(f, " + ")?;
+            }
+            if t.coeff.unsigned_abs() != 1 {
+                write!(f, "{} ", t.coeff.unsigned_abs())?;
+            }
+            for (k, x) in t.factors.iter().enumerate() {
+                if k > 0 {
+                    write!(f, " ")?;
+                }
+                write!(f, "{}", x)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// parses and validates an expression in index notation, reporting all problems found
+pub fn ricci(s: &str) -> Result<RicciExpr, Vec<RicciError>> {
+    let mut p = Parser::new(s);
+    let e = p.parse_expr();
+    p.validate(&e);
+    if p.errors.is_empty() {
+        Ok(e)
+    } else {
+        p.errors.sort_by_key(|x| x.offset);
+        Err(p.errors)
+    }
+}
+
+impl FromStr for RicciExpr {
+    type Err = Vec<RicciError>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ricci(s)
+    }
+}
+
+#[test]
+fn test_ricci_ascii() {
+    let e = RicciExpr::parse("A^i_j B^j_k - C^i_k").expect("parse");
+    assert_eq!(e.terms.len(), 2);
+    assert_eq!(e.terms[0].factors.len(), 2);
+    assert_eq!(e.terms[1].coeff, -1);
+    assert_eq!(
+        e.terms[0].free_indices(),
+        vec![
+            TensorIndex {
+                name: "i".to_string(),
+                pos: IndexPos::Upper
+            },
+            TensorIndex {
+                name: "k".to_string(),
+                pos: IndexPos::Lower
+            }
+        ]
+    );
+    assert!(ricci("A^i_j B^j_k - C^i_k").is_ok());
+}
+
+#[test]
+fn test_ricci_braces() {
+    let a = RicciExpr::parse("A^{ij}_{kl}").expect("parse");
+    let b = RicciExpr::parse("A^ij_kl").expect("parse");
+    assert_eq!(a, b);
+    assert_eq!(a.terms[0].factors[0].indices.len(), 4);
+    assert_eq!(format!("{}", a), "A^{ij}_{kl}");
+}
+
+#[test]
+fn test_ricci_unicode() {
+    let a = RicciExpr::parse("Aⁱʲₖₗ").expect("parse");
+    let b = RicciExpr::parse("A^{ij}_{kl}").expect("parse");
+    assert_eq!(a, b);
+
+    let c = RicciExpr::parse("Rᵅᵦ Xᵝ").expect("parse");
+    assert_eq!(format!("{}", c), "R^{α}_{β} X^{β}");
+    assert!(ricci("Rᵅᵦ Xᵝ").is_ok());
+
+    //mixing of both styles
+    let d = RicciExpr::parse("T^{a}₁ S¹").expect("parse");
+    assert_eq!(format!("{}", d), "T^{a}_{1} S^{1}");
+}
+
+#[test]
+fn test_ricci_invalid() {
+    assert!(ricci("A^{ij").is_err());
+    assert!(ricci("A^").is_err());
+    assert!(ricci("A^i B^i").is_err());
+    assert!(ricci("A^i_i B_i").is_err());
+    assert!(ricci("A^i + B^j").is_err());
+    assert!(ricci("A^i + ").is_err());
+    assert!(ricci("A^i ? B_i").is_err());
+}
+
+#[test]
+fn test_ricci_error_positions() {
+    let e = ricci("A^i ? B_i").expect_err("error expected");
+    assert_eq!(
+        e,
+        vec![RicciError {
+            kind: RicciErrorKind::UnexpectedChar,
+            offset: 4,
+            found: Some("?".to_string()),
+        }]
+    );
+
+    let e = ricci("A^{ij B_j").expect_err("error expected");
+    assert_eq!(e[0].kind, RicciErrorKind::UnclosedBrace);
+    assert_eq!(e[0].offset, 2);
+
+    //offsets are in bytes
+    let e = ricci("Aⁱ Bⁱ").expect_err("error expected");
+    assert_eq!(e.len(), 1);
+    assert_eq!(e[0].kind, RicciErrorKind::SameVariance);
+    assert_eq!(e[0].offset, "Aⁱ B".len());
+    assert_eq!(e[0].found, Some("i".to_string()));
+
+    assert_eq!(
+        format!("{}", e[0]),
+        "contracted index must appear once upper and once lower 'i' at byte 6"
+    );
+}
+
+#[test]
+fn test_ricci_multiple_errors() {
+    let e = ricci("A^i_i B_i + C^{k!} ? + * D").expect_err("error expected");
+    let kinds: Vec<RicciErrorKind> = e.iter().map(|x| x.kind).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            RicciErrorKind::IndexRepeated,
+            RicciErrorKind::FreeIndexMismatch,
+            RicciErrorKind::InvalidIndex,
+            RicciErrorKind::UnexpectedChar,
+            RicciErrorKind::MissingFactor,
+        ]
+    );
+    assert_eq!(e[2].found, Some("!".to_string()));
+
+    let e = ricci("A^i + B^j - C^").expect_err("error expected");
+    let kinds: Vec<RicciErrorKind> = e.iter().map(|x| x.kind).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            RicciErrorKind::FreeIndexMismatch,
+            RicciErrorKind::FreeIndexMismatch,
+            RicciErrorKind::MissingIndex,
+        ]
+    );
+}
+
+#[test]
+fn test_ricci_multichar() {
+    let e = RicciExpr::parse("Riem^{mu nu}_{rho sigma} X^rho").expect("parse");
+    let f = &e.terms[0].factors;
+    assert_eq!(f[0].name, "Riem");
+    let names: Vec<&str> = f[0].indices.iter().map(|x| x.name.as_str()).collect();
+    assert_eq!(names, vec!["mu", "nu", "rho", "sigma"]);
+    assert_eq!(f[1].name, "X");
+    assert_eq!(format!("{}", e), "Riem^{mu nu}_{rho sigma} X^{rho}");
+
+    //single character indices r, h, o unless delimited
+    assert_eq!(e.terms[0].free_indices().len(), 7);
+    let e = RicciExpr::parse("Riem^{mu nu}_{rho sigma} X^{rho }").expect("parse");
+    assert_eq!(e.terms[0].free_indices().len(), 3);
+
+    //latex style indices
+    let a = RicciExpr::parse(r"Gamma^{\lambda}_{\mu\nu}").expect("parse");
+    let b = RicciExpr::parse("Gamma^{lambda }_{mu nu}").expect("parse");
+    assert_eq!(a, b);
+    assert!(ricci(r"Gamma^\lambda_{\mu\nu} V^\mu").is_ok());
+
+    //without whitespace braces still hold single character indices
+    let c = RicciExpr::parse("A^{ij}").expect("parse");
+    assert_eq!(c.terms[0].factors[0].indices.len(), 2);
+
+    //display output parses back to the same expression
+    let d = RicciExpr::parse(&format!("{}", a)).expect("parse");
+    assert_eq!(a, d);
+
+    let e = ricci(r"A^{\ }").expect_err("error expected");
+    assert_eq!(e[0].kind, RicciErrorKind::InvalidIndex);
+}
+
+#[test]
+fn test_ricci_coefficient() {
+    let e = RicciExpr::parse("2 A^i - 3B^i").expect("parse");
+    assert_eq!(e.terms[0].coeff, 2);
+    assert_eq!(e.terms[1].coeff, -3);
+    assert_eq!(format!("{}", e), "2 A^{i} - 3 B^{i}");
+}
+
+#[test]
+fn test_ricci_canonical_dummies() {
+    //renaming of contracted indices
+    let a = RicciExpr::parse("A^i_j B^j_k")
+        .expect("parse")
+        .canonical()
+        .expect("canonical");
+    let b = RicciExpr::parse("A^i_m B^m_k")
+        .expect("parse")
+        .canonical()
+        .expect("canonical");
+    assert_eq!(a, b);
+    assert_eq!(format!("{}", a), "A^{i}_{a} B^{a}_{k}");
+
+    //dummy names avoid free indices
+    let c = RicciExpr::parse("A^a_x B^x")
+        .expect("parse")
+        .canonical()
+        .expect("canonical");
+    assert_eq!(format!("{}", c), "A^{a}_{b} B^{b}");
+
+    //different index structure stays different
+    let d = RicciExpr::parse("A^i_j B^j_k")
+        .expect("parse")
+        .canonical()
+        .expect("canonical");
+    let e = RicciExpr::parse("A_j^i B^j_k")
+        .expect("parse")
+        .canonical()
+        .expect("canonical");
+    assert_ne!(d, e);
+}
+
+#[test]
+fn test_ricci_canonical_factor_order() {
+    let a = RicciExpr::parse("B^j_k A^i_j")
+        .expect("parse")
+        .canonical()
+        .expect("canonical");
+    let b = RicciExpr::parse("A^i_m B^m_k")
+        .expect("parse")
+        .canonical()
+        .expect("canonical");
+    assert_eq!(a, b);
+
+    //ties between factors of the same tensor are broken by the dummy structure
+    let c = RicciExpr::parse("X^p_q X^q_r X^r_p")
+        .expect("parse")
+        .canonical()
+        .expect("canonical");
+    let d = RicciExpr::parse("X^s_t X^u_s X^t_u")
+        .expect("parse")
+        .canonical()
+        .expect("canonical");
+    assert_eq!(c, d);
+
+    let e = RicciExpr::parse("A^i_j A^j_k V^k")
+        .expect("parse")
+        .canonical()
+        .expect("canonical");
+    let f = RicciExpr::parse("A^m_k V^k A^i_m")
+        .expect("parse")
+        .canonical()
+        .expect("canonical");
+    assert_eq!(e, f);
+}
+
+#[test]
+fn test_ricci_canonical_terms() {
+    let a = RicciExpr::parse("A^i_j V^j + B^i - V^k A^i_k")
+        .expect("parse")
+        .canonical()
+        .expect("canonical");
+    assert_eq!(format!("{}", a), "B^{i}");
+
+    let b = RicciExpr::parse("C^i + A^i_j V^j + V^k A^i_k")
+        .expect("parse")
+        .canonical()
+        .expect("canonical");
+    let c = RicciExpr::parse("2 A^i_a V^a + C^i")
+        .expect("parse")
+        .canonical()
+        .expect("canonical");
+    assert_eq!(b, c);
+
+    let d = RicciExpr::parse("A^i - A^i")
+        .expect("parse")
+        .canonical()
+        .expect("canonical");
+    assert!(d.terms.is_empty());
+    assert_eq!(format!("{}", d), "0");
+
+    //combined coefficients out of range are reported
+    let e = RicciExpr::parse("2147483647 A^i + 2147483647 A^i").expect("parse");
+    let err = e.canonical().expect_err("coefficient overflow");
+    assert_eq!(err.kind, RicciErrorKind::CoefficientOverflow);
+    let e = RicciExpr::parse("-2147483647 A^i - A^i")
+        .expect("parse")
+        .canonical()
+        .expect("canonical");
+    assert_eq!(e.terms[0].coeff, i32::MIN);
+    assert_eq!(format!("{}", e), "-2147483648 A^{i}");
+}
+
+#[test]
+fn test_ricci_result() {
+    let e = ricci("A^i_j B^j_k - C^i_k").expect("valid expression");
+    assert_eq!(e.rank(), 2);
+    assert_eq!(e.valence(), (1, 1));
+    let free: Vec<String> = e.free_indices().into_iter().map(|x| x.name).collect();
+    assert_eq!(free, vec!["i", "k"]);
+    assert_eq!(
+        e.contractions(),
+        vec![
+            vec![Contraction {
+                index: "j".to_string(),
+                upper: (1, 0),
+                lower: (0, 1),
+            }],
+            vec![]
+        ]
+    );
+
+    //full contraction gives a scalar
+    let s: RicciExpr = "G_{mu nu} V^{mu } W^{nu }"
+        .parse()
+        .expect("valid expression");
+    assert_eq!(s.rank(), 0);
+    assert_eq!(s.contractions()[0].len(), 2);
+
+    assert!("A^i B^i".parse::<RicciExpr>().is_err());
+}
+
+#[test]
+fn test_ricci_contraction_plan() {
+    let e = ricci("A_{ij} B^j_k C^k").expect("valid expression");
+    let dims: HashMap<String, usize> = ["i", "j", "k"]
+        .iter()
+        .map(|x| (x.to_string(), 100))
+        .collect();
+
+    //naive: (A B) C creates a 100x100 intermediate
+    let naive = e.terms[0].naive_contraction_plan(&dims);
+    assert_eq!(naive.steps.len(), 2);
+    assert_eq!((naive.steps[0].lhs, naive.steps[0].rhs), (0, 1));
+    assert_eq!(naive.steps[0].size, 100 * 100);
+    assert_eq!(naive.size, 100 * 100 + 100);
+    assert_eq!(naive.flops, 100 * 100 * 100 + 100 * 100);
+
+    //optimal: A (B C) only creates vectors
+    let plan = e.terms[0].contraction_plan(&dims);
+    assert_eq!(plan.steps.len(), 2);
+    assert_eq!((plan.steps[0].lhs, plan.steps[0].rhs), (1, 2));
+    assert_eq!(plan.steps[0].indices, vec!["j"]);
+    assert_eq!((plan.steps[1].lhs, plan.steps[1].rhs), (0, 3));
+    assert_eq!(plan.steps[1].indices, vec!["i"]);
+    assert_eq!(plan.size, 200);
+    assert_eq!(plan.flops, 2 * 100 * 100);
+}
+
+#[test]
+fn test_ricci_contraction_plan_chain() {
+    //matrix chain with varying dimensions, optimal is (A B) (C D)
+    let e = ricci("A^a_b B^b_c C^c_d D^d_e").expect("valid expression");
+    let dims: HashMap<String, usize> = vec![("a", 2), ("b", 50), ("c", 3), ("d", 40), ("e", 2)]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect();
+    let plan = e.terms[0].contraction_plan(&dims);
+    assert_eq!(plan.size, 2 * 3 + 3 * 2 + 2 * 2);
+    assert!(plan.size <= e.terms[0].naive_contraction_plan(&dims).size);
+    assert_eq!(plan.steps.last().expect("steps").indices, vec!["a", "e"]);
+
+    //greedy fallback for many factors gives a full pairing
+    let s: Vec<String> = (0..20)
+        .map(|x| format!("X^{{i{} }}_{{i{} }}", x, x + 1))
+        .collect();
+    let e = ricci(&s.join(" ")).expect("valid expression");
+    let dims: HashMap<String, usize> = (0..21).map(|x| (format!("i{}", x), 4)).collect();
+    let plan = e.terms[0].contraction_plan(&dims);
+    assert_eq!(plan.steps.len(), 19);
+    assert_eq!(plan.steps.last().expect("steps").indices, vec!["i0", "i20"]);
+}
+
+#[test]
+fn test_ricci_contraction_plan_threshold() {
+    let chain = |n: usize| {
+        let s: Vec<String> = (0..n)
+            .map(|x| format!("X^{{i{} }}_{{i{} }}", x, x + 1))
+            .collect();
+        ricci(&s.join(" ")).expect("valid expression")
+    };
+    let dims: HashMap<String, usize> = (0..=70).map(|x| (format!("i{}", x), 4)).collect();
+
+    //largest exhaustive search, every order of a uniform chain costs the same
+    let e = chain(CONTRACTION_DP_MAX_FACTORS);
+    let plan = e.terms[0].contraction_plan(&dims);
+    assert_eq!(plan.steps.len(), CONTRACTION_DP_MAX_FACTORS - 1);
+    assert_eq!(plan.size, (CONTRACTION_DP_MAX_FACTORS - 1) * 16);
+    assert_eq!(plan.size, e.terms[0].naive_contraction_plan(&dims).size);
+
+    //greedy order past 64 factors
+    let e = chain(70);
+    let plan = e.terms[0].contraction_plan(&dims);
+    assert_eq!(plan.steps.len(), 69);
+    assert_eq!(plan.steps.last().expect("steps").indices, vec!["i0", "i70"]);
+}
+
+#[test]
+fn test_ricci_delta() {
+    let e = ricci("δ^a_b X^b").expect("valid expression");
+    assert_eq!(e.terms[0].factors[0].name, DELTA);
+    assert_eq!(format!("{}", e.contract_deltas()), "X^{a}");
+
+    let e = ricci("δ^a_b δ^b_c").expect("valid expression");
+    assert_eq!(format!("{}", e.contract_deltas()), "δ^{a}_{c}");
+
+    //free deltas and traces are kept
+    let e = ricci("δ^a_b + δ^c_c A^a_b").expect("valid expression");
+    assert_eq!(e.contract_deltas(), e);
+}
+
+#[test]
+fn test_ricci_derivative() {
+    //d(A^i_j B^j_k)/d(B^m_n) = A^i_m δ^n_k
+    let e = ricci("A^i_j B^j_k").expect("valid expression");
+    let d = e.derivative("B^m_n").expect("derivative");
+    assert_eq!(format!("{}", d), "A^{i}_{m} δ^{n}_{k}");
+    assert_eq!(d.valence(), (2, 2));
+
+    //product rule over repeated factors: d(x^T A x)/d(x^m) = A_{mb} x^b + x^a A_{am}
+    let e = ricci("X^a A_{ab} X^b").expect("valid expression");
+    let d = e.derivative("X^m").expect("derivative");
+    assert_eq!(d.terms.len(), 2);
+    assert_eq!(d.rank(), 1);
+    assert_eq!(d.free_indices()[0].pos, IndexPos::Lower);
+    let expected = ricci("A_{mb} X^b + X^a A_{am}").expect("valid expression");
+    assert_eq!(
+        d.canonical().expect("canonical"),
+        expected.canonical().expect("canonical")
+    );
+
+    //dummy indices clashing with the target are renamed first
+    let e = ricci("A^i_j V^j").expect("valid expression");
+    let d = e.derivative("V^j").expect("derivative");
+    assert_eq!(format!("{}", d), "A^{i}_{j}");
+
+    //trace: d(A^a_a)/d(A^i_j) = δ^j_i
+    let e = ricci("A^a_a").expect("valid expression");
+    let d = e.derivative("A^i_j").expect("derivative");
+    assert_eq!(format!("{}", d), "δ^{j}_{i}");
+
+    //tensor not present
+    let d = e.derivative("B^i").expect("derivative");
+    assert_eq!(format!("{}", d), "0");
+
+    let err = ricci("A^i_j V^j")
+        .expect("valid expression")
+        .derivative("V^i")
+        .expect_err("error expected");
+    assert_eq!(err[0].kind, RicciErrorKind::IndexClash);
+
+    let err = e.derivative("A^m_m").expect_err("error expected");
+    assert_eq!(err[0].kind, RicciErrorKind::IndexRepeated);
+    assert_eq!(err[0].offset, 4);
+
+    let err = e.derivative("A^m B_n").expect_err("error expected");
+    assert_eq!(err[0].kind, RicciErrorKind::NotSingleFactor);
+}
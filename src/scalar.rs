@@ -0,0 +1,190 @@
+//! evaluation of graphs on generic scalar types
+//!
+//! graphs made of elementary operations, and the adjoint graphs built from them by rev, can
+//! be evaluated on any type implementing Scalar, such as fixed-point, decimal or
+//! stochastically rounded types, instead of ValType
+//!
+//! this is an evaluator beside the core rather than a generic core: graphs are still built,
+//! differentiated and evaluated with ValType values, and only operations with an elementary
+//! scalar form can be evaluated here
+
+use std::collections::HashMap;
+use std::fmt;
+
+use num_traits::{Num, NumCast};
+
+use crate::core::{topo_order, PtrVWrap, ScalarOp};
+
+/// operation without an elementary scalar form
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScalarError {
+    /// debug representation of the operation
+    pub op: String,
+}
+
+impl fmt::Display for ScalarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "operation {} has no generic scalar form", self.op)
+    }
+}
+
+impl std::error::Error for ScalarError {}
+
+/// scalar type graphs can be evaluated on
+///
+/// arithmetic is carried out in the type itself, so it keeps its own rounding; functions
+/// num_traits::Num has no counterpart for (sin, exp, ...) are evaluated in f64
+pub trait Scalar: Copy + PartialOrd + Num + NumCast {}
+
+impl<T: Copy + PartialOrd + Num + NumCast> Scalar for T {}
+
+fn cast<T: Scalar>(v: f64) -> T {
+    T::from(v).expect("scalar type cannot represent constant")
+}
+
+fn to_f64<T: Scalar>(v: T) -> f64 {
+    v.to_f64().expect("scalar value has no f64 form")
+}
+
+/// f of x evaluated in f64
+fn via_f64<T: Scalar>(x: T, f: impl Fn(f64) -> f64) -> T {
+    cast(f(to_f64(x)))
+}
+
+fn sigmoid(v: f64) -> f64 {
+    if v >= 0. {
+        1. / (1. + (-v).exp())
+    } else {
+        v.exp() / (1. + v.exp())
+    }
+}
+
+fn apply<T: Scalar>(op: ScalarOp, x: &[T]) -> T {
+    match op {
+        ScalarOp::Add => x[0] + x[1],
+        ScalarOp::Mul => x[0] * x[1],
+        ScalarOp::Div => x[0] / x[1],
+        ScalarOp::Atan2 => cast(to_f64(x[0]).atan2(to_f64(x[1]))),
+        ScalarOp::Sin => via_f64(x[0], f64::sin),
+        ScalarOp::Cos => via_f64(x[0], f64::cos),
+        ScalarOp::Tan => via_f64(x[0], f64::tan),
+        ScalarOp::Tanh => via_f64(x[0], f64::tanh),
+        ScalarOp::Sigmoid => via_f64(x[0], sigmoid),
+        ScalarOp::Softplus => via_f64(x[0], |v| v.max(0.) + (-v.abs()).exp().ln_1p()),
+        ScalarOp::Exp => via_f64(x[0], f64::exp),
+        ScalarOp::Ln => via_f64(x[0], f64::ln),
+        ScalarOp::Sqrt => via_f64(x[0], f64::sqrt),
+        ScalarOp::Pow => cast(to_f64(x[0]).powf(to_f64(x[1]))),
+        ScalarOp::Rem => x[0] - x[1] * via_f64(x[0] / x[1], f64::floor),
+        ScalarOp::Sum => x.iter().fold(T::zero(), |a, &b| a + b),
+        ScalarOp::Fma => x[0] * x[1] + x[2],
+        ScalarOp::Lerp => x[0] + x[2] * (x[1] - x[0]),
+        ScalarOp::Scale(factor) => x[0] * cast(factor as f64),
+    }
+}
+
+/// values of roots with leaves[i] set to x[i]
+pub(crate) fn eval_roots<T: Scalar>(
+    roots: &[PtrVWrap],
+    leaves: &[PtrVWrap],
+    x: &[T],
+) -> Result<Vec<T>, ScalarError> {
+    assert_eq!(leaves.len(), x.len());
+    let mut vals: HashMap<PtrVWrap, T> = leaves.iter().cloned().zip(x.iter().cloned()).collect();
+    let mut args = vec![];
+    for n in topo_order(roots) {
+        if vals.contains_key(&n) {
+            continue;
+        }
+        let v = if let Some(v) = n.fixed_value() {
+            cast(v.into())
+        } else {
+            let op = n
+                .scalar_op()
+                .ok_or_else(|| ScalarError { op: n.op_name() })?;
+            args.clear();
+            args.extend(n.inputs().iter().map(|i| vals[i]));
+            apply(op, &args)
+        };
+        vals.insert(n, v);
+    }
+    Ok(roots.iter().map(|r| vals[r]).collect())
+}
+
+/// value of output with leaves[i] set to x[i], other leaves keep their current values
+pub fn eval_scalar<T: Scalar>(
+    output: &PtrVWrap,
+    leaves: &[PtrVWrap],
+    x: &[T],
+) -> Result<T, ScalarError> {
+    Ok(eval_roots(std::slice::from_ref(output), leaves, x)?[0])
+}
+
+/// value and gradient of output wrt leaves with leaves[i] set to x[i], evaluated on the
+/// adjoint graphs built by rev
+pub fn grad_scalar<T: Scalar>(
+    output: &PtrVWrap,
+    leaves: &[PtrVWrap],
+    x: &[T],
+) -> Result<(T, Vec<T>), ScalarError> {
+    let adjoints = output.rev();
+    let mut roots = vec![output.clone()];
+    roots.extend(leaves.iter().filter_map(|l| adjoints.get(l).cloned()));
+    let mut vals = eval_roots(&roots, leaves, x)?.into_iter();
+    let v = vals.next().expect("output value missing");
+    let grad = leaves
+        .iter()
+        .map(|l| match adjoints.get(l) {
+            Some(_) => vals.next().expect("adjoint value missing"),
+            None => T::zero(),
+        })
+        .collect();
+    Ok((v, grad))
+}
+
+#[cfg(test)]
+use crate::core::{Add, BesselJ0, Leaf, Lerp, Mul, Pow, Sin};
+#[cfg(test)]
+use crate::valtype::ValType;
+
+#[test]
+fn test_grad_scalar() {
+    //y=x0*sin(x1)+lerp(x0,c,x1)**2 where c=3, evaluated at x0=0.5, x1=2
+    let x0 = Leaf(ValType::F(0.));
+    let x1 = Leaf(ValType::F(0.));
+    let c = Leaf(ValType::F(3.));
+    let c3 = c.clone();
+    let l = Lerp(x0.clone(), c, x1.clone());
+    let y = Add(
+        Mul(x0.clone(), Sin(x1.clone())),
+        Pow(l, Leaf(ValType::F(2.))),
+    );
+    let leaves = [x0.clone(), x1.clone()];
+
+    //lerp=x0+x1*(3-x0)=5.5
+    //dy/dx0=sin(x1)+2*lerp*(1-x1), dy/dx1=x0*cos(x1)+2*lerp*(3-x0)
+    let (a, b) = (0.5f64, 2f64);
+    let lv = a + b * (3. - a);
+    let (v, g) = grad_scalar(&y, &leaves, &[a, b]).expect("evaluation failed");
+    assert!((v - (a * b.sin() + lv * lv)).abs() < 1e-9);
+    assert!((g[0] - (b.sin() + 2. * lv * (1. - b))).abs() < 1e-9);
+    assert!((g[1] - (a * b.cos() + 2. * lv * (3. - a))).abs() < 1e-9);
+
+    //same graph on f32
+    let v: f32 = eval_scalar(&y, &leaves, &[0.5f32, 2.]).expect("evaluation failed");
+    assert!((v - (a * b.sin() + lv * lv) as f32).abs() < 1e-4);
+
+    //leaves outside the graph get a zero gradient
+    let other = Leaf(ValType::F(0.));
+    let (_, g) = grad_scalar(&x0, &[x0.clone(), other], &[1f64, 2.]).expect("evaluation failed");
+    assert_eq!(g, vec![1., 0.]);
+
+    //integers are not floats, arithmetic stays exact in the type
+    let z = Add(Mul(x0.clone(), x1.clone()), Mul(x0.clone(), c3.clone()));
+    let (v, g) = grad_scalar(&z, &leaves, &[7i64, 5]).expect("evaluation failed");
+    assert_eq!((v, g), (56, vec![8, 7]));
+
+    let e =
+        eval_scalar(&BesselJ0(x0.clone()), &[x0], &[1f64]).expect_err("bessel is not elementary");
+    assert!(e.op.contains("Bessel"));
+}
@@ -0,0 +1,138 @@
+//! canonical text form of graphs for golden snapshot tests
+//!
+//! nodes are numbered in topological order, which only depends on the structure of the
+//! graph and the order of inputs, so the text of a graph built the same way is the same on
+//! every run; a stored snapshot of e.g. the adjoint graphs of a function guards against
+//! unintended changes in the tangent and adjoint rules
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+use crate::core::{topo_order, PtrVWrap};
+
+/// environment variable that makes check_snapshot overwrite stored snapshots
+pub const UPDATE_SNAPSHOTS: &str = "DYNAGRAD_UPDATE_SNAPSHOTS";
+
+/// canonical text of the graphs of the given roots
+///
+/// one line `nK = op(inputs)` per node, nodes without inputs also give their value, and a
+/// final line lists the roots
+pub fn to_canonical(roots: &[PtrVWrap]) -> String {
+    let mut ids: HashMap<PtrVWrap, usize> = HashMap::new();
+    let mut text = String::new();
+    for (k, n) in topo_order(roots).into_iter().enumerate() {
+        let inputs = n.inputs();
+        if inputs.is_empty() {
+            match n.fixed_value() {
+                Some(v) => writeln!(text, "n{} = {} {:?}", k, n.op_name(), v).unwrap(),
+                None => writeln!(text, "n{} = {}", k, n.op_name()).unwrap(),
+            }
+        } else {
+            let args: Vec<String> = inputs.iter().map(|i| format!("n{}", ids[i])).collect();
+            writeln!(text, "n{} = {}({})", k, n.op_name(), args.join(", ")).unwrap();
+        }
+        ids.insert(n, k);
+    }
+    let outs: Vec<String> = roots.iter().map(|r| format!("n{}", ids[r])).collect();
+    writeln!(text, "out {}", outs.join(", ")).unwrap();
+    text
+}
+
+/// first difference between a graph and its stored snapshot
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotMismatch {
+    /// 1-based line number
+    pub line: usize,
+    /// stored line, None past the end of the snapshot
+    pub expected: Option<String>,
+    /// line of the graph, None past the end of its text
+    pub actual: Option<String>,
+}
+
+impl fmt::Display for SnapshotMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |l: &Option<String>| l.clone().unwrap_or_else(|| "<end>".to_string());
+        write!(
+            f,
+            "snapshot differs at line {}: expected `{}`, found `{}`",
+            self.line,
+            show(&self.expected),
+            show(&self.actual)
+        )
+    }
+}
+
+impl std::error::Error for SnapshotMismatch {}
+
+/// compares the canonical text of the roots with the snapshot stored at path
+///
+/// a missing snapshot, or any snapshot while UPDATE_SNAPSHOTS is set, is written instead;
+/// panics if the file cannot be read or written
+pub fn check_snapshot(roots: &[PtrVWrap], path: &Path) -> Result<(), SnapshotMismatch> {
+    let text = to_canonical(roots);
+    if !path.exists() || std::env::var_os(UPDATE_SNAPSHOTS).is_some() {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).expect("cannot create snapshot directory");
+        }
+        fs::write(path, &text).expect("cannot write snapshot");
+        return Ok(());
+    }
+    let stored = fs::read_to_string(path).expect("cannot read snapshot");
+    let (mut a, mut b) = (stored.lines(), text.lines());
+    for line in 1.. {
+        match (a.next(), b.next()) {
+            (None, None) => break,
+            (e, f) if e != f => {
+                return Err(SnapshotMismatch {
+                    line,
+                    expected: e.map(String::from),
+                    actual: f.map(String::from),
+                })
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// check_snapshot, panicking with the first difference
+pub fn assert_snapshot(roots: &[PtrVWrap], path: &Path) {
+    if let Err(e) = check_snapshot(roots, path) {
+        panic!("{} ({})", e, path.display());
+    }
+}
+
+#[test]
+fn test_snapshot() {
+    use crate::core::{Leaf, Mul, Sin};
+    use crate::valtype::ValType;
+
+    //y=a*sin(b) and its adjoints
+    let a = Leaf(ValType::F(2.));
+    let b = Leaf(ValType::F(0.5));
+    let y = Mul(a.clone(), Sin(b.clone()));
+    assert_eq!(
+        to_canonical(std::slice::from_ref(&y)),
+        "n0 = OpLeaf F(2.0)\nn1 = OpLeaf F(0.5)\nn2 = OpSin(n1)\nn3 = OpMul(n0, n2)\nout n3\n"
+    );
+    let adj = y.rev();
+    let roots = [adj[&a].clone(), adj[&b].clone()];
+    let text = to_canonical(&roots);
+    assert_eq!(text, to_canonical(&roots));
+
+    let path = std::env::temp_dir().join(format!("dynagrad_snapshot_{}.txt", std::process::id()));
+    let _ = fs::remove_file(&path);
+    assert_eq!(check_snapshot(&roots, &path), Ok(()));
+    assert_eq!(fs::read_to_string(&path).unwrap(), text);
+    //a rebuilt graph matches, a different one is reported at its first difference
+    let adj = Mul(a.clone(), Sin(b.clone())).rev();
+    assert_snapshot(&[adj[&a].clone(), adj[&b].clone()], &path);
+    let e = check_snapshot(&[adj[&b].clone(), adj[&a].clone()], &path)
+        .expect_err("roots in another order");
+    assert!(e.line > 1);
+    assert!(e.to_string().starts_with("snapshot differs at line"));
+    fs::remove_file(&path).unwrap();
+}
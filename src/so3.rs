@@ -0,0 +1,135 @@
+//! exponential and logarithm maps between rotation vectors and rotation matrices
+//!
+//! the angle dependent coefficients are smooth functions of theta^2, so derivatives of any
+//! order are exact and finite at the identity
+
+#![allow(non_snake_case)]
+
+use crate::core::{constant, AcosSq, Add, Div, Dot, Minus, Mul, PtrVWrap, SincSqrt};
+use crate::valtype::ValType;
+
+#[track_caller]
+fn scale(k: f32, x: PtrVWrap) -> PtrVWrap {
+    Mul(constant(ValType::F(k)), x)
+}
+
+/// rotation matrix exp([omega]x) by rodrigues' formula
+///
+/// R = I + A*K + B*K^2 with A = sin(t)/t, B = (1-cos(t))/t^2, t = |omega|,
+/// expanded as R_ij = cos(t)*delta_ij + A*K_ij + B*omega_i*omega_j
+#[allow(dead_code)]
+#[track_caller]
+pub fn So3Exp(omega: &[PtrVWrap; 3]) -> Vec<Vec<PtrVWrap>> {
+    let s = Dot(omega.to_vec(), omega.to_vec());
+    let a = SincSqrt(s.clone());
+    //B(s) = A(s/4)^2/2
+    let half_angle = SincSqrt(scale(0.25, s.clone()));
+    let b = scale(0.5, Mul(half_angle.clone(), half_angle));
+    let cos = Minus(constant(ValType::F(1.)), Mul(b.clone(), s));
+    let neg = |x: &PtrVWrap| scale(-1., x.clone());
+    let (x, y, z) = (&omega[0], &omega[1], &omega[2]);
+    let k = [
+        [None, Some(neg(z)), Some(y.clone())],
+        [Some(z.clone()), None, Some(neg(x))],
+        [Some(neg(y)), Some(x.clone()), None],
+    ];
+    (0..3)
+        .map(|i| {
+            (0..3)
+                .map(|j| {
+                    let outer = Mul(b.clone(), Mul(omega[i].clone(), omega[j].clone()));
+                    match &k[i][j] {
+                        Some(kij) => Add(Mul(a.clone(), kij.clone()), outer),
+                        None => Add(cos.clone(), outer),
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// rotation vector of a rotation matrix, the inverse of So3Exp for angles below pi
+///
+/// omega = t/(2 sin(t)) * (R21-R12, R02-R20, R10-R01) with cos(t) = (tr(R)-1)/2,
+/// where t/sin(t) = 1/A(t^2) stays smooth through the identity
+#[allow(dead_code)]
+#[track_caller]
+pub fn So3Log(r: &[Vec<PtrVWrap>]) -> [PtrVWrap; 3] {
+    assert!(
+        r.len() == 3 && r.iter().all(|row| row.len() == 3),
+        "So3Log expects a 3x3 matrix"
+    );
+    let trace = Add(Add(r[0][0].clone(), r[1][1].clone()), r[2][2].clone());
+    let c = scale(0.5, Minus(trace, constant(ValType::F(1.))));
+    let coef = Div(constant(ValType::F(0.5)), SincSqrt(AcosSq(c)));
+    let skew = |i: usize, j: usize| Mul(coef.clone(), Minus(r[i][j].clone(), r[j][i].clone()));
+    [skew(2, 1), skew(0, 2), skew(1, 0)]
+}
+
+#[cfg(test)]
+fn eq_f32(a: f32, b: f32) -> bool {
+    (a - b).abs() < 0.01
+}
+
+#[test]
+fn test_so3_exp() {
+    use crate::core::Leaf;
+
+    //quarter turn about z
+    let omega = [
+        Leaf(ValType::F(0.)),
+        Leaf(ValType::F(0.)),
+        Leaf(ValType::F(std::f32::consts::FRAC_PI_2)),
+    ];
+    let mut r = So3Exp(&omega);
+    let expected = [[0., -1., 0.], [1., 0., 0.], [0., 0., 1.]];
+    for i in 0..3 {
+        for j in 0..3 {
+            assert!(eq_f32(r[i][j].apply_fwd().into(), expected[i][j]));
+        }
+    }
+
+    //at the identity R=I+K+K^2/2+...
+    //dR21/dx=1, d2R11/dx2=-1
+    let x = Leaf(ValType::F(0.)).active();
+    let omega = [x.clone(), Leaf(ValType::F(0.)), Leaf(ValType::F(0.))];
+    let mut r = So3Exp(&omega);
+    assert!(eq_f32(r[1][1].apply_fwd().into(), 1.));
+    assert!(eq_f32(r[2][1].fwd().apply_fwd().into(), 1.));
+    assert!(eq_f32(r[1][1].fwd().fwd().apply_fwd().into(), -1.));
+}
+
+#[test]
+fn test_so3_log() {
+    use crate::core::Leaf;
+
+    //log(exp(omega))=omega with identity jacobian
+    let x = Leaf(ValType::F(0.3)).active();
+    let omega = [x.clone(), Leaf(ValType::F(-0.2)), Leaf(ValType::F(0.5))];
+    let mut w = So3Log(&So3Exp(&omega));
+    let expected = [0.3, -0.2, 0.5];
+    for i in 0..3 {
+        assert!(eq_f32(w[i].apply_fwd().into(), expected[i]));
+    }
+    assert!(eq_f32(w[0].fwd().apply_fwd().into(), 1.));
+    assert!(eq_f32(w[1].fwd().apply_fwd().into(), 0.));
+
+    //at R=I, omega=0 and d omega_x/d R21=1/2
+    let r: Vec<Vec<PtrVWrap>> = (0..3)
+        .map(|i| {
+            (0..3)
+                .map(|j| {
+                    let v = if i == j { 1. } else { 0. };
+                    if (i, j) == (2, 1) {
+                        Leaf(ValType::F(v)).active()
+                    } else {
+                        Leaf(ValType::F(v))
+                    }
+                })
+                .collect()
+        })
+        .collect();
+    let mut w = So3Log(&r);
+    assert!(eq_f32(w[0].apply_fwd().into(), 0.));
+    assert!(eq_f32(w[0].fwd().apply_fwd().into(), 0.5));
+}
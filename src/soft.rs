@@ -0,0 +1,294 @@
+//! smooth relaxations of discrete selections (sorting, ranking, categorical sampling)
+//!
+//! comparisons go through log-sum-exp weights exp((x_i - lse)/tau), which never overflow
+//! and stay differentiable to any order
+
+#![allow(non_snake_case)]
+
+use crate::core::{
+    constant, soft_sigmoid, Add, Exp, Max, Min, Minus, Mul, PtrVWrap, SoftMax, TopKThreshold,
+};
+use crate::dist::Rng;
+use crate::valtype::ValType;
+
+///current values of the nodes, evaluating them
+#[cfg(test)]
+fn values(x: &[PtrVWrap]) -> Vec<f32> {
+    x.iter().map(|v| v.clone().apply_fwd().into()).collect()
+}
+
+/// soft ascending ranks r_i = 1 + sum(sigmoid((x_i-x_j)/tau), j!=i)
+///
+/// ranks are 1-based and approach the hard ranks as tau goes to 0
+#[allow(dead_code)]
+#[track_caller]
+pub fn SoftRank(x: &[PtrVWrap], tau: f32) -> Vec<PtrVWrap> {
+    assert!(tau > 0., "tau must be positive");
+    (0..x.len())
+        .map(|i| {
+            (0..x.len())
+                .filter(|&j| j != i)
+                .map(|j| soft_sigmoid(x[i].clone(), x[j].clone(), tau))
+                .fold(constant(ValType::F(1.)), Add)
+        })
+        .collect()
+}
+
+///hard ascending sort as an odd-even transposition network of Min/Max nodes, so the order
+///is decided whenever the graph is evaluated and adjoints reach the element in each place
+fn sort_network(x: &[PtrVWrap]) -> Vec<PtrVWrap> {
+    let mut s = x.to_vec();
+    for pass in 0..s.len() {
+        for j in (pass % 2..s.len().saturating_sub(1)).step_by(2) {
+            let (a, b) = (s[j].clone(), s[j + 1].clone());
+            s[j] = Min(a.clone(), b.clone());
+            s[j + 1] = Max(a, b);
+        }
+    }
+    s
+}
+
+/// soft ascending sort of a vector of nodes
+///
+/// entry i is sum(P_ij*x_j) with P_ij = softmax_j(-(s_i-x_j)^2/tau) and s the hard sorted
+/// inputs, so the relaxation approaches the hard sort as tau goes to 0
+///
+/// s comes from a network of Min/Max nodes rather than the values at build time, so the
+/// graph stays valid after the inputs change through set_val
+#[allow(dead_code)]
+#[track_caller]
+pub fn SoftSort(x: &[PtrVWrap], tau: f32) -> Vec<PtrVWrap> {
+    assert!(tau > 0., "tau must be positive");
+    let inv_tau = constant(ValType::F(-1. / tau));
+    sort_network(x)
+        .into_iter()
+        .map(|si| {
+            let z: Vec<PtrVWrap> = x
+                .iter()
+                .map(|xj| {
+                    let d = Minus(si.clone(), xj.clone());
+                    Mul(inv_tau.clone(), Mul(d.clone(), d))
+                })
+                .collect();
+            let lse = SoftMax(z.clone(), 1.);
+            z.into_iter()
+                .zip(x.iter())
+                .map(|(zj, xj)| Mul(Exp(Minus(zj, lse.clone())), xj.clone()))
+                .reduce(Add)
+                .unwrap()
+        })
+        .collect()
+}
+
+/// relaxed one-hot sample y_i = softmax_i((l_i + g_i)/tau) of the categorical distribution
+/// with probabilities softmax(l)
+///
+/// the gumbel noise g_i = -ln(-ln(u_i)) is drawn from seed and embedded as constants, so
+/// adjoints wrt the logits are exact, and y approaches a hard one-hot sample as tau goes to 0
+#[allow(dead_code)]
+#[track_caller]
+pub fn GumbelSoftmax(logits: &[PtrVWrap], tau: f32, seed: u64) -> Vec<PtrVWrap> {
+    assert!(tau > 0., "tau must be positive");
+    assert!(!logits.is_empty(), "GumbelSoftmax needs at least one logit");
+    let mut rng = Rng::new(seed);
+    let inv_tau = constant(ValType::F(1. / tau));
+    let z: Vec<PtrVWrap> = logits
+        .iter()
+        .map(|l| {
+            let g = -(-rng.uniform().ln()).ln();
+            Mul(
+                inv_tau.clone(),
+                Add(l.clone(), constant(ValType::F(g as f32))),
+            )
+        })
+        .collect();
+    let lse = SoftMax(z.clone(), 1.);
+    z.into_iter()
+        .map(|zi| Exp(Minus(zi, lse.clone())))
+        .collect()
+}
+
+/// soft top-k selection weights w_i = sigmoid((x_i-t)/tau) in (0,1)
+///
+/// the threshold t is solved so the weights sum to k, and its derivatives come from the
+/// implicit function theorem, so adjoints are exact rather than unrolled through the solver
+#[allow(dead_code)]
+#[track_caller]
+pub fn SoftTopK(x: &[PtrVWrap], k: usize, tau: f32) -> Vec<PtrVWrap> {
+    assert!(tau > 0., "tau must be positive");
+    assert!(
+        k > 0 && k < x.len(),
+        "k must lie strictly between 0 and the input length"
+    );
+    let t = TopKThreshold(x.to_vec(), k as f32, tau);
+    x.iter()
+        .map(|xi| soft_sigmoid(xi.clone(), t.clone(), tau))
+        .collect()
+}
+
+#[cfg(test)]
+fn eq_f32(a: f32, b: f32) -> bool {
+    (a - b).abs() < 0.01
+}
+
+#[test]
+fn test_soft_rank() {
+    use crate::core::Leaf;
+
+    //x=(3,1,2), ranks=(3,1,2) for small tau
+    let x = vec![
+        Leaf(ValType::F(3.)),
+        Leaf(ValType::F(1.)),
+        Leaf(ValType::F(2.)),
+    ];
+    let mut r = SoftRank(&x, 0.01);
+    for (i, e) in [3., 1., 2.].iter().enumerate() {
+        assert!(eq_f32(r[i].apply_fwd().into(), *e));
+    }
+
+    //tau=1, x=(0,0): r_0=1.5, dr_0/dx_0=sigmoid'(0)=0.25
+    let l0 = Leaf(ValType::F(0.)).active();
+    let y = vec![l0.clone(), Leaf(ValType::F(0.))];
+    let mut r = SoftRank(&y, 1.);
+    assert!(eq_f32(r[0].apply_fwd().into(), 1.5));
+    assert!(eq_f32(r[0].fwd().apply_fwd().into(), 0.25));
+
+    //no overflow for widely separated inputs
+    let z = vec![Leaf(ValType::F(1000.)).active(), Leaf(ValType::F(-1000.))];
+    let mut r = SoftRank(&z, 0.1);
+    assert!(eq_f32(r[0].apply_fwd().into(), 2.));
+    assert!(eq_f32(r[0].fwd().apply_fwd().into(), 0.));
+}
+
+#[test]
+fn test_soft_sort() {
+    use crate::core::Leaf;
+
+    //x=(3,1,2) sorts to (1,2,3) for small tau
+    let l0 = Leaf(ValType::F(3.)).active();
+    let x = vec![l0.clone(), Leaf(ValType::F(1.)), Leaf(ValType::F(2.))];
+    let mut s = SoftSort(&x, 0.05);
+    for (i, e) in [1., 2., 3.].iter().enumerate() {
+        assert!(eq_f32(s[i].apply_fwd().into(), *e));
+    }
+    //the largest output follows x_0
+    assert!(eq_f32(s[2].fwd().apply_fwd().into(), 1.));
+    assert!(eq_f32(s[0].fwd().apply_fwd().into(), 0.));
+
+    //large tau blends towards the mean
+    let mut s = SoftSort(&x, 1000.);
+    assert!(eq_f32(s[0].apply_fwd().into(), 2.));
+
+    //the order follows new input values without rebuilding the graph
+    let mut l1 = x[1].clone();
+    let mut s = SoftSort(&x, 0.05);
+    l1.set_val(ValType::F(5.));
+    for (i, e) in [2., 3., 5.].iter().enumerate() {
+        assert!(eq_f32(s[i].apply_fwd().into(), *e));
+    }
+    //x_0=3 is now the middle entry
+    assert!(eq_f32(s[1].fwd().apply_fwd().into(), 1.));
+    assert!(eq_f32(s[2].fwd().apply_fwd().into(), 0.));
+}
+
+#[test]
+fn test_soft_topk_fwd() {
+    use crate::core::Leaf;
+
+    //x=(3,1,2), k=2 selects x_0 and x_2 for small tau
+    let x = vec![
+        Leaf(ValType::F(3.)),
+        Leaf(ValType::F(1.)),
+        Leaf(ValType::F(2.)),
+    ];
+    let mut w = SoftTopK(&x, 2, 0.01);
+    for (i, e) in [1., 0., 1.].iter().enumerate() {
+        assert!(eq_f32(w[i].apply_fwd().into(), *e));
+    }
+
+    //tau=1, x=(0,0), k=1: t=0, w=(0.5,0.5)
+    //dt/dx_0=0.5, dw_0/dx_0=0.25*(1-0.5)=0.125, dw_1/dx_0=-0.125
+    let l0 = Leaf(ValType::F(0.)).active();
+    let y = vec![l0.clone(), Leaf(ValType::F(0.))];
+    let mut w = SoftTopK(&y, 1, 1.);
+    assert!(eq_f32(w[0].apply_fwd().into(), 0.5));
+    assert!(eq_f32(w[0].fwd().apply_fwd().into(), 0.125));
+    assert!(eq_f32(w[1].fwd().apply_fwd().into(), -0.125));
+}
+
+#[test]
+fn test_soft_topk_sum() {
+    use crate::core::Leaf;
+
+    //y=sum(w_i) with w=softtopk(x, k=1, tau=1) where x=(1,0)
+    //weights always sum to k, so dy/dx_j=0
+    let l0 = Leaf(ValType::F(1.)).active();
+    let l1 = Leaf(ValType::F(0.));
+    let w = SoftTopK(&[l0.clone(), l1.clone()], 1, 1.);
+    let mut y = w.into_iter().reduce(Add).unwrap();
+    assert!(eq_f32(y.apply_fwd().into(), 1.));
+    assert!(eq_f32(y.fwd().apply_fwd().into(), 0.));
+}
+
+#[test]
+fn test_soft_topk_rev() {
+    use crate::core::Leaf;
+
+    //y=w_0 with w=softtopk(x, k=1, tau=1) where x=(0,0)
+    //dy/dx_0=0.125, dy/dx_1=-0.125
+    let l0 = Leaf(ValType::F(0.));
+    let l1 = Leaf(ValType::F(0.));
+    let w = SoftTopK(&[l0.clone(), l1.clone()], 1, 1.);
+    let mut adj = w[0].rev();
+    for (l, e) in [(&l0, 0.125), (&l1, -0.125)] {
+        let d: f32 = adj
+            .get_mut(l)
+            .expect("leaf adjoint missing")
+            .apply_rev()
+            .into();
+        assert!(eq_f32(d, e));
+    }
+}
+
+#[test]
+fn test_gumbel_softmax() {
+    use crate::core::Leaf;
+
+    //weights sum to 1 and dy_i/dl_j = y_i*(delta_ij - y_j)/tau
+    let l = vec![
+        Leaf(ValType::F(1.)),
+        Leaf(ValType::F(0.)),
+        Leaf(ValType::F(-1.)),
+    ];
+    let tau = 0.5;
+    let mut y = GumbelSoftmax(&l, tau, 3);
+    let v = values(&y);
+    assert!(eq_f32(v.iter().sum(), 1.));
+    let mut adj = y[0].rev();
+    for (j, lj) in l.iter().enumerate() {
+        let d: f32 = adj
+            .get_mut(lj)
+            .expect("logit adjoint missing")
+            .apply_rev()
+            .into();
+        let delta = if j == 0 { 1. } else { 0. };
+        assert!(eq_f32(d, v[0] * (delta - v[j]) / tau));
+    }
+    //same seed gives the same sample
+    let w = GumbelSoftmax(&l, tau, 3);
+    assert!(eq_f32(
+        w[1].clone().apply_fwd().into(),
+        y[1].apply_fwd().into()
+    ));
+
+    //argmax of near hard samples follows softmax(l)=(0.665,0.245,0.090)
+    let mut counts = [0f32; 3];
+    for seed in 0..2000 {
+        let v = values(&GumbelSoftmax(&l, 0.01, seed));
+        let k = (0..3).max_by(|&a, &b| v[a].total_cmp(&v[b])).unwrap();
+        counts[k] += 1. / 2000.;
+    }
+    for (c, e) in counts.iter().zip([0.665, 0.245, 0.090]) {
+        assert!((c - e).abs() < 0.04);
+    }
+}
@@ -1,4 +1,4 @@
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ValType {
     F(f32),
     D(f64),
@@ -24,3 +24,14 @@ impl From<ValType> for f32 {
         }
     }
 }
+
+impl From<ValType> for f64 {
+    fn from(s: ValType) -> Self {
+        match s {
+            ValType::F(x) => x as f64,
+            ValType::D(x) => x,
+            ValType::I(x) => x as f64,
+            ValType::L(x) => x as f64,
+        }
+    }
+}
@@ -0,0 +1,226 @@
+//! export of graphs as a self-contained html page
+//!
+//! the graph is drawn as svg with leaves on the left and the output on the right, each
+//! node placed in the column of its longest path from a leaf. hovering a node shows its
+//! operation, construction location and annotations, value and the adjoint of the output
+//! wrt it; clicking a node highlights the
+//! paths from it to the output. the page can be zoomed with the mouse wheel and panned by
+//! dragging, and needs no network access
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::core::{topo_order, PtrVWrap};
+use crate::valtype::ValType;
+
+const COLUMN: usize = 160;
+const ROW: usize = 56;
+const WIDTH: usize = 120;
+const HEIGHT: usize = 36;
+
+const SCRIPT: &str = r#"
+const svg = document.querySelector('svg');
+let box = svg.viewBox.baseVal;
+let drag = null;
+svg.addEventListener('wheel', e => {
+  e.preventDefault();
+  const k = e.deltaY > 0 ? 1.2 : 1 / 1.2;
+  const r = svg.getBoundingClientRect();
+  const x = box.x + (e.clientX - r.left) / r.width * box.width;
+  const y = box.y + (e.clientY - r.top) / r.height * box.height;
+  box.x = x - (x - box.x) * k;
+  box.y = y - (y - box.y) * k;
+  box.width *= k;
+  box.height *= k;
+});
+svg.addEventListener('mousedown', e => { drag = [e.clientX, e.clientY]; });
+window.addEventListener('mouseup', () => { drag = null; });
+window.addEventListener('mousemove', e => {
+  if (!drag) return;
+  const r = svg.getBoundingClientRect();
+  box.x -= (e.clientX - drag[0]) / r.width * box.width;
+  box.y -= (e.clientY - drag[1]) / r.height * box.height;
+  drag = [e.clientX, e.clientY];
+});
+const edges = [...document.querySelectorAll('.edge')];
+function select(id) {
+  const on = new Set([id]);
+  const stack = [id];
+  while (stack.length) {
+    const n = stack.pop();
+    for (const e of edges) {
+      if (e.dataset.from === n && !on.has(e.dataset.to)) {
+        on.add(e.dataset.to);
+        stack.push(e.dataset.to);
+      }
+    }
+  }
+  for (const g of document.querySelectorAll('.node')) {
+    g.classList.toggle('on', on.has(g.id));
+  }
+  for (const e of edges) {
+    e.classList.toggle('on', on.has(e.dataset.from) && on.has(e.dataset.to));
+  }
+}
+for (const g of document.querySelectorAll('.node')) {
+  g.addEventListener('click', e => { e.stopPropagation(); select(g.id); });
+}
+"#;
+
+const STYLE: &str = r#"
+body { margin: 0; font-family: sans-serif; }
+svg { width: 100vw; height: 100vh; cursor: grab; }
+.node rect { fill: #eef3fb; stroke: #4a6fa5; }
+.node.leaf rect { fill: #fdf3e1; stroke: #b07a1e; }
+.node.output rect { stroke-width: 3; }
+.node text { font-size: 12px; text-anchor: middle; pointer-events: none; }
+.edge { fill: none; stroke: #999; }
+.node.on rect { fill: #ffd8d8; stroke: #c0392b; }
+.edge.on { stroke: #c0392b; stroke-width: 2; }
+"#;
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// op name without the Op prefix and parameters
+fn label(op: &str) -> &str {
+    let name = op
+        .split(|c: char| !c.is_alphanumeric())
+        .next()
+        .unwrap_or(op);
+    name.strip_prefix("Op").unwrap_or(name)
+}
+
+/// html page showing the graph of output at the current leaf values
+pub fn to_html(output: &PtrVWrap) -> String {
+    output.clone().apply_fwd();
+    let adjoints: HashMap<PtrVWrap, ValType> = output.rev_retained().values().into_iter().collect();
+    let order = topo_order(std::slice::from_ref(output));
+
+    //column of the longest path from a leaf, row in order of appearance within the column
+    let mut pos: HashMap<PtrVWrap, (usize, usize)> = HashMap::new();
+    let mut rows: Vec<usize> = vec![];
+    for n in order.iter() {
+        let col = n.inputs().iter().map(|i| pos[i].0 + 1).max().unwrap_or(0);
+        if rows.len() <= col {
+            rows.resize(col + 1, 0);
+        }
+        pos.insert(n.clone(), (col, rows[col]));
+        rows[col] += 1;
+    }
+    let center = |n: &PtrVWrap| {
+        let (c, r) = pos[n];
+        (c * COLUMN + WIDTH / 2 + 20, r * ROW + HEIGHT / 2 + 20)
+    };
+    let ids: HashMap<PtrVWrap, usize> = order.iter().cloned().zip(0..).collect();
+    let width = rows.len() * COLUMN + 40;
+    let height = rows.iter().max().unwrap_or(&1) * ROW + 40;
+
+    let mut svg = String::new();
+    for n in order.iter() {
+        let (x1, y1) = center(n);
+        for i in n.inputs() {
+            let (x0, y0) = center(&i);
+            let (x0, x1) = (x0 + WIDTH / 2, x1 - WIDTH / 2);
+            let mid = (x0 + x1) / 2;
+            writeln!(
+                svg,
+                r#"<path class="edge" data-from="n{}" data-to="n{}" d="M{} {} C{} {} {} {} {} {}"/>"#,
+                ids[&i], ids[n], x0, y0, mid, y0, mid, y1, x1, y1
+            )
+            .unwrap();
+        }
+    }
+    for n in order.iter() {
+        let op = n.op_name();
+        let (x, y) = center(n);
+        let val = n.0.borrow().val;
+        let value = val.map_or("-".to_string(), |v| format!("{:.4}", f32::from(v)));
+        let adjoint = match adjoints.get(n) {
+            Some(a) => format!("{:.4}", f32::from(*a)),
+            None if n.is_frozen() => "frozen".to_string(),
+            None => "-".to_string(),
+        };
+        let mut class = String::from("node");
+        if n.inputs().is_empty() {
+            class.push_str(" leaf");
+        }
+        if n == output {
+            class.push_str(" output");
+        }
+        writeln!(
+            svg,
+            concat!(
+                r#"<g class="{}" id="n{}"><title>n{} {}{}&#10;value: {}&#10;adjoint: {}</title>"#,
+                r#"<rect x="{}" y="{}" width="{}" height="{}" rx="6"/>"#,
+                r#"<text x="{}" y="{}">{}</text><text x="{}" y="{}">{}</text></g>"#
+            ),
+            class,
+            ids[n],
+            ids[n],
+            escape(&op),
+            escape(&n.origin()),
+            value,
+            adjoint,
+            x - WIDTH / 2,
+            y - HEIGHT / 2,
+            WIDTH,
+            HEIGHT,
+            x,
+            y - 3,
+            escape(label(&op)),
+            x,
+            y + 12,
+            value
+        )
+        .unwrap();
+    }
+
+    format!(
+        concat!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>dynagrad graph</title>",
+            "<style>{}</style></head><body>\n",
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {} {}\">\n{}</svg>\n",
+            "<script>{}</script></body></html>\n"
+        ),
+        STYLE, width, height, svg, SCRIPT
+    )
+}
+
+/// writes the page of to_html to path
+pub fn write_html(output: &PtrVWrap, path: &Path) -> io::Result<()> {
+    fs::write(path, to_html(output))
+}
+
+#[test]
+fn test_html() {
+    use crate::core::{Leaf, Mul, Sin};
+
+    //y=a*sin(b)
+    let a = Leaf(ValType::F(2.));
+    let b = Leaf(ValType::F(0.));
+    let y = Mul(a.clone(), Sin(b.clone()));
+    let html = to_html(&y);
+    assert!(html.starts_with("<!DOCTYPE html>"));
+    assert_eq!(html.matches(r#"<g class="node"#).count(), 4);
+    assert_eq!(html.matches(r#"class="edge""#).count(), 3);
+    //dy/da=sin(b)=0, dy/db=a*cos(b)=2, dy/dy=1
+    assert!(html.contains(r#"id="n1"><title>n1 OpLeaf&#10;value: 0.0000&#10;adjoint: 2.0000"#));
+    assert!(html.contains("OpMul&#10;value: 0.0000&#10;adjoint: 1.0000"));
+    assert!(html.contains(r#"<g class="node output" id="n3">"#));
+    assert!(html.contains(r#"data-from="n2" data-to="n3""#));
+    //a single sweep of the graph does not touch the leaves
+    assert_eq!(a.adjoint(), None);
+
+    let path = std::env::temp_dir().join(format!("dynagrad_graph_{}.html", std::process::id()));
+    write_html(&y, &path).unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), html);
+    fs::remove_file(&path).unwrap();
+}